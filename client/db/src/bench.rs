@@ -32,6 +32,7 @@ use sp_runtime::traits::{Block as BlockT, HashFor};
 use sp_runtime::Storage;
 use sp_state_machine::{
 	DBValue, backend::Backend as StateBackend, StorageCollection, ChildStorageCollection, ProofRecorder,
+	OwnedPrefix,
 };
 use kvdb::{KeyValueDB, DBTransaction};
 use crate::storage_cache::{CachingState, SharedCache, new_shared_cache};
@@ -373,6 +374,13 @@ impl<B: BlockT> StateBackend<HashFor<B>> for BenchmarkingState<B> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.storage_root(delta))
 	}
 
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.borrow().as_ref().map_or(Default::default(), |s| s.storage_root_with_pruning(delta))
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -381,6 +389,15 @@ impl<B: BlockT> StateBackend<HashFor<B>> for BenchmarkingState<B> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.child_storage_root(child_info, delta))
 	}
 
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, bool, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.borrow().as_ref()
+			.map_or(Default::default(), |s| s.child_storage_root_with_pruning(child_info, delta))
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.pairs())
 	}
@@ -34,7 +34,7 @@ use sp_runtime::traits::{
 	Block as BlockT, Header as HeaderT, HashFor, NumberFor, One, Zero, CheckedSub,
 };
 use sp_runtime::generic::{BlockId, DigestItem, ChangesTrieSignal};
-use sp_state_machine::{ChangesTrieBuildCache, ChangesTrieCacheAction};
+use sp_state_machine::{ChangesTrieBuildCache, ChangesTrieCacheAction, ChangesTrieError};
 use crate::{Database, DbHash};
 use crate::utils::{self, Meta, meta_keys};
 use crate::cache::{
@@ -408,25 +408,27 @@ impl<Block: BlockT> sp_state_machine::ChangesTrieRootsStorage<HashFor<Block>, Nu
 	fn build_anchor(
 		&self,
 		hash: Block::Hash,
-	) -> Result<sp_state_machine::ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>, String> {
+	) -> Result<sp_state_machine::ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>, ChangesTrieError<NumberFor<Block>>> {
 		utils::read_header::<Block>(&*self.db, self.key_lookup_column, self.header_column, BlockId::Hash(hash))
-			.map_err(|e| e.to_string())
+			.map_err(|e| ChangesTrieError::Storage(e.to_string()))
 			.and_then(|maybe_header| maybe_header.map(|header|
 				sp_state_machine::ChangesTrieAnchorBlockId {
 					hash,
 					number: *header.number(),
 				}
-			).ok_or_else(|| format!("Unknown header: {}", hash)))
+			).ok_or_else(|| ChangesTrieError::UnknownBlock(hash.as_ref().to_vec())))
 	}
 
 	fn root(
 		&self,
 		anchor: &sp_state_machine::ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>,
 		block: NumberFor<Block>,
-	) -> Result<Option<Block::Hash>, String> {
+	) -> Result<Option<Block::Hash>, ChangesTrieError<NumberFor<Block>>> {
 		// check API requirement: we can't get NEXT block(s) based on anchor
 		if block > anchor.number {
-			return Err(format!("Can't get changes trie root at {} using anchor at {}", block, anchor.number));
+			return Err(ChangesTrieError::Storage(
+				format!("Can't get changes trie root at {} using anchor at {}", block, anchor.number),
+			));
 		}
 
 		// we need to get hash of the block to resolve changes trie root
@@ -439,7 +441,7 @@ impl<Block: BlockT> sp_state_machine::ChangesTrieRootsStorage<HashFor<Block>, Nu
 			let mut current_hash: Block::Hash = convert_hash(&anchor.hash);
 			let maybe_anchor_header: Block::Header = utils::require_header::<Block>(
 				&*self.db, self.key_lookup_column, self.header_column, BlockId::Number(current_num)
-			).map_err(|e| e.to_string())?;
+			).map_err(|e| ChangesTrieError::Storage(e.to_string()))?;
 			if maybe_anchor_header.hash() == current_hash {
 				// if anchor is canonicalized, then the block is also canonicalized
 				BlockId::Number(block)
@@ -450,7 +452,7 @@ impl<Block: BlockT> sp_state_machine::ChangesTrieRootsStorage<HashFor<Block>, Nu
 				while current_num != block {
 					let current_header: Block::Header = utils::require_header::<Block>(
 						&*self.db, self.key_lookup_column, self.header_column, BlockId::Hash(current_hash)
-					).map_err(|e| e.to_string())?;
+					).map_err(|e| ChangesTrieError::Storage(e.to_string()))?;
 
 					current_hash = *current_header.parent_hash();
 					current_num = current_num - One::one();
@@ -467,7 +469,7 @@ impl<Block: BlockT> sp_state_machine::ChangesTrieRootsStorage<HashFor<Block>, Nu
 				self.header_column,
 				block_id,
 			)
-			.map_err(|e| e.to_string())?
+			.map_err(|e| ChangesTrieError::Storage(e.to_string()))?
 			.digest()
 			.log(DigestItem::as_changes_trie_root)
 			.cloned()
@@ -492,7 +494,7 @@ where
 		self.build_cache.read().with_changed_keys(root, functor)
 	}
 
-	fn get(&self, key: &Block::Hash, _prefix: Prefix) -> Result<Option<Vec<u8>>, String> {
+	fn get(&self, key: &Block::Hash, _prefix: Prefix) -> Result<Option<Vec<u8>>, ChangesTrieError<NumberFor<Block>>> {
 		Ok(self.db.get(self.changes_tries_column, key.as_ref()))
 	}
 }
@@ -829,15 +831,18 @@ mod tests {
 		let config_at_1 = Some(ChangesTrieConfiguration {
 			digest_interval: 4,
 			digest_levels: 2,
+			..Default::default()
 		});
 		let config_at_3 = Some(ChangesTrieConfiguration {
 			digest_interval: 8,
 			digest_levels: 1,
+			..Default::default()
 		});
 		let config_at_5 = None;
 		let config_at_7 = Some(ChangesTrieConfiguration {
 			digest_interval: 8,
 			digest_levels: 1,
+			..Default::default()
 		});
 
 		// insert some blocks
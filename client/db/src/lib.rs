@@ -78,7 +78,7 @@ use sp_runtime::traits::{
 use sp_state_machine::{
 	DBValue, ChangesTrieTransaction, ChangesTrieCacheAction, UsageInfo as StateUsageInfo,
 	StorageCollection, ChildStorageCollection, OffchainChangesCollection,
-	backend::Backend as StateBackend, StateMachineStats, IndexOperation,
+	backend::Backend as StateBackend, StateMachineStats, IndexOperation, OwnedPrefix,
 };
 use crate::utils::{DatabaseType, Meta, meta_keys, read_db, read_meta};
 use crate::changes_tries_storage::{DbChangesTrieStorage, DbChangesTrieStorageTransaction};
@@ -241,6 +241,13 @@ impl<B: BlockT> StateBackend<HashFor<B>> for RefTrackingState<B> {
 		self.state.storage_root(delta)
 	}
 
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.storage_root_with_pruning(delta)
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -249,6 +256,14 @@ impl<B: BlockT> StateBackend<HashFor<B>> for RefTrackingState<B> {
 		self.state.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, bool, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.child_storage_root_with_pruning(child_info, delta)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.state.pairs()
 	}
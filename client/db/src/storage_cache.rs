@@ -31,7 +31,7 @@ use sp_core::hexdisplay::HexDisplay;
 use sp_core::storage::ChildInfo;
 use sp_state_machine::{
 	backend::Backend as StateBackend, TrieBackend, StorageKey, StorageValue,
-	StorageCollection, ChildStorageCollection,
+	StorageCollection, ChildStorageCollection, OwnedPrefix,
 };
 use log::trace;
 use crate::{utils::Meta, stats::StateUsageStats};
@@ -670,6 +670,13 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 		self.state.storage_root(delta)
 	}
 
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.storage_root_with_pruning(delta)
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -678,6 +685,14 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 		self.state.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, bool, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.state.child_storage_root_with_pruning(child_info, delta)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.state.pairs()
 	}
@@ -864,6 +879,13 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Syncin
 		self.caching_state().storage_root(delta)
 	}
 
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.caching_state().storage_root_with_pruning(delta)
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -872,6 +894,14 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Syncin
 		self.caching_state().child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, bool, Self::Transaction, Vec<(OwnedPrefix, B::Hash)>) where B::Hash: Ord {
+		self.caching_state().child_storage_root_with_pruning(child_info, delta)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.caching_state().pairs()
 	}
@@ -30,7 +30,7 @@ use sp_core::storage::{well_known_keys, ChildInfo};
 use sp_core::offchain::storage::InMemOffchainStorage;
 use sp_state_machine::{
 	Backend as StateBackend, TrieBackend, InMemoryBackend, ChangesTrieTransaction,
-	StorageCollection, ChildStorageCollection, IndexOperation,
+	StorageCollection, ChildStorageCollection, IndexOperation, OwnedPrefix,
 };
 use sp_runtime::{generic::BlockId, Justification, Justifications, Storage};
 use sp_runtime::traits::{Block as BlockT, NumberFor, Zero, Header, HashFor};
@@ -520,6 +520,17 @@ impl<H: Hasher> StateBackend<H> for GenesisOrUnavailableState<H>
 		}
 	}
 
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		match *self {
+			GenesisOrUnavailableState::Genesis(ref state) =>
+				state.storage_root_with_pruning(delta),
+			GenesisOrUnavailableState::Unavailable => Default::default(),
+		}
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -535,6 +546,21 @@ impl<H: Hasher> StateBackend<H> for GenesisOrUnavailableState<H>
 		}
 	}
 
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		match *self {
+			GenesisOrUnavailableState::Genesis(ref state) => {
+				let (root, is_equal, _, pruned) = state.child_storage_root_with_pruning(child_info, delta);
+				(root, is_equal, Default::default(), pruned)
+			},
+			GenesisOrUnavailableState::Unavailable =>
+				(H::Out::default(), true, Default::default(), Vec::new()),
+		}
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		match *self {
 			GenesisOrUnavailableState::Genesis(ref state) => state.pairs(),
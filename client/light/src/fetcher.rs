@@ -30,7 +30,7 @@ use sp_runtime::traits::{
 	AtLeast32Bit, CheckedConversion,
 };
 use sp_state_machine::{
-	ChangesTrieRootsStorage, ChangesTrieAnchorBlockId, ChangesTrieConfigurationRange,
+	ChangesTrieRootsStorage, ChangesTrieAnchorBlockId, ChangesTrieConfigurationRange, ChangesTrieError,
 	InMemoryChangesTrieStorage, TrieBackend, read_proof_check, key_changes_proof_check_with_db,
 	read_child_proof_check,
 };
@@ -142,7 +142,7 @@ impl<E, H, B: BlockT, S: BlockchainStorage<B>> LightDataChecker<E, H, B, S> {
 				remote_max_block,
 				request.storage_key.as_ref(),
 				&request.key)
-			.map_err(|err| ClientError::ChangesTrieAccessFailed(err))?;
+			.map_err(|err| ClientError::ChangesTrieAccessFailed(err.to_string()))?;
 			result.extend(result_range);
 		}
 
@@ -310,21 +310,21 @@ struct RootsStorage<'a, Number: AtLeast32Bit, Hash: 'a> {
 impl<'a, H, Number, Hash> ChangesTrieRootsStorage<H, Number> for RootsStorage<'a, Number, Hash>
 	where
 		H: Hasher,
-		Number: std::fmt::Display + std::hash::Hash + Clone + AtLeast32Bit + Encode + Decode + Send + Sync + 'static,
+		Number: std::fmt::Display + std::fmt::Debug + std::hash::Hash + Clone + AtLeast32Bit + Encode + Decode + Send + Sync + 'static,
 		Hash: 'a + Send + Sync + Clone + AsRef<[u8]>,
 {
 	fn build_anchor(
 		&self,
 		_hash: H::Out,
-	) -> Result<sp_state_machine::ChangesTrieAnchorBlockId<H::Out, Number>, String> {
-		Err("build_anchor is only called when building block".into())
+	) -> Result<sp_state_machine::ChangesTrieAnchorBlockId<H::Out, Number>, ChangesTrieError<Number>> {
+		Err(ChangesTrieError::Storage("build_anchor is only called when building block".into()))
 	}
 
 	fn root(
 		&self,
 		_anchor: &ChangesTrieAnchorBlockId<H::Out, Number>,
 		block: Number,
-	) -> Result<Option<H::Out>, String> {
+	) -> Result<Option<H::Out>, ChangesTrieError<Number>> {
 		// we can't ask for roots from parallel forks here => ignore anchor
 		let root = if block < self.roots.0 {
 			self.prev_roots.get(&Number::unique_saturated_from(block)).cloned()
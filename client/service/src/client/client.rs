@@ -50,7 +50,7 @@ use sp_runtime::{
 };
 use sp_state_machine::{
 	DBValue, Backend as StateBackend, ChangesTrieAnchorBlockId,
-	prove_read, prove_child_read, ChangesTrieRootsStorage, ChangesTrieStorage,
+	prove_read, prove_child_read, ChangesTrieRootsStorage, ChangesTrieStorage, ChangesTrieError,
 	ChangesTrieConfigurationRange, key_changes, key_changes_proof,
 	prove_range_read_with_size, read_range_proof_check,
 };
@@ -451,9 +451,10 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 		impl<'a, Block: BlockT> ChangesTrieRootsStorage<HashFor<Block>, NumberFor<Block>> for
 			AccessedRootsRecorder<'a, Block>
 		{
-			fn build_anchor(&self, hash: Block::Hash)
-				-> Result<ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>, String>
-			{
+			fn build_anchor(&self, hash: Block::Hash) -> Result<
+				ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>,
+				ChangesTrieError<NumberFor<Block>>,
+			> {
 				self.storage.build_anchor(hash)
 			}
 
@@ -461,7 +462,7 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 				&self,
 				anchor: &ChangesTrieAnchorBlockId<Block::Hash, NumberFor<Block>>,
 				block: NumberFor<Block>,
-			) -> Result<Option<Block::Hash>, String> {
+			) -> Result<Option<Block::Hash>, ChangesTrieError<NumberFor<Block>>> {
 				let root = self.storage.root(anchor, block)?;
 				if block < self.min {
 					if let Some(ref root) = root {
@@ -492,7 +493,11 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 				self.storage.with_cached_changed_keys(root, functor)
 			}
 
-			fn get(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>, String> {
+			fn get(
+				&self,
+				key: &Block::Hash,
+				prefix: Prefix,
+			) -> Result<Option<DBValue>, ChangesTrieError<NumberFor<Block>>> {
 				self.storage.get(key, prefix)
 			}
 		}
@@ -535,7 +540,7 @@ impl<B, E, Block, RA> Client<B, E, Block, RA> where
 				storage_key,
 				&key.0,
 			)
-			.map_err(|err| sp_blockchain::Error::ChangesTrieAccessFailed(err))?;
+			.map_err(|err| sp_blockchain::Error::ChangesTrieAccessFailed(err.to_string()))?;
 			proof.extend(proof_range);
 		}
 
@@ -1635,7 +1640,7 @@ impl<B, E, Block, RA> StorageProvider<Block, B> for Client<B, E, Block, RA> wher
 				storage_key,
 				&key.0)
 				.and_then(|r| r.map(|r| r.map(|(block, tx)| (block, tx))).collect::<Result<_, _>>())
-				.map_err(|err| sp_blockchain::Error::ChangesTrieAccessFailed(err))?;
+				.map_err(|err| sp_blockchain::Error::ChangesTrieAccessFailed(err.to_string()))?;
 			result.extend(result_range);
 		}
 
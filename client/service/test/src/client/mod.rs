@@ -1904,6 +1904,7 @@ fn imports_blocks_with_changes_tries_config_change() {
 		.changes_trie_config(Some(ChangesTrieConfiguration {
 			digest_interval: 4,
 			digest_levels: 2,
+			..Default::default()
 		})).build();
 
 	// ===================================================================
@@ -1943,6 +1944,7 @@ fn imports_blocks_with_changes_tries_config_change() {
 		block.push_changes_trie_configuration_update(Some(ChangesTrieConfiguration {
 			digest_interval: 5,
 			digest_levels: 1,
+			..Default::default()
 		})).unwrap();
 		let block = block.build().unwrap().block;
 		block_on(client.import(BlockOrigin::Own, block)).unwrap();
@@ -1969,6 +1971,7 @@ fn imports_blocks_with_changes_tries_config_change() {
 		block.push_changes_trie_configuration_update(Some(ChangesTrieConfiguration {
 			digest_interval: 3,
 			digest_levels: 1,
+			..Default::default()
 		})).unwrap();
 		let block = block.build().unwrap().block;
 		block_on(client.import(BlockOrigin::Own, block)).unwrap();
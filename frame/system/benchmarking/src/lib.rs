@@ -78,6 +78,7 @@ benchmarks! {
 		let changes_trie_config = ChangesTrieConfiguration {
 			digest_interval: d,
 			digest_levels: d,
+			..Default::default()
 		};
 	}: _(RawOrigin::Root, Some(changes_trie_config))
 	verify {
@@ -19,12 +19,25 @@
 
 #[cfg(any(feature = "std", test))]
 use serde::{Serialize, Deserialize};
-use codec::{Encode, Decode};
+use codec::{Encode, Decode, EncodeLike, Error, Input, Output};
+use hash_db::Hasher;
 use num_traits::Zero;
+use sp_std::vec::Vec;
+
+/// Value of the encoded `digest_levels` that marks the extended encoding of the configuration.
+///
+/// Configurations that only use `digest_interval`, `digest_levels` and default values of other
+/// fields are encoded exactly as before other fields have been introduced, so their encoding
+/// (and hence values, stored under the `CHANGES_TRIE_CONFIG` key and signalled in digests)
+/// doesn't change. Other configurations are encoded as `digest_interval`, this marker,
+/// `digest_levels` and all other fields.
+const EXTENDED_ENCODING_MARKER: u32 = u32::MAX;
 
 /// Substrate changes trie configuration.
+///
+/// See `EXTENDED_ENCODING_MARKER` for details on SCALE encoding of the configuration.
 #[cfg_attr(any(feature = "std", test), derive(Serialize, Deserialize, parity_util_mem::MallocSizeOf))]
-#[derive(Debug, Clone, PartialEq, Eq, Default, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ChangesTrieConfiguration {
 	/// Interval (in blocks) at which level1-digests are created. Digests are not
 	/// created when this is less or equal to 1.
@@ -37,6 +50,36 @@ pub struct ChangesTrieConfiguration {
 	/// && maximal digests interval will be truncated to the last interval that fits
 	/// `u32` limits.
 	pub digest_levels: u32,
+	/// Changes of top-level storage keys that start with any of these prefixes are not
+	/// inserted into changes tries. Since these keys never enter non-digest tries, they
+	/// are also missing from digest tries.
+	///
+	/// Defaults to `ChangesTrieConfiguration::default_ignored_prefixes()`, which is also what
+	/// configurations in the original encoding are decoded with.
+	///
+	/// This is a part of the consensus-critical configuration: changing the prefixes
+	/// changes roots of all changes tries that are built after the change.
+	#[cfg_attr(
+		any(feature = "std", test),
+		serde(default = "ChangesTrieConfiguration::default_ignored_prefixes"),
+	)]
+	pub ignored_prefixes: Vec<Vec<u8>>,
+	/// Digest values that list more than this number of blocks are stored as the compact
+	/// bitmap of blocks, instead of the explicit list. `None` means that lists are always
+	/// stored explicitly.
+	///
+	/// This is a part of the consensus-critical configuration: changing the threshold
+	/// changes roots of all digest tries that are built after the change.
+	#[cfg_attr(any(feature = "std", test), serde(default))]
+	pub digest_value_threshold: Option<u32>,
+	/// If true, extrinsic lists of keys that have been deleted in the block (i.e. that have
+	/// existed before the block and don't exist after it) are marked with the reserved deletion
+	/// index, so that changes trie users could tell deletions from updates.
+	///
+	/// This is a part of the consensus-critical configuration: changing the flag changes roots
+	/// of all changes tries that are built after the change.
+	#[cfg_attr(any(feature = "std", test), serde(default))]
+	pub record_deletions: bool,
 }
 
 /// Substrate changes trie configuration range.
@@ -50,10 +93,96 @@ pub struct ChangesTrieConfigurationRange<Number, Hash> {
 	pub config: Option<ChangesTrieConfiguration>,
 }
 
+impl Default for ChangesTrieConfiguration {
+	fn default() -> Self {
+		Self {
+			digest_interval: 0,
+			digest_levels: 0,
+			ignored_prefixes: Self::default_ignored_prefixes(),
+			digest_value_threshold: None,
+			record_deletions: false,
+		}
+	}
+}
+
+impl Encode for ChangesTrieConfiguration {
+	fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+		self.digest_interval.encode_to(dest);
+		if !self.requires_extended_encoding() {
+			self.digest_levels.encode_to(dest);
+			return;
+		}
+
+		EXTENDED_ENCODING_MARKER.encode_to(dest);
+		self.digest_levels.encode_to(dest);
+		self.ignored_prefixes.encode_to(dest);
+		self.digest_value_threshold.encode_to(dest);
+		self.record_deletions.encode_to(dest);
+	}
+}
+
+impl EncodeLike for ChangesTrieConfiguration {}
+
+impl Decode for ChangesTrieConfiguration {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let digest_interval = u32::decode(input)?;
+		let digest_levels = u32::decode(input)?;
+		if digest_levels != EXTENDED_ENCODING_MARKER {
+			return Ok(Self::new(digest_interval, digest_levels));
+		}
+
+		Ok(Self {
+			digest_interval,
+			digest_levels: u32::decode(input)?,
+			ignored_prefixes: Decode::decode(input)?,
+			digest_value_threshold: Decode::decode(input)?,
+			record_deletions: Decode::decode(input)?,
+		})
+	}
+}
+
 impl ChangesTrieConfiguration {
 	/// Create new configuration given digest interval and levels.
+	///
+	/// Changes of `default_ignored_prefixes()` are skipped and all other options are disabled.
+	/// The configuration is encoded using the original layout of two fields.
 	pub fn new(digest_interval: u32, digest_levels: u32) -> Self {
-		Self { digest_interval, digest_levels }
+		Self {
+			digest_interval,
+			digest_levels,
+			..Default::default()
+		}
+	}
+
+	/// Prefixes of system keys that are changed in every block and are never queried.
+	///
+	/// These are ignored by default configurations and by configurations, decoded from the
+	/// original encoding. To keep changes of these keys, the configuration must have other
+	/// `ignored_prefixes`.
+	pub fn default_ignored_prefixes() -> Vec<Vec<u8>> {
+		sp_std::vec![crate::storage::well_known_keys::EXTRINSIC_INDEX.to_vec()]
+	}
+
+	/// Canonical hash of the configuration, i.e. hash of its SCALE encoding.
+	///
+	/// Could be included into genesis and headers, so that nodes are able to detect
+	/// configuration mismatch.
+	pub fn hash<H: Hasher>(&self) -> H::Out {
+		H::hash(&self.encode())
+	}
+
+	/// Returns true if configuration can't be encoded using the original layout of two
+	/// fields.
+	fn requires_extended_encoding(&self) -> bool {
+		self.digest_levels == EXTENDED_ENCODING_MARKER
+			|| self.ignored_prefixes != Self::default_ignored_prefixes()
+			|| self.digest_value_threshold.is_some()
+			|| self.record_deletions
+	}
+
+	/// Is changes of given top-level storage key ignored?
+	pub fn is_ignored_key(&self, key: &[u8]) -> bool {
+		self.ignored_prefixes.iter().any(|prefix| key.starts_with(prefix))
 	}
 
 	/// Is digest build enabled?
@@ -77,21 +206,43 @@ impl ChangesTrieConfiguration {
 			&& ((block - zero) % self.digest_interval.into()).is_zero()
 	}
 
+	/// Is this configuration valid?
+	///
+	/// Configuration that requires digests to be created must have digest interval
+	/// greater than one.
+	pub fn is_valid(&self) -> bool {
+		self.digest_levels == 0 || self.digest_interval > 1
+	}
+
 	/// Returns max digest interval. One if digests are not created at all.
 	pub fn max_digest_interval(&self) -> u32 {
+		self.max_digest_level_and_interval().1
+	}
+
+	/// Returns number of digest levels that are actually created. It is less than
+	/// `digest_levels` if max digest interval doesn't fit `u32` limits.
+	pub fn effective_digest_levels(&self) -> u32 {
+		self.max_digest_level_and_interval().0
+	}
+
+	/// Returns max digest level and interval, capped by `u32` limits.
+	fn max_digest_level_and_interval(&self) -> (u32, u32) {
 		if !self.is_digest_build_enabled() {
-			return 1;
+			return (0, 1);
 		}
 
-		// we'll get >1 loop iteration only when bad configuration parameters are selected
-		let mut current_level = self.digest_levels;
-		loop {
-			if let Some(max_digest_interval) = self.digest_interval.checked_pow(current_level) {
-				return max_digest_interval;
+		// we'll stop before digest_levels only when bad configuration parameters are selected
+		let mut current_level = 0;
+		let mut current_interval = 1u32;
+		while current_level < self.digest_levels {
+			match current_interval.checked_mul(self.digest_interval) {
+				Some(next_interval) => current_interval = next_interval,
+				None => break,
 			}
-
-			current_level -= 1;
+			current_level += 1;
 		}
+
+		(current_level, current_interval)
 	}
 
 	/// Returns max level digest block number that has been created at block <= passed block number.
@@ -205,12 +356,49 @@ impl ChangesTrieConfiguration {
 
 #[cfg(test)]
 mod tests {
+	use codec::{Encode, Decode};
 	use super::ChangesTrieConfiguration;
 
 	fn config(interval: u32, levels: u32) -> ChangesTrieConfiguration {
 		ChangesTrieConfiguration {
 			digest_interval: interval,
 			digest_levels: levels,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn configuration_without_options_has_original_encoding() {
+		#[derive(Encode, Decode)]
+		struct OriginalConfiguration {
+			digest_interval: u32,
+			digest_levels: u32,
+		}
+
+		let original = OriginalConfiguration { digest_interval: 8, digest_levels: 4 }.encode();
+		assert_eq!(config(8, 4).encode(), original);
+		assert_eq!(ChangesTrieConfiguration::decode(&mut &original[..]).unwrap(), config(8, 4));
+		assert_eq!(ChangesTrieConfiguration::new(8, 4), config(8, 4));
+		assert_eq!(ChangesTrieConfiguration::default(), config(0, 0));
+		assert_eq!(
+			config(8, 4).ignored_prefixes,
+			ChangesTrieConfiguration::default_ignored_prefixes(),
+		);
+		assert!(config(8, 4).is_ignored_key(crate::storage::well_known_keys::EXTRINSIC_INDEX));
+	}
+
+	#[test]
+	fn configuration_with_options_roundtrips() {
+		let configs = vec![
+			config(2, ::std::u32::MAX),
+			ChangesTrieConfiguration { ignored_prefixes: Vec::new(), ..config(8, 4) },
+			ChangesTrieConfiguration { ignored_prefixes: vec![b":event".to_vec()], ..config(8, 4) },
+			ChangesTrieConfiguration { digest_value_threshold: Some(16), ..config(8, 4) },
+			ChangesTrieConfiguration { record_deletions: true, ..config(8, 4) },
+		];
+		for config in configs {
+			let encoded = (Some(config.clone()), 42u32).encode();
+			assert_eq!(Decode::decode(&mut &encoded[..]), Ok((Some(config), 42u32)));
 		}
 	}
 
@@ -264,12 +452,45 @@ mod tests {
 		test_with_zero(17);
 	}
 
+	#[test]
+	fn is_valid_works() {
+		assert!(config(0, 0).is_valid());
+		assert!(config(1, 0).is_valid());
+		assert!(config(2, 1).is_valid());
+		assert!(config(4, 64).is_valid());
+		assert!(!config(0, 1).is_valid());
+		assert!(!config(1, 2).is_valid());
+	}
+
 	#[test]
 	fn max_digest_interval_works() {
 		assert_eq!(config(0, 0).max_digest_interval(), 1);
 		assert_eq!(config(2, 2).max_digest_interval(), 4);
 		assert_eq!(config(8, 4).max_digest_interval(), 4096);
 		assert_eq!(config(::std::u32::MAX, 1024).max_digest_interval(), ::std::u32::MAX);
+		assert_eq!(config(4, 64).max_digest_interval(), 1 << 30);
+		assert_eq!(config(2, ::std::u32::MAX).max_digest_interval(), 1 << 31);
+	}
+
+	#[test]
+	fn effective_digest_levels_works() {
+		assert_eq!(config(0, 0).effective_digest_levels(), 0);
+		assert_eq!(config(1, 8).effective_digest_levels(), 0);
+		assert_eq!(config(8, 4).effective_digest_levels(), 4);
+		assert_eq!(config(4, 64).effective_digest_levels(), 15);
+		assert_eq!(config(::std::u32::MAX, 1024).effective_digest_levels(), 1);
+	}
+
+	#[test]
+	fn digest_level_at_block_is_capped_when_interval_overflows() {
+		assert_eq!(
+			config(4, 64).digest_level_at_block(0u64, 1u64 << 30),
+			Some((15, 1 << 30, 1 << 28)),
+		);
+		assert_eq!(
+			config(4, 64).digest_level_at_block(0u64, 1u64 << 40),
+			Some((15, 1 << 30, 1 << 28)),
+		);
 	}
 
 	#[test]
@@ -0,0 +1,108 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rand::{SeedableRng, rngs::StdRng};
+use sp_core::{Blake2Hasher, ChangesTrieConfiguration};
+use sp_state_machine::{
+	InMemoryBackend, InMemoryChangesTrieStorage, ChangesTrieAnchorBlockId, ChangesTrieState,
+	ChangesTrieConfigurationRange, key_changes, try_build_changes_trie,
+	changes_trie_bench_utils::{SyntheticChain, key, random_changes},
+};
+
+criterion_group!(
+	benches,
+	build_non_digest_block,
+	build_level1_digest_block,
+	build_level2_digest_block,
+	drilldown_key_changes,
+);
+criterion_main!(benches);
+
+/// Seed of the RNG that generates changes of all benchmarks.
+const SEED: u64 = 42;
+/// Number of keys that are changed in every block of synthetic chains.
+const KEYS_PER_BLOCK: u32 = 100;
+/// Number of distinct keys that are changed in synthetic chains.
+const KEY_SPACE: u32 = 10_000;
+
+fn config(digest_interval: u32, digest_levels: u32) -> ChangesTrieConfiguration {
+	ChangesTrieConfiguration { digest_interval, digest_levels, ..Default::default() }
+}
+
+fn build_non_digest_block(c: &mut Criterion) {
+	let mut group = c.benchmark_group("build non-digest block");
+	group.sample_size(10);
+	for changed_keys in vec![100, 10_000, 100_000] {
+		let changes = random_changes(&mut StdRng::seed_from_u64(SEED), changed_keys, u32::max_value(), 100);
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryChangesTrieStorage::<Blake2Hasher, u64>::new();
+		let state = ChangesTrieState::new(config(16, 2), 0, &storage);
+		let parent = ChangesTrieAnchorBlockId { hash: Default::default(), number: 0 };
+
+		group.bench_with_input(BenchmarkId::from_parameter(changed_keys), &changes, |b, changes| b.iter(|| {
+			try_build_changes_trie(&backend, Some(&state), changes, &parent).unwrap()
+		}));
+	}
+	group.finish();
+}
+
+/// Build the digest block that follows the synthetic chain of given configuration.
+fn build_digest_block(c: &mut Criterion, name: &str, config: ChangesTrieConfiguration) {
+	let parent_number = config.max_digest_interval() as u64 - 1;
+	let chain = SyntheticChain::generate(config.clone(), SEED, parent_number, KEYS_PER_BLOCK, KEY_SPACE);
+	let changes = random_changes(&mut StdRng::seed_from_u64(SEED + 1), KEYS_PER_BLOCK, KEY_SPACE, 100);
+	let backend = InMemoryBackend::<Blake2Hasher>::default();
+	let state = ChangesTrieState::new(config, 0, &chain.storage);
+	let parent = ChangesTrieAnchorBlockId { hash: Default::default(), number: parent_number };
+
+	let mut group = c.benchmark_group(name);
+	group.sample_size(10);
+	group.bench_function("build", |b| b.iter(|| {
+		try_build_changes_trie(&backend, Some(&state), &changes, &parent).unwrap()
+	}));
+	group.finish();
+}
+
+fn build_level1_digest_block(c: &mut Criterion) {
+	build_digest_block(c, "build level1 digest block (interval 16)", config(16, 1))
+}
+
+fn build_level2_digest_block(c: &mut Criterion) {
+	build_digest_block(c, "build level2 digest block (interval 16, levels 2)", config(16, 2))
+}
+
+fn drilldown_key_changes(c: &mut Criterion) {
+	let chain = SyntheticChain::generate(config(16, 2), SEED, 10_000, KEYS_PER_BLOCK, KEY_SPACE);
+	let anchor = ChangesTrieAnchorBlockId { hash: Default::default(), number: chain.best_block };
+	let key = key(0);
+
+	let mut group = c.benchmark_group("key changes drilldown over 10k blocks");
+	group.sample_size(10);
+	group.bench_function("drilldown", |b| b.iter(|| {
+		key_changes::<Blake2Hasher, u64>(
+			ChangesTrieConfigurationRange { config: &chain.config, zero: 0, end: None },
+			&chain.storage,
+			1,
+			&anchor,
+			chain.best_block,
+			None,
+			&key,
+		).unwrap().collect::<Result<Vec<_>, _>>().unwrap()
+	}));
+	group.finish();
+}
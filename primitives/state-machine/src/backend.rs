@@ -31,6 +31,9 @@ use sp_std::vec::Vec;
 #[cfg(feature = "std")]
 use sp_core::traits::RuntimeCode;
 
+/// Owned version of the trie node prefix (`hash_db::Prefix`).
+pub type OwnedPrefix = (Vec<u8>, Option<u8>);
+
 /// A state backend is used to read state data and can have changes committed
 /// to it.
 ///
@@ -83,6 +86,30 @@ pub trait Backend<H: Hasher>: sp_std::fmt::Debug {
 		Ok(self.child_storage(child_info, key)?.is_some())
 	}
 
+	/// For every given key, true if it exists in storage.
+	///
+	/// Existence is `None` if the backend lacks data that is required to check it (e.g. when it
+	/// has been created from a proof that doesn't include this data).
+	///
+	/// Backends may override this to answer all checks at once.
+	fn exists_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<bool>>, Self::Error> {
+		keys.iter().map(|key| self.exists_storage(key).map(Some)).collect()
+	}
+
+	/// For every given key, true if it exists in child storage.
+	///
+	/// Existence is `None` if the backend lacks data that is required to check it (e.g. when it
+	/// has been created from a proof that doesn't include this data).
+	///
+	/// Backends may override this to answer all checks at once.
+	fn exists_child_storage_batch(
+		&self,
+		child_info: &ChildInfo,
+		keys: &[&[u8]],
+	) -> Result<Vec<Option<bool>>, Self::Error> {
+		keys.iter().map(|key| self.exists_child_storage(child_info, key).map(Some)).collect()
+	}
+
 	/// Return the next key in storage in lexicographic order or `None` if there is no value.
 	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error>;
 
@@ -146,6 +173,19 @@ pub trait Backend<H: Hasher>: sp_std::fmt::Debug {
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, Self::Transaction) where H::Out: Ord;
 
+	/// Same as `storage_root`, but also returns prefixes and hashes of trie nodes that are
+	/// replaced or removed by the delta. These nodes aren't referenced by the new root and may
+	/// be pruned once the old root is no longer needed.
+	///
+	/// Backends that can't track removed nodes return an empty list.
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		let (root, transaction) = self.storage_root(delta);
+		(root, transaction, Vec::new())
+	}
+
 	/// Calculate the child storage root, with given delta over what is already stored in
 	/// the backend, and produce a "transaction" that can be used to commit. The second argument
 	/// is true if child storage root equals default storage root.
@@ -155,9 +195,54 @@ pub trait Backend<H: Hasher>: sp_std::fmt::Debug {
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord;
 
+	/// Same as `child_storage_root`, but also returns prefixes and hashes of child trie nodes
+	/// that are replaced or removed by the delta (see `storage_root_with_pruning`).
+	///
+	/// Backends that can't track removed nodes return an empty list.
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		let (root, is_default, transaction) = self.child_storage_root(child_info, delta);
+		(root, is_default, transaction, Vec::new())
+	}
+
 	/// Get all key/value pairs into a Vec.
+	///
+	/// The whole state is loaded into memory, so this shouldn't be used for large states.
+	/// Use `for_pairs` to iterate large states page by page instead.
 	fn pairs(&self) -> Vec<(StorageKey, StorageValue)>;
 
+	/// Call `f` for at most `limit` key/value pairs of the top-level storage, in lexicographic
+	/// order of keys, starting at `start_key` (inclusive) or at the first key if it is `None`.
+	/// Iteration stops early (after the pair is processed) as soon as `f` returns false.
+	///
+	/// Returns the key to start the next call at, or `None` if the end of the storage has been
+	/// reached. Resuming at the returned key visits every pair exactly once, so the whole state
+	/// could be processed in pages of bounded size.
+	fn for_pairs<F: FnMut(&[u8], &[u8]) -> bool>(
+		&self,
+		start_key: Option<&[u8]>,
+		limit: usize,
+		mut f: F,
+	) -> Result<Option<StorageKey>, Self::Error> {
+		let mut visited = 0;
+		let mut stopped = false;
+		let mut next_key = None;
+		self.apply_to_key_values_while(None, None, start_key, |key, value| {
+			if visited == limit || stopped {
+				next_key = Some(key);
+				return false;
+			}
+
+			visited += 1;
+			stopped = !f(&key, &value);
+			true
+		}, false)?;
+		Ok(next_key)
+	}
+
 	/// Get all keys with given prefix
 	fn keys(&self, prefix: &[u8]) -> Vec<StorageKey> {
 		let mut all = Vec::new();
@@ -302,7 +387,7 @@ impl<H: Hasher, KF: sp_trie::KeyFunction<H>> Consolidate for sp_trie::GenericMem
 }
 
 /// Insert input pairs into memory db.
-#[cfg(test)]
+#[cfg(feature = "std")]
 pub(crate) fn insert_into_memory_db<H, I>(mdb: &mut sp_trie::MemoryDB<H>, input: I) -> Option<H::Out>
 	where
 		H: Hasher,
@@ -0,0 +1,171 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes trie input audit helpers.
+//!
+//! Used to find out why different nodes have built different changes tries for the same
+//! block: input of every node is dumped in the canonical form and then the dumps (or the
+//! inputs themselves) are compared.
+
+use std::cmp::Ordering;
+use std::fmt::Write;
+use sp_core::hexdisplay::HexDisplay;
+use crate::changes_trie::{BlockNumber, input::InputPair};
+
+/// Difference between two changes trie inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputDiff<Number: BlockNumber> {
+	/// The pair is only present in the first input.
+	OnlyInFirst(InputPair<Number>),
+	/// The pair is only present in the second input.
+	OnlyInSecond(InputPair<Number>),
+	/// Pairs with the same trie key have different values in the inputs.
+	ValueMismatch(InputPair<Number>, InputPair<Number>),
+}
+
+/// Dump changes trie input in the canonical human-readable form.
+///
+/// Every pair is written on the separate line and pairs are sorted in the order of trie keys,
+/// so inputs that are producing the same changes trie have the same dumps.
+pub fn dump_input<Number: BlockNumber>(input: &[InputPair<Number>]) -> String {
+	let mut dump = String::new();
+	for (_, pair) in sorted(input) {
+		// writing to the String never fails
+		let _ = match pair {
+			InputPair::ExtrinsicIndex(key, extrinsics) => writeln!(
+				dump,
+				"extrinsics #{} 0x{}: {:?}",
+				key.block,
+				HexDisplay::from(&key.key),
+				extrinsics,
+			),
+			InputPair::DigestIndex(key, blocks) => writeln!(
+				dump,
+				"digest #{} 0x{}: {:?}",
+				key.block,
+				HexDisplay::from(&key.key),
+				blocks,
+			),
+			InputPair::ChildIndex(key, root) => writeln!(
+				dump,
+				"child #{} 0x{}: 0x{}",
+				key.block,
+				HexDisplay::from(&*key.storage_key),
+				HexDisplay::from(root),
+			),
+			InputPair::MissingBlocks(key, blocks) => writeln!(
+				dump,
+				"missing #{}: {:?}",
+				key.block,
+				blocks,
+			),
+		};
+	}
+	dump
+}
+
+/// Compare two changes trie inputs.
+///
+/// Returns differences in the order of trie keys, so the first element is the first divergence
+/// of inputs. Order of pairs within the inputs doesn't matter.
+pub fn diff_inputs<Number: BlockNumber>(
+	first: &[InputPair<Number>],
+	second: &[InputPair<Number>],
+) -> Vec<InputDiff<Number>> {
+	let first = sorted(first);
+	let second = sorted(second);
+	let mut first = first.into_iter().peekable();
+	let mut second = second.into_iter().peekable();
+	let mut diff = Vec::new();
+	loop {
+		let ordering = match (first.peek(), second.peek()) {
+			(Some((first_key, _)), Some((second_key, _))) => first_key.cmp(second_key),
+			(Some(_), None) => Ordering::Less,
+			(None, Some(_)) => Ordering::Greater,
+			(None, None) => break,
+		};
+
+		match ordering {
+			Ordering::Less => {
+				let (_, pair) = first.next().expect("peeked above; qed");
+				diff.push(InputDiff::OnlyInFirst(pair.clone()));
+			},
+			Ordering::Greater => {
+				let (_, pair) = second.next().expect("peeked above; qed");
+				diff.push(InputDiff::OnlyInSecond(pair.clone()));
+			},
+			Ordering::Equal => {
+				let (_, first_pair) = first.next().expect("peeked above; qed");
+				let (_, second_pair) = second.next().expect("peeked above; qed");
+				if first_pair.value_encoded() != second_pair.value_encoded() {
+					diff.push(InputDiff::ValueMismatch(first_pair.clone(), second_pair.clone()));
+				}
+			},
+		}
+	}
+	diff
+}
+
+/// Sort input pairs by their trie keys (and values, to make duplicate keys order canonical).
+fn sorted<Number: BlockNumber>(input: &[InputPair<Number>]) -> Vec<(Vec<u8>, &InputPair<Number>)> {
+	let mut sorted = input.iter()
+		.map(|pair| ((pair.key_encoded(), pair.value_encoded()), pair))
+		.collect::<Vec<_>>();
+	sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+	sorted.into_iter().map(|((key, _), pair)| (key, pair)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::changes_trie::input::{ExtrinsicIndex, DigestIndex};
+	use super::*;
+
+	fn input() -> Vec<InputPair<u64>> {
+		vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![1] }, vec![0, 2].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![2] }, vec![1].into()),
+			InputPair::DigestIndex(DigestIndex { block: 4, key: vec![1] }, vec![1, 3].into()),
+		]
+	}
+
+	#[test]
+	fn permuted_inputs_are_equal() {
+		let mut permuted = input();
+		permuted.reverse();
+
+		assert_eq!(dump_input(&input()), dump_input(&permuted));
+		assert_eq!(dump_input(&input()), "extrinsics #4 0x01: [0, 2]\n\
+			extrinsics #4 0x02: [1]\n\
+			digest #4 0x01: [1, 3]\n");
+		assert!(diff_inputs(&input(), &permuted).is_empty());
+	}
+
+	#[test]
+	fn differing_extrinsic_index_is_reported() {
+		let mut changed = input();
+		changed[1] = InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![2] }, vec![1, 3].into());
+		changed.push(InputPair::DigestIndex(DigestIndex { block: 4, key: vec![5] }, vec![2].into()));
+
+		assert_eq!(diff_inputs(&input(), &changed), vec![
+			InputDiff::ValueMismatch(input()[1].clone(), changed[1].clone()),
+			InputDiff::OnlyInSecond(changed[3].clone()),
+		]);
+		assert_eq!(diff_inputs(&changed[..1], &input()[..2]), vec![
+			InputDiff::OnlyInSecond(input()[1].clone()),
+		]);
+	}
+}
@@ -0,0 +1,133 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generators of synthetic chains with changes tries.
+//!
+//! Used by benchmarks and tests that need many blocks with realistic changes tries. All data
+//! is generated by the RNG with given seed, so the same parameters produce the same chain.
+
+use std::collections::{BTreeMap, BTreeSet};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use sp_core::Blake2Hasher;
+use crate::{InMemoryBackend, StorageKey, overlayed_changes::OverlayedChanges};
+use crate::changes_trie::{AnchorBlockId, Configuration, InMemoryStorage, State, try_build_changes_trie};
+
+/// Storage key with given index.
+pub fn key(index: u32) -> StorageKey {
+	index.to_be_bytes().to_vec()
+}
+
+/// Generate changes of `keys` keys that are randomly selected from the first `key_space` keys
+/// (see `key`). Every change is made by the random extrinsic of the first `extrinsics` extrinsics.
+pub fn random_changes<R: Rng>(rng: &mut R, keys: u32, key_space: u32, extrinsics: u32) -> OverlayedChanges {
+	into_overlay(random_change_list(rng, keys, key_space, extrinsics))
+}
+
+/// Chain with changes tries, where every block has changed random keys.
+pub struct SyntheticChain {
+	/// Configuration of changes tries of the chain.
+	pub config: Configuration,
+	/// Changes tries of the chain.
+	pub storage: InMemoryStorage<Blake2Hasher, u64>,
+	/// Number of the last block of the chain.
+	pub best_block: u64,
+	changes: BTreeMap<StorageKey, BTreeSet<(u64, u32)>>,
+}
+
+impl SyntheticChain {
+	/// Generate chain of `blocks` blocks (starting from block#1), where every block changes
+	/// `keys_per_block` keys that are randomly selected from the first `key_space` keys.
+	pub fn generate(config: Configuration, seed: u64, blocks: u64, keys_per_block: u32, key_space: u32) -> Self {
+		let mut rng = StdRng::seed_from_u64(seed);
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let mut changes = BTreeMap::<_, BTreeSet<_>>::new();
+		for block in 1..=blocks {
+			let block_changes = random_change_list(&mut rng, keys_per_block, key_space, keys_per_block);
+			for (key_index, extrinsic) in &block_changes {
+				changes.entry(key(*key_index)).or_default().insert((block, *extrinsic));
+			}
+
+			let state = State::new(config.clone(), 0, &storage);
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let (mdb, root, _) = try_build_changes_trie(&backend, Some(&state), &into_overlay(block_changes), &parent)
+				.expect("building changes trie of the synthetic chain")
+				.expect("changes tries are enabled");
+			storage.insert(block, root, mdb);
+		}
+
+		SyntheticChain { config, storage, best_block: blocks, changes }
+	}
+
+	/// Changes of given key, in descending order.
+	///
+	/// Note that `key_changes` walks digests breadth-first, so its result has to be sorted before
+	/// comparison.
+	pub fn key_changes(&self, key: &[u8]) -> Vec<(u64, u32)> {
+		self.changes.get(key)
+			.map(|changes| changes.iter().rev().cloned().collect())
+			.unwrap_or_default()
+	}
+}
+
+/// Generate (key index, extrinsic) pairs of random changes.
+fn random_change_list<R: Rng>(rng: &mut R, keys: u32, key_space: u32, extrinsics: u32) -> Vec<(u32, u32)> {
+	(0..keys)
+		.map(|_| (rng.gen_range(0, key_space), rng.gen_range(0, extrinsics.max(1))))
+		.collect()
+}
+
+/// Apply (key index, extrinsic) changes to the new overlay.
+fn into_overlay(changes: Vec<(u32, u32)>) -> OverlayedChanges {
+	let mut overlay = OverlayedChanges::default();
+	overlay.set_collect_extrinsics(true);
+	for (key_index, extrinsic) in changes {
+		overlay.set_extrinsic_index(extrinsic).expect("synthetic extrinsic indices are never reserved; qed");
+		overlay.set_storage(key(key_index), Some(vec![1]));
+	}
+	overlay
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::changes_trie::{ConfigurationRange, key_changes};
+	use super::*;
+
+	#[test]
+	fn drilldown_over_synthetic_chain_finds_all_changes() {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let chain = SyntheticChain::generate(config, 42, 100, 8, 32);
+		let anchor = AnchorBlockId { hash: Default::default(), number: chain.best_block };
+		for key_index in 0..32 {
+			let key = key(key_index);
+			let changes = key_changes::<Blake2Hasher, u64>(
+				ConfigurationRange { config: &chain.config, zero: 0, end: None },
+				&chain.storage,
+				1,
+				&anchor,
+				chain.best_block,
+				None,
+				&key,
+			).and_then(|changes| changes.collect::<Result<Vec<_>, _>>())
+				.map(|mut changes| {
+					changes.sort_by(|a, b| b.cmp(a));
+					changes
+				});
+			assert_eq!(changes, Ok(chain.key_changes(&key)));
+		}
+	}
+}
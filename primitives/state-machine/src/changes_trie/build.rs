@@ -17,39 +17,56 @@
 
 //! Structures and functions required to build changes trie for given block.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::collections::btree_map::Entry;
-use codec::{Decode, Encode};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use codec::Encode;
 use hash_db::Hasher;
 use num_traits::One;
 use crate::{
-	StorageKey,
+	StorageKey, ExtrinsicId,
 	backend::Backend,
-	overlayed_changes::{OverlayedChanges, OverlayedValue},
-	trie_backend_essence::TrieBackendEssence,
+	overlayed_changes::OverlayedChanges,
 	changes_trie::{
-		AnchorBlockId, ConfigurationRange, Storage, BlockNumber,
-		build_iterator::digest_build_iterator,
-		input::{InputKey, InputPair, DigestIndex, ExtrinsicIndex, ChildIndex},
+		AnchorBlockId, BuildParams, Configuration, ConfigurationRange, Storage, BlockNumber, Error,
+		KeyKind, ExistenceCache, EmptyBlockPolicy, MissingRootPolicy,
+		HeapSize, InMemoryStorage, block_number_from_u128, empty_root, encode_input_pair,
+		build_iterator::DigestBuildIterator,
+		changes_source::{ChangesSource, ChangedKey},
+		input::{
+			InputPair, DigestIndex, DigestIndexValue, ExtrinsicIndex, ExtrinsicList, ChildIndex,
+			MissingBlocksIndex, cmp_trie_keys,
+		},
+		reader::Reader,
 	},
 };
-use sp_core::storage::{ChildInfo, PrefixedStorageKey};
+use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo, PrefixedStorageKey};
+use sp_trie::{MemoryDB, TrieMut};
+use sp_trie::trie_types::TrieDBMut;
 
 /// Prepare input pairs for building a changes trie of given block.
 ///
 /// Returns Err if storage error has occurred OR if storage haven't returned
-/// required data.
-pub(crate) fn prepare_input<'a, B, H, Number>(
+/// required data. The kind of the failure could be inspected using the returned `Error`.
+///
+/// This is the eager version of `prepare_input_iter`: all input pairs are collected and
+/// validated before they're returned.
+///
+/// The `block` is the block which changes have been collected in the `overlay`. It must
+/// follow the `parent` and match the block the `overlay` has been reset for (if any),
+/// otherwise `StaleExtrinsicChanges` error is returned.
+pub fn prepare_input<'a, B, H, Number>(
 	backend: &'a B,
 	storage: &'a dyn Storage<H, Number>,
 	config: ConfigurationRange<'a, Number>,
 	overlay: &'a OverlayedChanges,
 	parent: &'a AnchorBlockId<H::Out, Number>,
+	block: Number,
 ) -> Result<(
 		impl Iterator<Item=InputPair<Number>> + 'a,
 		Vec<(ChildIndex<Number>, impl Iterator<Item=InputPair<Number>> + 'a)>,
 		Vec<Number>,
-	), String>
+	), Error<Number>>
 	where
 		B: Backend<H>,
 		H: Hasher + 'a,
@@ -57,50 +74,547 @@ pub(crate) fn prepare_input<'a, B, H, Number>(
 		Number: BlockNumber,
 {
 	let number = parent.number.clone() + One::one();
-	let (extrinsics_input, children_extrinsics_input) = prepare_extrinsics_input(
+	let is_digest_block = digest_level_at_block(config.clone(), number.clone()).is_some();
+	check_changes_block(config.clone(), &number, is_digest_block, block)?;
+	let (input, children_input, digest_input_blocks) = prepare_input_iter(
 		backend,
-		&number,
+		storage,
+		&Default::default(),
+		config,
 		overlay,
-	)?;
-	let (digest_input, mut children_digest_input, digest_input_blocks) = prepare_digest_input::<H, Number>(
 		parent,
+		Default::default(),
+	)?;
+
+	let input = input.collect::<Result<Vec<_>, _>>()?;
+	validate_sorted_input(&number, is_digest_block, &input)?;
+	let children_input = children_input.into_iter()
+		.map(|(child_index, input)| input.collect::<Result<Vec<_>, _>>().map(|input| (child_index, input)))
+		.collect::<Result<Vec<_>, _>>()?;
+	for (_, input) in &children_input {
+		validate_sorted_input(&number, is_digest_block, input)?;
+	}
+
+	Ok((
+		input.into_iter(),
+		children_input.into_iter().map(|(child_index, input)| (child_index, input.into_iter())).collect(),
+		digest_input_blocks,
+	))
+}
+
+/// Check that input pairs of the changes trie of given block could be inserted into the trie.
+///
+/// This is the eager version of `InputValidator`.
+#[cfg(test)]
+pub(crate) fn validate_input<Number: BlockNumber>(
+	block: &Number,
+	is_digest_block: bool,
+	input: &[InputPair<Number>],
+) -> Result<(), Error<Number>> {
+	let mut validator = InputValidator::new(block.clone(), is_digest_block);
+	input.iter().try_for_each(|pair| validator.validate(pair))
+}
+
+/// Same as `validate_input`, but also requires pairs to be sorted by the trie key.
+fn validate_sorted_input<Number: BlockNumber>(
+	block: &Number,
+	is_digest_block: bool,
+	input: &[InputPair<Number>],
+) -> Result<(), Error<Number>> {
+	let mut validator = InputValidator::sorted(block.clone(), is_digest_block);
+	input.iter().try_for_each(|pair| validator.validate(pair))
+}
+
+/// Returns true if input pairs are sorted in `InputPair` order and there are no duplicates.
+pub(crate) fn is_sorted_input<Number: BlockNumber>(input: &[InputPair<Number>]) -> bool {
+	input.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+/// Validator of input pairs of the single changes trie.
+///
+/// Fails if multiple pairs have the same trie key (then the latter pair would silently
+/// overwrite the former), if some pair belongs to other block or if there's a digest pair
+/// and digest isn't built at the block.
+pub(crate) struct InputValidator<Number: BlockNumber> {
+	block: Number,
+	is_digest_block: bool,
+	trie_keys: ValidatedTrieKeys,
+}
+
+/// Trie keys of the validated pairs that are required to detect duplicates.
+enum ValidatedTrieKeys {
+	/// Pairs may come in any order, so all keys are kept.
+	All(HashSet<Vec<u8>>),
+	/// Pairs must be sorted by the trie key, so only the last key is kept. The other buffer is
+	/// reused to encode the next key.
+	Last(Option<Vec<u8>>, Vec<u8>),
+}
+
+impl<Number: BlockNumber> InputValidator<Number> {
+	/// Create validator of the changes trie input of given block.
+	pub fn new(block: Number, is_digest_block: bool) -> Self {
+		InputValidator {
+			block,
+			is_digest_block,
+			trie_keys: ValidatedTrieKeys::All(HashSet::new()),
+		}
+	}
+
+	/// Create validator of the changes trie input of given block, that also requires pairs to
+	/// be sorted by the trie key. Memory it occupies doesn't depend on the input size.
+	pub fn sorted(block: Number, is_digest_block: bool) -> Self {
+		InputValidator {
+			block,
+			is_digest_block,
+			trie_keys: ValidatedTrieKeys::Last(None, Vec::new()),
+		}
+	}
+
+	/// Check that the pair could be inserted into the trie along with all previously validated pairs.
+	pub fn validate(&mut self, pair: &InputPair<Number>) -> Result<(), Error<Number>> {
+		let (pair_block, key) = match pair {
+			InputPair::ExtrinsicIndex(index, _) => (&index.block, &index.key[..]),
+			InputPair::DigestIndex(index, _) => (&index.block, &index.key[..]),
+			InputPair::ChildIndex(index, _) => (&index.block, &index.storage_key[..]),
+			InputPair::MissingBlocks(index, _) => (&index.block, &[][..]),
+		};
+		let encode_trie_key = |trie_key: &mut Vec<u8>| match pair {
+			InputPair::ExtrinsicIndex(index, _) => index.encode_to(trie_key),
+			InputPair::DigestIndex(index, _) => index.encode_to(trie_key),
+			InputPair::ChildIndex(index, _) => index.encode_to(trie_key),
+			InputPair::MissingBlocks(index, _) => index.encode_to(trie_key),
+		};
+
+		if *pair_block != self.block {
+			return Err(Error::InvalidInputBlock {
+				key: key.to_vec(),
+				block: pair_block.clone(),
+				expected: self.block.clone(),
+			});
+		}
+		if !self.is_digest_block && matches!(pair, InputPair::DigestIndex(_, _) | InputPair::MissingBlocks(_, _)) {
+			return Err(Error::UnexpectedDigestBlock(self.block.clone()));
+		}
+		match self.trie_keys {
+			ValidatedTrieKeys::All(ref mut trie_keys) => {
+				let mut trie_key = Vec::new();
+				encode_trie_key(&mut trie_key);
+				if !trie_keys.insert(trie_key) {
+					return Err(Error::DuplicateInputKey(key.to_vec()));
+				}
+			},
+			ValidatedTrieKeys::Last(ref mut last_trie_key, ref mut trie_key) => {
+				trie_key.clear();
+				encode_trie_key(trie_key);
+				match last_trie_key {
+					Some(last_trie_key) => match (*last_trie_key).cmp(trie_key) {
+						std::cmp::Ordering::Equal => return Err(Error::DuplicateInputKey(key.to_vec())),
+						std::cmp::Ordering::Greater => return Err(Error::UnsortedInputKey(key.to_vec())),
+						std::cmp::Ordering::Less => std::mem::swap(last_trie_key, trie_key),
+					},
+					None => *last_trie_key = Some(std::mem::take(trie_key)),
+				}
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// Existence cache, limits and statistics of the input preparation that aren't part of the
+/// `BuildParams`.
+#[derive(Default)]
+pub(crate) struct InputContext<'a, 'b> {
+	/// Cache that is consulted before checking existence of deleted keys in the backend. If
+	/// `None`, existence is always checked in the backend.
+	pub existence_cache: Option<&'a dyn ExistenceCache>,
+	/// Limit of the heap memory that is occupied by the digest input.
+	pub memory_limit: Option<&'b mut MemoryLimit>,
+	/// Counter of keys of digest input tries that can't be decoded. If `None`, these keys
+	/// aren't tolerated.
+	pub invalid_keys: Option<&'b AtomicU64>,
+	/// Counter of changed keys that are skipped by the changes filter.
+	pub skipped_keys: Option<&'a AtomicU64>,
+	/// Flag that cancels reading of the digest input once it is set.
+	pub cancel: Option<&'b AtomicBool>,
+}
+
+/// Number of insertions after which the memory limit is checked.
+const MEMORY_LIMIT_CHECK_INTERVAL: usize = 64;
+
+/// Limit of the heap memory that is occupied by the changes trie input while it is prepared.
+///
+/// The limit is only checked once per `MEMORY_LIMIT_CHECK_INTERVAL` insertions, so that
+/// the common path isn't slowed down. So the limit may be exceeded by the size of
+/// these insertions before it is detected.
+pub(crate) struct MemoryLimit {
+	limit: usize,
+	used: usize,
+	insertions: usize,
+}
+
+impl MemoryLimit {
+	/// Create new limit of given number of bytes.
+	pub fn new(limit: usize) -> Self {
+		MemoryLimit {
+			limit,
+			used: 0,
+			insertions: 0,
+		}
+	}
+
+	/// Record insertion of given number of bytes.
+	pub fn record<Number: BlockNumber>(&mut self, bytes: usize) -> Result<(), Error<Number>> {
+		self.used = self.used.saturating_add(bytes);
+		self.insertions += 1;
+		if self.insertions % MEMORY_LIMIT_CHECK_INTERVAL == 0 {
+			self.check()
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Check that the limit hasn't been exceeded.
+	pub fn check<Number: BlockNumber>(&self) -> Result<(), Error<Number>> {
+		if self.used > self.limit {
+			return Err(Error::MemoryLimitExceeded {
+				used: self.used,
+				limit: self.limit,
+			});
+		}
+
+		Ok(())
+	}
+}
+
+/// Limits of extrinsics that are listed by the changes trie of the single block.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExtrinsicsLimits {
+	/// Max number of extrinsics in the block.
+	pub max_per_block: u32,
+	/// Max number of extrinsics that may change the single key.
+	pub max_per_key: u32,
+}
+
+impl Default for ExtrinsicsLimits {
+	fn default() -> Self {
+		ExtrinsicsLimits {
+			max_per_block: u32::MAX,
+			max_per_key: u32::MAX,
+		}
+	}
+}
+
+impl ExtrinsicsLimits {
+	/// Check that extrinsics that have changed the key are within limits.
+	///
+	/// Reserved indices of changes that are made outside of extrinsics aren't checked against
+	/// the per-block limit, but they're counted by the per-key limit.
+	pub fn check<Number: BlockNumber>(&self, key: &[u8], extrinsics: &ExtrinsicList) -> Result<(), Error<Number>> {
+		// extrinsics are sorted => the last extrinsic index is the largest one
+		let extrinsics_in_block = extrinsics.iter()
+			.rev()
+			.find_map(|extrinsic| extrinsic.extrinsic_index())
+			.map(|index| index.saturating_add(1))
+			.unwrap_or(0);
+		if extrinsics_in_block > self.max_per_block {
+			return Err(Error::TooManyExtrinsics { key: key.to_vec(), count: extrinsics_in_block });
+		}
+
+		let count = extrinsics.len().min(u32::MAX as usize) as u32;
+		if count > self.max_per_key {
+			return Err(Error::TooManyExtrinsics { key: key.to_vec(), count });
+		}
+
+		Ok(())
+	}
+}
+
+/// Prepare input pairs for building a changes trie of given block, keeping memory that is
+/// occupied by the input under `max_heap_bytes`.
+///
+/// Returns `Error::MemoryLimitExceeded` if (estimated) size of the prepared input exceeds
+/// the limit. Then the caller may fall back to producing a block without changes trie.
+///
+/// Input pairs of every trie are sorted (according to `InputPair` order) and have no duplicates,
+/// and child tries are sorted by their `ChildIndex`, so the input could be searched with
+/// `binary_search`.
+pub fn prepare_input_with_limit<'a, B, H, Number>(
+	backend: &'a B,
+	storage: &'a dyn Storage<H, Number>,
+	config: ConfigurationRange<'a, Number>,
+	overlay: &'a OverlayedChanges,
+	parent: &'a AnchorBlockId<H::Out, Number>,
+	max_heap_bytes: usize,
+) -> Result<(
+		Vec<InputPair<Number>>,
+		Vec<(ChildIndex<Number>, Vec<InputPair<Number>>)>,
+		Vec<Number>,
+	), Error<Number>>
+	where
+		B: Backend<H>,
+		H: Hasher + 'a,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	prepare_input_with_limit_from_source(backend, storage, config, overlay, parent, max_heap_bytes)
+}
+
+/// Same as `prepare_input_with_limit`, but changes are taken from the `ChangesSource` (e.g.
+/// `CollectedChanges`) instead of the `OverlayedChanges`.
+pub fn prepare_input_with_limit_from_source<'a, B, H, Number>(
+	backend: &'a B,
+	storage: &'a dyn Storage<H, Number>,
+	config: ConfigurationRange<'a, Number>,
+	changes: &'a dyn ChangesSource,
+	parent: &'a AnchorBlockId<H::Out, Number>,
+	max_heap_bytes: usize,
+) -> Result<(
+		Vec<InputPair<Number>>,
+		Vec<(ChildIndex<Number>, Vec<InputPair<Number>>)>,
+		Vec<Number>,
+	), Error<Number>>
+	where
+		B: Backend<H>,
+		H: Hasher + 'a,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	let mut memory_limit = MemoryLimit::new(max_heap_bytes);
+	let (mut input, children_input, digest_input_blocks) = prepare_input_iter(
+		backend,
+		storage,
+		&Default::default(),
 		config,
+		changes,
+		parent,
+		InputContext { memory_limit: Some(&mut memory_limit), ..Default::default() },
+	)?;
+
+	let pair_size = std::mem::size_of::<InputPair<Number>>();
+	let mut collect_input = |input: &mut dyn Iterator<Item=Result<InputPair<Number>, Error<Number>>>| {
+		input
+			.map(|pair| -> Result<_, Error<Number>> {
+				let pair = pair?;
+				// keys and blocks of digest pairs have been recorded when digest input was built
+				let heap_size = match pair {
+					InputPair::DigestIndex(_, _) => 0,
+					_ => pair.heap_size(),
+				};
+				memory_limit.record::<Number>(pair_size + heap_size)?;
+				Ok(pair)
+			})
+			.collect::<Result<Vec<_>, _>>()
+	};
+	let input = collect_input(&mut input)?;
+	let children_input = children_input.into_iter()
+		.map(|(child_index, mut input)| collect_input(&mut input).map(|input| (child_index, input)))
+		.collect::<Result<Vec<_>, _>>()?;
+	memory_limit.check::<Number>()?;
+
+	debug_assert!(is_sorted_input(&input));
+	debug_assert!(children_input.iter().all(|(_, input)| is_sorted_input(input)));
+	debug_assert!(children_input.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+	Ok((input, children_input, digest_input_blocks))
+}
+
+/// Prepare input pairs for building a changes trie of given block, lazily.
+///
+/// If nothing has been changed in the block and digest isn't built there, the input is empty
+/// and the changes aren't inspected at all.
+///
+/// ExtrinsicIndex pairs are produced from the changes while iterating. Existence of keys that are
+/// deleted in the overlay (and may exist according to the `context.existence_cache`) is checked
+/// in the backend at once, and backend errors are returned as `Err` items. DigestIndex pairs follow
+/// ExtrinsicIndex pairs. Returns Err if changes tries storage has failed to provide the data,
+/// required to build digest.
+///
+/// Keys of digest input tries that can't be decoded are counted in `context.invalid_keys` if it
+/// is `Some`. Otherwise, `InvalidInputKey` error is returned. Changed keys that are skipped by
+/// the `params.changes_filter` are counted in `context.skipped_keys`. The
+/// `params.empty_block_policy` tells how the roots of digest input blocks without changes are
+/// recognized and the `params.on_missing_root` tells what to do if some of these roots are
+/// missing.
+///
+/// Input of every trie is produced in the `InputPair` order (which is the order of trie keys):
+/// ExtrinsicIndex pairs sorted by key, then DigestIndex pairs sorted by key, then the
+/// MissingBlocks pair. Child tries are sorted by their `ChildIndex`. Trie may be built
+/// bottom-up right from this input (see `try_build_changes_trie_streaming`). Other tools
+/// (e.g. comparison of inputs, prepared by different implementations) rely on this order, so it
+/// must be kept.
+pub(crate) fn prepare_input_iter<'a, B, H, Number>(
+	backend: &'a B,
+	storage: &'a dyn Storage<H, Number>,
+	params: &BuildParams<'a>,
+	config: ConfigurationRange<'a, Number>,
+	changes: &'a dyn ChangesSource,
+	parent: &'a AnchorBlockId<H::Out, Number>,
+	context: InputContext<'a, '_>,
+) -> Result<(
+		impl Iterator<Item=Result<InputPair<Number>, Error<Number>>> + 'a,
+		Vec<(ChildIndex<Number>, impl Iterator<Item=Result<InputPair<Number>, Error<Number>>> + 'a)>,
+		Vec<Number>,
+	), Error<Number>>
+	where
+		B: Backend<H>,
+		H: Hasher + 'a,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	if !config.config.is_valid() {
+		return Err(Error::InvalidConfiguration(config.config.clone()));
+	}
+
+	let number = parent.number.clone() + One::one();
+	let digest_level = digest_level_at_block(config.clone(), number.clone());
+	let digest_input_blocks = digest_level_input_blocks(config.clone(), digest_level.as_ref());
+	if let Some(changes_block) = changes.block_number() {
+		let changes_block = block_number_from_u128(changes_block)
+			.ok_or_else(|| Error::StaleExtrinsicChanges(number.clone()))?;
+		check_changes_block(config.clone(), &number, digest_level.is_some(), changes_block)?;
+	}
+	if let Some(index) = changes.excess_extrinsic_index() {
+		return Err(Error::TooManyExtrinsics {
+			key: EXTRINSIC_INDEX.to_vec(),
+			count: index.saturating_add(1),
+		});
+	}
+
+	// digest is built even if nothing has been changed in the block => only blocks without
+	// digest could be skipped
+	if digest_level.is_none() && changes.is_empty() {
+		return Ok((None.into_iter().flatten(), Vec::new(), Vec::new()));
+	}
+
+	let (extrinsics_input, children_extrinsics_input) = prepare_extrinsics_input(
+		backend,
+		params,
+		&number,
+		config.config,
+		changes,
+		&context,
+	);
+	let (digest_input, children_digest_input, digest_input_blocks) = prepare_digest_input::<H, Number>(
+		parent,
+		digest_input_blocks,
 		number,
 		storage,
+		params,
+		context,
 	)?;
 
-	let mut children_digest = Vec::with_capacity(children_extrinsics_input.len());
+	let (input, children_input) = combine_input(
+		extrinsics_input,
+		children_extrinsics_input,
+		digest_input.map(Ok),
+		children_digest_input.into_iter()
+			.map(|(child_index, dig_iter)| (child_index, dig_iter.map(Ok)))
+			.collect::<BTreeMap<_, _>>(),
+	);
+
+	Ok((Some(input).into_iter().flatten(), children_input, digest_input_blocks))
+}
+
+/// Combine ExtrinsicIndex and DigestIndex input pairs of the top-level and child changes tries.
+///
+/// ExtrinsicIndex pairs of every trie are followed by its DigestIndex pairs. Child tries are
+/// sorted by their `ChildIndex`.
+fn combine_input<Number, E, CE, D, CD>(
+	extrinsics_input: E,
+	children_extrinsics_input: BTreeMap<ChildIndex<Number>, CE>,
+	digest_input: D,
+	mut children_digest_input: BTreeMap<ChildIndex<Number>, CD>,
+) -> (
+	impl Iterator<Item=E::Item>,
+	Vec<(ChildIndex<Number>, impl Iterator<Item=E::Item>)>,
+)
+	where
+		Number: BlockNumber,
+		E: Iterator,
+		CE: Iterator<Item=E::Item>,
+		D: Iterator<Item=E::Item>,
+		CD: Iterator<Item=E::Item>,
+{
+	let mut children_input = Vec::with_capacity(children_extrinsics_input.len());
 	for (child_index, ext_iter) in children_extrinsics_input.into_iter() {
 		let dig_iter = children_digest_input.remove(&child_index);
-		children_digest.push((
+		children_input.push((
 			child_index,
 			Some(ext_iter).into_iter().flatten()
 				.chain(dig_iter.into_iter().flatten()),
 		));
 	}
 	for (child_index, dig_iter) in children_digest_input.into_iter() {
-		children_digest.push((
+		children_input.push((
 			child_index,
 			None.into_iter().flatten()
 				.chain(Some(dig_iter).into_iter().flatten()),
 		));
 	}
+	children_input.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+	(extrinsics_input.chain(digest_input), children_input)
+}
 
+/// Input pairs of the top-level and child changes tries of the single block.
+pub type InputParts<Number> = (Vec<InputPair<Number>>, BTreeMap<ChildIndex<Number>, Vec<InputPair<Number>>>);
+
+/// ExtrinsicIndex input pairs of the top-level and child changes tries.
+pub(crate) type ExtrinsicsInput<Number> = InputParts<Number>;
+
+/// Prepare ExtrinsicIndex input pairs of the top-level and child changes tries of given block.
+///
+/// Digest input isn't prepared, because it doesn't depend on the changes. So when two sources are
+/// producing the same ExtrinsicIndex input pairs, they're producing the same changes tries.
+pub(crate) fn prepare_extrinsics_input_pairs<B, H, Number>(
+	backend: &B,
+	existence_cache: &dyn ExistenceCache,
+	params: &BuildParams,
+	block: &Number,
+	config: &Configuration,
+	changes: &dyn ChangesSource,
+) -> Result<ExtrinsicsInput<Number>, Error<Number>>
+	where
+		B: Backend<H>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	let (top, children) = prepare_extrinsics_input(
+		backend,
+		params,
+		block,
+		config,
+		changes,
+		&InputContext { existence_cache: Some(existence_cache), ..Default::default() },
+	);
 	Ok((
-		extrinsics_input.chain(digest_input),
-		children_digest,
-		digest_input_blocks,
+		top.collect::<Result<_, _>>()?,
+		children.into_iter()
+			.map(|(child_index, pairs)| pairs.collect::<Result<_, _>>().map(|pairs| (child_index, pairs)))
+			.collect::<Result<_, _>>()?,
 	))
 }
+
 /// Prepare ExtrinsicIndex input pairs.
+///
+/// Changes of top-level keys that are ignored by the configuration are skipped. Changes that
+/// are skipped by the `params.changes_filter` are counted in `context.skipped_keys`. If
+/// extrinsics that have changed the included key exceed the limits of `params`,
+/// `Error::TooManyExtrinsics` is returned.
+/// If the configuration records deletions, lists of deleted keys are marked with the
+/// `ExtrinsicId::KEY_DELETION`.
 fn prepare_extrinsics_input<'a, B, H, Number>(
 	backend: &'a B,
+	params: &BuildParams<'a>,
 	block: &Number,
-	overlay: &'a OverlayedChanges,
-) -> Result<(
-		impl Iterator<Item=InputPair<Number>> + 'a,
-		BTreeMap<ChildIndex<Number>, impl Iterator<Item=InputPair<Number>> + 'a>,
-	), String>
+	config: &'a Configuration,
+	changes: &'a dyn ChangesSource,
+	context: &InputContext<'a, '_>,
+) -> (
+	impl Iterator<Item=Result<InputPair<Number>, Error<Number>>> + 'a,
+	BTreeMap<ChildIndex<Number>, impl Iterator<Item=Result<InputPair<Number>, Error<Number>>> + 'a>,
+)
 	where
 		B: Backend<H>,
 		H: Hasher + 'a,
@@ -108,233 +622,830 @@ fn prepare_extrinsics_input<'a, B, H, Number>(
 {
 	let mut children_result = BTreeMap::new();
 
-	for (child_changes, child_info) in overlay.children() {
+	for child_info in changes.changed_children() {
 		let child_index = ChildIndex::<Number> {
 			block: block.clone(),
 			storage_key: child_info.prefixed_storage_key(),
 		};
 
+		let child_changes = changes.changed_keys(Some(&child_info));
 		let iter = prepare_extrinsics_input_inner(
-			backend, block, overlay,
-			Some(child_info.clone()),
+			backend, params, config.record_deletions, block,
+			Some(child_info),
 			child_changes,
-		)?;
+			context,
+		);
 		children_result.insert(child_index, iter);
 	}
 
-	let top = prepare_extrinsics_input_inner(backend, block, overlay, None, overlay.changes())?;
-
-	Ok((top, children_result))
+	let top_changes = changes.changed_keys(None).filter(move |change| !config.is_ignored_key(change.key));
+	let top = prepare_extrinsics_input_inner(
+		backend,
+		params,
+		config.record_deletions,
+		block,
+		None,
+		top_changes,
+		context,
+	);
+
+	(top, children_result)
 }
 
 fn prepare_extrinsics_input_inner<'a, B, H, Number>(
 	backend: &'a B,
+	params: &BuildParams<'a>,
+	record_deletions: bool,
 	block: &Number,
-	overlay: &'a OverlayedChanges,
 	child_info: Option<ChildInfo>,
-	changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>
-) -> Result<impl Iterator<Item=InputPair<Number>> + 'a, String>
+	changes: impl Iterator<Item=ChangedKey<'a>> + 'a,
+	context: &InputContext<'a, '_>,
+) -> impl Iterator<Item=Result<InputPair<Number>, Error<Number>>> + 'a
 	where
 		B: Backend<H>,
-		H: Hasher,
+		H: Hasher + 'a,
 		Number: BlockNumber,
 {
-	changes
-		.filter_map(|(k, v)| {
-			let extrinsics = v.extrinsics();
-			if !extrinsics.is_empty() {
-				Some((k, extrinsics))
+	let changes_filter = params.changes_filter;
+	let extrinsics_limits = params.extrinsics_limits();
+	let existence_cache = context.existence_cache.unwrap_or(&crate::changes_trie::NoExistenceCache);
+	let skipped_keys = context.skipped_keys;
+
+	// every key is met once AND changes are sorted by the trie key => pairs are also sorted
+	let block = block.clone();
+	let mut changes = changes
+		.filter_map(|ChangedKey { key: k, extrinsics, final_value_is_some }| {
+			if extrinsics.is_empty() {
+				return None;
+			}
+
+			// if existence is required, the existence cache may tell that the key has never been in the storage
+			let is_deletion_candidate = record_deletions && !final_value_is_some;
+			let known_existence = if !(changes_filter.requires_existence(final_value_is_some) || is_deletion_candidate)
+				|| !existence_cache.may_exist(child_info.as_ref(), k)
+			{
+				Some(false)
 			} else {
 				None
-			}
+			};
+			Some((k, extrinsics, final_value_is_some, known_existence))
 		})
-		.try_fold(BTreeMap::new(), |mut map: BTreeMap<&[u8], (ExtrinsicIndex<Number>, Vec<u32>)>, (k, extrinsics)| {
-			match map.entry(k) {
-				Entry::Vacant(entry) => {
-					// ignore temporary values (values that have null value at the end of operation
-					// AND are not in storage at the beginning of operation
-					if let Some(child_info) = child_info.as_ref() {
-						if !overlay.child_storage(child_info, k).map(|v| v.is_some()).unwrap_or_default() {
-							if !backend.exists_child_storage(&child_info, k)
-								.map_err(|e| format!("{}", e))? {
-								return Ok(map);
-							}
-						}
-					} else {
-						if !overlay.storage(k).map(|v| v.is_some()).unwrap_or_default() {
-							if !backend.exists_storage(k).map_err(|e| format!("{}", e))? {
-								return Ok(map);
-							}
-						}
-					};
-
-					let extrinsics = extrinsics.into_iter().collect();
-					entry.insert((ExtrinsicIndex {
-						block: block.clone(),
-						key: k.to_vec(),
-					}, extrinsics));
+		.collect::<Vec<_>>();
+	changes.sort_by(|(k1, _, _, _), (k2, _, _, _)| cmp_trie_keys(k1, k2));
+
+	// by default, temporary values (values that have null value at the end of operation
+	// AND are not in storage at the beginning of operation) are ignored. Existence of all
+	// keys that is required by the filter (unless known in advance) is checked in the backend at once.
+	let keys_to_check = changes.iter()
+		.filter(|(_, _, _, known_existence)| known_existence.is_none())
+		.map(|(k, _, _, _)| *k)
+		.collect::<Vec<_>>();
+	let mut exists_in_backend = match child_info.as_ref() {
+		Some(child_info) => backend.exists_child_storage_batch(child_info, &keys_to_check),
+		None => backend.exists_storage_batch(&keys_to_check),
+	}
+		.map_err(|e| format!("{}", e))
+		.and_then(|exists| if exists.len() == keys_to_check.len() {
+			Ok(exists.into_iter())
+		} else {
+			Err(format!(
+				"existence batch has {} entries for {} keys",
+				exists.len(),
+				keys_to_check.len(),
+			))
+		});
+
+	changes.into_iter()
+		.scan(false, move |failed, (k, mut extrinsics, final_value_is_some, known_existence)| {
+			if *failed {
+				return None;
+			}
+
+			let exists = match (known_existence, exists_in_backend.as_mut()) {
+				(Some(exists), _) => exists,
+				(None, Ok(exists_in_backend)) => match exists_in_backend.next()
+					.expect("existence batch has an entry for every key with unknown existence; qed")
+				{
+					Some(exists) => exists,
+					None => {
+						*failed = true;
+						return Some(Some(Err(Error::MissingProofNode(k.to_vec()))));
+					},
 				},
-				Entry::Occupied(mut entry) => {
-					// we do not need to check for temporary values here, because entry is Occupied
-					// AND we are checking it before insertion
-					let entry_extrinsics = &mut entry.get_mut().1;
-					entry_extrinsics.extend(
-						extrinsics.into_iter()
-					);
-					entry_extrinsics.sort();
+				(None, Err(e)) => {
+					*failed = true;
+					return Some(Some(Err(Error::Backend(e.clone()))));
 				},
-			}
+			};
 
-			Ok(map)
+			Some(if changes_filter.include(k, final_value_is_some, exists) {
+				if let Err(e) = extrinsics_limits.check(k, &extrinsics) {
+					*failed = true;
+					return Some(Some(Err(e)));
+				}
+				if record_deletions && !final_value_is_some && exists {
+					extrinsics.insert(ExtrinsicId::KEY_DELETION);
+				}
+
+				Some(Ok(InputPair::ExtrinsicIndex(ExtrinsicIndex {
+					block: block.clone(),
+					key: k.to_vec(),
+				}, extrinsics)))
+			} else {
+				if let Some(skipped_keys) = skipped_keys {
+					skipped_keys.fetch_add(1, Ordering::Relaxed);
+				}
+				None
+			})
 		})
-		.map(|pairs| pairs.into_iter().map(|(_, (k, v))| InputPair::ExtrinsicIndex(k, v)))
+		.flatten()
 }
 
-
 /// Prepare DigestIndex input pairs.
+///
+/// Changed keys of digest input blocks are taken from the pending digest of the storage, if
+/// all of them have been noted there. Otherwise, they are read from changes tries.
+///
+/// The `digest_input_blocks` are the input blocks of the digest that is built at `block` (see
+/// `digest_level_input_blocks`), or empty if digest isn't built at this block.
+///
+/// Input blocks that are skipped according to the `missing_root_policy` are listed in the
+/// `MissingBlocks` pair, which follows the DigestIndex pairs of the top-level trie.
 fn prepare_digest_input<'a, H, Number>(
 	parent: &'a AnchorBlockId<H::Out, Number>,
-	config: ConfigurationRange<Number>,
+	digest_input_blocks: Vec<Number>,
 	block: Number,
 	storage: &'a dyn Storage<H, Number>,
+	params: &BuildParams,
+	context: InputContext,
 ) -> Result<(
 		impl Iterator<Item=InputPair<Number>> + 'a,
 		BTreeMap<ChildIndex<Number>, impl Iterator<Item=InputPair<Number>> + 'a>,
 		Vec<Number>,
-	), String>
+	), Error<Number>>
 	where
 		H: Hasher,
 		H::Out: 'a + Encode,
 		Number: BlockNumber,
 {
-	let build_skewed_digest = config.end.as_ref() == Some(&block);
-	let block_for_digest = if build_skewed_digest {
-		config.config.next_max_level_digest_range(config.zero.clone(), block.clone())
-			.map(|(_, end)| end)
-			.unwrap_or_else(|| block.clone())
+	// if changed keys of all digest input blocks have been noted, tries aren't read
+	let pending_digest_input_blocks_keys = storage.pending_digest()
+		.and_then(|pending_digest| pending_digest.take_blocks(&digest_input_blocks));
+	let mut missing_blocks = Vec::new();
+	let digest_input_blocks_keys = match pending_digest_input_blocks_keys {
+		Some(digest_input_blocks_keys) => digest_input_blocks_keys,
+		None => {
+			let (digest_input_blocks_roots, missing_roots_blocks) = digest_build_blocks_roots(
+				parent,
+				storage,
+				params.empty_block_policy,
+				params.on_missing_root,
+				&digest_input_blocks,
+			)?;
+			missing_blocks = missing_roots_blocks;
+
+			#[cfg(feature = "parallel")]
+			let digest_input_blocks_keys = digest_build_blocks_keys_parallel(
+				storage,
+				&digest_input_blocks_roots,
+				context.invalid_keys,
+				context.cancel,
+			)?;
+			#[cfg(not(feature = "parallel"))]
+			let digest_input_blocks_keys = digest_build_blocks_keys(
+				storage,
+				&digest_input_blocks_roots,
+				context.invalid_keys,
+				context.cancel,
+			)?;
+
+			digest_input_blocks_keys
+		},
+	};
+
+	let included_blocks = digest_input_blocks.iter()
+		.filter(|digest_build_block| !missing_blocks.contains(digest_build_block))
+		.cloned()
+		.collect::<Vec<_>>();
+	let (map, child_map) = digest_input_maps(&block, &included_blocks, digest_input_blocks_keys, context.memory_limit)?;
+	let missing_blocks_pair = if missing_blocks.is_empty() {
+		None
 	} else {
-		block.clone()
+		Some(InputPair::MissingBlocks(MissingBlocksIndex { block }, missing_blocks.into()))
 	};
 
-	let digest_input_blocks = digest_build_iterator(config, block_for_digest).collect::<Vec<_>>();
-	digest_input_blocks.clone().into_iter()
-		.try_fold(
-			(BTreeMap::new(), BTreeMap::new()), move |(mut map, mut child_map), digest_build_block| {
-			let extrinsic_prefix = ExtrinsicIndex::key_neutral_prefix(digest_build_block.clone());
-			let digest_prefix = DigestIndex::key_neutral_prefix(digest_build_block.clone());
-			let child_prefix = ChildIndex::key_neutral_prefix(digest_build_block.clone());
-			let trie_root = storage.root(parent, digest_build_block.clone())?;
-			let trie_root = trie_root.ok_or_else(|| format!("No changes trie root for block {}", digest_build_block.clone()))?;
-
-			let insert_to_map = |map: &mut BTreeMap<_,_>, key: StorageKey| {
-				match map.entry(key.clone()) {
-					Entry::Vacant(entry) => {
-						entry.insert((DigestIndex {
-							block: block.clone(),
-							key,
-						}, vec![digest_build_block.clone()]));
-					},
-					Entry::Occupied(mut entry) => {
-						// DigestIndexValue must be sorted. Here we are relying on the fact that digest_build_iterator()
-						// returns blocks in ascending order => we only need to check for duplicates
-						//
-						// is_dup_block could be true when key has been changed in both digest block
-						// AND other blocks that it covers
-						let is_dup_block = entry.get().1.last() == Some(&digest_build_block);
-						if !is_dup_block {
-							entry.get_mut().1.push(digest_build_block.clone());
-						}
-					},
-				}
-			};
+	Ok((
+		map.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)).chain(missing_blocks_pair),
+		child_map.into_iter().map(|(sk, pairs)|
+			(sk, pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)))).collect(),
+		digest_input_blocks,
+	))
+}
 
-			// try to get all updated keys from cache
-			let populated_from_cache = storage.with_cached_changed_keys(
-				&trie_root,
-				&mut |changed_keys| {
-					for (storage_key, changed_keys) in changed_keys {
-						let map = match storage_key {
-							Some(storage_key) => child_map
-								.entry(ChildIndex::<Number> {
-									block: block.clone(),
-									storage_key: storage_key.clone(),
-								})
-								.or_default(),
-							None => &mut map,
-						};
-						for changed_key in changed_keys.iter().cloned() {
-							insert_to_map(map, changed_key);
-						}
-					}
-				}
-			);
-			if populated_from_cache {
-				return Ok((map, child_map));
-			}
+/// Compute root of the changes trie of given block from the precomputed input pairs.
+///
+/// ExtrinsicIndex pairs are prepared from the changes of the block, and DigestIndex (and
+/// MissingBlocks) pairs are supplied by the caller, so changes tries of previous blocks aren't
+/// read. Supplied digest pairs could be checked with `verify_digest_pairs_against_proof`.
+///
+/// Unlike the input, that is prepared by the node itself, supplied pairs are always validated.
+pub fn compute_root_from_parts<H, Number>(
+	config: ConfigurationRange<Number>,
+	block: Number,
+	extrinsics_input: InputParts<Number>,
+	digest_input: InputParts<Number>,
+) -> Result<H::Out, Error<Number>>
+	where
+		H: Hasher,
+		Number: BlockNumber,
+{
+	if !config.config.is_valid() {
+		return Err(Error::InvalidConfiguration(config.config.clone()));
+	}
 
-			let mut children_roots = BTreeMap::<PrefixedStorageKey, _>::new();
-			{
-				let trie_storage = TrieBackendEssence::<_, H>::new(
-					crate::changes_trie::TrieBackendStorageAdapter(storage),
-					trie_root,
-				);
+	let into_iters = |children: BTreeMap<ChildIndex<Number>, Vec<InputPair<Number>>>| children
+		.into_iter()
+		.map(|(child_index, pairs)| (child_index, pairs.into_iter()))
+		.collect();
+	let (input, children_input) = combine_input(
+		extrinsics_input.0.into_iter(),
+		into_iters(extrinsics_input.1),
+		digest_input.0.into_iter(),
+		into_iters(digest_input.1),
+	);
+	let input = input.collect::<Vec<_>>();
+	let children_input = children_input.into_iter()
+		.map(|(child_index, pairs)| (child_index, pairs.collect::<Vec<_>>()))
+		.collect::<Vec<_>>();
+
+	let is_digest_block = digest_level_at_block(config.clone(), block.clone()).is_some();
+	let (_, root) = build_input_tries::<H, _>(
+		block,
+		is_digest_block,
+		config.config.digest_value_threshold,
+		&input,
+		&children_input,
+	)?;
+	Ok(root)
+}
 
-				trie_storage.for_key_values_with_prefix(&child_prefix, |mut key, mut value|
-					if let Ok(InputKey::ChildIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
-						if let Ok(value) = <Vec<u8>>::decode(&mut value) {
-							let mut trie_root = <H as Hasher>::Out::default();
-							trie_root.as_mut().copy_from_slice(&value[..]);
-							children_roots.insert(trie_key.storage_key, trie_root);
-						}
-					});
-
-				trie_storage.for_keys_with_prefix(&extrinsic_prefix, |mut key|
-					if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-
-				trie_storage.for_keys_with_prefix(&digest_prefix, |mut key|
-					if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-			}
+/// Check that DigestIndex pairs, supplied to `compute_root_from_parts`, are the digest input
+/// of given block.
+///
+/// Changed keys of digest input blocks are read from the `proof` of their changes tries.
+/// Roots of these tries must be given for all digest input blocks, otherwise the
+/// `DigestInputRootsMissing` error is returned. Returns false if the supplied pairs differ
+/// from the pairs that are built from the proven keys.
+pub fn verify_digest_pairs_against_proof<H, Number>(
+	config: ConfigurationRange<Number>,
+	block: Number,
+	digest_input_blocks_roots: &[(Number, H::Out)],
+	proof: Vec<Vec<u8>>,
+	digest_input: &InputParts<Number>,
+) -> Result<bool, Error<Number>>
+	where
+		H: Hasher,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	let digest_level = digest_level_at_block(config.clone(), block.clone());
+	let digest_input_blocks = digest_level_input_blocks(config, digest_level.as_ref());
+
+	let mut roots = Vec::with_capacity(digest_input_blocks.len());
+	let mut missing_blocks = Vec::new();
+	for digest_input_block in &digest_input_blocks {
+		match digest_input_blocks_roots.iter().find(|(root_block, _)| root_block == digest_input_block) {
+			Some(block_root) => roots.push(block_root.clone()),
+			None => missing_blocks.push(digest_input_block.clone()),
+		}
+	}
+	if !missing_blocks.is_empty() {
+		return Err(Error::DigestInputRootsMissing(missing_blocks));
+	}
 
-			for (storage_key, trie_root) in children_roots.into_iter() {
-				let child_index = ChildIndex::<Number> {
-					block: block.clone(),
-					storage_key,
-				};
+	let storage = InMemoryStorage::<H, Number>::with_proof(proof);
+	let digest_input_blocks_keys = digest_build_blocks_keys(&storage, &roots, None, None)?;
+	let (map, child_map) = digest_input_maps(&block, &digest_input_blocks, digest_input_blocks_keys, None)?;
 
-				let mut map = child_map.entry(child_index).or_default();
-				let trie_storage = TrieBackendEssence::<_, H>::new(
-					crate::changes_trie::TrieBackendStorageAdapter(storage),
-					trie_root,
-				);
-				trie_storage.for_keys_with_prefix(&extrinsic_prefix, |mut key|
-					if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-
-				trie_storage.for_keys_with_prefix(&digest_prefix, |mut key|
-					if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-			}
-			Ok((map, child_map))
+	// the order of pairs doesn't affect the trie root => it isn't checked
+	let sorted = |pairs: &[InputPair<Number>]| {
+		let mut pairs = pairs.to_vec();
+		pairs.sort();
+		pairs
+	};
+	let expected_input = map.into_iter()
+		.map(|(_, (k, v))| InputPair::DigestIndex(k, v))
+		.collect::<Vec<_>>();
+	let expected_children_input = child_map.into_iter()
+		.map(|(child_index, pairs)| (
+			child_index,
+			pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)).collect::<Vec<_>>(),
+		))
+		.collect::<BTreeMap<_, _>>();
+	let children_input = digest_input.1.iter()
+		.filter(|(_, pairs)| !pairs.is_empty())
+		.map(|(child_index, pairs)| (child_index.clone(), sorted(pairs)))
+		.collect::<BTreeMap<_, _>>();
+
+	Ok(sorted(&digest_input.0) == expected_input && children_input == expected_children_input)
+}
+
+/// Build the changes trie of given block from the input pairs of its top-level and child tries.
+///
+/// Roots of child tries are inserted into the top-level trie, unless their input is empty. Input
+/// pairs are validated before insertion.
+pub(crate) fn build_input_tries<H, Number>(
+	block: Number,
+	is_digest_block: bool,
+	digest_value_threshold: Option<u32>,
+	input: &[InputPair<Number>],
+	children_input: &[(ChildIndex<Number>, Vec<InputPair<Number>>)],
+) -> Result<(MemoryDB<H>, H::Out), Error<Number>>
+	where
+		H: Hasher,
+		Number: BlockNumber,
+{
+	let mut key = Vec::new();
+	let mut value = Vec::new();
+	let mut mdb = MemoryDB::default();
+	let mut child_roots = Vec::with_capacity(children_input.len());
+	for (child_index, pairs) in children_input {
+		if pairs.is_empty() {
+			continue;
+		}
+
+		let mut root = Default::default();
+		{
+			let mut trie = TrieDBMut::<H>::new(&mut mdb, &mut root);
+			let mut validator = InputValidator::new(block.clone(), is_digest_block);
+			insert_input_pairs(&mut trie, &mut validator, digest_value_threshold, pairs, &mut key, &mut value)?;
+		}
+		child_roots.push(InputPair::ChildIndex(child_index.clone(), root.as_ref().to_vec()));
+	}
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMut::<H>::new(&mut mdb, &mut root);
+		let mut validator = InputValidator::new(block, is_digest_block);
+		insert_input_pairs(&mut trie, &mut validator, digest_value_threshold, &child_roots, &mut key, &mut value)?;
+		insert_input_pairs(&mut trie, &mut validator, digest_value_threshold, input, &mut key, &mut value)?;
+	}
+
+	Ok((mdb, root))
+}
+
+/// Insert input pairs into the changes trie.
+fn insert_input_pairs<H: Hasher, Number: BlockNumber>(
+	trie: &mut TrieDBMut<H>,
+	validator: &mut InputValidator<Number>,
+	digest_value_threshold: Option<u32>,
+	pairs: &[InputPair<Number>],
+	key: &mut Vec<u8>,
+	value: &mut Vec<u8>,
+) -> Result<(), Error<Number>> {
+	for pair in pairs {
+		validator.validate(pair)?;
+
+		encode_input_pair(&mut None, digest_value_threshold, pair, key, value);
+		trie.insert(key, value).map_err(|e| Error::Storage(format!("{:?}", e)))?;
+	}
+
+	Ok(())
+}
+
+/// Level of the digest that is built at some block.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DigestLevel<Number> {
+	/// Block the digest is built at.
+	pub at: Number,
+	/// Block the digest is built for. When skewed digest is built at the last block of the
+	/// configuration range, this is the next max level digest block.
+	pub block: Number,
+	/// Level of the digest.
+	pub level: u32,
+	/// Step between digest input blocks of the highest level.
+	pub step: u32,
+}
+
+impl<Number: BlockNumber> DigestLevel<Number> {
+	/// Returns the first block of the range, covered by the digest. All digest input blocks
+	/// are within `range_start()..at`.
+	pub fn range_start(&self, digest_interval: u32) -> Number {
+		let range_len: Number = self.step.saturating_mul(digest_interval).into();
+		self.block.clone() + One::one() - range_len
+	}
+}
+
+/// Check that block, listed by the digest of given block, is covered by this digest.
+///
+/// Blocks where digest isn't built may only list blocks that are below them and are covered by
+/// the configuration.
+pub(crate) fn check_digest_listed_block<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	digest_block: &Number,
+	listed: &Number,
+) -> Result<(), Error<Number>> {
+	let digest_interval = config.config.digest_interval;
+	let zero = config.zero.clone();
+	let range_start = digest_level_at_block(config, digest_block.clone())
+		.map(|digest_level| digest_level.range_start(digest_interval))
+		.unwrap_or_else(|| zero + One::one());
+	if *listed < range_start || *listed >= *digest_block {
+		return Err(Error::InvalidDigestBlock { digest_block: digest_block.clone(), listed: listed.clone() });
+	}
+
+	Ok(())
+}
+
+/// Returns level of the digest that is built at given block, or None if digest isn't built there.
+pub(crate) fn digest_level_at_block<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	block: Number,
+) -> Option<DigestLevel<Number>> {
+	let build_skewed_digest = config.end.as_ref() == Some(&block);
+	let block_for_digest = if build_skewed_digest {
+		config.config.next_max_level_digest_range(config.zero.clone(), block.clone())
+			.map(|(_, end)| end)
+			.unwrap_or_else(|| block.clone())
+	} else {
+		block.clone()
+	};
+
+	config.config.digest_level_at_block(config.zero.clone(), block_for_digest.clone())
+		.map(|(level, _, step)| DigestLevel {
+			at: block,
+			block: block_for_digest,
+			level,
+			step,
 		})
-		.map(|(pairs, child_pairs)| (
-			pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)),
-			child_pairs.into_iter().map(|(sk, pairs)|
-				(sk, pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)))).collect(),
-			digest_input_blocks,
+}
+
+/// Returns blocks, whose changed keys are included into digest of given level.
+fn digest_level_input_blocks<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	digest_level: Option<&DigestLevel<Number>>,
+) -> Vec<Number> {
+	match digest_level {
+		Some(digest_level) => DigestBuildIterator::new(
+			digest_level.block.clone(),
+			config.end.unwrap_or_else(|| digest_level.block.clone()),
+			config.config.digest_interval,
+			digest_level.step,
+		).collect(),
+		None => Vec::new(),
+	}
+}
+
+/// Check that changes have been collected for the block the changes trie is built for.
+///
+/// If changes have been collected for other block (e.g. they're reused across blocks) and digest
+/// is built at only one of these blocks, the error tells that digest pairs are unexpected (or
+/// missing) at the block of changes.
+fn check_changes_block<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	block: &Number,
+	is_digest_block: bool,
+	changes_block: Number,
+) -> Result<(), Error<Number>> {
+	if changes_block == *block {
+		return Ok(());
+	}
+
+	let is_changes_digest_block = digest_level_at_block(config, changes_block.clone()).is_some();
+	match (is_digest_block, is_changes_digest_block) {
+		(true, false) => Err(Error::UnexpectedDigestBlock(changes_block)),
+		(false, true) => Err(Error::MissingDigestBlock(changes_block)),
+		_ => Err(Error::StaleExtrinsicChanges(block.clone())),
+	}
+}
+
+/// Returns blocks, whose changed keys are included into digest, built at given block.
+///
+/// Returns empty vec if digest isn't built at given block.
+pub(crate) fn digest_input_blocks<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	block: Number,
+) -> Vec<Number> {
+	let digest_level = digest_level_at_block(config.clone(), block);
+	digest_level_input_blocks(config, digest_level.as_ref())
+}
+
+/// DigestIndex pairs of the single changes trie, indexed by the changed key.
+pub(crate) type DigestInputMap<Number> = BTreeMap<TrieOrderedKey, (DigestIndex<Number>, DigestIndexValue<Number>)>;
+
+/// Storage key that is ordered like the trie key of the pair it is included into (see
+/// `cmp_trie_keys`), so pairs of the `DigestInputMap` are iterated in the trie order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TrieOrderedKey(pub StorageKey);
+
+impl Ord for TrieOrderedKey {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		cmp_trie_keys(&self.0, &other.0)
+	}
+}
+
+impl PartialOrd for TrieOrderedKey {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+/// Build DigestIndex pairs of the top-level and child changes tries of given block from
+/// keys that have been changed in every digest input block.
+pub(crate) fn digest_input_maps<Number: BlockNumber>(
+	block: &Number,
+	digest_input_blocks: &[Number],
+	digest_input_blocks_keys: Vec<DigestBuildBlockKeys>,
+	mut memory_limit: Option<&mut MemoryLimit>,
+) -> Result<(DigestInputMap<Number>, BTreeMap<ChildIndex<Number>, DigestInputMap<Number>>), Error<Number>> {
+	let mut map = BTreeMap::new();
+	let mut child_map = BTreeMap::new();
+	// returns (estimated) number of bytes that the map has grown by
+	let insert_to_map = |map: &mut DigestInputMap<Number>, key: StorageKey, digest_build_block: &Number| {
+		match map.entry(TrieOrderedKey(key.clone())) {
+			Entry::Vacant(entry) => {
+				let key_size = key.capacity();
+				entry.insert((DigestIndex {
+					block: block.clone(),
+					key,
+				}, vec![digest_build_block.clone()].into()));
+				2 * key_size + std::mem::size_of::<Number>()
+			},
+			Entry::Occupied(mut entry) => {
+				// digest_build_iterator() returns blocks in ascending order AND every block is visited
+				// once => this is always a push to the end of the list
+				entry.get_mut().1.insert(digest_build_block.clone());
+				std::mem::size_of::<Number>()
+			},
+		}
+	};
+	let mut record_insertion = |inserted_bytes: usize| -> Result<(), Error<Number>> {
+		match memory_limit.as_mut() {
+			Some(memory_limit) => memory_limit.record(inserted_bytes),
+			None => Ok(()),
+		}
+	};
+
+	for (digest_build_block, block_keys) in digest_input_blocks.iter().zip(digest_input_blocks_keys) {
+		for key in block_keys.top {
+			record_insertion(insert_to_map(&mut map, key, digest_build_block))?;
+		}
+		for (storage_key, keys) in block_keys.children {
+			let map = child_map
+				.entry(ChildIndex::<Number> {
+					block: block.clone(),
+					storage_key,
+				})
+				.or_insert_with(BTreeMap::new);
+			for key in keys {
+				record_insertion(insert_to_map(map, key, digest_build_block))?;
+			}
+		}
+	}
+	if let Some(memory_limit) = memory_limit {
+		memory_limit.check::<Number>()?;
+	}
+
+	Ok((map, child_map))
+}
+
+/// Keys that have been changed in the single digest input block.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct DigestBuildBlockKeys {
+	/// Changed keys of the top-level storage.
+	pub top: BTreeSet<StorageKey>,
+	/// Changed keys of child storages.
+	pub children: BTreeMap<PrefixedStorageKey, BTreeSet<StorageKey>>,
+}
+
+impl DigestBuildBlockKeys {
+	/// Restore keys from the digest cache entry.
+	fn from_digest_cache_entry(entry: &[(StorageKey, KeyKind)]) -> Self {
+		let mut block_keys = DigestBuildBlockKeys::default();
+		for (key, kind) in entry {
+			match kind {
+				KeyKind::Top => block_keys.top.insert(key.clone()),
+				KeyKind::Child(storage_key) => block_keys.children
+					.entry(storage_key.clone())
+					.or_default()
+					.insert(key.clone()),
+			};
+		}
+		block_keys
+	}
+
+	/// Convert keys into the digest cache entry.
+	fn to_digest_cache_entry(&self) -> Vec<(StorageKey, KeyKind)> {
+		self.top.iter()
+			.map(|key| (key.clone(), KeyKind::Top))
+			.chain(self.children.iter().flat_map(|(storage_key, keys)| keys.iter()
+				.map(move |key| (key.clone(), KeyKind::Child(storage_key.clone())))))
+			.collect()
+	}
+}
+
+/// Read changes trie roots of all digest input blocks at once.
+///
+/// Roots of blocks without changes are resolved to the root of the empty changes trie,
+/// according to the `empty_block_policy`. Blocks without changes trie roots are either
+/// returned separately (and aren't included into the returned roots), or reported with the
+/// `DigestInputRootsMissing` error naming all of them, according to the `missing_root_policy`.
+pub(crate) fn digest_build_blocks_roots<H, Number>(
+	parent: &AnchorBlockId<H::Out, Number>,
+	storage: &dyn Storage<H, Number>,
+	empty_block_policy: EmptyBlockPolicy,
+	missing_root_policy: MissingRootPolicy,
+	digest_input_blocks: &[Number],
+) -> Result<(Vec<(Number, H::Out)>, Vec<Number>), Error<Number>>
+	where
+		H: Hasher,
+		Number: BlockNumber,
+{
+	let read_roots = |blocks: &[Number]| {
+		let roots = storage.roots(parent, blocks)?;
+		if roots.len() != blocks.len() {
+			return Err(Error::Storage(format!(
+				"Changes trie storage has returned {} roots for {} blocks",
+				roots.len(),
+				blocks.len(),
+			)));
+		}
+		Ok(roots)
+	};
+
+	let roots = read_roots(digest_input_blocks)?;
+	// non-empty changes tries of different blocks never have the same root
+	let parents_roots = match empty_block_policy {
+		EmptyBlockPolicy::ReuseParentRoot => read_roots(&digest_input_blocks.iter()
+			.map(|block| block.clone() - One::one())
+			.collect::<Vec<_>>())?,
+		EmptyBlockPolicy::OmitRoot | EmptyBlockPolicy::EmptyTrieRoot => Vec::new(),
+	};
+
+	let mut blocks_roots = Vec::with_capacity(digest_input_blocks.len());
+	let mut missing_blocks = Vec::new();
+	for (index, (digest_build_block, trie_root)) in digest_input_blocks.iter().zip(roots).enumerate() {
+		let digest_build_block = digest_build_block.clone();
+		match trie_root {
+			Some(trie_root) if parents_roots.get(index) == Some(&Some(trie_root)) =>
+				blocks_roots.push((digest_build_block, empty_root::<H>())),
+			Some(trie_root) => blocks_roots.push((digest_build_block, trie_root)),
+			None if empty_block_policy == EmptyBlockPolicy::OmitRoot =>
+				blocks_roots.push((digest_build_block, empty_root::<H>())),
+			None => missing_blocks.push(digest_build_block),
+		}
+	}
+
+	if !missing_blocks.is_empty() && missing_root_policy == MissingRootPolicy::Fail {
+		return Err(Error::DigestInputRootsMissing(missing_blocks));
+	}
+
+	Ok((blocks_roots, missing_blocks))
+}
+
+/// Read changed keys of every digest input block, one block after another.
+///
+/// If the `cancel` flag is set, reading stops before the next input block with `Cancelled` error.
+pub(crate) fn digest_build_blocks_keys<H, Number>(
+	storage: &dyn Storage<H, Number>,
+	digest_input_blocks_roots: &[(Number, H::Out)],
+	invalid_keys: Option<&AtomicU64>,
+	cancel: Option<&AtomicBool>,
+) -> Result<Vec<DigestBuildBlockKeys>, Error<Number>>
+	where
+		H: Hasher,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	digest_input_blocks_roots.iter()
+		.map(|(digest_build_block, trie_root)| digest_build_block_keys(
+			storage,
+			digest_build_block.clone(),
+			*trie_root,
+			invalid_keys,
+			cancel,
+		))
+		.collect()
+}
+
+/// Read changed keys of every digest input block, using the global thread pool.
+///
+/// The result is the same as the result of `digest_build_blocks_keys`.
+#[cfg(feature = "parallel")]
+fn digest_build_blocks_keys_parallel<H, Number>(
+	storage: &dyn Storage<H, Number>,
+	digest_input_blocks_roots: &[(Number, H::Out)],
+	invalid_keys: Option<&AtomicU64>,
+	cancel: Option<&AtomicBool>,
+) -> Result<Vec<DigestBuildBlockKeys>, Error<Number>>
+	where
+		H: Hasher,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	use rayon::prelude::*;
+
+	digest_input_blocks_roots.par_iter()
+		.map(|(digest_build_block, trie_root)| digest_build_block_keys(
+			storage,
+			digest_build_block.clone(),
+			*trie_root,
+			invalid_keys,
+			cancel,
 		))
+		.collect()
+}
+
+/// Read keys that have been changed in given digest input block.
+fn digest_build_block_keys<H, Number>(
+	storage: &dyn Storage<H, Number>,
+	digest_build_block: Number,
+	trie_root: H::Out,
+	invalid_keys: Option<&AtomicU64>,
+	cancel: Option<&AtomicBool>,
+) -> Result<DigestBuildBlockKeys, Error<Number>>
+	where
+		H: Hasher,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	if cancel.map(|cancel| cancel.load(Ordering::Relaxed)).unwrap_or(false) {
+		return Err(Error::Cancelled);
+	}
+
+	// nothing has been changed in the block => there's nothing to read
+	if trie_root == empty_root::<H>() {
+		return Ok(DigestBuildBlockKeys::default());
+	}
+
+	// try to get all updated keys from the digest cache
+	let digest_cache = storage.digest_cache();
+	if let Some(entry) = digest_cache.and_then(|cache| cache.get(&digest_build_block, &trie_root)) {
+		return Ok(DigestBuildBlockKeys::from_digest_cache_entry(&entry));
+	}
+
+	let mut block_keys = DigestBuildBlockKeys::default();
+
+	// try to get all updated keys from cache
+	let populated_from_cache = storage.with_cached_changed_keys(
+		&trie_root,
+		&mut |changed_keys| {
+			for (storage_key, changed_keys) in changed_keys {
+				let keys = match storage_key {
+					Some(storage_key) => block_keys.children
+						.entry(storage_key.clone())
+						.or_default(),
+					None => &mut block_keys.top,
+				};
+				keys.extend(changed_keys.iter().cloned());
+			}
+		}
+	);
+	if populated_from_cache {
+		return Ok(block_keys);
+	}
+
+	// upper nodes of consecutive digest input tries are often the same => they're cached
+	let mut reader = Reader::new(storage, digest_build_block.clone(), trie_root);
+	if let Some(node_cache) = storage.node_cache() {
+		reader = reader.with_node_cache(node_cache);
+	}
+	if let Some(invalid_keys) = invalid_keys {
+		reader = reader.tolerate_invalid_keys(invalid_keys);
+	}
+	if let Some(cancel) = cancel {
+		reader = reader.cancellable(cancel);
+	}
+	let mut children_roots = BTreeMap::<PrefixedStorageKey, _>::new();
+	reader.for_child_roots(|storage_key, trie_root| {
+		children_roots.insert(storage_key, trie_root);
+	})?;
+	reader.for_changed_keys(|key| {
+		block_keys.top.insert(key);
+	})?;
+
+	for (storage_key, trie_root) in children_roots.into_iter() {
+		let keys = block_keys.children.entry(storage_key).or_default();
+		reader.child(trie_root).for_changed_keys(|key| {
+			keys.insert(key);
+		})?;
+	}
+
+	if let Some(digest_cache) = digest_cache {
+		digest_cache.insert(digest_build_block, trie_root, block_keys.to_digest_cache_entry());
+	}
+
+	Ok(block_keys)
 }
 
 #[cfg(test)]
 mod test {
+	use std::sync::atomic::{AtomicUsize, Ordering};
 	use sp_core::Blake2Hasher;
 	use crate::InMemoryBackend;
-	use crate::changes_trie::{RootsStorage, Configuration, storage::InMemoryStorage};
+	use crate::changes_trie::{
+		RootsStorage, Configuration, ChangesFilter, DefaultChangesFilter, PendingDigest,
+		storage::{InMemoryStorage, InMemoryStorageBuilder},
+	};
 	use crate::changes_trie::build_cache::{IncompleteCacheAction, IncompleteCachedBuildData};
 	use super::*;
 
@@ -355,93 +1466,97 @@ mod test {
 			(vec![105], vec![255]),
 		].into_iter().collect::<std::collections::BTreeMap<_, _>>().into();
 		let prefixed_child_trie_key1 = child_info_1.prefixed_storage_key();
-		let storage = InMemoryStorage::with_inputs(vec![
-			(zero + 1, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![100] }, vec![1, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![101] }, vec![0, 2]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![105] }, vec![0, 2, 4]),
-			]),
-			(zero + 2, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0]),
-			]),
-			(zero + 3, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 3, key: vec![100] }, vec![0]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 3, key: vec![105] }, vec![1]),
-			]),
-			(zero + 4, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1]),
-
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3]),
-			]),
-			(zero + 5, Vec::new()),
-			(zero + 6, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 6, key: vec![105] }, vec![2]),
-			]),
-			(zero + 7, Vec::new()),
-			(zero + 8, vec![
-				InputPair::DigestIndex(DigestIndex { block: zero + 8, key: vec![105] }, vec![zero + 6]),
-			]),
-			(zero + 9, Vec::new()), (zero + 10, Vec::new()), (zero + 11, Vec::new()), (zero + 12, Vec::new()),
-			(zero + 13, Vec::new()), (zero + 14, Vec::new()), (zero + 15, Vec::new()),
-		], vec![(prefixed_child_trie_key1.clone(), vec![
-				(zero + 1, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![100] }, vec![1, 3]),
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![101] }, vec![0, 2]),
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![105] }, vec![0, 2, 4]),
-				]),
-				(zero + 2, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0]),
-				]),
-				(zero + 4, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0, 3]),
-
-					InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-				]),
-			]),
-		]);
+		let storage = InMemoryStorageBuilder::new()
+			.block(zero + 1, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![100] }, vec![1, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![101] }, vec![0, 2].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![105] }, vec![0, 2, 4].into()),
+			])
+			.block(zero + 2, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0].into()),
+			])
+			.block(zero + 3, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 3, key: vec![100] }, vec![0].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 3, key: vec![105] }, vec![1].into()),
+			])
+			.block(zero + 4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3].into()),
+			])
+			.block(zero + 5, Vec::new())
+			.block(zero + 6, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 6, key: vec![105] }, vec![2].into()),
+			])
+			.block(zero + 7, Vec::new())
+			.block(zero + 8, vec![
+				InputPair::DigestIndex(DigestIndex { block: zero + 8, key: vec![105] }, vec![zero + 6].into()),
+			])
+			.block(zero + 9, Vec::new())
+			.block(zero + 10, Vec::new())
+			.block(zero + 11, Vec::new())
+			.block(zero + 12, Vec::new())
+			.block(zero + 13, Vec::new())
+			.block(zero + 14, Vec::new())
+			.block(zero + 15, Vec::new())
+			.child_block(prefixed_child_trie_key1.clone(), zero + 1, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![100] }, vec![1, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![101] }, vec![0, 2].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 1, key: vec![105] }, vec![0, 2, 4].into()),
+			])
+			.child_block(prefixed_child_trie_key1.clone(), zero + 2, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0].into()),
+			])
+			.child_block(prefixed_child_trie_key1.clone(), zero + 4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 2, key: vec![102] }, vec![0, 3].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+			])
+			.require_contiguous()
+			.build();
 
 		let mut changes = OverlayedChanges::default();
 		changes.set_collect_extrinsics(true);
 
 		changes.start_transaction();
 
-		changes.set_extrinsic_index(1);
+		changes.set_extrinsic_index(1).unwrap();
 		changes.set_storage(vec![101], Some(vec![203]));
 
-		changes.set_extrinsic_index(3);
+		changes.set_extrinsic_index(3).unwrap();
 		changes.set_storage(vec![100], Some(vec![202]));
 		changes.set_child_storage(&child_info_1, vec![100], Some(vec![202]));
 
 		changes.commit_transaction().unwrap();
 
-		changes.set_extrinsic_index(0);
+		changes.set_extrinsic_index(0).unwrap();
 		changes.set_storage(vec![100], Some(vec![0]));
-		changes.set_extrinsic_index(2);
+		changes.set_extrinsic_index(2).unwrap();
 		changes.set_storage(vec![100], Some(vec![200]));
 
-		changes.set_extrinsic_index(0);
+		changes.set_extrinsic_index(0).unwrap();
 		changes.set_storage(vec![103], Some(vec![0]));
-		changes.set_extrinsic_index(1);
+		changes.set_extrinsic_index(1).unwrap();
 		changes.set_storage(vec![103], None);
 
-		changes.set_extrinsic_index(0);
+		changes.set_extrinsic_index(0).unwrap();
 		changes.set_child_storage(&child_info_1, vec![100], Some(vec![0]));
-		changes.set_extrinsic_index(2);
+		changes.set_extrinsic_index(2).unwrap();
 		changes.set_child_storage(&child_info_1, vec![100], Some(vec![200]));
 
-		changes.set_extrinsic_index(0);
+		changes.set_extrinsic_index(0).unwrap();
 		changes.set_child_storage(&child_info_2, vec![100], Some(vec![0]));
-		changes.set_extrinsic_index(2);
+		changes.set_extrinsic_index(2).unwrap();
 		changes.set_child_storage(&child_info_2, vec![100], Some(vec![200]));
 
-		changes.set_extrinsic_index(1);
+		changes.set_extrinsic_index(1).unwrap();
 
-		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
 
 		(backend, storage, changes, config)
 	}
@@ -454,6 +1569,180 @@ mod test {
 		}
 	}
 
+	fn collect_input<B: Backend<Blake2Hasher>>(
+		backend: &B,
+		storage: &dyn Storage<Blake2Hasher, u64>,
+		config: &Configuration,
+		changes: &OverlayedChanges,
+		parent: &AnchorBlockId<sp_core::H256, u64>,
+	) -> Result<(Vec<InputPair<u64>>, Vec<(ChildIndex<u64>, Vec<InputPair<u64>>)>), Error<u64>> {
+		let (top, children, _) = prepare_input(
+			backend,
+			storage,
+			configuration_range(config, 0),
+			changes,
+			parent,
+			parent.number + 1,
+		)?;
+		Ok((
+			top.collect(),
+			children.into_iter().map(|(index, input)| (index, input.collect())).collect(),
+		))
+	}
+
+	#[test]
+	fn input_is_the_same_for_overlay_and_collected_changes() {
+		use crate::changes_trie::CollectedChanges;
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let child_info_1 = ChildInfo::new_default(b"storage_key1");
+		let child_info_2 = ChildInfo::new_default(b"storage_key2");
+		let mut collected_changes = CollectedChanges::default();
+		collected_changes.note_change(None, vec![101], 1, true);
+		collected_changes.note_change(None, vec![100], 3, true);
+		collected_changes.note_change(Some(&child_info_1), vec![100], 3, true);
+		collected_changes.note_change(None, vec![100], 0, true);
+		collected_changes.note_change(None, vec![100], 2, true);
+		collected_changes.note_change(None, vec![103], 0, true);
+		collected_changes.note_change(None, vec![103], 1, false);
+		collected_changes.note_change(Some(&child_info_1), vec![100], 0, true);
+		collected_changes.note_change(Some(&child_info_1), vec![100], 2, true);
+		collected_changes.note_change(Some(&child_info_2), vec![100], 0, true);
+		collected_changes.note_change(Some(&child_info_2), vec![100], 2, true);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let prepare = |changes: &dyn ChangesSource| prepare_input_with_limit_from_source(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			changes,
+			&parent,
+			usize::max_value(),
+		);
+		let (top, children, digest_input_blocks) = prepare(&changes).unwrap();
+		assert_eq!(prepare(&collected_changes), Ok((top.clone(), children.clone(), digest_input_blocks)));
+		assert_eq!(top.len(), 7);
+		assert_eq!(children.len(), 2);
+	}
+
+	#[test]
+	fn input_order_does_not_depend_on_changes_order() {
+		let (backend, storage, _, config) = prepare_for_build(0);
+		let child_infos = vec![
+			ChildInfo::new_default(b"storage_key1"),
+			ChildInfo::new_default(b"storage_key2"),
+			ChildInfo::new_default(b"storage_key3"),
+		];
+		let mut changed = Vec::new();
+		for extrinsic in 0..4u32 {
+			for key in 100..108u8 {
+				if (key as u32 + extrinsic) % 3 == 0 {
+					changed.push((extrinsic, None, vec![key]));
+					changed.push((extrinsic, Some(&child_infos[key as usize % 3]), vec![key]));
+				}
+			}
+		}
+
+		let prepare = |changed: &mut dyn Iterator<Item=&(u32, Option<&ChildInfo>, Vec<u8>)>| {
+			let mut changes = OverlayedChanges::default();
+			changes.set_collect_extrinsics(true);
+			for (extrinsic, child_info, key) in changed {
+				changes.set_extrinsic_index(*extrinsic).unwrap();
+				match child_info {
+					Some(child_info) => changes.set_child_storage(child_info, key.clone(), Some(vec![1])),
+					None => changes.set_storage(key.clone(), Some(vec![1])),
+				}
+			}
+			let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+			prepare_input_with_limit(
+				&backend,
+				&storage,
+				configuration_range(&config, 0),
+				&changes,
+				&parent,
+				usize::max_value(),
+			).unwrap()
+		};
+
+		let (top, children, digest_input_blocks) = prepare(&mut changed.iter());
+		assert_eq!(prepare(&mut changed.iter().rev()), (top.clone(), children.clone(), digest_input_blocks));
+
+		// extrinsic pairs go first, then digest pairs, both sorted by key
+		let kinds = top.iter()
+			.map(|pair| matches!(pair, InputPair::ExtrinsicIndex(_, _)))
+			.collect::<Vec<_>>();
+		assert_eq!(kinds, vec![true; 8].into_iter().chain(vec![false; 4]).collect::<Vec<_>>());
+		let mut sorted = top.clone();
+		sorted.reverse();
+		sorted.sort();
+		assert_eq!(sorted, top);
+		let digest_pair = InputPair::DigestIndex(DigestIndex { block: 4, key: vec![105] }, vec![1, 3].into());
+		assert_eq!(top.binary_search(&digest_pair), Ok(11));
+
+		let child_indices = children.iter().map(|(child_index, _)| child_index.clone()).collect::<Vec<_>>();
+		let mut sorted_child_indices = child_indices.clone();
+		sorted_child_indices.sort();
+		assert_eq!(child_indices, sorted_child_indices);
+		assert_eq!(child_indices.len(), 3);
+		for (_, input) in &children {
+			assert!(is_sorted_input(input));
+		}
+	}
+
+	#[test]
+	fn digest_is_built_on_top_of_overlayed_changes_tries() {
+		use crate::changes_trie::{OverlayedChangesTrieStorage, State, try_build_changes_trie};
+
+		let (backend, _, _, config) = prepare_for_build(0);
+		let storage_without_blocks_after_4 = || {
+			let (_, storage, _, _) = prepare_for_build(0);
+			(5..16).for_each(|block| storage.remove(&block));
+			storage
+		};
+		let build = |storage: &dyn Storage<Blake2Hasher, u64>, block: u64| {
+			let mut changes = OverlayedChanges::default();
+			changes.set_collect_extrinsics(true);
+			changes.set_extrinsic_index(0).unwrap();
+			changes.set_storage(vec![100 + block as u8], Some(vec![1]));
+			let state = State::new(config.clone(), 0, storage);
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let (trie, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+				.unwrap()
+				.unwrap();
+			(root, trie)
+		};
+
+		// blocks 5..=8 are built and committed one after another
+		let storage = storage_without_blocks_after_4();
+		let mut expected_roots = Vec::new();
+		for block in 5..=8 {
+			let (root, trie) = build(&storage, block);
+			storage.insert(block, root, trie);
+			expected_roots.push(root);
+		}
+
+		// blocks 5..=8 are built speculatively, without committing them
+		let storage = OverlayedChangesTrieStorage::new(storage_without_blocks_after_4());
+		let mut roots = Vec::new();
+		for block in 5..=8 {
+			let (root, trie) = build(&storage, block);
+			storage.insert(block, root, trie);
+			roots.push(root);
+		}
+		assert_eq!(roots, expected_roots);
+		let anchor = AnchorBlockId { hash: Default::default(), number: 8 };
+		assert_eq!(storage.inner().roots(&anchor, &[5, 6, 7, 8]), Ok(vec![None; 4]));
+		assert_eq!(storage.root(&anchor, 8), Ok(Some(roots[3])));
+
+		// block 5 is committed and blocks 6..=8 are abandoned
+		let (root, trie) = storage.commit(&5).unwrap();
+		assert_eq!(root, roots[0]);
+		storage.inner().insert(5, root, trie);
+		storage.revert(&6);
+		assert_eq!(storage.overlayed_blocks(), Vec::<u64>::new());
+		assert_eq!(storage.roots(&anchor, &[5, 6]), Ok(vec![Some(roots[0]), None]));
+	}
+
 	#[test]
 	fn build_changes_trie_nodes_on_non_digest_block() {
 		fn test_with_zero(zero: u64) {
@@ -467,21 +1756,22 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![103] }, vec![0, 1]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![103] }, vec![0, 1].into()),
 			]);
 			assert_eq!(changes_trie_nodes.1.into_iter()
 				.map(|(k,v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(), vec![
 				(ChildIndex { block: zero + 5u64, storage_key: child_trie_key1 },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5u64, key: vec![100] }, vec![0, 2, 3]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5u64, key: vec![100] }, vec![0, 2, 3].into()),
 					]),
 				(ChildIndex { block: zero + 5, storage_key: child_trie_key2 },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![100] }, vec![0, 2]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![100] }, vec![0, 2].into()),
 					]),
 			]);
 
@@ -505,31 +1795,32 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1]),
-
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3].into()),
 			]);
 			assert_eq!(changes_trie_nodes.1.into_iter()
 				.map(|(k,v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(), vec![
 				(ChildIndex { block: zero + 4u64, storage_key: child_trie_key1.clone() },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4u64, key: vec![100] }, vec![0, 2, 3]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4u64, key: vec![100] }, vec![0, 2, 3].into()),
 
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1]),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1].into()),
 					]),
 				(ChildIndex { block: zero + 4, storage_key: child_trie_key2.clone() },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2].into()),
 					]),
 			]);
 		}
@@ -540,50 +1831,314 @@ mod test {
 	}
 
 	#[test]
-	fn build_changes_trie_nodes_on_digest_block_l2() {
-		fn test_with_zero(zero: u64) {
-			let child_trie_key1 = ChildInfo::new_default(b"storage_key1").prefixed_storage_key();
-			let child_trie_key2 = ChildInfo::new_default(b"storage_key2").prefixed_storage_key();
-			let (backend, storage, changes, config) = prepare_for_build(zero);
-			let parent = AnchorBlockId { hash: Default::default(), number: zero + 15 };
-			let changes_trie_nodes = prepare_input(
+	fn changes_trie_root_is_computed_from_parts() {
+		use crate::changes_trie::{ProofRecorder, State, try_build_changes_trie};
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let roots = (1..4)
+			.map(|block| (block, storage.root(&parent, block).unwrap().unwrap()))
+			.collect::<Vec<_>>();
+
+		// build changes trie of block#4, recording nodes of digest input tries
+		let storage = ProofRecorder::new(storage);
+		let state = State::new(config.clone(), 0, &storage);
+		let (_, expected_root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+			.unwrap()
+			.unwrap();
+		let proof = storage.extract_proof();
+
+		// split the input of block#4 into extrinsic and digest pairs
+		let (input, children_input, _) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+		let is_digest_pair = |pair: &InputPair<u64>| matches!(pair, InputPair::DigestIndex(_, _));
+		let mut extrinsics_input: InputParts<u64> = Default::default();
+		let mut digest_input: InputParts<u64> = Default::default();
+		let (input_digest, input_extrinsics) = input.partition::<Vec<_>, _>(is_digest_pair);
+		extrinsics_input.0 = input_extrinsics;
+		digest_input.0 = input_digest;
+		for (child_index, pairs) in children_input {
+			let (child_digest, child_extrinsics) = pairs.partition::<Vec<_>, _>(is_digest_pair);
+			extrinsics_input.1.insert(child_index.clone(), child_extrinsics);
+			digest_input.1.insert(child_index, child_digest);
+		}
+
+		let compute_root = |digest_input: &InputParts<u64>| compute_root_from_parts::<Blake2Hasher, _>(
+			configuration_range(&config, 0),
+			4,
+			extrinsics_input.clone(),
+			digest_input.clone(),
+		).unwrap();
+		let verify = |digest_input: &InputParts<u64>| verify_digest_pairs_against_proof::<Blake2Hasher, _>(
+			configuration_range(&config, 0),
+			4,
+			&roots,
+			proof.clone(),
+			digest_input,
+		);
+		assert_eq!(compute_root(&digest_input), expected_root);
+		assert_eq!(verify(&digest_input), Ok(true));
+
+		// digest pair that isn't backed by the digest input tries is detected
+		let mut tampered_digest_input = digest_input.clone();
+		tampered_digest_input.0[3] = InputPair::DigestIndex(DigestIndex { block: 4, key: vec![105] }, vec![1].into());
+		assert_ne!(compute_root(&tampered_digest_input), expected_root);
+		assert_eq!(verify(&tampered_digest_input), Ok(false));
+
+		// digest pairs can't be verified without roots of all digest input blocks
+		assert_eq!(
+			verify_digest_pairs_against_proof::<Blake2Hasher, _>(
+				configuration_range(&config, 0),
+				4,
+				&roots[..2],
+				proof.clone(),
+				&digest_input,
+			),
+			Err(Error::DigestInputRootsMissing(vec![3])),
+		);
+	}
+
+	#[test]
+	fn empty_changes_are_only_skipped_at_non_digest_blocks() {
+		use crate::changes_trie::{State, try_build_changes_trie};
+
+		let child_trie_key1 = ChildInfo::new_default(b"storage_key1").prefixed_storage_key();
+		let (backend, storage, _, config) = prepare_for_build(0);
+		let changes = OverlayedChanges::default();
+		assert!(ChangesSource::is_empty(&changes));
+		let prepare = |block: u64| {
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let (input, children_input, digest_input_blocks) = prepare_input(
 				&backend,
 				&storage,
-				configuration_range(&config, zero),
+				configuration_range(&config, 0),
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
-			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![103] }, vec![0, 1]),
-
-				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![100] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![101] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![102] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![103] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![105] }, vec![zero + 4, zero + 8]),
-			]);
-			assert_eq!(changes_trie_nodes.1.into_iter()
-				.map(|(k,v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(), vec![
-				(ChildIndex { block: zero + 16u64, storage_key: child_trie_key1.clone() },
-					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16u64, key: vec![100] }, vec![0, 2, 3]),
-
-						InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![102] }, vec![zero + 4]),
-					]),
-				(ChildIndex { block: zero + 16, storage_key: child_trie_key2.clone() },
-					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![100] }, vec![0, 2]),
-					]),
-			]);
-		}
+			(
+				input.collect::<Vec<_>>(),
+				children_input.into_iter().map(|(k, v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(),
+				digest_input_blocks,
+			)
+		};
 
-		test_with_zero(0);
+		// nothing is changed at non-digest block => there's no input and the trie is empty
+		assert_eq!(prepare(5), (vec![], vec![], vec![]));
+		let state = State::new(config.clone(), 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 4 };
+		let (_, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+			.unwrap()
+			.unwrap();
+		assert_eq!(root, empty_root::<Blake2Hasher>());
+
+		// nothing is changed at digest block => the trie only contains digest
+		assert_eq!(prepare(4), (
+			vec![
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![100] }, vec![1, 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![101] }, vec![1].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![102] }, vec![2].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![105] }, vec![1, 3].into()),
+			],
+			vec![
+				(ChildIndex { block: 4, storage_key: child_trie_key1 }, vec![
+					InputPair::DigestIndex(DigestIndex { block: 4, key: vec![100] }, vec![1].into()),
+					InputPair::DigestIndex(DigestIndex { block: 4, key: vec![101] }, vec![1].into()),
+					InputPair::DigestIndex(DigestIndex { block: 4, key: vec![102] }, vec![2].into()),
+					InputPair::DigestIndex(DigestIndex { block: 4, key: vec![105] }, vec![1].into()),
+				]),
+			],
+			vec![1, 2, 3],
+		));
+	}
+
+	#[test]
+	fn first_digest_covers_only_blocks_after_configuration_activation() {
+		// changes tries are enabled at block 5 => there are no changes tries for blocks 1..4
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::with_inputs(vec![
+			(5, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 5, key: vec![100] }, vec![0].into()),
+			]),
+			(6, vec![]),
+			(7, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 7, key: vec![100] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 7, key: vec![101] }, vec![0].into()),
+			]),
+		], vec![]);
+		let changes = OverlayedChanges::default();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 7 };
+		let (top, children, digest_input_blocks) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 4),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+		assert_eq!(digest_input_blocks, vec![5, 6, 7]);
+		assert_eq!(top.collect::<Vec<_>>(), vec![
+			InputPair::DigestIndex(DigestIndex { block: 8, key: vec![100] }, vec![5, 7].into()),
+			InputPair::DigestIndex(DigestIndex { block: 8, key: vec![101] }, vec![7].into()),
+		]);
+		assert!(children.is_empty());
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_on_digest_block_l2() {
+		fn test_with_zero(zero: u64) {
+			let child_trie_key1 = ChildInfo::new_default(b"storage_key1").prefixed_storage_key();
+			let child_trie_key2 = ChildInfo::new_default(b"storage_key2").prefixed_storage_key();
+			let (backend, storage, changes, config) = prepare_for_build(zero);
+			let parent = AnchorBlockId { hash: Default::default(), number: zero + 15 };
+			let changes_trie_nodes = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(&config, zero),
+				&changes,
+				&parent,
+				parent.number + 1,
+			).unwrap();
+			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![100] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![101] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![102] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![103] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![105] }, vec![zero + 4, zero + 8].into()),
+			]);
+			assert_eq!(changes_trie_nodes.1.into_iter()
+				.map(|(k,v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(), vec![
+				(ChildIndex { block: zero + 16u64, storage_key: child_trie_key1.clone() },
+					vec![
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16u64, key: vec![100] }, vec![0, 2, 3].into()),
+
+						InputPair::DigestIndex(DigestIndex { block: zero + 16, key: vec![102] }, vec![zero + 4].into()),
+					]),
+				(ChildIndex { block: zero + 16, storage_key: child_trie_key2.clone() },
+					vec![
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![100] }, vec![0, 2].into()),
+					]),
+			]);
+		}
+
+		test_with_zero(0);
 		test_with_zero(16);
 		test_with_zero(17);
 	}
 
+	#[test]
+	fn build_changes_trie_nodes_on_digest_block_uses_anchor_fork() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let main_parent = AnchorBlockId {
+			hash: storage.root(&AnchorBlockId { hash: Default::default(), number: 15 }, 15).unwrap().unwrap(),
+			number: 15,
+		};
+
+		// fork#1 starts at block 4, where only key 106 has been changed. Blocks 5..=14
+		// of fork#1 are the same as blocks of the main fork and key 107 has been changed at block 15
+		let insert_fork_block = |block: u64, key: Vec<u8>| {
+			let mut fork_mdb = sp_trie::MemoryDB::default();
+			let fork_root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+				&mut fork_mdb,
+				vec![
+					InputPair::ExtrinsicIndex(ExtrinsicIndex { block, key }, vec![0].into()),
+				].into_iter().map(Into::into),
+			).unwrap();
+			storage.insert_at(1, block, fork_root, fork_mdb);
+			fork_root
+		};
+		insert_fork_block(4, vec![106]);
+		for block in 5..15 {
+			let root = storage.root(&main_parent, block).unwrap().unwrap();
+			storage.insert_at(1, block, root, Default::default());
+		}
+		let fork_parent = AnchorBlockId { hash: insert_fork_block(15, vec![107]), number: 15 };
+
+		// the canonical fork isn't switched => roots are resolved using the anchor only
+		let digest_input = |parent: &AnchorBlockId<sp_core::H256, u64>| prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			parent,
+			parent.number + 1,
+		).unwrap().0
+			.filter(|pair| matches!(pair, InputPair::DigestIndex(_, _)))
+			.collect::<Vec<_>>();
+
+		assert_eq!(digest_input(&fork_parent), vec![
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![8].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![106] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![107] }, vec![15].into()),
+		]);
+		assert_eq!(digest_input(&main_parent), vec![
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![100] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![101] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![102] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![103] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![4, 8].into()),
+		]);
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_on_digest_block_uses_canonical_fork() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+
+		// fork#1 starts at block 4, where only key 106 has been changed. Blocks 5..=15
+		// of fork#1 are the same as blocks of the main fork
+		let mut fork_mdb = sp_trie::MemoryDB::default();
+		let fork_root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+			&mut fork_mdb,
+			vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4u64, key: vec![106] }, vec![0].into()),
+			].into_iter().map(Into::into),
+		).unwrap();
+		storage.insert_at(1, 4, fork_root, fork_mdb);
+		for block in 5..16 {
+			let root = storage.root(&parent, block).unwrap().unwrap();
+			storage.insert_at(1, block, root, Default::default());
+		}
+
+		let digest_input = |storage: &InMemoryStorage<Blake2Hasher, u64>| prepare_input(
+			&backend,
+			storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap().0
+			.filter(|pair| matches!(pair, InputPair::DigestIndex(_, _)))
+			.collect::<Vec<_>>();
+
+		storage.switch_fork(1);
+		assert_eq!(digest_input(&storage), vec![
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![8].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![106] }, vec![4].into()),
+		]);
+
+		storage.switch_fork(crate::changes_trie::storage::MAIN_FORK);
+		assert_eq!(digest_input(&storage), vec![
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![100] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![101] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![102] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![103] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![4, 8].into()),
+		]);
+	}
+
 	#[test]
 	fn build_changes_trie_nodes_on_skewed_digest_block() {
 		fn test_with_zero(zero: u64) {
@@ -597,11 +2152,12 @@ mod test {
 				configuration_range.clone(),
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![103] }, vec![0, 1]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![103] }, vec![0, 1].into()),
 			]);
 
 			configuration_range.end = Some(zero + 11);
@@ -611,17 +2167,18 @@ mod test {
 				configuration_range,
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![103] }, vec![0, 1]),
-
-				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![100] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![101] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![102] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![103] }, vec![zero + 4]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![105] }, vec![zero + 4, zero + 8]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![100] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![101] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![102] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![103] }, vec![zero + 4].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 11, key: vec![105] }, vec![zero + 4, zero + 8].into()),
 			]);
 		}
 
@@ -647,31 +2204,32 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				parent.number + 1,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1]),
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1]),
-
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![101] }, vec![1].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1, zero + 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+				InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1, zero + 3].into()),
 			]);
 			assert_eq!(changes_trie_nodes.1.into_iter()
 				.map(|(k,v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(), vec![
 				(ChildIndex { block: zero + 4u64, storage_key: child_trie_key1.clone() },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4u64, key: vec![100] }, vec![0, 2, 3]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4u64, key: vec![100] }, vec![0, 2, 3].into()),
 
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2]),
-						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1]),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![100] }, vec![zero + 1].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![101] }, vec![zero + 1].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![102] }, vec![zero + 2].into()),
+						InputPair::DigestIndex(DigestIndex { block: zero + 4, key: vec![105] }, vec![zero + 1].into()),
 					]),
 				(ChildIndex { block: zero + 4, storage_key: child_trie_key2.clone() },
 					vec![
-						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2]),
+						InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2].into()),
 					]),
 			]);
 
@@ -682,6 +2240,1719 @@ mod test {
 		test_with_zero(17);
 	}
 
+	#[test]
+	fn changes_filter_affects_changes_trie_root() {
+		use crate::changes_trie::{
+			AllChangesFilter, BuildParams, BuildStats, NoExistenceCache, State, try_build_changes_trie_with_stats,
+		};
+
+		let (backend, storage, mut changes, config) = prepare_for_build(0);
+		// 110: missing from backend, set to None in overlay
+		changes.set_storage(vec![110], None);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let build = |params: BuildParams| {
+			let state = State::new(config.clone(), 0, &storage).with_build_params(params);
+			let mut stats = Some(BuildStats::default());
+			let (_, root, _) = try_build_changes_trie_with_stats(&backend, Some(&state), &changes, &parent, &mut stats)
+				.unwrap()
+				.unwrap();
+			(root, stats.unwrap())
+		};
+		let (default_root, default_stats) = build(BuildParams::default());
+		let (all_root, all_stats) = build(BuildParams { changes_filter: &AllChangesFilter, ..Default::default() });
+		assert_ne!(default_root, all_root);
+		assert_eq!(default_stats.skipped_transient, 1);
+		assert_eq!(all_stats.skipped_transient, 0);
+		assert_eq!(all_stats.extrinsic_pairs, default_stats.extrinsic_pairs + 1);
+
+		// the temporary value has been written to the overlay, but it isn't reported as changed
+		assert_eq!(changes.storage(&[110]), Some(None));
+		assert!(!default_stats.changed_keys.top.contains(&vec![110]));
+		assert_eq!(default_stats.changed_keys.filtered_out, 1);
+		assert!(all_stats.changed_keys.top.contains(&vec![110]));
+		assert_eq!(all_stats.changed_keys.filtered_out, 0);
+		assert_eq!(
+			default_stats.changed_keys.top.iter().cloned().collect::<Vec<_>>(),
+			vec![vec![100], vec![101], vec![103]],
+		);
+
+		// the temporary value is only included by the filter that includes all changes
+		let temporary_value = InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![110] }, vec![1].into());
+		let extrinsics_input = |changes_filter: &dyn ChangesFilter| prepare_extrinsics_input_pairs::<_, Blake2Hasher, u64>(
+			&backend,
+			&NoExistenceCache,
+			&BuildParams { changes_filter, ..Default::default() },
+			&4,
+			&config,
+			&changes,
+		).unwrap().0;
+		assert!(!extrinsics_input(&DefaultChangesFilter).contains(&temporary_value));
+		assert!(extrinsics_input(&AllChangesFilter).contains(&temporary_value));
+	}
+
+	#[test]
+	fn deleted_keys_are_marked_when_configuration_records_deletions() {
+		use crate::changes_trie::{AllChangesFilter, NoExistenceCache};
+
+		let (backend, _, mut changes, config) = prepare_for_build(0);
+		// 110: missing from backend, set to None in overlay
+		changes.set_storage(vec![110], None);
+
+		let recording_config = Configuration { record_deletions: true, ..config.clone() };
+		let extrinsics_input = |config: &Configuration, changes_filter: &dyn ChangesFilter| {
+			prepare_extrinsics_input_pairs::<_, Blake2Hasher, u64>(
+				&backend,
+				&NoExistenceCache,
+				&BuildParams { changes_filter, ..Default::default() },
+				&4,
+				config,
+				&changes,
+			).unwrap().0
+		};
+
+		// 103 has existed in the backend and it has been deleted by the extrinsic 1
+		let key_103 = |extrinsics: Vec<ExtrinsicId>| InputPair::ExtrinsicIndex(
+			ExtrinsicIndex { block: 4, key: vec![103] },
+			extrinsics.into(),
+		);
+		assert!(extrinsics_input(&config, &DefaultChangesFilter)
+			.contains(&key_103(vec![ExtrinsicId(0), ExtrinsicId(1)])));
+		assert_eq!(extrinsics_input(&recording_config, &DefaultChangesFilter), vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![100] }, vec![0, 2, 3].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![101] }, vec![1].into()),
+			key_103(vec![ExtrinsicId(0), ExtrinsicId(1), ExtrinsicId::KEY_DELETION]),
+		]);
+
+		// existence is checked even if the filter doesn't require it, so the temporary value isn't
+		// marked as deleted
+		let all_input = extrinsics_input(&recording_config, &AllChangesFilter);
+		assert!(all_input.contains(&key_103(vec![ExtrinsicId(0), ExtrinsicId(1), ExtrinsicId::KEY_DELETION])));
+		assert!(all_input.contains(
+			&InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![110] }, vec![1].into()),
+		));
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_ignores_rolled_back_changes() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![100], Some(vec![1]));
+
+		// the runtime tries some operation inside extrinsic#0 and rolls it back
+		changes.start_transaction();
+		changes.set_storage(vec![100], Some(vec![2]));
+		changes.set_storage(vec![101], Some(vec![2]));
+		changes.set_extrinsic_index(1).unwrap();
+		changes.set_storage(vec![102], Some(vec![2]));
+		changes.rollback_transaction().unwrap();
+
+		// and continues with other writes
+		changes.set_storage(vec![103], Some(vec![3]));
+		changes.start_transaction();
+		changes.set_storage(vec![104], Some(vec![4]));
+		changes.commit_transaction().unwrap();
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let changes_trie_nodes = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+		assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![100] }, vec![0].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![103] }, vec![0].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![104] }, vec![0].into()),
+		]);
+	}
+
+	#[test]
+	fn changes_outside_of_extrinsics_are_attributed_to_reserved_indices() {
+		use crate::{BLOCK_INITIALIZATION_EXTRINSIC_INDEX, BLOCK_FINALIZATION_EXTRINSIC_INDEX};
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+
+		changes.note_block_initialization();
+		changes.set_storage(vec![100], Some(vec![1]));
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![101], Some(vec![2]));
+		// the index of the last extrinsic is still set when the block is finalized
+		changes.note_block_finalization();
+		changes.set_storage(vec![100], Some(vec![3]));
+		changes.set_storage(vec![102], Some(vec![4]));
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (top, _, _) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+		assert_eq!(top.collect::<Vec<_>>(), vec![
+			InputPair::ExtrinsicIndex(
+				ExtrinsicIndex { block: 1, key: vec![100] },
+				vec![BLOCK_INITIALIZATION_EXTRINSIC_INDEX, BLOCK_FINALIZATION_EXTRINSIC_INDEX].into(),
+			),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![101] }, vec![0].into()),
+			InputPair::ExtrinsicIndex(
+				ExtrinsicIndex { block: 1, key: vec![102] },
+				vec![BLOCK_FINALIZATION_EXTRINSIC_INDEX].into(),
+			),
+		]);
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_skips_ignored_keys() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+
+		// the runtime only updates system keys
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(b":hot:timestamp".to_vec(), Some(vec![1]));
+		changes.set_storage(b":hot:events".to_vec(), Some(vec![2]));
+		changes.set_storage(
+			sp_core::storage::well_known_keys::EXTRINSIC_INDEX.to_vec(),
+			Some(1u32.encode()),
+		);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let input = |config: &Configuration| {
+			let (top, children, _) = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(config, 0),
+				&changes,
+				&parent,
+				parent.number + 1,
+			).unwrap();
+			(top.collect::<Vec<_>>(), children.len())
+		};
+
+		let mut config = Configuration { ignored_prefixes: Vec::new(), ..Configuration::new(4, 2) };
+		assert_eq!(input(&config).0.len(), 3);
+
+		// the extrinsic index is ignored by default
+		config.ignored_prefixes = Configuration::new(4, 2).ignored_prefixes;
+		assert_eq!(input(&config).0.len(), 2);
+
+		config.ignored_prefixes.push(b":hot:".to_vec());
+		assert_eq!(input(&config), (vec![], 0));
+	}
+
+	#[test]
+	fn estimated_changes_trie_size_matches_extrinsics_input() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (top_input, children_input, _) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+
+		// estimation doesn't include encoded block number of every pair
+		let input_size = |input: Vec<InputPair<u64>>| input.into_iter()
+			.map(|pair| {
+				let (key, value): (Vec<u8>, Vec<u8>) = pair.into();
+				(key.len() + value.len() - 1u64.encode().len()) as u64
+			})
+			.sum::<u64>();
+		let actual_size = input_size(top_input.collect())
+			+ children_input.into_iter().map(|(_, input)| input_size(input.collect())).sum::<u64>();
+
+		assert_eq!(changes.extrinsic_changes().estimated_trie_size(), actual_size);
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_fails_when_digest_input_root_is_missing() {
+		fn test_with_zero(zero: u64) {
+			let (backend, storage, changes, config) = prepare_for_build(zero);
+
+			// block zero + 20 is the l1 digest block, but there are no changes tries for blocks
+			// zero + 17 ..= zero + 19 in the storage
+			let parent = AnchorBlockId { hash: Default::default(), number: zero + 19 };
+			let result = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(&config, zero),
+				&changes,
+				&parent,
+				parent.number + 1,
+			);
+			match result {
+				Err(Error::DigestInputRootsMissing(blocks)) =>
+					assert_eq!(blocks, vec![zero + 17, zero + 18, zero + 19]),
+				Err(err) => panic!("unexpected error: {}", err),
+				Ok(_) => panic!("prepare_input should fail when digest input root is missing"),
+			}
+		}
+
+		test_with_zero(0);
+		test_with_zero(16);
+		test_with_zero(17);
+	}
+
+	/// Changes trie storage that counts requests for roots and trie nodes.
+	struct CountingStorage {
+		inner: InMemoryStorage<Blake2Hasher, u64>,
+		root_requests: AtomicUsize,
+		roots_requests: AtomicUsize,
+		get_requests: AtomicUsize,
+		changed_keys_requests: parking_lot::Mutex<Vec<sp_core::H256>>,
+	}
+
+	impl CountingStorage {
+		fn new(inner: InMemoryStorage<Blake2Hasher, u64>) -> Self {
+			CountingStorage {
+				inner,
+				root_requests: Default::default(),
+				roots_requests: Default::default(),
+				get_requests: Default::default(),
+				changed_keys_requests: Default::default(),
+			}
+		}
+	}
+
+	impl RootsStorage<Blake2Hasher, u64> for CountingStorage {
+		fn build_anchor(&self, hash: sp_core::H256) -> Result<AnchorBlockId<sp_core::H256, u64>, Error<u64>> {
+			self.inner.build_anchor(hash)
+		}
+
+		fn root(&self, anchor: &AnchorBlockId<sp_core::H256, u64>, block: u64) -> Result<Option<sp_core::H256>, Error<u64>> {
+			self.root_requests.fetch_add(1, Ordering::Relaxed);
+			self.inner.root(anchor, block)
+		}
+
+		fn roots(
+			&self,
+			anchor: &AnchorBlockId<sp_core::H256, u64>,
+			blocks: &[u64],
+		) -> Result<Vec<Option<sp_core::H256>>, Error<u64>> {
+			self.roots_requests.fetch_add(1, Ordering::Relaxed);
+			self.inner.roots(anchor, blocks)
+		}
+	}
+
+	impl Storage<Blake2Hasher, u64> for CountingStorage {
+		fn as_roots_storage(&self) -> &dyn RootsStorage<Blake2Hasher, u64> {
+			self
+		}
+
+		fn with_cached_changed_keys(
+			&self,
+			root: &sp_core::H256,
+			functor: &mut dyn FnMut(&std::collections::HashMap<Option<PrefixedStorageKey>, std::collections::HashSet<StorageKey>>),
+		) -> bool {
+			self.changed_keys_requests.lock().push(*root);
+			self.inner.with_cached_changed_keys(root, functor)
+		}
+
+		fn digest_cache(&self) -> Option<&crate::changes_trie::DigestCache<sp_core::H256, u64>> {
+			self.inner.digest_cache()
+		}
+
+		fn pending_digest(&self) -> Option<&crate::changes_trie::PendingDigest<u64>> {
+			self.inner.pending_digest()
+		}
+
+		fn node_cache(&self) -> Option<std::sync::Arc<crate::node_cache::NodeCache<Blake2Hasher>>> {
+			self.inner.node_cache()
+		}
+
+		fn get(&self, key: &sp_core::H256, prefix: hash_db::Prefix) -> Result<Option<sp_trie::DBValue>, Error<u64>> {
+			self.get_requests.fetch_add(1, Ordering::Relaxed);
+			self.inner.get(key, prefix)
+		}
+	}
+
+	#[test]
+	fn input_preparation_is_aborted_when_memory_limit_is_exceeded() {
+		let (backend, storage, _, config) = prepare_for_build(0);
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes.set_extrinsic_index(0).unwrap();
+		for key in 0..10_000u32 {
+			changes.set_storage(key.to_be_bytes().repeat(8), Some(vec![1; 32]));
+		}
+		assert!(changes.heap_size() >= 10_000 * (32 + 32));
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let prepare = |max_heap_bytes| prepare_input_with_limit(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			max_heap_bytes,
+		);
+
+		// the limit trips at the same point every time
+		let error = prepare(100_000).unwrap_err();
+		assert!(matches!(error, Error::MemoryLimitExceeded { limit: 100_000, .. }));
+		assert_eq!(prepare(100_000).unwrap_err(), error);
+
+		// when there's enough memory, all input is prepared
+		let (top, children, _) = prepare(usize::max_value()).unwrap();
+		assert_eq!(top.len(), 10_000);
+		assert!(children.is_empty());
+
+		// digest input is also limited
+		let (_, _, changes, _) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		assert!(matches!(
+			prepare_input_with_limit(&backend, &storage, configuration_range(&config, 0), &changes, &parent, 10),
+			Err(Error::MemoryLimitExceeded { limit: 10, .. }),
+		));
+	}
+
+	#[test]
+	fn digest_input_memory_is_recorded_once() {
+		let (backend, storage, _, config) = prepare_for_build(0);
+		let changes = OverlayedChanges::default();
+
+		// block 4 is the l1 digest block => there are only digest pairs in its input
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let prepare = |max_heap_bytes| prepare_input_with_limit(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			max_heap_bytes,
+		);
+		let (top, children, _) = prepare(usize::max_value()).unwrap();
+		let pairs = top.iter().chain(children.iter().flat_map(|(_, pairs)| pairs.iter())).collect::<Vec<_>>();
+		assert!(!pairs.is_empty());
+
+		// every digest pair is recorded once: the pair itself, its key (and its copy that orders
+		// the digest input) and its blocks
+		let expected = pairs.iter()
+			.map(|pair| match pair {
+				InputPair::DigestIndex(index, blocks) => std::mem::size_of::<InputPair<u64>>()
+					+ 2 * index.key.capacity()
+					+ blocks.len() * std::mem::size_of::<u64>(),
+				_ => unreachable!("there are no extrinsic changes in the block; qed"),
+			})
+			.sum::<usize>();
+		assert!(prepare(expected).is_ok());
+		assert_eq!(
+			prepare(expected - 1).unwrap_err(),
+			Error::MemoryLimitExceeded { used: expected, limit: expected - 1 },
+		);
+	}
+
+	#[test]
+	fn pending_digest_matches_digest_built_from_changes_tries() {
+		fn insert_changes_tries(
+			storage: &InMemoryStorage<Blake2Hasher, u64>,
+			block: u64,
+			mut top: Vec<InputPair<u64>>,
+			children: &[(ChildIndex<u64>, Vec<InputPair<u64>>)],
+		) -> Vec<InputPair<u64>> {
+			let mut mdb = sp_trie::MemoryDB::default();
+			for (child_index, pairs) in children {
+				let child_root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+					&mut mdb,
+					pairs.clone().into_iter().map(Into::into),
+				).unwrap();
+				top.push(InputPair::ChildIndex(child_index.clone(), child_root.as_ref().to_vec()));
+			}
+			let root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+				&mut mdb,
+				top.clone().into_iter().map(Into::into),
+			).unwrap_or_default();
+			storage.insert(block, root, mdb);
+			top
+		}
+
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		// digests are built from changes tries of `storage` and from pending digest of `pending_storage`
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let mut pending_storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		pending_storage.set_pending_digest(Some(PendingDigest::new()));
+		let pending_digest = PendingDigest::new();
+
+		let is_digest_pair = |pair: &&InputPair<u64>| matches!(pair, InputPair::DigestIndex(_, _));
+		let mut digest_blocks = Vec::new();
+		for block in 1..=16u64 {
+			let mut changes = OverlayedChanges::default();
+			changes.set_collect_extrinsics(true);
+			changes.set_extrinsic_index(0).unwrap();
+			changes.set_storage(vec![(block % 3) as u8], Some(vec![1]));
+			changes.set_extrinsic_index(1).unwrap();
+			changes.set_storage(vec![10 + (block % 5) as u8], Some(vec![1]));
+			if block % 2 == 0 {
+				changes.set_child_storage(&child_info, vec![(block % 7) as u8], Some(vec![1]));
+			}
+
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let prepare = |storage: &InMemoryStorage<Blake2Hasher, u64>| {
+				let (top, children, _) = prepare_input(
+					&backend,
+					storage,
+					configuration_range(&config, 0),
+					&changes,
+					&parent,
+					parent.number + 1,
+				).unwrap();
+				(
+					top.collect::<Vec<_>>(),
+					children.into_iter()
+						.map(|(child_index, pairs)| (child_index, pairs.collect::<Vec<_>>()))
+						.collect::<Vec<_>>(),
+				)
+			};
+			let (top, children) = prepare(&storage);
+			assert_eq!(prepare(&pending_storage), (top.clone(), children.clone()));
+
+			let pending_digest_input = pending_digest.take_if_digest_block(configuration_range(&config, 0), block);
+			if let Some((digest_top, digest_children)) = pending_digest_input {
+				assert_eq!(digest_top, top.iter().filter(is_digest_pair).cloned().collect::<Vec<_>>());
+				assert_eq!(
+					digest_children,
+					children.iter()
+						.map(|(child_index, pairs)| (
+							child_index.clone(),
+							pairs.iter().filter(is_digest_pair).cloned().collect::<Vec<_>>(),
+						))
+						.filter(|(_, pairs)| !pairs.is_empty())
+						.collect::<Vec<_>>(),
+				);
+				digest_blocks.push(block);
+			}
+
+			let top = insert_changes_tries(&storage, block, top, &children);
+			insert_changes_tries(&pending_storage, block, top.clone(), &children);
+			pending_storage.pending_digest().unwrap().note_block(block, &top, &children);
+			pending_digest.note_block(block, &top, &children);
+		}
+
+		assert_eq!(digest_blocks, vec![4, 8, 12, 16]);
+		// blocks are taken from the pending digest when digest is built
+		assert_eq!(pending_storage.pending_digest().unwrap().len(), 1);
+
+		// nothing is taken if some input block is missing
+		pending_digest.purge_from(&0);
+		pending_digest.note_block(17, &[], &[]);
+		pending_digest.note_block(19, &[], &[]);
+		assert!(pending_digest.take_if_digest_block(configuration_range(&config, 0), 20).is_none());
+		assert_eq!(pending_digest.len(), 2);
+	}
+
+	#[test]
+	fn input_is_prepared_for_any_block_number_type() {
+		fn prepare_digest_block_input<Number: BlockNumber>() -> Vec<InputPair<Number>> {
+			let config = Configuration { digest_interval: 4, digest_levels: 1, ..Default::default() };
+			let storage = InMemoryStorage::<Blake2Hasher, Number>::with_inputs(
+				(1u32..4).map(|block| (block.into(), vec![InputPair::ExtrinsicIndex(
+					ExtrinsicIndex { block: block.into(), key: vec![block as u8] },
+					vec![0].into(),
+				)])).collect(),
+				Vec::new(),
+			);
+			let backend = InMemoryBackend::<Blake2Hasher>::default();
+			let mut changes = OverlayedChanges::default();
+			changes.set_collect_extrinsics(true);
+			changes.set_extrinsic_index(0).unwrap();
+			changes.set_storage(vec![4], Some(vec![4]));
+
+			let parent = AnchorBlockId { hash: Default::default(), number: 3u32.into() };
+			let (top, _, _) = prepare_input(
+				&backend,
+				&storage,
+				ConfigurationRange { config: &config, zero: 0u32.into(), end: None },
+				&changes,
+				&parent,
+				4u32.into(),
+			).unwrap();
+			top.collect()
+		}
+
+		let u32_input = prepare_digest_block_input::<u32>();
+		let u64_input = prepare_digest_block_input::<u64>();
+		assert_eq!(u32_input, vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![4] }, vec![0].into()),
+			InputPair::DigestIndex(DigestIndex { block: 4, key: vec![1] }, vec![1].into()),
+			InputPair::DigestIndex(DigestIndex { block: 4, key: vec![2] }, vec![2].into()),
+			InputPair::DigestIndex(DigestIndex { block: 4, key: vec![3] }, vec![3].into()),
+		]);
+		assert_eq!(format!("{:?}", u32_input), format!("{:?}", u64_input));
+
+		// block numbers are encoded using their own encoding
+		for (u32_pair, u64_pair) in u32_input.iter().zip(u64_input.iter()) {
+			assert_eq!(u32_pair.key_encoded().len() + 4, u64_pair.key_encoded().len());
+			assert_eq!(
+				&InputPair::<u32>::decode_from_trie_pair(&u32_pair.key_encoded(), &u32_pair.value_encoded()).unwrap(),
+				u32_pair,
+			);
+		}
+	}
+
+	#[test]
+	fn digest_input_tries_nodes_are_read_from_node_cache() {
+		use std::sync::Arc;
+		use crate::node_cache::NodeCache;
+		use crate::changes_trie::{BuildStats, State, try_build_changes_trie_with_stats};
+
+		// block 16 is the l2 digest block => it reads tries of 3 l1 digest blocks and 3 regular blocks
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let build = |node_cache: Option<Arc<NodeCache<Blake2Hasher>>>| {
+			let (backend, mut inner, changes, config) = prepare_for_build(0);
+			inner.set_node_cache(node_cache);
+			let storage = CountingStorage::new(inner);
+			let state = State::new(config, 0, &storage);
+			let mut stats = Some(BuildStats::default());
+			let (_, root, _) = try_build_changes_trie_with_stats(&backend, Some(&state), &changes, &parent, &mut stats)
+				.unwrap()
+				.unwrap();
+			(root, storage.get_requests.load(Ordering::Relaxed), stats.unwrap())
+		};
+
+		let (root, get_requests, stats) = build(None);
+		assert_eq!((stats.node_cache_hits, stats.node_cache_misses), (0, 0));
+		let (cached_root, cached_get_requests, stats) = build(Some(Arc::new(NodeCache::new(1024))));
+		assert_eq!(cached_root, root);
+		assert!(cached_get_requests < get_requests);
+		assert!(stats.node_cache_hits > 0);
+		assert!(stats.node_cache_misses > 0);
+	}
+
+	#[test]
+	fn digest_input_roots_are_read_once_for_sibling_candidates() {
+		use crate::changes_trie::{CachedStorage, State, try_build_changes_trie};
+
+		// block 16 is the l2 digest block => roots of blocks 4, 8, 12, 13, 14 and 15 are read
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let (backend, inner, _, config) = prepare_for_build(0);
+		let storage = CachedStorage::new(CountingStorage::new(inner), 1024);
+		let state = State::new(config, 0, &storage);
+
+		// every candidate changes its own key, so candidates have different changes tries
+		let candidate_roots = (0..3u8).map(|candidate| {
+			let (_, _, mut changes, _) = prepare_for_build(0);
+			changes.set_extrinsic_index(0).unwrap();
+			changes.set_storage(vec![200 + candidate], Some(vec![candidate]));
+			let (_, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+				.unwrap()
+				.unwrap();
+			root
+		}).collect::<BTreeSet<_>>();
+		assert_eq!(candidate_roots.len(), 3);
+
+		assert_eq!(storage.inner().root_requests.load(Ordering::Relaxed), 6);
+		assert_eq!(storage.root_misses(), 6);
+		assert_eq!(storage.root_hits(), 12);
+	}
+
+	/// Changes trie storage that keeps nodes of every changes trie in the shard of its block.
+	///
+	/// Panics if the node is read without knowing its block, or if it isn't in the shard.
+	struct ShardedStorage {
+		roots: InMemoryStorage<Blake2Hasher, u64>,
+		shards: BTreeMap<u64, InMemoryStorage<Blake2Hasher, u64>>,
+	}
+
+	struct Shard<'a> {
+		block: u64,
+		nodes: &'a InMemoryStorage<Blake2Hasher, u64>,
+	}
+
+	impl<'a> crate::TrieBackendStorage<Blake2Hasher> for Shard<'a> {
+		type Overlay = sp_trie::MemoryDB<Blake2Hasher>;
+
+		fn get(&self, key: &sp_core::H256, prefix: hash_db::Prefix) -> Result<Option<sp_trie::DBValue>, String> {
+			match Storage::get(self.nodes, key, prefix).map_err(|e| e.to_string())? {
+				Some(node) => Ok(Some(node)),
+				None => panic!("Node {:?} is not in the shard of block {}", key, self.block),
+			}
+		}
+	}
+
+	impl RootsStorage<Blake2Hasher, u64> for ShardedStorage {
+		fn build_anchor(&self, hash: sp_core::H256) -> Result<AnchorBlockId<sp_core::H256, u64>, Error<u64>> {
+			self.roots.build_anchor(hash)
+		}
+
+		fn root(&self, anchor: &AnchorBlockId<sp_core::H256, u64>, block: u64) -> Result<Option<sp_core::H256>, Error<u64>> {
+			self.roots.root(anchor, block)
+		}
+	}
+
+	impl Storage<Blake2Hasher, u64> for ShardedStorage {
+		fn as_roots_storage(&self) -> &dyn RootsStorage<Blake2Hasher, u64> {
+			self
+		}
+
+		fn with_cached_changed_keys(
+			&self,
+			_root: &sp_core::H256,
+			_functor: &mut dyn FnMut(&std::collections::HashMap<Option<PrefixedStorageKey>, std::collections::HashSet<StorageKey>>),
+		) -> bool {
+			false
+		}
+
+		fn as_trie_backend_storage(&self, block: &u64) -> Option<crate::changes_trie::BlockTrieStorage<'_, Blake2Hasher>> {
+			let nodes = self.shards.get(block).unwrap_or_else(|| panic!("There's no shard of block {}", block));
+			Some(Box::new(Shard { block: *block, nodes }))
+		}
+
+		fn get(&self, key: &sp_core::H256, _prefix: hash_db::Prefix) -> Result<Option<sp_trie::DBValue>, Error<u64>> {
+			panic!("Node {:?} is read without knowing its block", key)
+		}
+	}
+
+	#[test]
+	fn digest_input_tries_nodes_are_read_from_block_shards() {
+		use crate::changes_trie::{BuildStats, State, try_build_changes_trie_with_stats};
+
+		let child_storage_key = ChildInfo::new_default(b"storage_key1").prefixed_storage_key();
+		let top_input = |block: u64| vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block, key: vec![100] }, vec![0].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block, key: vec![100 + block as u8] }, vec![1].into()),
+		];
+		let child_input = |block: u64| vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block, key: vec![200] }, vec![0].into()),
+		];
+		let storage = InMemoryStorage::with_inputs(
+			(1..=3).map(|block| (block, top_input(block))).collect(),
+			vec![(child_storage_key.clone(), (1..=3).map(|block| (block, child_input(block))).collect())],
+		);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let sharded_storage = ShardedStorage {
+			roots: InMemoryStorage::with_blocks(
+				(1..=3).map(|block| (block, storage.root(&parent, block).unwrap().unwrap())).collect(),
+			),
+			shards: (1..=3).map(|block| (block, InMemoryStorage::with_inputs(
+				vec![(block, top_input(block))],
+				vec![(child_storage_key.clone(), vec![(block, child_input(block))])],
+			))).collect(),
+		};
+
+		// block 4 is the l1 digest block of blocks 1, 2 and 3
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let build = |storage: &dyn Storage<Blake2Hasher, u64>| {
+			let state = State::new(config.clone(), 0, storage);
+			let mut stats = Some(BuildStats::default());
+			let (_, root, _) = try_build_changes_trie_with_stats(&backend, Some(&state), &changes, &parent, &mut stats)
+				.unwrap()
+				.unwrap();
+			(root, stats.unwrap().storage_reads)
+		};
+
+		let (root, reads) = build(&storage);
+		let (sharded_root, sharded_reads) = build(&sharded_storage);
+		assert_eq!(sharded_root, root);
+		assert_eq!(sharded_reads, reads);
+		assert!(reads > 0);
+	}
+
+	#[test]
+	fn changed_keys_are_enumerated() {
+		let (_, inner, _, config) = prepare_for_build(0);
+		let storage = CountingStorage::new(inner);
+		let enumerate = |begin: u64, end: u64, prefix: &[u8]| {
+			storage.root_requests.store(0, Ordering::Relaxed);
+			let mut changed_keys = Vec::new();
+			crate::changes_trie::enumerate_changed_keys_with_prefix(
+				configuration_range(&config, 0),
+				&storage,
+				begin,
+				&AnchorBlockId { hash: Default::default(), number: end },
+				prefix,
+				|block, key| changed_keys.push((block, key[0])),
+			).unwrap();
+			(changed_keys, storage.root_requests.load(Ordering::Relaxed))
+		};
+
+		// end block is the digest block => it isn't reported twice
+		assert_eq!(enumerate(1, 4, &[]), (vec![
+			(1, 100), (1, 101), (1, 105),
+			(2, 102),
+			(3, 100), (3, 105),
+			(4, 100), (4, 101), (4, 103),
+		], 5));
+		// digest#8 isn't empty => every block is read
+		assert_eq!(enumerate(5, 8, &[]), (vec![(6, 105)], 5));
+		// digest#12 is empty => blocks 9..11 are skipped
+		assert_eq!(enumerate(9, 12, &[]), (vec![], 1));
+		// digest#12 is out of range => every block is read
+		assert_eq!(enumerate(9, 11, &[]), (vec![], 3));
+		// keys with prefix
+		assert_eq!(enumerate(1, 4, &[101]), (vec![(1, 101), (4, 101)], 5));
+		// digest#8 has no keys with prefix => blocks 5..7 are skipped
+		assert_eq!(enumerate(5, 8, &[101]), (vec![], 1));
+
+		let mut changed_keys = Vec::new();
+		let result = crate::changes_trie::enumerate_changed_keys(
+			configuration_range(&config, 0),
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 4 },
+			|block, key| changed_keys.push((block, key.to_vec())),
+		);
+		assert_eq!(result, Ok(()));
+		assert_eq!(changed_keys.len(), 9);
+		assert!(matches!(
+			crate::changes_trie::enumerate_changed_keys(
+				configuration_range(&config, 0),
+				&storage,
+				0,
+				&AnchorBlockId { hash: Default::default(), number: 4 },
+				|_, _| (),
+			),
+			Err(Error::RangeNotCovered { .. }),
+		));
+	}
+
+	#[test]
+	fn digest_input_roots_are_read_at_once() {
+		let (backend, inner, changes, config) = prepare_for_build(0);
+		let storage = CountingStorage::new(inner);
+
+		// block 16 is the l2 digest block => it includes 3 l1 digests and 3 regular blocks
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let (_, _, digest_input_blocks) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+
+		assert_eq!(digest_input_blocks, vec![4, 8, 12, 13, 14, 15]);
+		assert_eq!(storage.root_requests.load(Ordering::Relaxed), 0);
+		assert_eq!(storage.roots_requests.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn empty_tries_are_not_read_when_digest_is_built() {
+		let (backend, inner, changes, config) = prepare_for_build(0);
+		let storage = CountingStorage::new(inner);
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let root = |block: u64| storage.inner.root(&anchor, block).unwrap().unwrap();
+		for block in &[5, 7, 9, 10, 11, 12, 13, 14, 15] {
+			assert_eq!(root(*block), empty_root::<Blake2Hasher>());
+		}
+
+		let read_digest_input = |parent: u64| {
+			storage.changed_keys_requests.lock().clear();
+			let _ = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(&config, 0),
+				&changes,
+				&AnchorBlockId { hash: Default::default(), number: parent },
+				parent + 1,
+			).unwrap();
+			storage.changed_keys_requests.lock().iter().cloned().collect::<BTreeSet<_>>()
+		};
+
+		assert_eq!(read_digest_input(7), vec![root(6)].into_iter().collect());
+		assert_eq!(read_digest_input(11), BTreeSet::new());
+		assert_eq!(read_digest_input(15), vec![root(4), root(8)].into_iter().collect());
+	}
+
+	#[test]
+	fn digest_input_is_read_from_digest_cache() {
+		let (backend, mut inner, changes, config) = prepare_for_build(0);
+		inner.set_digest_cache(Some(crate::changes_trie::DigestCache::new(16)));
+		let storage = CountingStorage::new(inner);
+
+		// block 16 is the l2 digest block => it includes 3 l1 digests and 3 regular blocks
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let digest_input = || {
+			let (top, children, _) = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(&config, 0),
+				&changes,
+				&parent,
+				parent.number + 1,
+			).unwrap();
+			(top.collect::<Vec<_>>(), children.into_iter().map(|(k, v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>())
+		};
+
+		let uncached_input = digest_input();
+		assert_ne!(storage.get_requests.load(Ordering::Relaxed), 0);
+		// tries of blocks 12..15 are empty and aren't read (and cached)
+		assert_eq!(storage.inner.digest_cache().unwrap().len(), 2);
+
+		storage.get_requests.store(0, Ordering::Relaxed);
+		let cached_input = digest_input();
+		assert_eq!(storage.get_requests.load(Ordering::Relaxed), 0);
+		assert_eq!(cached_input, uncached_input);
+	}
+
+	#[test]
+	fn forged_input_is_rejected() {
+		let extrinsic_pair = |block: u64, key: Vec<u8>| InputPair::ExtrinsicIndex(
+			ExtrinsicIndex { block, key },
+			vec![0].into(),
+		);
+		let digest_pair = |block: u64, key: Vec<u8>| InputPair::DigestIndex(
+			DigestIndex { block, key },
+			vec![1].into(),
+		);
+
+		// the same key in different mappings is fine
+		assert_eq!(
+			validate_input(&4, true, &[extrinsic_pair(4, vec![100]), digest_pair(4, vec![100])]),
+			Ok(()),
+		);
+
+		// the same key in the same mapping is a collision
+		assert_eq!(
+			validate_input(&4, true, &[extrinsic_pair(4, vec![100]), digest_pair(4, vec![101]), extrinsic_pair(4, vec![100])]),
+			Err(Error::DuplicateInputKey(vec![100])),
+		);
+		assert_eq!(
+			validate_input(&4, true, &[digest_pair(4, vec![101]), digest_pair(4, vec![101])]),
+			Err(Error::DuplicateInputKey(vec![101])),
+		);
+
+		// pair of other block
+		assert_eq!(
+			validate_input(&4, true, &[extrinsic_pair(4, vec![100]), digest_pair(3, vec![101])]),
+			Err(Error::InvalidInputBlock { key: vec![101], block: 3, expected: 4 }),
+		);
+
+		// digest pair of non-digest block
+		assert_eq!(
+			validate_input(&5, false, &[extrinsic_pair(5, vec![100]), digest_pair(5, vec![101])]),
+			Err(Error::UnexpectedDigestBlock(5)),
+		);
+
+		// sorted input only keeps the last key
+		let validate_sorted_input = |input: &[InputPair<u64>]| {
+			let mut validator = InputValidator::sorted(4, true);
+			input.iter().try_for_each(|pair| validator.validate(pair))
+		};
+		assert_eq!(
+			validate_sorted_input(&[extrinsic_pair(4, vec![100]), extrinsic_pair(4, vec![1, 0]), digest_pair(4, vec![100])]),
+			Ok(()),
+		);
+		assert_eq!(
+			validate_sorted_input(&[extrinsic_pair(4, vec![100]), extrinsic_pair(4, vec![100])]),
+			Err(Error::DuplicateInputKey(vec![100])),
+		);
+		assert_eq!(
+			validate_sorted_input(&[extrinsic_pair(4, vec![1, 0]), extrinsic_pair(4, vec![100])]),
+			Err(Error::UnsortedInputKey(vec![100])),
+		);
+	}
+
+	#[test]
+	fn digest_listed_blocks_are_checked_against_digest_range() {
+		let (_, _, _, config) = prepare_for_build(0);
+		let config_range = configuration_range(&config, 0);
+		let level_at = |block: u64| digest_level_at_block(config_range.clone(), block);
+
+		// digest may only list blocks of the range it covers
+		assert_eq!(level_at(12).unwrap().range_start(4), 9);
+		assert_eq!(level_at(16).unwrap().range_start(4), 1);
+		assert_eq!(check_digest_listed_block(config_range.clone(), &12, &9), Ok(()));
+		assert_eq!(
+			check_digest_listed_block(config_range.clone(), &12, &8),
+			Err(Error::InvalidDigestBlock { digest_block: 12, listed: 8 }),
+		);
+		assert_eq!(
+			check_digest_listed_block(config_range.clone(), &12, &12),
+			Err(Error::InvalidDigestBlock { digest_block: 12, listed: 12 }),
+		);
+	}
+
+	#[test]
+	fn build_stats_are_collected() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+
+		// block 16 is the l2 digest block => it includes 3 l1 digests and 3 regular blocks
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let (top, children, _) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+		let mut expected_stats = crate::changes_trie::BuildStats {
+			digest_blocks_walked: 6,
+			..Default::default()
+		};
+		let mut note_pair = |storage_key: Option<&PrefixedStorageKey>, pair: InputPair<u64>| {
+			match pair {
+				InputPair::ExtrinsicIndex(ref index, _) => {
+					expected_stats.extrinsic_pairs += 1;
+					let changed_keys = &mut expected_stats.changed_keys;
+					match storage_key {
+						Some(storage_key) => changed_keys.children.entry(storage_key.clone()).or_default(),
+						None => &mut changed_keys.top,
+					}.insert(index.key.clone());
+				},
+				InputPair::DigestIndex(_, _) => expected_stats.digest_pairs += 1,
+				InputPair::ChildIndex(_, _) => (),
+				InputPair::MissingBlocks(_, ref blocks) => expected_stats.missing_digest_input_blocks += blocks.len() as u64,
+			}
+			let (key, value): (StorageKey, Vec<u8>) = pair.into();
+			expected_stats.encoded_input_bytes += key.len() + value.len();
+		};
+		top.for_each(|pair| note_pair(None, pair));
+		for (child_index, pairs) in children {
+			let storage_key = child_index.storage_key.clone();
+			note_pair(None, InputPair::ChildIndex(child_index, sp_core::H256::default().as_ref().to_vec()));
+			pairs.for_each(|pair| note_pair(Some(&storage_key), pair));
+		}
+
+		let state = crate::changes_trie::State::new(config.clone(), 0, &storage);
+		let mut stats = Some(Default::default());
+		let (_, root, _) = crate::changes_trie::try_build_changes_trie_with_stats(
+			&backend,
+			Some(&state),
+			&changes,
+			&parent,
+			&mut stats,
+		).unwrap().unwrap();
+		let stats = stats.unwrap();
+		assert!(stats.storage_reads > 0);
+		assert_eq!(
+			crate::changes_trie::BuildStats { storage_reads: 0, elapsed: Default::default(), ..stats },
+			expected_stats,
+		);
+
+		// the same trie is built when stats aren't collected
+		let (_, root_without_stats, _) = crate::changes_trie::try_build_changes_trie(
+			&backend,
+			Some(&state),
+			&changes,
+			&parent,
+		).unwrap().unwrap();
+		assert_eq!(root_without_stats, root);
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_fails_when_configuration_is_invalid() {
+		let (backend, storage, changes, _) = prepare_for_build(0);
+		let config = Configuration { digest_interval: 1, digest_levels: 2, ..Default::default() };
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let result = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		);
+		match result {
+			Err(Error::InvalidConfiguration(invalid_config)) => assert_eq!(invalid_config, config),
+			Err(err) => panic!("unexpected error: {}", err),
+			Ok(_) => panic!("prepare_input should fail when configuration is invalid"),
+		}
+	}
+
+	#[test]
+	fn build_changes_trie_nodes_fails_when_changes_are_stale() {
+		let (backend, storage, mut changes, config) = prepare_for_build(0);
+		let mut changes_of = |block: u64| {
+			changes.reset_for_block(block, Some(&config));
+			changes.set_extrinsic_index(0).unwrap();
+			changes.set_storage(vec![100], Some(vec![200]));
+			changes.clone()
+		};
+		let prepare = |changes: &OverlayedChanges, parent, block| prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			changes,
+			&AnchorBlockId { hash: Default::default(), number: parent },
+			block,
+		).map(|(top, _, _)| top.collect::<Vec<_>>());
+		let prepare_error = |changes: &OverlayedChanges, parent, block| match prepare(changes, parent, block) {
+			Err(err) => err,
+			Ok(_) => panic!("prepare_input should fail when changes are collected for other block"),
+		};
+
+		// neither block#5, nor block#7 are digest blocks
+		assert_eq!(prepare_error(&changes_of(5), 6, 7), Error::StaleExtrinsicChanges(7));
+		// digest is built at block#4, but not at block#5
+		assert_eq!(prepare_error(&changes_of(5), 3, 4), Error::UnexpectedDigestBlock(5));
+		// digest is built at block#8, but not at block#6
+		assert_eq!(prepare_error(&changes_of(8), 5, 6), Error::MissingDigestBlock(8));
+		// the overlay matches the explicit block, but the parent doesn't
+		assert_eq!(prepare_error(&changes_of(6), 4, 6), Error::StaleExtrinsicChanges(5));
+
+		// the explicit block is checked even if the overlay doesn't know the block
+		let mut unknown_block_changes = OverlayedChanges::default();
+		unknown_block_changes.set_collect_extrinsics(true);
+		unknown_block_changes.set_extrinsic_index(0).unwrap();
+		unknown_block_changes.set_storage(vec![100], Some(vec![200]));
+		assert_eq!(prepare_error(&unknown_block_changes, 6, 5), Error::StaleExtrinsicChanges(7));
+		assert!(prepare(&unknown_block_changes, 4, 5).is_ok());
+
+		let expected_pair =
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 5, key: vec![100] }, vec![0].into());
+		assert!(prepare(&changes_of(5), 4, 5).unwrap().contains(&expected_pair));
+
+		// the block number type of the overlay doesn't matter
+		let mut u32_block_changes = unknown_block_changes.clone();
+		u32_block_changes.reset_for_block(5u32, Some(&config));
+		u32_block_changes.set_extrinsic_index(0).unwrap();
+		u32_block_changes.set_storage(vec![100], Some(vec![200]));
+		assert!(prepare(&u32_block_changes, 4, 5).unwrap().contains(&expected_pair));
+	}
+
+	#[test]
+	fn invalid_keys_of_digest_input_tries_are_reported() {
+		use sp_trie::TrieMut;
+		use crate::changes_trie::{BuildParams, State, try_build_changes_trie_with_stats};
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+
+		// replace changes trie of block#3 with the trie that has garbage key under ExtrinsicIndex prefix
+		let mut invalid_key = ExtrinsicIndex::key_neutral_prefix(3u64);
+		invalid_key.push(4 << 2); // compact length of the 4-byte key, but the key itself is missing
+		let mut mdb = sp_trie::MemoryDB::<Blake2Hasher>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = sp_trie::trie_types::TrieDBMut::<Blake2Hasher>::new(&mut mdb, &mut root);
+			let (key, value): (Vec<u8>, Vec<u8>) =
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3u64, key: vec![100] }, vec![0].into()).into();
+			trie.insert(&key, &value).unwrap();
+			trie.insert(&invalid_key, &[0]).unwrap();
+		}
+		storage.insert(3, root, mdb);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		match prepare_input(&backend, &storage, configuration_range(&config, 0), &changes, &parent, 4) {
+			Err(err) => assert_eq!(err, Error::InvalidInputKey { block: 3, key: invalid_key.clone() }),
+			Ok(_) => panic!("prepare_input should fail when digest input trie has invalid key"),
+		}
+
+		let build = |params| {
+			let state = State::new(config.clone(), 0, &storage).with_build_params(params);
+			let mut stats = Some(Default::default());
+			try_build_changes_trie_with_stats(&backend, Some(&state), &changes, &parent, &mut stats)
+				.map(|_| stats.unwrap().invalid_input_keys)
+		};
+		assert_eq!(
+			build(BuildParams::default()),
+			Err(Error::InvalidInputKey { block: 3, key: invalid_key }),
+		);
+		assert_eq!(build(BuildParams { tolerate_invalid_keys: true, ..Default::default() }), Ok(1));
+	}
+
+	#[test]
+	fn too_many_extrinsics_are_rejected() {
+		use crate::changes_trie::{BuildParams, State, try_build_changes_trie};
+
+		// key 100 is changed by extrinsics 0, 2 and 3 (both in top-level and child tries), other
+		// keys are changed by less extrinsics with lower indices
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let build = |params| {
+			let state = State::new(config.clone(), 0, &storage).with_build_params(params);
+			try_build_changes_trie(&backend, Some(&state), &changes, &parent).map(|_| ())
+		};
+
+		assert_eq!(build(BuildParams { max_extrinsics_per_block: 4, max_extrinsics_per_key: 3, ..Default::default() }), Ok(()));
+		assert_eq!(
+			build(BuildParams { max_extrinsics_per_block: 3, ..Default::default() }),
+			Err(Error::TooManyExtrinsics { key: vec![100], count: 4 }),
+		);
+		assert_eq!(
+			build(BuildParams { max_extrinsics_per_key: 2, ..Default::default() }),
+			Err(Error::TooManyExtrinsics { key: vec![100], count: 3 }),
+		);
+
+		// reserved indices of changes that are made outside of extrinsics are only counted by the per-key limit
+		let mut extrinsics = ExtrinsicList::from(vec![0, 1]);
+		extrinsics.insert(crate::ExtrinsicId::BLOCK_FINALIZATION);
+		let limits = ExtrinsicsLimits { max_per_block: 2, max_per_key: 3 };
+		assert_eq!(limits.check::<u64>(&[105], &extrinsics), Ok(()));
+		let limits = ExtrinsicsLimits { max_per_block: 2, max_per_key: 2 };
+		assert_eq!(limits.check::<u64>(&[105], &extrinsics), Err(Error::TooManyExtrinsics { key: vec![105], count: 3 }));
+	}
+
+	#[test]
+	fn digest_is_built_from_tries_in_key_value_database() {
+		use crate::changes_trie::{
+			DbColumns, DbStorage, DbStorageTransaction, InMemoryKvBackend, State, try_build_changes_trie,
+		};
+		use crate::trie_backend_essence::TrieBackendStorage;
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+
+		// build changes trie of block#4
+		let state = State::new(config.clone(), 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let (trie, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+			.unwrap()
+			.unwrap();
+
+		// commit tries of the fixture, replacing block#4 with the built trie
+		let anchor = AnchorBlockId { hash: Default::default(), number: 15 };
+		let mut transaction = DbStorageTransaction::new(DbColumns::default());
+		for block in (1..16).filter(|block| *block != 4) {
+			let block_root = storage.root(&anchor, block).unwrap().unwrap();
+			transaction.insert_root::<Blake2Hasher, _>(block, block_root);
+		}
+		transaction.insert_trie(4u64, root, trie.clone());
+		transaction.insert_block_hash::<Blake2Hasher, _>(sp_core::H256::repeat_byte(15), 15u64);
+		storage.insert(4, root, trie);
+		transaction.insert_nodes(storage.into_mdb());
+		let db = InMemoryKvBackend::default();
+		db.commit(transaction);
+		let db_storage = DbStorage::<_, Blake2Hasher>::new(db, DbColumns::default());
+
+		// roots and nodes are read back
+		let anchor = db_storage.build_anchor(sp_core::H256::repeat_byte(15)).unwrap();
+		assert_eq!(anchor.number, 15u64);
+		assert_eq!(db_storage.root(&anchor, 4), Ok(Some(root)));
+		assert_eq!(db_storage.root(&anchor, 16), Ok(None));
+		assert!(TrieBackendStorage::<Blake2Hasher>::get(&db_storage, &root, hash_db::EMPTY_PREFIX)
+			.unwrap()
+			.is_some());
+
+		// and digests are built the same way they're built from the in-memory storage
+		let (_, storage, changes, _) = prepare_for_build(0);
+		let (trie, root, _) = try_build_changes_trie(
+			&backend,
+			Some(&State::new(config.clone(), 0, &storage)),
+			&changes,
+			&parent,
+		).unwrap().unwrap();
+		storage.insert(4, root, trie);
+		for digest_block in vec![8, 16] {
+			let parent = AnchorBlockId { hash: Default::default(), number: digest_block - 1 };
+			assert_eq!(
+				collect_input(&backend, &db_storage, &config, &changes, &parent).unwrap(),
+				collect_input(&backend, &storage, &config, &changes, &parent).unwrap(),
+			);
+		}
+	}
+
+	#[test]
+	fn changes_trie_input_is_prepared_from_proof() {
+		use crate::proving_backend::{ProvingBackend, create_proof_check_backend};
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+
+		// record all reads that are required to build changes trie of block#4
+		let proving_backend = ProvingBackend::new(&backend);
+		let input = collect_input(&proving_backend, &storage, &config, &changes, &parent).unwrap();
+		let proof = proving_backend.extract_proof();
+
+		// and build the same input using the proof only
+		let proof_backend = create_proof_check_backend::<Blake2Hasher>(*backend.root(), proof).unwrap();
+		assert_eq!(
+			collect_input(&proof_backend, &storage, &config, &changes, &parent).unwrap(),
+			input,
+		);
+
+		// values are large enough (and different) to be stored in separate trie nodes
+		let backend: InMemoryBackend<Blake2Hasher> = vec![
+			(vec![100], vec![254; 64]),
+			(vec![101], vec![255; 64]),
+		].into_iter().collect::<std::collections::BTreeMap<_, _>>().into();
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![100], None);
+
+		let proving_backend = ProvingBackend::new(&backend);
+		collect_input(&proving_backend, &storage, &config, &changes, &parent).unwrap();
+		let proof = proving_backend.extract_proof();
+
+		// existence of key#101 can't be checked using proof that only has node of key#100
+		changes.set_storage(vec![101], None);
+		let proof_backend = create_proof_check_backend::<Blake2Hasher>(*backend.root(), proof).unwrap();
+		match collect_input(&proof_backend, &storage, &config, &changes, &parent) {
+			Err(Error::MissingProofNode(_)) => (),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[cfg(feature = "parallel")]
+	#[test]
+	fn parallel_digest_input_is_the_same_as_sequential() {
+		fn test_with_zero(zero: u64) {
+			let (_, storage, _, config) = prepare_for_build(zero);
+			for digest_block in vec![zero + 4, zero + 8, zero + 16] {
+				let parent = AnchorBlockId { hash: Default::default(), number: digest_block - 1 };
+				let digest_input_blocks = crate::changes_trie::build_iterator::digest_build_iterator(
+					configuration_range(&config, zero),
+					digest_block,
+				).collect::<Vec<_>>();
+				assert!(!digest_input_blocks.is_empty());
+
+				let (digest_input_blocks_roots, _) = digest_build_blocks_roots(
+					&parent,
+					&storage,
+					Default::default(),
+					MissingRootPolicy::Fail,
+					&digest_input_blocks,
+				).unwrap();
+
+				assert_eq!(
+					digest_build_blocks_keys_parallel(&storage, &digest_input_blocks_roots, None, None).unwrap(),
+					digest_build_blocks_keys(&storage, &digest_input_blocks_roots, None, None).unwrap(),
+				);
+			}
+		}
+
+		test_with_zero(0);
+		test_with_zero(16);
+		test_with_zero(17);
+	}
+
+	#[test]
+	fn digest_input_roots_of_empty_blocks_are_resolved_by_policy() {
+		let root = sp_core::H256::repeat_byte;
+		let empty = empty_root::<Blake2Hasher>();
+		let parent = AnchorBlockId { hash: Default::default(), number: 7 };
+		let roots = |storage: &InMemoryStorage<Blake2Hasher, u64>, policy| {
+			digest_build_blocks_roots::<Blake2Hasher, u64>(&parent, storage, policy, MissingRootPolicy::Fail, &[5, 6, 7])
+				.map(|(roots, _)| roots)
+		};
+
+		// blocks 5 and 7 have reused roots of their parents
+		let storage = InMemoryStorage::with_blocks(vec![(4, root(4)), (5, root(4)), (6, root(6)), (7, root(6))]);
+		assert_eq!(
+			roots(&storage, EmptyBlockPolicy::ReuseParentRoot),
+			Ok(vec![(5, empty), (6, root(6)), (7, empty)]),
+		);
+		assert_eq!(
+			roots(&storage, EmptyBlockPolicy::EmptyTrieRoot),
+			Ok(vec![(5, root(4)), (6, root(6)), (7, root(6))]),
+		);
+
+		// blocks 5 and 7 have omitted roots
+		let storage = InMemoryStorage::with_blocks(vec![(4, root(4)), (6, root(6))]);
+		assert_eq!(
+			roots(&storage, EmptyBlockPolicy::OmitRoot),
+			Ok(vec![(5, empty), (6, root(6)), (7, empty)]),
+		);
+		assert_eq!(roots(&storage, EmptyBlockPolicy::EmptyTrieRoot), Err(Error::DigestInputRootsMissing(vec![5, 7])));
+	}
+
+	#[test]
+	fn digest_input_roots_are_missing() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		storage.remove(&9);
+		storage.remove(&10);
+
+		// block 12 is the l1 digest block of blocks 9, 10 and 11
+		let parent = AnchorBlockId { hash: Default::default(), number: 11 };
+		let prepare = |on_missing_root| prepare_input_iter(
+			&backend,
+			&storage,
+			&BuildParams { on_missing_root, ..Default::default() },
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			Default::default(),
+		).and_then(|(input, _, digest_input_blocks)| Ok((
+			input.collect::<Result<Vec<_>, _>>()?,
+			digest_input_blocks,
+		)));
+
+		assert_eq!(
+			prepare(MissingRootPolicy::Fail).map(|_| ()),
+			Err(Error::DigestInputRootsMissing(vec![9, 10])),
+		);
+
+		let (input, digest_input_blocks) = prepare(MissingRootPolicy::SkipAndRecord).unwrap();
+		assert_eq!(digest_input_blocks, vec![9, 10, 11]);
+		assert_eq!(input.last(), Some(&InputPair::MissingBlocks(MissingBlocksIndex { block: 12 }, vec![9, 10].into())));
+		validate_input(&12, true, &input).unwrap();
+	}
+
+	#[test]
+	fn digest_is_built_without_missing_input_blocks() {
+		use crate::changes_trie::{BuildParams, BuildStats, State, try_build_changes_trie_with_stats};
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 7 };
+		let build = |storage: &InMemoryStorage<Blake2Hasher, u64>, on_missing_root| {
+			let state = State::new(config.clone(), 0, storage)
+				.with_build_params(BuildParams { on_missing_root, ..Default::default() });
+			let mut stats = Some(BuildStats::default());
+			try_build_changes_trie_with_stats::<_, Blake2Hasher, u64>(&backend, Some(&state), &changes, &parent, &mut stats)
+				.map(|result| (result.unwrap().1, stats.unwrap()))
+		};
+		let (complete_root, _) = build(&storage, MissingRootPolicy::Fail).unwrap();
+
+		// block 8 is the l1 digest block of blocks 5, 6 and 7, and key 105 is changed in block 6
+		storage.remove(&6);
+		assert_eq!(build(&storage, MissingRootPolicy::Fail).map(|_| ()), Err(Error::DigestInputRootsMissing(vec![6])));
+
+		let (degraded_root, stats) = build(&storage, MissingRootPolicy::SkipAndRecord).unwrap();
+		assert_ne!(degraded_root, complete_root);
+		assert_eq!(stats.missing_digest_input_blocks, 1);
+		assert_eq!(stats.digest_blocks_walked, 2);
+		assert_eq!(stats.digest_pairs, 0);
+	}
+
+	/// Changes trie storage that sets the cancellation flag when changed keys of the
+	/// `cancel_at`-th digest input block are requested.
+	struct CancellingStorage<'a> {
+		inner: InMemoryStorage<Blake2Hasher, u64>,
+		cancel: &'a AtomicBool,
+		cancel_at: usize,
+		changed_keys_requests: AtomicUsize,
+	}
+
+	impl<'a> RootsStorage<Blake2Hasher, u64> for CancellingStorage<'a> {
+		fn build_anchor(&self, hash: sp_core::H256) -> Result<AnchorBlockId<sp_core::H256, u64>, Error<u64>> {
+			self.inner.build_anchor(hash)
+		}
+
+		fn root(&self, anchor: &AnchorBlockId<sp_core::H256, u64>, block: u64) -> Result<Option<sp_core::H256>, Error<u64>> {
+			self.inner.root(anchor, block)
+		}
+	}
+
+	impl<'a> Storage<Blake2Hasher, u64> for CancellingStorage<'a> {
+		fn as_roots_storage(&self) -> &dyn RootsStorage<Blake2Hasher, u64> {
+			self
+		}
+
+		fn with_cached_changed_keys(
+			&self,
+			root: &sp_core::H256,
+			functor: &mut dyn FnMut(&std::collections::HashMap<Option<PrefixedStorageKey>, std::collections::HashSet<StorageKey>>),
+		) -> bool {
+			if self.changed_keys_requests.fetch_add(1, Ordering::Relaxed) + 1 == self.cancel_at {
+				self.cancel.store(true, Ordering::Relaxed);
+			}
+			self.inner.with_cached_changed_keys(root, functor)
+		}
+
+		fn get(&self, key: &sp_core::H256, prefix: hash_db::Prefix) -> Result<Option<sp_trie::DBValue>, Error<u64>> {
+			self.inner.get(key, prefix)
+		}
+	}
+
+	#[test]
+	fn digest_build_is_cancelled_between_input_blocks() {
+		use crate::changes_trie::{BuildParams, State, try_build_changes_trie_with_stats};
+
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let cancel = AtomicBool::new(false);
+		let storage = CancellingStorage {
+			inner: storage,
+			cancel: &cancel,
+			cancel_at: 2,
+			changed_keys_requests: AtomicUsize::new(0),
+		};
+
+		// block 4 is the l1 digest block of blocks 1, 2 and 3; the flag is set while
+		// block 2 is read => block 3 is never read
+		let parent = AnchorBlockId { hash: Default::default(), number: 3 };
+		let state = State::new(config.clone(), 0, &storage)
+			.with_build_params(BuildParams { cancel: Some(&cancel), ..Default::default() });
+		assert_eq!(
+			try_build_changes_trie_with_stats::<_, Blake2Hasher, u64>(&backend, Some(&state), &changes, &parent, &mut None)
+				.map(|_| ()),
+			Err(Error::Cancelled),
+		);
+		assert_eq!(storage.changed_keys_requests.load(Ordering::Relaxed), 2);
+
+		// the same build succeeds when it isn't cancelled
+		cancel.store(false, Ordering::Relaxed);
+		let state = State::new(config, 0, &storage.inner);
+		assert!(
+			try_build_changes_trie_with_stats::<_, Blake2Hasher, u64>(&backend, Some(&state), &changes, &parent, &mut None)
+				.unwrap()
+				.is_some()
+		);
+	}
+
+	#[test]
+	fn prepare_input_iter_returns_backend_errors_as_items() {
+		let (_, storage, changes, config) = prepare_for_build(0);
+		// backend that has no trie nodes at all
+		let backend = crate::TrieBackend::new(
+			sp_trie::MemoryDB::<Blake2Hasher>::default(),
+			sp_core::H256::repeat_byte(1),
+		);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (mut input, _, _) = prepare_input_iter(
+			&backend,
+			&storage,
+			&Default::default(),
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			Default::default(),
+		).unwrap();
+
+		// key 100 has a value in the overlay => its existence in the backend isn't checked
+		assert_eq!(
+			input.next().unwrap().unwrap(),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![100] }, vec![0, 2, 3].into()),
+		);
+		// key 103 is deleted in the overlay => backend is asked whether it exists
+		assert!(input.any(|pair| matches!(pair, Err(Error::Backend(_)))));
+	}
+
+	/// Backend that records keys passed to `exists_storage`.
+	#[derive(Debug)]
+	struct CountingBackend {
+		inner: InMemoryBackend<Blake2Hasher>,
+		exists_storage_keys: std::cell::RefCell<Vec<StorageKey>>,
+		/// If true, the last entry of every existence batch is lost.
+		truncate_existence_batch: bool,
+	}
+
+	impl Backend<Blake2Hasher> for CountingBackend {
+		type Error = crate::DefaultError;
+		type Transaction = sp_trie::MemoryDB<Blake2Hasher>;
+		type TrieBackendStorage = sp_trie::MemoryDB<Blake2Hasher>;
+
+		fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+			self.inner.storage(key)
+		}
+
+		fn child_storage(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> Result<Option<Vec<u8>>, Self::Error> {
+			self.inner.child_storage(child_info, key)
+		}
+
+		fn exists_storage(&self, key: &[u8]) -> Result<bool, Self::Error> {
+			self.exists_storage_keys.borrow_mut().push(key.to_vec());
+			self.inner.exists_storage(key)
+		}
+
+		fn exists_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<bool>>, Self::Error> {
+			let mut exists = keys.iter()
+				.map(|key| self.exists_storage(key).map(Some))
+				.collect::<Result<Vec<_>, _>>()?;
+			if self.truncate_existence_batch {
+				exists.pop();
+			}
+			Ok(exists)
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+			self.inner.next_storage_key(key)
+		}
+
+		fn next_child_storage_key(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> Result<Option<StorageKey>, Self::Error> {
+			self.inner.next_child_storage_key(child_info, key)
+		}
+
+		fn apply_to_key_values_while<F: FnMut(Vec<u8>, Vec<u8>) -> bool>(
+			&self,
+			child_info: Option<&ChildInfo>,
+			prefix: Option<&[u8]>,
+			start_at: Option<&[u8]>,
+			f: F,
+			allow_missing: bool,
+		) -> Result<bool, Self::Error> {
+			self.inner.apply_to_key_values_while(child_info, prefix, start_at, f, allow_missing)
+		}
+
+		fn apply_to_keys_while<F: FnMut(&[u8]) -> bool>(
+			&self,
+			child_info: Option<&ChildInfo>,
+			prefix: Option<&[u8]>,
+			f: F,
+		) {
+			self.inner.apply_to_keys_while(child_info, prefix, f)
+		}
+
+		fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+			self.inner.for_key_values_with_prefix(prefix, f)
+		}
+
+		fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+			&self,
+			child_info: &ChildInfo,
+			prefix: &[u8],
+			f: F,
+		) {
+			self.inner.for_child_keys_with_prefix(child_info, prefix, f)
+		}
+
+		fn storage_root<'a>(
+			&self,
+			delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		) -> (sp_core::H256, Self::Transaction) {
+			self.inner.storage_root(delta)
+		}
+
+		fn child_storage_root<'a>(
+			&self,
+			child_info: &ChildInfo,
+			delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		) -> (sp_core::H256, bool, Self::Transaction) {
+			self.inner.child_storage_root(child_info, delta)
+		}
+
+		fn pairs(&self) -> Vec<(StorageKey, Vec<u8>)> {
+			self.inner.pairs()
+		}
+
+		fn register_overlay_stats(&self, _stats: &crate::stats::StateMachineStats) { }
+
+		fn usage_info(&self) -> crate::UsageInfo {
+			crate::UsageInfo::empty()
+		}
+	}
+
+	#[test]
+	fn existence_of_deleted_keys_is_checked_once_per_key() {
+		let (inner, storage, _, config) = prepare_for_build(0);
+		let backend = CountingBackend {
+			inner,
+			exists_storage_keys: Default::default(),
+			truncate_existence_batch: false,
+		};
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+
+		// every key is deleted by the same extrinsics in two phases
+		for phase in 0..2 {
+			changes.start_transaction();
+			for key in vec![vec![103], vec![104], vec![110]] {
+				changes.set_extrinsic_index(0).unwrap();
+				changes.set_storage(key.clone(), Some(vec![phase]));
+				changes.set_extrinsic_index(1).unwrap();
+				changes.set_storage(key, None);
+			}
+			changes.commit_transaction().unwrap();
+		}
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (top, _, _) = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		).unwrap();
+
+		assert_eq!(
+			*backend.exists_storage_keys.borrow(),
+			vec![vec![103], vec![104], vec![110]],
+		);
+		// key 110 is a temporary value => it isn't included
+		assert_eq!(top.collect::<Vec<_>>(), vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![103] }, vec![0, 1].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![104] }, vec![0, 1].into()),
+		]);
+	}
+
+	#[test]
+	fn existence_batch_shorter_than_keys_is_an_error() {
+		let (inner, storage, _, config) = prepare_for_build(0);
+		let backend = CountingBackend {
+			inner,
+			exists_storage_keys: Default::default(),
+			truncate_existence_batch: true,
+		};
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![103], None);
+		changes.set_storage(vec![104], None);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let result = prepare_input(
+			&backend,
+			&storage,
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			parent.number + 1,
+		);
+		match result {
+			Err(Error::Backend(_)) => (),
+			Err(err) => panic!("unexpected error: {}", err),
+			Ok(_) => panic!("prepare_input should fail when existence batch is too short"),
+		}
+	}
+
+	#[test]
+	fn existence_cache_is_consulted_before_backend() {
+		let (inner, storage, _, config) = prepare_for_build(0);
+		let backend = CountingBackend {
+			inner,
+			exists_storage_keys: Default::default(),
+			truncate_existence_batch: false,
+		};
+
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes.set_extrinsic_index(0).unwrap();
+		// key 100 is set => it is known to exist
+		changes.set_storage(vec![100], Some(vec![1]));
+		// keys 101, 103 and 110 are written and then deleted
+		for key in vec![vec![101], vec![103], vec![110]] {
+			changes.set_storage(key.clone(), Some(vec![1]));
+			changes.set_storage(key, None);
+		}
+
+		// cache knows about key 101 only, so keys 103 and 110 are considered temporary
+		// (even though key 103 exists in the backend)
+		let mut existence_cache = crate::changes_trie::InMemoryExistenceCache::new();
+		existence_cache.insert(None, vec![101]);
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (top, _, _) = prepare_input_iter(
+			&backend,
+			&storage,
+			&Default::default(),
+			configuration_range(&config, 0),
+			&changes,
+			&parent,
+			InputContext { existence_cache: Some(&existence_cache), ..Default::default() },
+		).unwrap();
+
+		assert_eq!(top.collect::<Result<Vec<_>, _>>().unwrap(), vec![
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![100] }, vec![0].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![101] }, vec![0].into()),
+		]);
+		assert_eq!(*backend.exists_storage_keys.borrow(), vec![vec![101]]);
+	}
+
 	#[test]
 	fn cache_is_used_when_changes_trie_is_built() {
 		let child_trie_key1 = ChildInfo::new_default(b"storage_key1").prefixed_storage_key();
@@ -716,15 +3987,16 @@ mod test {
 			configuration_range(&config, 0),
 			&changes,
 			&parent,
+			parent.number + 1,
 		).unwrap();
 		assert_eq!(root_changes_trie_nodes.collect::<Vec<InputPair<u64>>>(), vec![
-			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![100] }, vec![0, 2, 3]),
-			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![101] }, vec![1]),
-			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![103] }, vec![0, 1]),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![100] }, vec![0, 2, 3].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![101] }, vec![1].into()),
+			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![103] }, vec![0, 1].into()),
 
-			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![100] }, vec![4]),
-			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![102] }, vec![4]),
-			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![8]),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![100] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![102] }, vec![4].into()),
+			InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![8].into()),
 		]);
 
 		let child_changes_tries_nodes = child_changes_tries_nodes
@@ -737,19 +4009,19 @@ mod test {
 				storage_key: child_trie_key1.clone(),
 			}).unwrap(),
 			&vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16u64, key: vec![100] }, vec![0, 2, 3]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16u64, key: vec![100] }, vec![0, 2, 3].into()),
 
-				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![103] }, vec![4]),
-				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![104] }, vec![4]),
+				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![103] }, vec![4].into()),
+				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![104] }, vec![4].into()),
 			],
 		);
 		assert_eq!(
 			child_changes_tries_nodes.get(&ChildIndex { block: 16u64, storage_key: child_trie_key2.clone() }).unwrap(),
 			&vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16u64, key: vec![100] }, vec![0, 2]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16u64, key: vec![100] }, vec![0, 2].into()),
 
-				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![105] }, vec![4]),
-				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![106] }, vec![4]),
+				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![105] }, vec![4].into()),
+				InputPair::DigestIndex(DigestIndex { block: 16u64, key: vec![106] }, vec![4].into()),
 			],
 		);
 	}
@@ -18,7 +18,7 @@
 //! Structures and functions to return blocks whose changes are to be included
 //! in given block's changes trie.
 
-use num_traits::Zero;
+use num_traits::{CheckedAdd, Zero};
 use crate::changes_trie::{ConfigurationRange, BlockNumber};
 
 /// Returns iterator of OTHER blocks that are required for inclusion into
@@ -40,6 +40,72 @@ pub fn digest_build_iterator<'a, Number: BlockNumber>(
 	DigestBuildIterator::new(block.clone(), config.end.unwrap_or(block), config.config.digest_interval, digest_step)
 }
 
+/// Returns iterator of digest blocks whose changes tries will include changes of given
+/// block, either directly or through lower level digests. Blocks are guaranteed to be
+/// returned in ascending order: L1 digest block goes first, then L2 digest block, ...
+///
+/// If configuration ends before the max level digest is created, the last returned
+/// block is the skewed digest block (config.end). Digest blocks that do not fit into
+/// `Number` are never returned.
+pub fn digest_parent_blocks<'a, Number: BlockNumber + CheckedAdd>(
+	config: ConfigurationRange<'a, Number>,
+	block: Number,
+) -> impl Iterator<Item=Number> + 'a {
+	let digest_interval = config.config.digest_interval;
+	let digest_levels = if block > config.zero {
+		config.config.effective_digest_levels()
+	} else {
+		0
+	};
+
+	let mut digest_step = 1u32;
+	let mut last_parent: Option<Number> = None;
+	let mut is_done = false;
+	(0..digest_levels).filter_map(move |_| {
+		if is_done {
+			return None;
+		}
+
+		// we are safe to use non-checking mul here, because digest_step never exceeds
+		// max digest interval
+		digest_step *= digest_interval;
+		let step: Number = digest_step.into();
+		let distance_to_parent = match (block.clone() - config.zero.clone()) % step.clone() {
+			// block itself is a digest block of this level
+			rem if rem.is_zero() => return None,
+			rem => step - rem,
+		};
+		let parent = match block.checked_add(&distance_to_parent) {
+			Some(parent) => parent,
+			None => {
+				is_done = true;
+				return None;
+			},
+		};
+
+		// there are no digests after config.end, but skewed digest at config.end
+		// includes all blocks that are not yet covered by digests
+		let parent = match config.end.as_ref() {
+			Some(end) if parent >= *end => {
+				is_done = true;
+				if *end <= block {
+					return None;
+				}
+				end.clone()
+			},
+			_ => parent,
+		};
+
+		// the same block could be a digest of multiple levels
+		if last_parent.as_ref() == Some(&parent) {
+			return None;
+		}
+
+		last_parent = Some(parent.clone());
+		Some(parent)
+	})
+}
+
 /// Changes trie build iterator that returns numbers of OTHER blocks that are
 /// required for inclusion into changes trie of given block.
 #[derive(Debug)]
@@ -182,6 +248,7 @@ mod tests {
 				config: &Configuration {
 					digest_interval,
 					digest_levels,
+					..Default::default()
 				},
 				zero,
 				end,
@@ -210,6 +277,76 @@ mod tests {
 		digest_build_iterator(digest_interval, digest_levels, zero, block, end).collect()
 	}
 
+	fn digest_parent_blocks(
+		digest_interval: u32,
+		digest_levels: u32,
+		zero: u64,
+		block: u64,
+		end: Option<u64>,
+	) -> Vec<u64> {
+		super::digest_parent_blocks(
+			ConfigurationRange {
+				config: &Configuration {
+					digest_interval,
+					digest_levels,
+					..Default::default()
+				},
+				zero,
+				end,
+			},
+			block,
+		).collect()
+	}
+
+	/// Returns all digest blocks in (zero; max_block] range along with blocks that they include.
+	fn digest_build_sets(
+		config: &Configuration,
+		zero: u64,
+		end: Option<u64>,
+		max_block: u64,
+	) -> Vec<(u64, Vec<u64>)> {
+		(zero + 1..=max_block)
+			.filter(|block| end.map(|end| *block <= end).unwrap_or(true))
+			.filter_map(|block| {
+				// skewed digest is built at the end block
+				let block_for_digest = if end == Some(block) {
+					config.next_max_level_digest_range(zero, block)
+						.map(|(_, end)| end)
+						.unwrap_or(block)
+				} else {
+					block
+				};
+				let build_set = super::digest_build_iterator(
+					ConfigurationRange { config, zero, end },
+					block_for_digest,
+				).collect::<Vec<_>>();
+				if build_set.is_empty() {
+					None
+				} else {
+					Some((block, build_set))
+				}
+			})
+			.collect()
+	}
+
+	/// Calls `f` for every tested configuration along with the last block that is
+	/// covered by its max level digests.
+	fn for_every_test_configuration(f: impl Fn(&Configuration, u64, Option<u64>, u64)) {
+		let configs = vec![
+			Configuration { digest_interval: 4, digest_levels: 1, ..Default::default() },
+			Configuration { digest_interval: 4, digest_levels: 3, ..Default::default() },
+			Configuration { digest_interval: 2, digest_levels: 5, ..Default::default() },
+			Configuration { digest_interval: 16, digest_levels: 2, ..Default::default() },
+		];
+		for config in &configs {
+			for zero in vec![0, 17] {
+				for end in vec![None, Some(zero + 50), Some(zero + 64)] {
+					f(config, zero, end, zero + 256);
+				}
+			}
+		}
+	}
+
 	#[test]
 	fn suggest_digest_inclusion_returns_empty_iterator() {
 		fn test_with_zero(zero: u64) {
@@ -429,4 +566,97 @@ mod tests {
 		test_with_zero(16);
 		test_with_zero(17);
 	}
+
+	#[test]
+	fn every_block_is_included_into_exactly_one_digest() {
+		for_every_test_configuration(|config, zero, end, max_block| {
+			let build_sets = digest_build_sets(config, zero, end, max_block);
+			for block in zero + 1..=max_block {
+				let is_max_level_digest = (block - zero) % config.max_digest_interval() as u64 == 0;
+				let is_after_end = end.map(|end| block >= end).unwrap_or(false);
+				let expected_digests = if is_max_level_digest || is_after_end { 0 } else { 1 };
+				assert_eq!(
+					build_sets.iter().filter(|(_, build_set)| build_set.contains(&block)).count(),
+					expected_digests,
+					"block {} with config {:?}, zero {}, end {:?}", block, config, zero, end,
+				);
+			}
+		});
+	}
+
+	#[test]
+	fn digest_parent_blocks_is_consistent_with_digest_build_iterator() {
+		for_every_test_configuration(|config, zero, end, max_block| {
+			let build_sets = digest_build_sets(config, zero, end, max_block);
+			for block in zero..=max_block {
+				// follow the digests chain that is built by digest_build_iterator
+				let mut expected_parents = Vec::new();
+				let mut current = block;
+				while let Some((parent, _)) = build_sets.iter()
+					.find(|(_, build_set)| build_set.contains(&current))
+				{
+					expected_parents.push(*parent);
+					current = *parent;
+				}
+
+				assert_eq!(
+					super::digest_parent_blocks(ConfigurationRange { config, zero, end }, block)
+						.collect::<Vec<_>>(),
+					expected_parents,
+					"block {} with config {:?}, zero {}, end {:?}", block, config, zero, end,
+				);
+			}
+		});
+	}
+
+	#[test]
+	fn digest_parent_blocks_works() {
+		fn test_with_zero(zero: u64) {
+			assert_eq!(digest_parent_blocks(16, 3, zero, zero, None), Vec::<u64>::new());
+			assert_eq!(
+				digest_parent_blocks(16, 3, zero, zero + 1, None),
+				[16, 256, 4096].iter().map(|item| zero + item).collect::<Vec<_>>(),
+			);
+			assert_eq!(
+				digest_parent_blocks(16, 3, zero, zero + 16, None),
+				[256, 4096].iter().map(|item| zero + item).collect::<Vec<_>>(),
+			);
+			assert_eq!(
+				digest_parent_blocks(16, 3, zero, zero + 4081, None),
+				[4096].iter().map(|item| zero + item).collect::<Vec<_>>(),
+			);
+			assert_eq!(digest_parent_blocks(16, 3, zero, zero + 4096, None), Vec::<u64>::new());
+			assert_eq!(
+				digest_parent_blocks(16, 3, zero, zero + 1300, Some(zero + 1338)),
+				[1312, 1338].iter().map(|item| zero + item).collect::<Vec<_>>(),
+			);
+			assert_eq!(
+				digest_parent_blocks(16, 3, zero, zero + 1330, Some(zero + 1338)),
+				[1338].iter().map(|item| zero + item).collect::<Vec<_>>(),
+			);
+			assert_eq!(digest_parent_blocks(16, 3, zero, zero + 1338, Some(zero + 1338)), Vec::<u64>::new());
+			assert_eq!(digest_parent_blocks(16, 0, zero, zero + 1, None), Vec::<u64>::new());
+			assert_eq!(digest_parent_blocks(1, 16, zero, zero + 1, None), Vec::<u64>::new());
+		}
+
+		test_with_zero(0);
+		test_with_zero(16);
+		test_with_zero(17);
+	}
+
+	#[test]
+	fn digest_parent_blocks_saturates() {
+		// digest_interval^2 is greater than u32::MAX => there's only 1 digest level
+		assert_eq!(
+			digest_parent_blocks(::std::u32::MAX / 2 + 1, 16, 0, 1, None),
+			vec![::std::u32::MAX as u64 / 2 + 1],
+		);
+		// L1 digest block is greater than u64::MAX
+		assert_eq!(digest_parent_blocks(16, 3, 0, ::std::u64::MAX - 1, None), Vec::<u64>::new());
+		// L2 digest block is greater than u64::MAX
+		assert_eq!(
+			digest_parent_blocks(16, 3, 0, ::std::u64::MAX - 17, None),
+			vec![::std::u64::MAX - 15],
+		);
+	}
 }
@@ -0,0 +1,138 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Functions that enumerate all keys that have been changed in the given blocks range.
+
+use codec::{Decode, Encode};
+use hash_db::Hasher;
+use num_traits::One;
+use crate::changes_trie::{
+	AnchorBlockId, ConfigurationRange, Storage, BlockNumber, Error, TrieBackendStorageAdapter,
+};
+use crate::changes_trie::input::{InputKey, DigestIndex, ExtrinsicIndex};
+use crate::trie_backend_essence::TrieBackendEssence;
+
+/// Call `f` for every top-level storage key that has been changed in given blocks range.
+///
+/// Keys are reported in ascending order of blocks, once for every block where they have been
+/// changed. Digest blocks without entries are used to skip ranges of blocks without changes.
+pub fn enumerate_changed_keys<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	f: impl FnMut(Number, &[u8]),
+) -> Result<(), Error<Number>>
+	where H::Out: Encode
+{
+	enumerate_changed_keys_with_prefix(config, storage, begin, end, &[], f)
+}
+
+/// Same as `enumerate_changed_keys`, but only reports keys that start with given prefix.
+pub fn enumerate_changed_keys_with_prefix<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	prefix: &[u8],
+	mut f: impl FnMut(Number, &[u8]),
+) -> Result<(), Error<Number>>
+	where H::Out: Encode
+{
+	if begin > end.number {
+		return Err(Error::InvalidRange { begin, end: end.number.clone(), max: end.number.clone() });
+	}
+	if begin <= config.zero || config.end.as_ref().map(|config_end| end.number > *config_end).unwrap_or(false) {
+		return Err(Error::RangeNotCovered {
+			begin,
+			end: end.number.clone(),
+			zero: config.zero,
+			config_end: config.end,
+		});
+	}
+
+	// digest intervals, starting from the max-level digest
+	let digest_intervals = (1..=config.config.effective_digest_levels())
+		.rev()
+		.map(|level| config.config.digest_interval.pow(level))
+		.collect::<Vec<_>>();
+
+	let mut block = begin;
+	while block <= end.number {
+		// if the block is the first block of some digest range AND there are no entries in
+		// the digest, then only keys of the digest block itself have been changed in this range
+		let mut changes_block = block.clone();
+		let mut changes_root = None;
+		let relative_block = block.clone() - config.zero.clone() - One::one();
+		for digest_interval in &digest_intervals {
+			let digest_interval = Number::from(*digest_interval);
+			if !(relative_block.clone() % digest_interval.clone()).is_zero() {
+				continue;
+			}
+
+			let digest_block = block.clone() + digest_interval - One::one();
+			if digest_block > end.number {
+				continue;
+			}
+
+			let digest_root = changes_trie_root(storage, end, &digest_block)?;
+			let trie_storage = TrieBackendEssence::<_, H>::new(TrieBackendStorageAdapter(storage), digest_root);
+			let mut has_digest_entries = false;
+			trie_storage.try_for_keys_with_prefix(&DigestIndex::key_neutral_prefix(digest_block.clone()), |mut key| {
+				if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
+					has_digest_entries = trie_key.key.starts_with(prefix);
+				}
+				!has_digest_entries
+			}).map_err(Error::Storage)?;
+
+			if !has_digest_entries {
+				changes_block = digest_block;
+				changes_root = Some(digest_root);
+				break;
+			}
+		}
+
+		let changes_root = match changes_root {
+			Some(changes_root) => changes_root,
+			None => changes_trie_root(storage, end, &changes_block)?,
+		};
+		let trie_storage = TrieBackendEssence::<_, H>::new(TrieBackendStorageAdapter(storage), changes_root);
+		// DigestIndex entries are never reported => keys are reported once per block
+		trie_storage.try_for_keys_with_prefix(&ExtrinsicIndex::key_neutral_prefix(changes_block.clone()), |mut key| {
+			if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut key) {
+				if trie_key.key.starts_with(prefix) {
+					f(changes_block.clone(), &trie_key.key);
+				}
+			}
+			true
+		}).map_err(Error::Storage)?;
+
+		block = changes_block + One::one();
+	}
+
+	Ok(())
+}
+
+/// Read changes trie root of given block.
+fn changes_trie_root<H: Hasher, Number: BlockNumber>(
+	storage: &dyn Storage<H, Number>,
+	anchor: &AnchorBlockId<H::Out, Number>,
+	block: &Number,
+) -> Result<H::Out, Error<Number>> {
+	storage.as_roots_storage().root(anchor, block.clone())?
+		.ok_or_else(|| Error::StorageRootMissing(block.clone()))
+}
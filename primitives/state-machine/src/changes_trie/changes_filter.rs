@@ -0,0 +1,63 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries keys filter.
+
+/// Filter that decides whether the changed key is included into the changes trie.
+///
+/// The filter affects the changes trie root, so all nodes of the chain must use the same
+/// filter. Otherwise they'll disagree on the roots of changes tries.
+pub trait ChangesFilter: Send + Sync {
+	/// Returns true if existence of the key in the backend (before the block) is required to
+	/// decide whether the key is included. Otherwise, existence isn't checked, unless the final
+	/// value is None and the configuration records deletions.
+	fn requires_existence(&self, final_value_is_some: bool) -> bool;
+	/// Returns true if the changed key is included into the changes trie.
+	///
+	/// The `existed_in_backend` is false if existence hasn't been checked (see
+	/// `requires_existence`).
+	fn include(&self, key: &[u8], final_value_is_some: bool, existed_in_backend: bool) -> bool;
+}
+
+/// Filter that skips temporary values, i.e. keys that have been created and then deleted
+/// within the same block. This is the filter that is used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultChangesFilter;
+
+impl ChangesFilter for DefaultChangesFilter {
+	fn requires_existence(&self, final_value_is_some: bool) -> bool {
+		!final_value_is_some
+	}
+
+	fn include(&self, _key: &[u8], final_value_is_some: bool, existed_in_backend: bool) -> bool {
+		final_value_is_some || existed_in_backend
+	}
+}
+
+/// Filter that includes all changed keys, including temporary values.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllChangesFilter;
+
+impl ChangesFilter for AllChangesFilter {
+	fn requires_existence(&self, _final_value_is_some: bool) -> bool {
+		false
+	}
+
+	fn include(&self, _key: &[u8], _final_value_is_some: bool, _existed_in_backend: bool) -> bool {
+		true
+	}
+}
@@ -19,17 +19,23 @@
 //! (block, extrinsic) pairs where given key has been changed.
 
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use codec::{Decode, Encode, Codec};
-use hash_db::Hasher;
-use num_traits::Zero;
+use hash_db::{Hasher, Prefix, EMPTY_PREFIX};
+use parking_lot::Mutex;
+use num_traits::{One, Zero};
 use sp_core::storage::PrefixedStorageKey;
-use sp_trie::Recorder;
-use crate::changes_trie::{AnchorBlockId, ConfigurationRange, RootsStorage, Storage, BlockNumber};
-use crate::changes_trie::input::{DigestIndex, ExtrinsicIndex, DigestIndexValue, ExtrinsicIndexValue};
+use sp_trie::{DBValue, Recorder};
+use crate::changes_trie::{AnchorBlockId, ConfigurationRange, RootsStorage, Storage, BlockNumber, Error};
+use crate::changes_trie::input::{
+	ChangeKind, DigestIndex, ExtrinsicIndex, MissingBlocksIndex, decode_block_list, decode_extrinsic_list,
+};
 use crate::changes_trie::storage::{TrieBackendAdapter, InMemoryStorage};
+use crate::changes_trie::build::check_digest_listed_block;
 use crate::changes_trie::input::ChildIndex;
 use crate::changes_trie::surface_iterator::{surface_iterator, SurfaceIterator};
+use crate::{StorageKey, StorageValue, ExtrinsicId};
 use crate::proving_backend::ProvingBackendRecorder;
 use crate::trie_backend_essence::{TrieBackendEssence};
 
@@ -44,34 +50,113 @@ pub fn key_changes<'a, H: Hasher, Number: BlockNumber>(
 	max: Number,
 	storage_key: Option<&'a PrefixedStorageKey>,
 	key: &'a [u8],
-) -> Result<DrilldownIterator<'a, H, Number>, String> {
+) -> Result<DrilldownIterator<'a, H, Number>, Error<Number>> {
 	// we can't query any roots before root
 	let max = std::cmp::min(max, end.number.clone());
 
 	Ok(DrilldownIterator {
-		essence: DrilldownIteratorEssence {
+		essence: DrilldownIteratorEssence::new(
 			storage_key,
 			key,
-			roots_storage: storage.as_roots_storage(),
+			storage.as_roots_storage(),
 			storage,
-			begin: begin.clone(),
 			end,
-			config: config.clone(),
-			surface: surface_iterator(
-				config,
-				max,
-				begin,
-				end.number.clone(),
-			)?,
+			surface_iterator(config, max, begin, end.number.clone())?,
+		),
+	})
+}
 
-			extrinsics: Default::default(),
-			blocks: Default::default(),
+/// Provides access to historical states, required to read values of changed keys.
+pub trait StateProvider<Number> {
+	/// Read value of the (child) storage key at the end of given block.
+	///
+	/// Returns Ok(None) if state of the block is not available.
+	fn storage(
+		&self,
+		block: &Number,
+		storage_key: Option<&PrefixedStorageKey>,
+		key: &[u8],
+	) -> Result<Option<Option<StorageValue>>, String>;
+}
 
-			_hasher: ::std::marker::PhantomData::<H>::default(),
-		},
-	})
+/// Key changes query: the key and the range of blocks where its changes are looked for.
+#[derive(Clone)]
+pub struct KeyChangesQuery<'a, Hash: std::fmt::Debug, Number: BlockNumber> {
+	/// Configuration range of changes tries that are read.
+	pub config: ConfigurationRange<'a, Number>,
+	/// First block of the range (inclusive).
+	pub begin: Number,
+	/// Anchor block of the range. Changes tries are read from the fork of this block.
+	pub end: &'a AnchorBlockId<Hash, Number>,
+	/// Last block of the range (inclusive). Is capped by the `end` block.
+	pub max: Number,
+	/// Key of the child storage, if the key belongs to the child storage.
+	pub storage_key: Option<&'a PrefixedStorageKey>,
+	/// The key.
+	pub key: &'a [u8],
+}
+
+/// Return changes of given key at given blocks range, together with values of the key
+/// at the end of every changed block.
+///
+/// Changes are returned in the same order as `key_changes` returns them. The value is
+/// read once per block, even if key has been changed by several extrinsics of the block.
+/// If state of the block is not available, `Error::StatePruned` is returned for all
+/// changes of this block, instead of the value.
+pub fn key_changes_with_values<'a, H: Hasher, Number: BlockNumber>(
+	storage: &'a dyn Storage<H, Number>,
+	query: KeyChangesQuery<'a, H::Out, Number>,
+	state: &dyn StateProvider<Number>,
+) -> Result<Vec<(Number, u32, Result<Option<StorageValue>, Error<Number>>)>, Error<Number>>
+	where H::Out: Encode
+{
+	let KeyChangesQuery { config, begin, end, max, storage_key, key } = query;
+	let changes = key_changes(config, storage, begin, end, max, storage_key, key)?
+		.collect::<Result<Vec<_>, _>>()?;
+
+	// changes of the same block are always adjacent => remember the last read value only
+	let mut last_read: Option<(Number, Result<Option<Option<StorageValue>>, String>)> = None;
+	Ok(changes.into_iter().map(|(block, extrinsic)| {
+		let value = match last_read {
+			Some((ref last_block, ref value)) if *last_block == block => value.clone(),
+			_ => {
+				let value = state.storage(&block, storage_key, key);
+				last_read = Some((block.clone(), value.clone()));
+				value
+			},
+		};
+		let value = match value {
+			Ok(Some(value)) => Ok(value),
+			Ok(None) => Err(Error::StatePruned(block.clone())),
+			Err(e) => Err(Error::Backend(e)),
+		};
+		(block, extrinsic, value)
+	}).collect())
 }
 
+/// Return kinds of changes of given key at given blocks range, one per changed block.
+///
+/// Blocks are returned in the same order as `key_changes` returns them. Kinds are only known
+/// if the configuration records deletions. Otherwise `ChangeKind::Unknown` is returned for
+/// every changed block.
+pub fn key_change_kinds<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &'a dyn Storage<H, Number>,
+	begin: Number,
+	end: &'a AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&'a PrefixedStorageKey>,
+	key: &'a [u8],
+) -> Result<Vec<(Number, ChangeKind)>, Error<Number>>
+	where H::Out: Encode
+{
+	let mut changes = key_changes(config, storage, begin, end, max, storage_key, key)?;
+	changes.essence.change_kinds = Some(Vec::new());
+	for change in &mut changes {
+		change?;
+	}
+	Ok(changes.essence.change_kinds.take().unwrap_or_default())
+}
 
 /// Returns proof of changes of given key at given blocks range.
 /// `max` is the number of best known block.
@@ -83,31 +168,35 @@ pub fn key_changes_proof<'a, H: Hasher, Number: BlockNumber>(
 	max: Number,
 	storage_key: Option<&PrefixedStorageKey>,
 	key: &[u8],
-) -> Result<Vec<Vec<u8>>, String> where H::Out: Codec {
+) -> Result<Vec<Vec<u8>>, Error<Number>> where H::Out: Codec {
+	key_changes_proof_with(config, storage, begin, end, max, storage_key, key, false)
+}
+
+/// Generate the key changes proof, optionally skipping pruned changes tries.
+fn key_changes_proof_with<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+	tolerate_pruned: bool,
+) -> Result<Vec<Vec<u8>>, Error<Number>> where H::Out: Codec {
 	// we can't query any roots before root
 	let max = std::cmp::min(max, end.number.clone());
 
+	let mut essence = DrilldownIteratorEssence::new(
+		storage_key,
+		key,
+		storage.as_roots_storage(),
+		storage,
+		end,
+		surface_iterator(config, max, begin, end.number.clone())?,
+	);
+	essence.tolerate_pruned = tolerate_pruned;
 	let mut iter = ProvingDrilldownIterator {
-		essence: DrilldownIteratorEssence {
-			storage_key,
-			key,
-			roots_storage: storage.as_roots_storage(),
-			storage,
-			begin: begin.clone(),
-			end,
-			config: config.clone(),
-			surface: surface_iterator(
-				config,
-				max,
-				begin,
-				end.number.clone(),
-			)?,
-
-			extrinsics: Default::default(),
-			blocks: Default::default(),
-
-			_hasher: ::std::marker::PhantomData::<H>::default(),
-		},
+		essence,
 		proof_recorder: Default::default(),
 	};
 
@@ -131,7 +220,7 @@ pub fn key_changes_proof_check<'a, H: Hasher, Number: BlockNumber>(
 	max: Number,
 	storage_key: Option<&PrefixedStorageKey>,
 	key: &[u8]
-) -> Result<Vec<(Number, u32)>, String> where H::Out: Encode {
+) -> Result<Vec<(Number, u32)>, Error<Number>> where H::Out: Encode {
 	key_changes_proof_check_with_db(
 		config,
 		roots_storage,
@@ -154,32 +243,534 @@ pub fn key_changes_proof_check_with_db<'a, H: Hasher, Number: BlockNumber>(
 	max: Number,
 	storage_key: Option<&PrefixedStorageKey>,
 	key: &[u8]
-) -> Result<Vec<(Number, u32)>, String> where H::Out: Encode {
+) -> Result<Vec<(Number, u32)>, Error<Number>> where H::Out: Encode {
+	key_changes_proof_check_iterator(config, roots_storage, proof_db, begin, end, max, storage_key, key)?
+		.collect()
+}
+
+/// Create drilldown iterator that reads changes tries from the proof storage.
+fn key_changes_proof_check_iterator<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &'a dyn RootsStorage<H, Number>,
+	proof_db: &'a InMemoryStorage<H, Number>,
+	begin: Number,
+	end: &'a AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&'a PrefixedStorageKey>,
+	key: &'a [u8]
+) -> Result<DrilldownIterator<'a, H, Number>, Error<Number>> {
 	// we can't query any roots before root
 	let max = std::cmp::min(max, end.number.clone());
 
-	DrilldownIterator {
-		essence: DrilldownIteratorEssence {
+	Ok(DrilldownIterator {
+		essence: DrilldownIteratorEssence::new(
 			storage_key,
 			key,
 			roots_storage,
-			storage: proof_db,
-			begin: begin.clone(),
+			proof_db,
 			end,
-			config: config.clone(),
-			surface: surface_iterator(
-				config,
-				max,
-				begin,
-				end.number.clone(),
-			)?,
+			surface_iterator(config, max, begin, end.number.clone())?,
+		),
+	})
+}
 
-			extrinsics: Default::default(),
-			blocks: Default::default(),
+/// Changes of several keys at given blocks range, grouped by the key.
+pub type KeysChanges<Number> = BTreeMap<StorageKey, Vec<(Number, u32)>>;
 
-			_hasher: ::std::marker::PhantomData::<H>::default(),
-		},
-	}.collect()
+/// Return changes of every key of `keys` at given blocks range.
+/// `max` is the number of best known block.
+///
+/// Changes of every key are the same that `key_changes` returns for this key. But every changes
+/// trie is opened once for all keys and every trie node is read from the storage once, no matter
+/// how many keys are looked up in this trie.
+pub fn keys_changes<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	keys: &[StorageKey],
+) -> Result<KeysChanges<Number>, Error<Number>> where H::Out: Encode {
+	let storage = NodesLimitedStorage::new(storage, usize::max_value());
+	keys_changes_with(config, storage.as_roots_storage(), &storage, begin, end, max, storage_key, keys)
+}
+
+/// Returns the single proof of changes of all `keys` at given blocks range.
+/// `max` is the number of best known block.
+///
+/// Nodes that are shared by lookups of several keys are included into the proof once.
+pub fn keys_changes_proof<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	keys: &[StorageKey],
+) -> Result<Vec<Vec<u8>>, Error<Number>> where H::Out: Encode {
+	let storage = NodesLimitedStorage::new(storage, usize::max_value());
+	keys_changes_with(config, storage.as_roots_storage(), &storage, begin, end, max, storage_key, keys)?;
+	Ok(storage.extract_proof())
+}
+
+/// Check the proof, generated by `keys_changes_proof`, and return changes of all `keys` at
+/// given blocks range.
+pub fn keys_changes_proof_check<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &dyn RootsStorage<H, Number>,
+	proof: Vec<Vec<u8>>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	keys: &[StorageKey],
+) -> Result<KeysChanges<Number>, Error<Number>> where H::Out: Encode {
+	let proof_db = InMemoryStorage::<H, Number>::with_proof(proof);
+	keys_changes_with(config, roots_storage, &proof_db, begin, end, max, storage_key, keys)
+}
+
+/// Drill down changes tries for every key, sharing opened tries between all keys.
+fn keys_changes_with<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &dyn RootsStorage<H, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	keys: &[StorageKey],
+) -> Result<KeysChanges<Number>, Error<Number>> where H::Out: Encode {
+	// we can't query any roots before root
+	let max = std::cmp::min(max, end.number.clone());
+
+	let mut tries = HashMap::new();
+	let mut trie_reader = |_: &dyn Storage<H, Number>, root: H::Out, key: &[u8]| tries
+		.entry(root)
+		.or_insert_with(|| TrieBackendEssence::<_, H>::new(TrieBackendAdapter::new(storage), root))
+		.storage(key);
+
+	let mut changes = KeysChanges::new();
+	for key in keys {
+		if changes.contains_key(key) {
+			continue;
+		}
+
+		let mut essence = DrilldownIteratorEssence::new(
+			storage_key,
+			key,
+			roots_storage,
+			storage,
+			end,
+			surface_iterator(config.clone(), max.clone(), begin.clone(), end.number.clone())?,
+		);
+
+		let mut key_changes = Vec::new();
+		while let Some(change) = essence.next(&mut trie_reader) {
+			key_changes.push(change?);
+		}
+		changes.insert(key.clone(), key_changes);
+	}
+
+	Ok(changes)
+}
+
+/// Changes of the key, returned by the query that tolerates pruned changes tries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChangesResult<Number> {
+	/// Changes of the key, in descending order.
+	pub changes: Vec<(Number, u32)>,
+	/// The oldest block, starting from which `changes` are complete. Changes of earlier blocks
+	/// may be missing, because some of changes tries that were listing them have been pruned.
+	pub complete_from: Number,
+}
+
+/// Same as `key_changes`, but changes tries that have been pruned are skipped instead of
+/// failing the whole query.
+///
+/// The changes trie is treated as pruned if either its root or its root node is missing from
+/// the storage.
+pub fn key_changes_partial<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &'a dyn Storage<H, Number>,
+	begin: Number,
+	end: &'a AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&'a PrefixedStorageKey>,
+	key: &'a [u8],
+) -> Result<KeyChangesResult<Number>, Error<Number>> where H::Out: Encode {
+	let mut iter = key_changes(config, storage, begin, end, max, storage_key, key)?;
+	iter.essence.tolerate_pruned = true;
+	let changes = (&mut iter).collect::<Result<Vec<_>, _>>()?;
+	Ok(KeyChangesResult { changes, complete_from: iter.essence.complete_from() })
+}
+
+/// Same as `key_changes_proof`, but changes tries that have been pruned are skipped instead of
+/// failing the whole query. Nodes of the pruned tries are not included into the proof.
+pub fn key_changes_proof_partial<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+) -> Result<Vec<Vec<u8>>, Error<Number>> where H::Out: Codec {
+	key_changes_proof_with(config, storage, begin, end, max, storage_key, key, true)
+}
+
+/// Check the proof, generated by `key_changes_proof_partial`, and return changes of the key.
+///
+/// `complete_from` is computed from changes tries that are present in the proof, so the prover
+/// can't claim that the answer is complete for blocks which changes tries it hasn't provided.
+pub fn key_changes_proof_check_partial<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &dyn RootsStorage<H, Number>,
+	proof: Vec<Vec<u8>>,
+	begin: Number,
+	end: &AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8]
+) -> Result<KeyChangesResult<Number>, Error<Number>> where H::Out: Encode {
+	let proof_db = InMemoryStorage::with_proof(proof);
+	let mut iter = key_changes_proof_check_iterator(
+		config,
+		roots_storage,
+		&proof_db,
+		begin,
+		end,
+		max,
+		storage_key,
+		key,
+	)?;
+	iter.essence.tolerate_pruned = true;
+	let changes = (&mut iter).collect::<Result<Vec<_>, _>>()?;
+	Ok(KeyChangesResult { changes, complete_from: iter.essence.complete_from() })
+}
+
+/// Returns true if the key has been changed at any block of the (`since`; `until`] range.
+///
+/// Unlike `key_changes`, digests aren't drilled down when they prove that the key has been changed
+/// within the range, so in the best case only the highest digest that covers the range is read.
+pub fn key_changed_since<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	since: Number,
+	until: &AnchorBlockId<H::Out, Number>,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+) -> Result<bool, Error<Number>> where H::Out: Encode {
+	key_changed_since_with_reader(
+		config,
+		storage.as_roots_storage(),
+		storage,
+		since,
+		until,
+		storage_key,
+		key,
+		|storage, root, key| TrieBackendEssence::<_, H>::new(TrieBackendAdapter::new(storage), root).storage(key),
+	)
+}
+
+/// Same as `key_changed_since`, but also returns the proof of the answer.
+///
+/// The proof only contains changes tries nodes that have been read to answer the query. If the key
+/// hasn't been changed, these are the nodes that prove absence of the key in all changes tries that
+/// are covering the range.
+pub fn key_changed_since_proof<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &dyn Storage<H, Number>,
+	since: Number,
+	until: &AnchorBlockId<H::Out, Number>,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+) -> Result<(Vec<Vec<u8>>, bool), Error<Number>> where H::Out: Codec {
+	let mut proof_recorder = Recorder::<H::Out>::default();
+	let changed = key_changed_since_with_reader(
+		config,
+		storage.as_roots_storage(),
+		storage,
+		since,
+		until,
+		storage_key,
+		key,
+		|storage, root, key| ProvingBackendRecorder::<_, H> {
+			backend: &TrieBackendEssence::new(TrieBackendAdapter::new(storage), root),
+			proof_recorder: &mut proof_recorder,
+		}.storage(key),
+	)?;
+	Ok((recorded_proof(proof_recorder), changed))
+}
+
+/// Check the proof, generated by `key_changed_since_proof`, and return true if the key has been
+/// changed at any block of the (`since`; `until`] range.
+pub fn key_changed_since_proof_check<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &dyn RootsStorage<H, Number>,
+	proof: Vec<Vec<u8>>,
+	since: Number,
+	until: &AnchorBlockId<H::Out, Number>,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+) -> Result<bool, Error<Number>> where H::Out: Encode {
+	let proof_db = InMemoryStorage::<H, Number>::with_proof(proof);
+	key_changed_since_with_reader(
+		config,
+		roots_storage,
+		&proof_db,
+		since,
+		until,
+		storage_key,
+		key,
+		|storage, root, key| TrieBackendEssence::<_, H>::new(TrieBackendAdapter::new(storage), root).storage(key),
+	)
+}
+
+/// Answer the `key_changed_since` query, reading changes tries with given `trie_reader`.
+fn key_changed_since_with_reader<'a, H: Hasher, Number: BlockNumber, F>(
+	config: ConfigurationRange<'a, Number>,
+	roots_storage: &dyn RootsStorage<H, Number>,
+	storage: &dyn Storage<H, Number>,
+	since: Number,
+	until: &AnchorBlockId<H::Out, Number>,
+	storage_key: Option<&PrefixedStorageKey>,
+	key: &[u8],
+	trie_reader: F,
+) -> Result<bool, Error<Number>>
+	where
+		F: FnMut(&dyn Storage<H, Number>, H::Out, &[u8]) -> Result<Option<Vec<u8>>, String>,
+{
+	// nothing could be changed within the empty range
+	if since >= until.number {
+		return Ok(false);
+	}
+
+	let begin = since + One::one();
+	DrilldownIteratorEssence::new(
+		storage_key,
+		key,
+		roots_storage,
+		storage,
+		until,
+		surface_iterator(config, until.number.clone(), begin, until.number.clone())?,
+	).has_changes(trie_reader)
+}
+
+/// Limits of resources that may be spent on the single key changes query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChangesLimits {
+	/// Maximal number of changes tries that are read.
+	pub max_blocks_walked: u64,
+	/// Maximal number of returned changes.
+	pub max_results: usize,
+	/// Maximal number of distinct changes tries nodes that are read (and included into the proof).
+	pub max_proof_nodes: usize,
+	/// Maximal number of extrinsics in the single block. Changes tries that list more extrinsics
+	/// for the key are rejected with `Error::ValueTooLarge`.
+	pub max_extrinsics_per_block: u32,
+}
+
+/// Position where the key changes query has been stopped, because it has hit the limit.
+///
+/// The query is continued from this position if the cursor is passed to the query with the same
+/// range and key.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct KeyChangesCursor<Number> {
+	surface: Option<(Number, Number, u32, Option<u32>)>,
+	blocks: Vec<(Number, Option<u32>)>,
+	extrinsics: Vec<(Number, u32)>,
+}
+
+/// Changes of the key, returned by the single limited key changes query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChangesPage<Number> {
+	/// Changes of the key, in descending order.
+	pub changes: Vec<(Number, u32)>,
+	/// Position of the next page. `None` if all changes have been returned.
+	pub cursor: Option<KeyChangesCursor<Number>>,
+}
+
+/// Same as `key_changes`, but stops when any of `limits` is hit. Then the cursor is returned
+/// along with the changes that have been found so far.
+///
+/// Returns `Error::KeyChangesLimitsTooLow` if limits don't allow to make any progress.
+pub fn key_changes_limited<'a, H: Hasher, Number: BlockNumber>(
+	storage: &'a dyn Storage<H, Number>,
+	query: KeyChangesQuery<'a, H::Out, Number>,
+	limits: KeyChangesLimits,
+	cursor: Option<KeyChangesCursor<Number>>,
+) -> Result<KeyChangesPage<Number>, Error<Number>> where H::Out: Encode {
+	let storage = NodesLimitedStorage::new(storage, limits.max_proof_nodes);
+	key_changes_page(storage.as_roots_storage(), &storage, query, limits, cursor)
+}
+
+/// Same as `key_changes_proof`, but stops when any of `limits` is hit. Then the cursor is returned
+/// along with the proof and changes that have been found so far.
+///
+/// Returns `Error::KeyChangesLimitsTooLow` if limits don't allow to make any progress.
+pub fn key_changes_proof_limited<'a, H: Hasher, Number: BlockNumber>(
+	storage: &dyn Storage<H, Number>,
+	query: KeyChangesQuery<'a, H::Out, Number>,
+	limits: KeyChangesLimits,
+	cursor: Option<KeyChangesCursor<Number>>,
+) -> Result<(Vec<Vec<u8>>, KeyChangesPage<Number>), Error<Number>> where H::Out: Encode {
+	let storage = NodesLimitedStorage::new(storage, limits.max_proof_nodes);
+	let page = key_changes_page(storage.as_roots_storage(), &storage, query, limits, cursor)?;
+	Ok((storage.extract_proof(), page))
+}
+
+/// Check the proof, generated by `key_changes_proof_limited`, and return the same page of
+/// changes of the key.
+///
+/// `query`, `limits` and `cursor` must be the same that have been used to generate the proof.
+/// Chained proofs are checked by passing the cursor of the previous page.
+pub fn key_changes_proof_check_limited<'a, H: Hasher, Number: BlockNumber>(
+	roots_storage: &dyn RootsStorage<H, Number>,
+	proof: Vec<Vec<u8>>,
+	query: KeyChangesQuery<'a, H::Out, Number>,
+	limits: KeyChangesLimits,
+	cursor: Option<KeyChangesCursor<Number>>,
+) -> Result<KeyChangesPage<Number>, Error<Number>> where H::Out: Encode {
+	let proof_db = InMemoryStorage::<H, Number>::with_proof(proof);
+	let storage = NodesLimitedStorage::new(&proof_db, limits.max_proof_nodes);
+	key_changes_page(roots_storage, &storage, query, limits, cursor)
+}
+
+/// Collect the single page of key changes.
+fn key_changes_page<'a, H: Hasher, Number: BlockNumber>(
+	roots_storage: &dyn RootsStorage<H, Number>,
+	storage: &NodesLimitedStorage<H, Number>,
+	query: KeyChangesQuery<'a, H::Out, Number>,
+	limits: KeyChangesLimits,
+	cursor: Option<KeyChangesCursor<Number>>,
+) -> Result<KeyChangesPage<Number>, Error<Number>> where H::Out: Encode {
+	let KeyChangesQuery { config, begin, end, max, storage_key, key } = query;
+	// we can't query any roots before root
+	let max = std::cmp::min(max, end.number.clone());
+
+	let surface = match cursor {
+		Some(ref cursor) => SurfaceIterator::resume(config, begin, max, cursor.surface.clone()),
+		None => surface_iterator(config, max, begin, end.number.clone())?,
+	};
+	let mut essence = DrilldownIteratorEssence::new(storage_key, key, roots_storage, storage, end, surface);
+	essence.max_extrinsics_per_block = limits.max_extrinsics_per_block;
+	if let Some(cursor) = cursor {
+		essence.resume(cursor)?;
+	}
+
+	let mut trie_reader = |storage: &dyn Storage<H, Number>, root: H::Out, key: &[u8]|
+		TrieBackendEssence::<_, H>::new(TrieBackendAdapter::new(storage), root).storage(key);
+	let mut changes = Vec::new();
+	let mut blocks_walked = 0;
+	let cursor = loop {
+		if !essence.extrinsics.is_empty() {
+			if changes.len() >= limits.max_results {
+				break essence.cursor();
+			}
+			changes.extend(essence.extrinsics.pop_front());
+			continue;
+		}
+
+		if !essence.blocks.is_empty() {
+			if blocks_walked >= limits.max_blocks_walked {
+				break essence.cursor();
+			}
+
+			// the trie is either read completely, or the query is continued from this trie
+			let cursor = essence.cursor();
+			let (block, level) = essence.blocks.pop_front().expect("checked above; qed");
+			match essence.walk_block(block, level, &mut trie_reader) {
+				Ok(()) => blocks_walked += 1,
+				Err(_) if storage.is_limit_reached() => break cursor,
+				Err(err) => return Err(err),
+			}
+			continue;
+		}
+
+		match essence.surface.next() {
+			Some(Ok(block)) => essence.blocks.push_back(block),
+			Some(Err(err)) => return Err(err),
+			None => return Ok(KeyChangesPage { changes, cursor: None }),
+		}
+	};
+
+	if changes.is_empty() && blocks_walked == 0 {
+		return Err(Error::KeyChangesLimitsTooLow);
+	}
+
+	Ok(KeyChangesPage { changes, cursor: Some(cursor) })
+}
+
+/// Changes trie storage that remembers distinct trie nodes that have been read from the inner
+/// storage and refuses to read more than `max_nodes` nodes.
+struct NodesLimitedStorage<'a, H: Hasher, Number: BlockNumber> {
+	storage: &'a dyn Storage<H, Number>,
+	max_nodes: usize,
+	nodes: Mutex<HashMap<H::Out, DBValue>>,
+	limit_reached: AtomicBool,
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> NodesLimitedStorage<'a, H, Number> {
+	fn new(storage: &'a dyn Storage<H, Number>, max_nodes: usize) -> Self {
+		NodesLimitedStorage {
+			storage,
+			max_nodes,
+			nodes: Mutex::new(HashMap::new()),
+			limit_reached: AtomicBool::new(false),
+		}
+	}
+
+	/// Returns true if read has been refused, because of the limit.
+	fn is_limit_reached(&self) -> bool {
+		self.limit_reached.load(Ordering::Relaxed)
+	}
+
+	/// Get all trie nodes that have been read.
+	fn extract_proof(&self) -> Vec<Vec<u8>> {
+		self.nodes.lock().values().cloned().collect()
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> RootsStorage<H, Number> for NodesLimitedStorage<'a, H, Number> {
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		self.storage.root(anchor, block)
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> Storage<H, Number> for NodesLimitedStorage<'a, H, Number> {
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		_root: &H::Out,
+		_functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		false
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		let mut nodes = self.nodes.lock();
+		if let Some(node) = nodes.get(key) {
+			return Ok(Some(node.clone()));
+		}
+		if nodes.len() >= self.max_nodes {
+			self.limit_reached.store(true, Ordering::Relaxed);
+			return Err(Error::Storage(format!("Limit of {} changes trie nodes is reached", self.max_nodes)));
+		}
+
+		let node = self.storage.get(key, prefix)?;
+		if let Some(node) = node.as_ref() {
+			nodes.insert(*key, node.clone());
+		}
+		Ok(node)
+	}
 }
 
 /// Drilldown iterator - receives 'digest points' from surface iterator and explores
@@ -201,6 +792,13 @@ pub struct DrilldownIteratorEssence<'a, H, Number>
 
 	extrinsics: VecDeque<(Number, u32)>,
 	blocks: VecDeque<(Number, Option<u32>)>,
+	max_extrinsics_per_block: u32,
+	/// Skip changes tries that have been pruned instead of failing.
+	tolerate_pruned: bool,
+	/// The highest block with the pruned changes trie that has been skipped.
+	last_pruned: Option<Number>,
+	/// Kinds of changes at walked blocks, if they are collected.
+	change_kinds: Option<Vec<(Number, ChangeKind)>>,
 
 	_hasher: ::std::marker::PhantomData<H>,
 }
@@ -211,7 +809,39 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 		Number: BlockNumber,
 		H::Out: 'a,
 {
-	pub fn next<F>(&mut self, trie_reader: F) -> Option<Result<(Number, u32), String>>
+	/// Create essence that looks for changes of the key in changes tries of blocks, walked by
+	/// the `surface`. Changes before the beginning of the surface and after the `end` are
+	/// ignored.
+	pub(crate) fn new(
+		storage_key: Option<&'a PrefixedStorageKey>,
+		key: &'a [u8],
+		roots_storage: &'a dyn RootsStorage<H, Number>,
+		storage: &'a dyn Storage<H, Number>,
+		end: &'a AnchorBlockId<H::Out, Number>,
+		surface: SurfaceIterator<'a, Number>,
+	) -> Self {
+		DrilldownIteratorEssence {
+			storage_key,
+			key,
+			roots_storage,
+			storage,
+			begin: surface.begin().clone(),
+			end,
+			config: surface.config().clone(),
+			surface,
+
+			extrinsics: Default::default(),
+			blocks: Default::default(),
+			max_extrinsics_per_block: u32::MAX,
+			tolerate_pruned: false,
+			last_pruned: None,
+			change_kinds: None,
+
+			_hasher: ::std::marker::PhantomData::<H>::default(),
+		}
+	}
+
+	pub fn next<F>(&mut self, trie_reader: F) -> Option<Result<(Number, u32), Error<Number>>>
 		where
 			F: FnMut(&dyn Storage<H, Number>, H::Out, &[u8]) -> Result<Option<Vec<u8>>, String>,
 	{
@@ -222,80 +852,95 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 		}
 	}
 
-	fn do_next<F>(&mut self, mut trie_reader: F) -> Result<Option<(Number, u32)>, String>
+	/// Returns current position of the iterator.
+	fn cursor(&self) -> KeyChangesCursor<Number> {
+		KeyChangesCursor {
+			surface: self.surface.position(),
+			blocks: self.blocks.iter().cloned().collect(),
+			extrinsics: self.extrinsics.iter().cloned().collect(),
+		}
+	}
+
+	/// Continue iteration from the position, returned by `cursor`.
+	///
+	/// The surface iterator must be already resumed. Cursors that point outside of the range
+	/// are rejected.
+	fn resume(&mut self, cursor: KeyChangesCursor<Number>) -> Result<(), Error<Number>> {
+		let max = self.surface.max_block().clone();
+		let is_valid_block = |block: &Number, last: &Number| *block >= self.begin && block <= last;
+		let is_valid = cursor.surface.as_ref()
+			.map(|(current, _, digest_step, _)| *digest_step != 0 && is_valid_block(current, &max))
+			.unwrap_or(true)
+			&& cursor.blocks.iter().all(|(block, _)| is_valid_block(block, &max))
+			&& cursor.extrinsics.iter().all(|(block, _)| is_valid_block(block, &self.end.number));
+		if !is_valid {
+			return Err(Error::InvalidKeyChangesCursor);
+		}
+
+		self.blocks = cursor.blocks.into();
+		self.extrinsics = cursor.extrinsics.into();
+		Ok(())
+	}
+
+	/// Returns true if the key has been changed at any block of the range.
+	///
+	/// Digest input blocks aren't walked if the digest proves that the key has been changed within
+	/// the range. Digest input blocks that are before the range are skipped, because the highest
+	/// digest that covers the range may also cover blocks before its begin.
+	fn has_changes<F>(&mut self, mut trie_reader: F) -> Result<bool, Error<Number>>
 		where
 			F: FnMut(&dyn Storage<H, Number>, H::Out, &[u8]) -> Result<Option<Vec<u8>>, String>,
 	{
 		loop {
-			if let Some((block, extrinsic)) = self.extrinsics.pop_front() {
-				return Ok(Some((block, extrinsic)));
+			if !self.extrinsics.is_empty() {
+				return Ok(true);
 			}
 
 			if let Some((block, level)) = self.blocks.pop_front() {
-				// not having a changes trie root is an error because:
-				// we never query roots for future blocks
-				// AND trie roots for old blocks are known (both on full + light node)
-				let trie_root = self.roots_storage.root(&self.end, block.clone())?
-					.ok_or_else(|| format!("Changes trie root for block {} is not found", block.clone()))?;
-				let trie_root = if let Some(storage_key) = self.storage_key {
-					let child_key = ChildIndex {
-						block: block.clone(),
-						storage_key: storage_key.clone(),
-					}.encode();
-					if let Some(trie_root) = trie_reader(self.storage, trie_root, &child_key)?
-						.and_then(|v| <Vec<u8>>::decode(&mut &v[..]).ok())
-						.map(|v| {
-							let mut hash = H::Out::default();
-							hash.as_mut().copy_from_slice(&v[..]);
-							hash
-						}) {
-						trie_root
-					} else {
-						continue;
-					}
-				} else {
-					trie_root
-				};
-
-				// only return extrinsics for blocks before self.max
-				// most of blocks will be filtered out before pushing to `self.blocks`
-				// here we just throwing away changes at digest blocks we're processing
-				debug_assert!(block >= self.begin, "We shall not touch digests earlier than a range' begin");
-				if block <= self.end.number {
-					let extrinsics_key = ExtrinsicIndex { block: block.clone(), key: self.key.to_vec() }.encode();
-					let extrinsics = trie_reader(self.storage, trie_root, &extrinsics_key);
-					if let Some(extrinsics) = extrinsics? {
-						if let Ok(extrinsics) = ExtrinsicIndexValue::decode(&mut &extrinsics[..]) {
-							self.extrinsics.extend(extrinsics.into_iter().rev().map(|e| (block.clone(), e)));
-						}
-					}
+				if block < self.begin {
+					continue;
 				}
 
-				let blocks_key = DigestIndex { block: block.clone(), key: self.key.to_vec() }.encode();
-				let blocks = trie_reader(self.storage, trie_root, &blocks_key);
-				if let Some(blocks) = blocks? {
-					if let Ok(blocks) = <DigestIndexValue<Number>>::decode(&mut &blocks[..]) {
-						// filter level0 blocks here because we tend to use digest blocks,
-						// AND digest block changes could also include changes for out-of-range blocks
-						let begin = self.begin.clone();
-						let end = self.end.number.clone();
-						let config = self.config.clone();
-						self.blocks.extend(blocks.into_iter()
-							.rev()
-							.filter(|b| level.map(|level| level > 1).unwrap_or(true) || (*b >= begin && *b <= end))
-							.map(|b| {
-								let prev_level = level
-									.map(|level| Some(level - 1))
-									.unwrap_or_else(||
-										Some(config.config.digest_level_at_block(config.zero.clone(), b.clone())
-											.map(|(level, _, _)| level)
-											.unwrap_or_else(|| Zero::zero())));
-								(b, prev_level)
-							})
-						);
-					}
+				let queued_blocks = self.blocks.len();
+				self.walk_block(block, level, &mut trie_reader)?;
+				let is_changed_within_range = self.blocks.iter()
+					.skip(queued_blocks)
+					.any(|(block, level)| self.covers_range_only(block, *level));
+				if is_changed_within_range {
+					return Ok(true);
 				}
+				continue;
+			}
+
+			match self.surface.next() {
+				Some(Ok(block)) => self.blocks.push_back(block),
+				Some(Err(err)) => return Err(err),
+				None => return Ok(false),
+			}
+		}
+	}
+
+	/// Returns true if all blocks that are covered by the block of given digest level are
+	/// within the range.
+	fn covers_range_only(&self, block: &Number, level: Option<u32>) -> bool {
+		let covered_blocks = match level.and_then(|level| self.config.config.digest_interval.checked_pow(level)) {
+			Some(covered_blocks) => covered_blocks,
+			None => return false,
+		};
+		block.clone() + One::one() >= self.begin.clone() + covered_blocks.into() && *block <= self.end.number
+	}
+
+	fn do_next<F>(&mut self, mut trie_reader: F) -> Result<Option<(Number, u32)>, Error<Number>>
+		where
+			F: FnMut(&dyn Storage<H, Number>, H::Out, &[u8]) -> Result<Option<Vec<u8>>, String>,
+	{
+		loop {
+			if let Some((block, extrinsic)) = self.extrinsics.pop_front() {
+				return Ok(Some((block, extrinsic)));
+			}
 
+			if let Some((block, level)) = self.blocks.pop_front() {
+				self.walk_block(block, level, &mut trie_reader)?;
 				continue;
 			}
 
@@ -306,6 +951,174 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 			}
 		}
 	}
+
+	/// Read changes trie of given block. Extrinsics that have changed the key in this block and
+	/// digest input blocks where the key has been changed are queued.
+	fn walk_block<F>(
+		&mut self,
+		block: Number,
+		level: Option<u32>,
+		trie_reader: &mut F,
+	) -> Result<(), Error<Number>>
+		where
+			F: FnMut(&dyn Storage<H, Number>, H::Out, &[u8]) -> Result<Option<Vec<u8>>, String>,
+	{
+		// not having a changes trie root is an error because:
+		// we never query roots for future blocks
+		// AND trie roots for old blocks are known (both on full + light node)
+		let trie_root = match self.roots_storage.root(&self.end, block.clone())? {
+			Some(trie_root) => trie_root,
+			None if self.tolerate_pruned => {
+				self.skip_pruned(block);
+				return Ok(());
+			},
+			None => return Err(Error::StorageRootMissing(block)),
+		};
+		if !self.is_trie_available(&block, &trie_root)? {
+			return Ok(());
+		}
+
+		// digest that has been built without some of its input blocks can't be used to find
+		// changes of these blocks, so the result would be incomplete
+		if level != Some(0) {
+			let missing_blocks_key = MissingBlocksIndex { block: block.clone() }.encode();
+			let missing_blocks = trie_reader(self.storage, trie_root, &missing_blocks_key);
+			if let Some(missing_blocks) = missing_blocks.map_err(Error::Storage)? {
+				if let Some(missing_blocks) = ignore_undecodable(decode_block_list(&missing_blocks, max_digest_value_len(&self.config)))? {
+					let missing_blocks = missing_blocks.into_iter()
+						.filter(|b| *b >= self.begin && *b <= self.end.number)
+						.collect::<Vec<_>>();
+					if !missing_blocks.is_empty() {
+						return Err(Error::DegradedDigest { block, missing_blocks });
+					}
+				}
+			}
+		}
+
+		let trie_root = if let Some(storage_key) = self.storage_key {
+			let child_key = ChildIndex {
+				block: block.clone(),
+				storage_key: storage_key.clone(),
+			}.encode();
+			if let Some(trie_root) = trie_reader(self.storage, trie_root, &child_key).map_err(Error::Storage)?
+				.and_then(|v| <Vec<u8>>::decode(&mut &v[..]).ok())
+				.map(|v| {
+					let mut hash = H::Out::default();
+					hash.as_mut().copy_from_slice(&v[..]);
+					hash
+				}) {
+				if !self.is_trie_available(&block, &trie_root)? {
+					return Ok(());
+				}
+				trie_root
+			} else {
+				return Ok(());
+			}
+		} else {
+			trie_root
+		};
+
+		// only return extrinsics for blocks before self.max
+		// most of blocks will be filtered out before pushing to `self.blocks`
+		// here we just throwing away changes at digest blocks we're processing
+		debug_assert!(block >= self.begin, "We shall not touch digests earlier than a range' begin");
+		if block <= self.end.number {
+			let extrinsics_key = ExtrinsicIndex { block: block.clone(), key: self.key.to_vec() }.encode();
+			let extrinsics = trie_reader(self.storage, trie_root, &extrinsics_key);
+			if let Some(extrinsics) = extrinsics.map_err(Error::Storage)? {
+				if let Some(extrinsics) = ignore_undecodable(decode_extrinsic_list(&extrinsics, self.max_extrinsics_per_block))? {
+					// the deletion marker isn't an extrinsic, unless deletions aren't recorded
+					let records_deletions = self.config.config.record_deletions;
+					if let Some(change_kinds) = self.change_kinds.as_mut() {
+						change_kinds.push((block.clone(), extrinsics.change_kind(records_deletions)));
+					}
+					self.extrinsics.extend(extrinsics.into_iter()
+						.rev()
+						.filter(|e| !records_deletions || *e != ExtrinsicId::KEY_DELETION)
+						.map(|e| (block.clone(), e.into())));
+				}
+			}
+		}
+
+		let blocks_key = DigestIndex { block: block.clone(), key: self.key.to_vec() }.encode();
+		let blocks = trie_reader(self.storage, trie_root, &blocks_key);
+		if let Some(blocks) = blocks.map_err(Error::Storage)? {
+			if let Some(blocks) = ignore_undecodable(decode_block_list(&blocks, max_digest_value_len(&self.config)))? {
+				// filter level0 blocks here because we tend to use digest blocks,
+				// AND digest block changes could also include changes for out-of-range blocks
+				// forged digest may list the digest block itself or blocks that it doesn't cover,
+				// which would make us loop or read changes tries of unrelated blocks
+				// blocks before the range are always skipped, because blocks that they cover (if
+				// they're digests) are also before the range
+				for listed in blocks.iter() {
+					check_digest_listed_block(self.config.clone(), &block, listed)?;
+				}
+
+				let begin = self.begin.clone();
+				let end = self.end.number.clone();
+				let config = self.config.clone();
+				self.blocks.extend(blocks.into_iter()
+					.rev()
+					.filter(|b| *b >= begin && (level.map(|level| level > 1).unwrap_or(true) || *b <= end))
+					.map(|b| {
+						let prev_level = level
+							.map(|level| Some(level - 1))
+							.unwrap_or_else(||
+								Some(config.config.digest_level_at_block(config.zero.clone(), b.clone())
+									.map(|(level, _, _)| level)
+									.unwrap_or_else(|| Zero::zero())));
+						(b, prev_level)
+					})
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Returns false if pruned changes tries are tolerated and the root node of the changes trie
+	/// is missing from the storage. The block is then remembered as the pruned one.
+	fn is_trie_available(&mut self, block: &Number, trie_root: &H::Out) -> Result<bool, Error<Number>> {
+		if !self.tolerate_pruned || self.storage.get(trie_root, EMPTY_PREFIX)?.is_some() {
+			return Ok(true);
+		}
+
+		self.skip_pruned(block.clone());
+		Ok(false)
+	}
+
+	/// Remember that changes trie of given block has been pruned.
+	fn skip_pruned(&mut self, block: Number) {
+		if self.last_pruned.as_ref().map(|last_pruned| block > *last_pruned).unwrap_or(true) {
+			self.last_pruned = Some(block);
+		}
+	}
+
+	/// Returns the oldest block, starting from which all changes have been found. Changes at
+	/// the pruned block and before it may be missing.
+	fn complete_from(&self) -> Number {
+		match self.last_pruned {
+			Some(ref last_pruned) => last_pruned.clone() + One::one(),
+			None => self.begin.clone(),
+		}
+	}
+}
+
+/// Max number of blocks that digest of given configuration may list for a single key.
+///
+/// Every level of the digest lists less than `digest_interval` blocks.
+fn max_digest_value_len<Number>(config: &ConfigurationRange<Number>) -> u32 {
+	config.config.effective_digest_levels().saturating_mul(config.config.digest_interval)
+}
+
+/// Values that can't be decoded are skipped by the drilldown, but values that are too large
+/// or aren't in the canonical form are treated as an error.
+fn ignore_undecodable<T, Number: BlockNumber>(value: Result<T, Error<Number>>) -> Result<Option<T>, Error<Number>> {
+	match value {
+		Ok(value) => Ok(Some(value)),
+		Err(error @ Error::ValueTooLarge { .. }) | Err(error @ Error::NonCanonicalValue) => Err(error),
+		Err(_) => Ok(None),
+	}
 }
 
 /// Exploring drilldown operator.
@@ -318,10 +1131,18 @@ pub struct DrilldownIterator<'a, H, Number>
 	essence: DrilldownIteratorEssence<'a, H, Number>,
 }
 
+impl<'a, H: Hasher, Number: BlockNumber> DrilldownIterator<'a, H, Number> {
+	/// Reject changes tries that list more than `max` extrinsics of the single block for the key.
+	pub fn with_max_extrinsics_per_block(mut self, max: u32) -> Self {
+		self.essence.max_extrinsics_per_block = max;
+		self
+	}
+}
+
 impl<'a, H: Hasher, Number: BlockNumber> Iterator for DrilldownIterator<'a, H, Number>
 	where H::Out: Encode
 {
-	type Item = Result<(Number, u32), String>;
+	type Item = Result<(Number, u32), Error<Number>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		self.essence.next(|storage, root, key|
@@ -349,20 +1170,30 @@ impl<'a, H, Number> ProvingDrilldownIterator<'a, H, Number>
 	/// Consume the iterator, extracting the gathered proof in lexicographical order
 	/// by value.
 	pub fn extract_proof(self) -> Vec<Vec<u8>> {
-		self.proof_recorder.into_inner().drain()
-			.into_iter()
-			.map(|n| n.data.to_vec())
-			.collect()
+		recorded_proof(self.proof_recorder.into_inner())
 	}
 }
 
+/// Extract unique nodes, recorded by the proof recorder, in lexicographical order by value.
+///
+/// The same node is recorded every time it is read, so duplicates are removed.
+fn recorded_proof<O: Copy>(mut proof_recorder: Recorder<O>) -> Vec<Vec<u8>> {
+	let mut proof = proof_recorder.drain()
+		.into_iter()
+		.map(|n| n.data.to_vec())
+		.collect::<Vec<_>>();
+	proof.sort();
+	proof.dedup();
+	proof
+}
+
 impl<'a, H, Number> Iterator for ProvingDrilldownIterator<'a, H, Number>
 	where
 		Number: BlockNumber,
 		H: Hasher,
 		H::Out: 'a + Codec,
 {
-	type Item = Result<(Number, u32), String>;
+	type Item = Result<(Number, u32), Error<Number>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		let proof_recorder = &mut *self.proof_recorder.try_borrow_mut()
@@ -380,7 +1211,7 @@ mod tests {
 	use std::iter::FromIterator;
 	use crate::changes_trie::Configuration;
 	use crate::changes_trie::input::InputPair;
-	use crate::changes_trie::storage::InMemoryStorage;
+	use crate::changes_trie::storage::{InMemoryStorage, ProofRecorder, ProofCheckStorage};
 	use sp_runtime::traits::BlakeTwo256;
 	use super::*;
 
@@ -390,7 +1221,7 @@ mod tests {
 	}
 
 	fn prepare_for_drilldown() -> (Configuration, InMemoryStorage<BlakeTwo256, u64>) {
-		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
 		let backend = InMemoryStorage::with_inputs(vec![
 			// digest: 1..4 => [(3, 0)]
 			(1, vec![
@@ -398,20 +1229,20 @@ mod tests {
 			(2, vec![
 			]),
 			(3, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0].into()),
 			]),
 			(4, vec![
-				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, vec![3]),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, vec![3].into()),
 			]),
 			// digest: 5..8 => [(6, 3), (8, 1+2)]
 			(5, vec![]),
 			(6, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 6, key: vec![42] }, vec![3]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 6, key: vec![42] }, vec![3].into()),
 			]),
 			(7, vec![]),
 			(8, vec![
-				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 8, key: vec![42] }, vec![1, 2]),
-				InputPair::DigestIndex(DigestIndex { block: 8, key: vec![42] }, vec![6]),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 8, key: vec![42] }, vec![1, 2].into()),
+				InputPair::DigestIndex(DigestIndex { block: 8, key: vec![42] }, vec![6].into()),
 			]),
 			// digest: 9..12 => []
 			(9, vec![]),
@@ -423,19 +1254,19 @@ mod tests {
 			(14, vec![]),
 			(15, vec![]),
 			(16, vec![
-				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![4, 8]),
+				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![4, 8].into()),
 			]),
 		], vec![(child_key(), vec![
 				(1, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![42] }, vec![0]),
+					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![42] }, vec![0].into()),
 				]),
 				(2, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 2, key: vec![42] }, vec![3]),
+					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 2, key: vec![42] }, vec![3].into()),
 				]),
 				(16, vec![
-					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![42] }, vec![5]),
+					InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![42] }, vec![5].into()),
 
-					InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![2]),
+					InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![2].into()),
 				]),
 			]),
 		]);
@@ -451,6 +1282,54 @@ mod tests {
 		}
 	}
 
+	fn prepare_for_keys_drilldown() -> (Configuration, InMemoryStorage<BlakeTwo256, u64>) {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let backend = InMemoryStorage::with_inputs(vec![
+			(1, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![100] }, vec![1, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![105] }, vec![0, 2, 4].into()),
+			]),
+			(2, vec![]),
+			(3, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![100] }, vec![0].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![105] }, vec![1].into()),
+			]),
+			(4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![103] }, vec![0, 1].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![100] }, vec![1, 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![105] }, vec![1, 3].into()),
+			]),
+			(5, vec![]),
+			(6, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 6, key: vec![105] }, vec![2].into()),
+			]),
+			(7, vec![]),
+			(8, vec![
+				InputPair::DigestIndex(DigestIndex { block: 8, key: vec![105] }, vec![6].into()),
+			]),
+			(9, vec![]),
+			(10, vec![]),
+			(11, vec![]),
+			(12, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 12, key: vec![103] }, vec![4].into()),
+			]),
+			(13, vec![]),
+			(14, vec![]),
+			(15, vec![]),
+			(16, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![105] }, vec![0].into()),
+
+				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![100] }, vec![4].into()),
+				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![103] }, vec![4, 12].into()),
+				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![105] }, vec![4, 8].into()),
+			]),
+		], vec![]);
+
+		(config, backend)
+	}
+
 	#[test]
 	fn drilldown_iterator_works() {
 		let (config, storage) = prepare_for_drilldown();
@@ -521,6 +1400,63 @@ mod tests {
 		assert_eq!(drilldown_result, Ok(vec![(6, 3)]));
 	}
 
+	#[test]
+	fn drilldown_with_values_works() {
+		struct TestStateProvider {
+			states: std::collections::BTreeMap<u64, crate::InMemoryBackend<BlakeTwo256>>,
+			reads: RefCell<Vec<u64>>,
+		}
+
+		impl StateProvider<u64> for TestStateProvider {
+			fn storage(
+				&self,
+				block: &u64,
+				storage_key: Option<&PrefixedStorageKey>,
+				key: &[u8],
+			) -> Result<Option<Option<StorageValue>>, String> {
+				use crate::Backend;
+
+				assert!(storage_key.is_none());
+				self.reads.borrow_mut().push(*block);
+				match self.states.get(block) {
+					Some(state) => state.storage(key).map(Some).map_err(|e| format!("{}", e)),
+					None => Ok(None),
+				}
+			}
+		}
+
+		let (config, storage) = prepare_for_drilldown();
+		// state of block#3 has been pruned and key has been deleted at block#8
+		let provider = TestStateProvider {
+			states: vec![
+				(6, vec![(vec![42], vec![6])].into_iter().collect::<std::collections::BTreeMap<_, _>>().into()),
+				(8, crate::InMemoryBackend::default()),
+			].into_iter().collect(),
+			reads: Default::default(),
+		};
+
+		let result = key_changes_with_values::<BlakeTwo256, u64>(
+			&storage,
+			KeyChangesQuery {
+				config: configuration_range(&config, 0),
+				begin: 1,
+				end: &AnchorBlockId { hash: Default::default(), number: 16 },
+				max: 16,
+				storage_key: None,
+				key: &[42],
+			},
+			&provider,
+		);
+		assert_eq!(result, Ok(vec![
+			(8, 2, Ok(None)),
+			(8, 1, Ok(None)),
+			(6, 3, Ok(Some(vec![6]))),
+			(3, 0, Err(Error::StatePruned(3))),
+		]));
+		// value of the key at block#8 is read once
+		assert_eq!(*provider.reads.borrow(), vec![8, 6, 3]);
+	}
+
 	#[test]
 	fn drilldown_iterator_fails_when_storage_fails() {
 		let (config, storage) = prepare_for_drilldown();
@@ -547,10 +1483,35 @@ mod tests {
 		).and_then(|i| i.collect::<Result<Vec<_>, _>>()).is_err());
 	}
 
+	#[test]
+	fn drilldown_iterator_fails_when_digest_root_is_missing() {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		// there's no changes trie for block#4, which is referenced by the L2 digest at block#16
+		let storage = InMemoryStorage::with_inputs(vec![
+			(3, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0].into()),
+			]),
+			(16, vec![
+				InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![4].into()),
+			]),
+		], vec![]);
+
+		let drilldown_result: Result<Vec<_>, Error<u64>> = key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 16 },
+			16,
+			None,
+			&[42],
+		).and_then(Result::from_iter);
+		assert_eq!(drilldown_result, Err(Error::StorageRootMissing(4)));
+	}
+
 	#[test]
 	fn drilldown_iterator_fails_when_range_is_invalid() {
 		let (config, storage) = prepare_for_drilldown();
-		assert!(key_changes::<BlakeTwo256, u64>(
+		assert_eq!(key_changes::<BlakeTwo256, u64>(
 			configuration_range(&config, 0),
 			&storage,
 			1,
@@ -558,7 +1519,7 @@ mod tests {
 			50,
 			None,
 			&[42],
-		).is_err());
+		).err(), Some(Error::InvalidRange { begin: 1, end: 100, max: 50 }));
 		assert!(key_changes::<BlakeTwo256, u64>(
 			configuration_range(&config, 0),
 			&storage,
@@ -571,6 +1532,46 @@ mod tests {
 	}
 
 
+	#[test]
+	fn drilldown_iterator_detects_degraded_digest() {
+		// digest at block 8 has been built without block 6, where the key has been changed
+		let (config, _) = prepare_for_drilldown();
+		let storage = InMemoryStorage::<BlakeTwo256, u64>::with_inputs((1..=16).map(|block| (block, match block {
+			3 => vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0].into())],
+			4 => vec![InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, vec![3].into())],
+			8 => vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 8, key: vec![42] }, vec![1, 2].into()),
+				InputPair::MissingBlocks(MissingBlocksIndex { block: 8 }, vec![6].into()),
+			],
+			16 => vec![InputPair::DigestIndex(DigestIndex { block: 16, key: vec![42] }, vec![4, 8].into())],
+			_ => vec![],
+		})).collect(), vec![]);
+		let end = AnchorBlockId { hash: Default::default(), number: 8 };
+		let changes = |begin| key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			begin,
+			&end,
+			8,
+			None,
+			&[42],
+		).and_then(Result::from_iter);
+
+		assert_eq!(changes(1), Err(Error::DegradedDigest { block: 8, missing_blocks: vec![6] }));
+		assert_eq!(changes(5), Err(Error::DegradedDigest { block: 8, missing_blocks: vec![6] }));
+		// missing block is out of the range
+		assert_eq!(changes(7), Ok(vec![(8, 2), (8, 1)]));
+
+		// light client detects the degraded digest using the proof
+		let proof = key_changes_proof::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 7, &end, 8, None, &[42]).unwrap();
+		let check = |begin| key_changes_proof_check::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, proof.clone(), begin, &end, 8, None, &[42]);
+		storage.clear_storage();
+		assert_eq!(check(7), Ok(vec![(8, 2), (8, 1)]));
+		assert_eq!(check(5), Err(Error::DegradedDigest { block: 8, missing_blocks: vec![6] }));
+	}
+
 	#[test]
 	fn proving_drilldown_iterator_works() {
 		// happens on remote full node:
@@ -606,9 +1607,177 @@ mod tests {
 		assert_eq!(local_result_child, Ok(vec![(16, 5), (2, 3)]));
 	}
 
+	#[test]
+	fn keys_drilldown_matches_single_key_drilldowns() {
+		let (config, storage) = prepare_for_keys_drilldown();
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let keys = vec![vec![100], vec![103], vec![105]];
+		let key_changes_of = |key: &[u8]| key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, key,
+		).and_then(Result::from_iter);
+
+		let expected = keys.iter()
+			.map(|key| (key.clone(), key_changes_of(key).unwrap()))
+			.collect::<KeysChanges<u64>>();
+		assert_eq!(expected[&vec![100]], vec![(4, 3), (4, 2), (4, 0), (3, 0), (1, 3), (1, 1)]);
+		assert_eq!(expected[&vec![103]], vec![(12, 4), (4, 1), (4, 0)]);
+		assert_eq!(expected[&vec![105]], vec![(16, 0), (6, 2), (3, 1), (1, 4), (1, 2), (1, 0)]);
+
+		let keys_result = keys_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &keys);
+		assert_eq!(keys_result, Ok(expected.clone()));
+
+		// the merged proof is enough to check changes of every key, both at once and one by one
+		let proof = keys_changes_proof::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &keys).unwrap();
+		let (local_config, local_storage) = prepare_for_keys_drilldown();
+		local_storage.clear_storage();
+		let local_result = keys_changes_proof_check::<BlakeTwo256, u64>(
+			configuration_range(&local_config, 0), &local_storage, proof.clone(), 1, &anchor, 16, None, &keys);
+		assert_eq!(local_result, Ok(expected.clone()));
+		for key in &keys {
+			let local_result = key_changes_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&local_config, 0), &local_storage, proof.clone(), 1, &anchor, 16, None, key);
+			assert_eq!(local_result.as_ref(), Ok(&expected[key]));
+		}
+	}
+
+	#[test]
+	fn partial_drilldown_skips_pruned_tries() {
+		let (config, storage) = prepare_for_drilldown();
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let full_proof = key_changes_proof_partial::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42]).unwrap();
+		assert_eq!(
+			key_changes_partial::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42]),
+			Ok(KeyChangesResult { changes: vec![(8, 2), (8, 1), (6, 3), (3, 0)], complete_from: 1 }),
+		);
+
+		// prune changes tries of blocks 1..4
+		let mut pruned_nodes = HashSet::new();
+		crate::changes_trie::prune(&storage, 1, 4, &anchor, |node| { pruned_nodes.insert(node); });
+		storage.remove_from_storage(&pruned_nodes);
+
+		assert!(key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42],
+		).and_then(|i| i.collect::<Result<Vec<_>, _>>()).is_err());
+		let expected = Ok(KeyChangesResult { changes: vec![(8, 2), (8, 1), (6, 3)], complete_from: 5 });
+		assert_eq!(
+			key_changes_partial::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42]),
+			expected,
+		);
+
+		// the same result is proved
+		let proof = key_changes_proof_partial::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42]).unwrap();
+		let (_, local_storage) = prepare_for_drilldown();
+		local_storage.clear_storage();
+		let check = |proof| key_changes_proof_check_partial::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &local_storage, proof, 1, &anchor, 16, None, &[42]);
+		assert_eq!(check(proof), expected);
+		assert_eq!(
+			check(full_proof),
+			Ok(KeyChangesResult { changes: vec![(8, 2), (8, 1), (6, 3), (3, 0)], complete_from: 1 }),
+		);
+
+		// nothing is authoritative if the digest of block#16 isn't in the proof
+		assert_eq!(check(Vec::new()), Ok(KeyChangesResult { changes: vec![], complete_from: 17 }));
+	}
+
+	#[test]
+	fn key_changed_since_works() {
+		let (config, storage) = prepare_for_drilldown();
+		let changed_since = |since, until, storage_key: Option<&PrefixedStorageKey>| key_changed_since::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			since,
+			&AnchorBlockId { hash: Default::default(), number: until },
+			storage_key,
+			&[42],
+		);
+
+		assert_eq!(changed_since(0, 16, None), Ok(true));
+		assert_eq!(changed_since(7, 16, None), Ok(true));
+		// digest#16 covers changes of the block#8, which is outside of the range
+		assert_eq!(changed_since(8, 16, None), Ok(false));
+		assert_eq!(changed_since(8, 8, None), Ok(false));
+		assert_eq!(changed_since(3, 5, None), Ok(false));
+		assert_eq!(changed_since(2, 5, None), Ok(true));
+		assert_eq!(changed_since(2, 15, Some(&child_key())), Ok(false));
+		assert_eq!(changed_since(2, 16, Some(&child_key())), Ok(true));
+		assert_eq!(changed_since(0, 1, Some(&child_key())), Ok(true));
+	}
+
+	#[test]
+	fn key_changed_since_proof_works() {
+		let (config, storage) = prepare_for_drilldown();
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let check = |proof, since| {
+			let (local_config, local_storage) = prepare_for_drilldown();
+			local_storage.clear_storage();
+			key_changed_since_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&local_config, 0), &local_storage, proof, since, &anchor, None, &[42])
+		};
+
+		// change is proved by the digest#16 alone, so it is smaller than the full changes proof
+		let (proof, changed) = key_changed_since_proof::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 0, &anchor, None, &[42]).unwrap();
+		let changes_proof = key_changes_proof::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 1, &anchor, 16, None, &[42]).unwrap();
+		assert!(changed);
+		assert!(proof.len() < changes_proof.len());
+		assert_eq!(check(proof, 0), Ok(true));
+
+		// absence of changes is also proved
+		let (mut proof, changed) = key_changed_since_proof::<BlakeTwo256, u64>(
+			configuration_range(&config, 0), &storage, 8, &anchor, None, &[42]).unwrap();
+		assert!(!changed);
+		assert_eq!(check(proof.clone(), 8), Ok(false));
+		proof.pop();
+		assert!(check(proof, 8).is_err());
+	}
+
+	#[test]
+	fn proof_check_fails_when_proof_is_incomplete() {
+		let (remote_config, remote_storage) = prepare_for_drilldown();
+		let remote_proof = key_changes_proof::<BlakeTwo256, u64>(
+			configuration_range(&remote_config, 0), &remote_storage, 1,
+			&AnchorBlockId { hash: Default::default(), number: 16 }, 16, None, &[42]).unwrap();
+
+		// every node of the proof is required to replay the query
+		for missing_node in 0..remote_proof.len() {
+			let mut proof = remote_proof.clone();
+			proof.remove(missing_node);
+
+			let (local_config, local_storage) = prepare_for_drilldown();
+			local_storage.clear_storage();
+			assert!(key_changes_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&local_config, 0), &local_storage, proof, 1,
+				&AnchorBlockId { hash: Default::default(), number: 16 }, 16, None, &[42]).is_err());
+		}
+	}
+
+	#[test]
+	fn proof_check_fails_when_roots_do_not_match() {
+		let (remote_config, remote_storage) = prepare_for_drilldown();
+		let remote_proof = key_changes_proof::<BlakeTwo256, u64>(
+			configuration_range(&remote_config, 0), &remote_storage, 1,
+			&AnchorBlockId { hash: Default::default(), number: 16 }, 16, None, &[42]).unwrap();
+
+		// the light node knows different roots for the same blocks
+		let local_roots = InMemoryStorage::<BlakeTwo256, u64>::with_blocks(
+			(1..=16).map(|block| (block, Default::default())).collect(),
+		);
+		assert!(key_changes_proof_check::<BlakeTwo256, u64>(
+			configuration_range(&remote_config, 0), &local_roots, remote_proof, 1,
+			&AnchorBlockId { hash: Default::default(), number: 16 }, 16, None, &[42]).is_err());
+	}
+
 	#[test]
 	fn drilldown_iterator_works_with_skewed_digest() {
-		let config = Configuration { digest_interval: 4, digest_levels: 3 };
+		let config = Configuration { digest_interval: 4, digest_levels: 3, ..Default::default() };
 		let mut config_range = configuration_range(&config, 0);
 		config_range.end = Some(91);
 
@@ -620,12 +1789,12 @@ mod tests {
 		// regular blocks: 89, 90, 91
 		let mut input = (1u64..92u64).map(|b| (b, vec![])).collect::<Vec<_>>();
 		// changed at block#63 and covered by L3 digest at block#64
-		input[63 - 1].1.push(InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 63, key: vec![42] }, vec![0]));
-		input[64 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 64, key: vec![42] }, vec![63]));
+		input[63 - 1].1.push(InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 63, key: vec![42] }, vec![0].into()));
+		input[64 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 64, key: vec![42] }, vec![63].into()));
 		// changed at block#79 and covered by L2 digest at block#80 + skewed digest at block#91
-		input[79 - 1].1.push(InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 79, key: vec![42] }, vec![1]));
-		input[80 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 80, key: vec![42] }, vec![79]));
-		input[91 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 91, key: vec![42] }, vec![80]));
+		input[79 - 1].1.push(InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 79, key: vec![42] }, vec![1].into()));
+		input[80 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 80, key: vec![42] }, vec![79].into()));
+		input[91 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 91, key: vec![42] }, vec![80].into()));
 		let storage = InMemoryStorage::with_inputs(input, vec![]);
 
 		let drilldown_result = key_changes::<BlakeTwo256, u64>(
@@ -639,4 +1808,397 @@ mod tests {
 		).and_then(Result::from_iter);
 		assert_eq!(drilldown_result, Ok(vec![(79, 1), (63, 0)]));
 	}
+
+	#[test]
+	fn recorded_proof_is_enough_to_replay_key_changes() {
+		let (config, storage) = prepare_for_drilldown();
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let roots = (1..=16u64)
+			.filter_map(|block| storage.root(&anchor, block).unwrap().map(|root| (block, root)))
+			.collect::<std::collections::BTreeMap<_, _>>();
+
+		let recorder = ProofRecorder::new(storage);
+		let key_changes_at = |storage: &dyn Storage<BlakeTwo256, u64>, key: &[u8]| key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			storage,
+			1,
+			&anchor,
+			16,
+			None,
+			key,
+		).and_then(Result::from_iter);
+		let recorded_result = key_changes_at(&recorder, &[42]);
+		assert_eq!(recorded_result, Ok(vec![(8, 2), (8, 1), (6, 3), (3, 0)]));
+
+		// every read is answered from the proof
+		let check_storage = ProofCheckStorage::<BlakeTwo256, u64>::new(
+			recorder.extract_proof(),
+			move |block| Ok(roots.get(&block).cloned()),
+		);
+		assert_eq!(key_changes_at(&check_storage, &[42]), recorded_result);
+
+		// nothing could be read from the empty proof
+		let empty_check_storage = ProofCheckStorage::<BlakeTwo256, u64>::new(
+			Vec::new(),
+			move |_| Ok(Some(Default::default())),
+		);
+		assert!(key_changes_at(&empty_check_storage, &[42]).is_err());
+	}
+
+	const NO_LIMITS: KeyChangesLimits = KeyChangesLimits {
+		max_blocks_walked: u64::max_value(),
+		max_results: usize::max_value(),
+		max_proof_nodes: usize::max_value(),
+		max_extrinsics_per_block: u32::MAX,
+	};
+
+	fn key_changes_page_at(
+		storage: &InMemoryStorage<BlakeTwo256, u64>,
+		config: &Configuration,
+		limits: KeyChangesLimits,
+		cursor: Option<KeyChangesCursor<u64>>,
+	) -> Result<KeyChangesPage<u64>, Error<u64>> {
+		key_changes_limited::<BlakeTwo256, u64>(
+			storage,
+			KeyChangesQuery {
+				config: configuration_range(config, 0),
+				begin: 1,
+				end: &AnchorBlockId { hash: Default::default(), number: 16 },
+				max: 16,
+				storage_key: None,
+				key: &[42],
+			},
+			limits,
+			cursor,
+		)
+	}
+
+	#[test]
+	fn limited_drilldown_resumes_at_digest_boundary() {
+		let (config, storage) = prepare_for_drilldown();
+		assert_eq!(
+			key_changes_page_at(&storage, &config, NO_LIMITS, None),
+			Ok(KeyChangesPage { changes: vec![(8, 2), (8, 1), (6, 3), (3, 0)], cursor: None }),
+		);
+
+		// digests of blocks 16 and 8 are walked => we stop before walking l1 digest of block 4
+		let limits = KeyChangesLimits { max_blocks_walked: 2, ..NO_LIMITS };
+		let page = key_changes_page_at(&storage, &config, limits, None).unwrap();
+		assert_eq!(page.changes, vec![(8, 2), (8, 1)]);
+		let cursor = page.cursor.unwrap();
+		assert_eq!(cursor.blocks, vec![(4, Some(1)), (6, Some(0))]);
+
+		let page = key_changes_page_at(&storage, &config, limits, Some(cursor)).unwrap();
+		assert_eq!(page.changes, vec![(6, 3)]);
+		let page = key_changes_page_at(&storage, &config, limits, page.cursor).unwrap();
+		assert_eq!(page, KeyChangesPage { changes: vec![(3, 0)], cursor: None });
+	}
+
+	#[test]
+	fn limited_drilldown_resumes_inside_digest_range() {
+		let (config, storage) = prepare_for_drilldown();
+
+		// change of block 3 is found when the limit is hit => we stop inside range of l1 digest of block 4
+		let limits = KeyChangesLimits { max_results: 3, ..NO_LIMITS };
+		let page = key_changes_page_at(&storage, &config, limits, None).unwrap();
+		assert_eq!(page.changes, vec![(8, 2), (8, 1), (6, 3)]);
+		let cursor = page.cursor.unwrap();
+		assert_eq!(cursor.extrinsics, vec![(3, 0)]);
+
+		// cursor survives encoding
+		let cursor = KeyChangesCursor::decode(&mut &cursor.encode()[..]).unwrap();
+		let page = key_changes_page_at(&storage, &config, limits, Some(cursor)).unwrap();
+		assert_eq!(page, KeyChangesPage { changes: vec![(3, 0)], cursor: None });
+
+		// cursor that points outside of the range is rejected
+		let cursor = KeyChangesCursor { surface: None, blocks: vec![(17, Some(0))], extrinsics: Vec::new() };
+		assert_eq!(
+			key_changes_page_at(&storage, &config, limits, Some(cursor)),
+			Err(Error::InvalidKeyChangesCursor),
+		);
+	}
+
+	#[test]
+	fn limited_drilldown_proofs_are_chained() {
+		let (config, storage) = prepare_for_drilldown();
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let limits = KeyChangesLimits { max_proof_nodes: 3, ..NO_LIMITS };
+		let query = KeyChangesQuery {
+			config: configuration_range(&config, 0),
+			begin: 1,
+			end: &anchor,
+			max: 16,
+			storage_key: None,
+			key: &[42],
+		};
+
+		let mut changes = Vec::new();
+		let mut pages = 0;
+		let mut cursor = None;
+		loop {
+			let (proof, page) = key_changes_proof_limited::<BlakeTwo256, u64>(
+				&storage, query.clone(), limits, cursor.clone(),
+			).unwrap();
+			assert!(proof.len() <= limits.max_proof_nodes);
+
+			let checked_page = key_changes_proof_check_limited::<BlakeTwo256, u64>(
+				&storage, proof, query.clone(), limits, cursor,
+			).unwrap();
+			assert_eq!(checked_page, page);
+
+			pages += 1;
+			changes.extend(page.changes);
+			cursor = page.cursor;
+			if cursor.is_none() {
+				break;
+			}
+		}
+		assert!(pages > 1);
+		assert_eq!(changes, vec![(8, 2), (8, 1), (6, 3), (3, 0)]);
+
+		// not a single trie could be read
+		let limits = KeyChangesLimits { max_proof_nodes: 0, ..NO_LIMITS };
+		assert_eq!(
+			key_changes_proof_limited::<BlakeTwo256, u64>(&storage, query, limits, None).map(|(_, page)| page),
+			Err(Error::KeyChangesLimitsTooLow),
+		);
+	}
+
+	#[test]
+	fn oversized_values_are_rejected() {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let anchor = AnchorBlockId { hash: Default::default(), number: 4 };
+		let key_changes_at = |storage: &InMemoryStorage<BlakeTwo256, u64>, max_extrinsics_per_block: u32|
+			key_changes::<BlakeTwo256, u64>(configuration_range(&config, 0), storage, 1, &anchor, 4, None, &[42])
+				.and_then(|changes| changes.with_max_extrinsics_per_block(max_extrinsics_per_block)
+					.collect::<Result<Vec<_>, _>>());
+
+		// digest with 2 levels of interval 4 may list at most 8 blocks
+		let storage = InMemoryStorage::with_inputs(vec![
+			(3, vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0, 1, 2].into())]),
+			(4, vec![InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, (1..=9).collect())]),
+		], vec![]);
+		assert_eq!(key_changes_at(&storage, 3), Err(Error::ValueTooLarge { len: 9, max_len: 8 }));
+
+		// block may have at most given number of extrinsics
+		let storage = InMemoryStorage::with_inputs(vec![
+			(3, vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![42] }, vec![0, 1, 2].into())]),
+			(4, vec![InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, vec![3].into())]),
+		], vec![]);
+		assert_eq!(key_changes_at(&storage, 3), Ok(vec![(3, 2), (3, 1), (3, 0)]));
+		assert_eq!(key_changes_at(&storage, 2), Err(Error::ValueTooLarge { len: 3, max_len: 2 }));
+		let limits = KeyChangesLimits { max_extrinsics_per_block: 2, ..NO_LIMITS };
+		assert_eq!(
+			key_changes_limited::<BlakeTwo256, u64>(
+				&storage,
+				KeyChangesQuery {
+					config: configuration_range(&config, 0),
+					begin: 1,
+					end: &anchor,
+					max: 4,
+					storage_key: None,
+					key: &[42],
+				},
+				limits,
+				None,
+			),
+			Err(Error::ValueTooLarge { len: 3, max_len: 2 }),
+		);
+
+		// forged proof with huge length prefix is rejected before anything is allocated
+		let mut proof_db = sp_trie::MemoryDB::<BlakeTwo256>::default();
+		let root = crate::backend::insert_into_memory_db::<BlakeTwo256, _>(&mut proof_db, vec![(
+			DigestIndex { block: 4u64, key: vec![42] }.encode(),
+			codec::Compact(u32::MAX).encode(),
+		)]).unwrap();
+		let proof = proof_db.drain().into_iter().map(|(_, (node, _))| node).collect();
+		let roots_storage = InMemoryStorage::<BlakeTwo256, u64>::with_blocks(vec![(4, root)]);
+		assert_eq!(
+			key_changes_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &roots_storage, proof, 1, &anchor, 4, None, &[42],
+			),
+			Err(Error::ValueTooLarge { len: u32::MAX, max_len: 8 }),
+		);
+	}
+
+	#[test]
+	fn non_canonical_values_are_rejected() {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let anchor = AnchorBlockId { hash: Default::default(), number: 4 };
+		let check_forged_proof = |trie_key: Vec<u8>, trie_value: Vec<u8>| {
+			let mut proof_db = sp_trie::MemoryDB::<BlakeTwo256>::default();
+			let root = crate::backend::insert_into_memory_db::<BlakeTwo256, _>(
+				&mut proof_db,
+				vec![(trie_key, trie_value)],
+			).unwrap();
+			let proof = proof_db.drain().into_iter().map(|(_, (node, _))| node).collect();
+			let roots_storage = InMemoryStorage::<BlakeTwo256, u64>::with_blocks(vec![(4, root)]);
+			key_changes_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &roots_storage, proof, 1, &anchor, 4, None, &[42],
+			)
+		};
+
+		assert_eq!(
+			check_forged_proof(ExtrinsicIndex { block: 4u64, key: vec![42] }.encode(), vec![1u32, 3].encode()),
+			Ok(vec![(4, 3), (4, 1)]),
+		);
+		assert_eq!(
+			check_forged_proof(ExtrinsicIndex { block: 4u64, key: vec![42] }.encode(), vec![3u32, 1, 1].encode()),
+			Err(Error::NonCanonicalValue),
+		);
+		assert_eq!(
+			check_forged_proof(DigestIndex { block: 4u64, key: vec![42] }.encode(), vec![3u64, 3].encode()),
+			Err(Error::NonCanonicalValue),
+		);
+	}
+
+	#[test]
+	fn digest_blocks_outside_of_digest_range_are_rejected() {
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let key_changes_with_digest_at_8 = |listed: Vec<u64>| {
+			let mut input = (1u64..=8).map(|block| (block, vec![])).collect::<Vec<_>>();
+			input[8 - 1].1.push(InputPair::DigestIndex(DigestIndex { block: 8, key: vec![42] }, listed.into()));
+			let storage = InMemoryStorage::with_inputs(input, vec![]);
+			key_changes::<BlakeTwo256, u64>(
+				configuration_range(&config, 0),
+				&storage,
+				1,
+				&AnchorBlockId { hash: Default::default(), number: 8 },
+				8,
+				None,
+				&[42],
+			).and_then(Result::from_iter)
+		};
+
+		// L1 digest at block#8 covers blocks 5..=7
+		assert_eq!(key_changes_with_digest_at_8(vec![5, 7]), Ok(vec![]));
+		assert_eq!(
+			key_changes_with_digest_at_8(vec![7, 8]),
+			Err(Error::InvalidDigestBlock { digest_block: 8, listed: 8 }),
+		);
+		assert_eq!(
+			key_changes_with_digest_at_8(vec![6, 9]),
+			Err(Error::InvalidDigestBlock { digest_block: 8, listed: 9 }),
+		);
+		assert_eq!(
+			key_changes_with_digest_at_8(vec![3, 6]),
+			Err(Error::InvalidDigestBlock { digest_block: 8, listed: 3 }),
+		);
+
+		// proof of digest that lists the digest block itself is rejected
+		let mut proof_db = sp_trie::MemoryDB::<BlakeTwo256>::default();
+		let root = crate::backend::insert_into_memory_db::<BlakeTwo256, _>(&mut proof_db, vec![(
+			DigestIndex { block: 4u64, key: vec![42] }.encode(),
+			vec![4u64].encode(),
+		)]).unwrap();
+		let proof = proof_db.drain().into_iter().map(|(_, (node, _))| node).collect();
+		let roots_storage = InMemoryStorage::<BlakeTwo256, u64>::with_blocks(vec![(4, root)]);
+		assert_eq!(
+			key_changes_proof_check::<BlakeTwo256, u64>(
+				configuration_range(&config, 0),
+				&roots_storage,
+				proof,
+				1,
+				&AnchorBlockId { hash: Default::default(), number: 4 },
+				4,
+				None,
+				&[42],
+			),
+			Err(Error::InvalidDigestBlock { digest_block: 4, listed: 4 }),
+		);
+	}
+
+	#[test]
+	fn bitmap_digest_values_are_drilled_down() {
+		// key is changed in every block of the 64-blocks digest
+		let build_tries = |config: &Configuration| {
+			let backend = crate::InMemoryBackend::<BlakeTwo256>::default();
+			let storage = InMemoryStorage::<BlakeTwo256, u64>::new();
+			for block in 1..=64u64 {
+				let mut changes = crate::overlayed_changes::OverlayedChanges::default();
+				changes.set_collect_extrinsics(true);
+				changes.set_extrinsic_index(0).unwrap();
+				changes.set_storage(vec![42], Some(vec![block as u8]));
+				let state = crate::changes_trie::State::new(config.clone(), 0, &storage);
+				let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+				let (mdb, root, _) = crate::changes_trie::try_build_changes_trie(
+					&backend, Some(&state), &changes, &parent,
+				).unwrap().unwrap();
+				storage.insert(block, root, mdb);
+			}
+			storage
+		};
+
+		let anchor = AnchorBlockId { hash: Default::default(), number: 64 };
+		let expected = (1..=64u64).rev().map(|block| (block, 0)).collect::<Vec<_>>();
+		let mut proof_sizes = Vec::new();
+		for digest_value_threshold in vec![None, Some(8)] {
+			let config = Configuration {
+				digest_interval: 64,
+				digest_levels: 1,
+				digest_value_threshold,
+				..Default::default()
+			};
+			let storage = build_tries(&config);
+			let changes = key_changes::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &storage, 1, &anchor, 64, None, &[42],
+			).and_then(|changes| changes.collect::<Result<Vec<_>, _>>());
+			assert_eq!(changes, Ok(expected.clone()));
+
+			let proof = key_changes_proof::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &storage, 1, &anchor, 64, None, &[42],
+			).unwrap();
+			proof_sizes.push(proof.iter().map(|node| node.len()).sum::<usize>());
+			assert_eq!(
+				key_changes_proof_check::<BlakeTwo256, u64>(
+					configuration_range(&config, 0), &storage, proof, 1, &anchor, 64, None, &[42],
+				),
+				Ok(expected.clone()),
+			);
+		}
+
+		// digest trie of block 64 lists 63 blocks, which takes 1 + 63 * 8 bytes when listed
+		// explicitly and 1 + 8 + 1 + 8 bytes as the bitmap
+		assert!(proof_sizes[0] - proof_sizes[1] >= (1 + 63 * 8) - (1 + 8 + 1 + 8));
+	}
+
+	#[test]
+	fn key_change_kinds_report_deletions() {
+		use sp_core::traits::Externalities;
+		use crate::{TestExternalities, NO_EXTRINSIC_INDEX};
+
+		for record_deletions in vec![false, true] {
+			let config = Configuration {
+				digest_interval: 4,
+				digest_levels: 1,
+				record_deletions,
+				..Default::default()
+			};
+			let mut ext = TestExternalities::<BlakeTwo256, u64>::new_with_changes_trie(config.clone());
+
+			// the key is inserted at block 1, updated at block 2 and deleted at block 3
+			ext.ext().set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+			ext.commit_block();
+			ext.ext().set_storage(b"doe".to_vec(), b"deer".to_vec());
+			ext.commit_block();
+			ext.ext().clear_storage(b"doe");
+			ext.commit_block();
+
+			let anchor = AnchorBlockId { hash: ext.parent_hash(), number: 3 };
+			let storage = ext.changes_trie_storage();
+			let changes = key_changes::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &*storage, 1, &anchor, 3, None, b"doe",
+			).and_then(|changes| changes.collect::<Result<Vec<_>, _>>());
+			assert_eq!(changes, Ok(vec![(3, NO_EXTRINSIC_INDEX), (2, NO_EXTRINSIC_INDEX), (1, NO_EXTRINSIC_INDEX)]));
+
+			let kinds = key_change_kinds::<BlakeTwo256, u64>(
+				configuration_range(&config, 0), &*storage, 1, &anchor, 3, None, b"doe",
+			);
+			let expected = match record_deletions {
+				true => vec![(3, ChangeKind::Deleted), (2, ChangeKind::Updated), (1, ChangeKind::Updated)],
+				false => vec![(3, ChangeKind::Unknown), (2, ChangeKind::Unknown), (1, ChangeKind::Unknown)],
+			};
+			assert_eq!(kinds, Ok(expected));
+		}
+	}
 }
@@ -0,0 +1,157 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sources of the changes that are included into changes tries.
+
+use std::collections::{BTreeMap, BTreeSet};
+use sp_core::storage::ChildInfo;
+use crate::StorageKey;
+use crate::overlayed_changes::{OverlayedChanges, OverlayedValue, ExtrinsicId};
+use crate::changes_trie::input::ExtrinsicList;
+
+/// Key that has been changed in the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedKey<'a> {
+	/// The changed key.
+	pub key: &'a [u8],
+	/// Indices of extrinsics that have changed the key.
+	pub extrinsics: ExtrinsicList,
+	/// True if the key has a value at the end of the block.
+	pub final_value_is_some: bool,
+}
+
+/// Source of the changes, that the changes trie input is prepared from.
+pub trait ChangesSource {
+	/// Child storages that have been changed in the block.
+	fn changed_children(&self) -> Vec<ChildInfo>;
+	/// Keys of the top-level (if `child_info` is `None`) or child storage that have been changed
+	/// in the block, in ascending order.
+	fn changed_keys<'a>(&'a self, child_info: Option<&ChildInfo>) -> Box<dyn Iterator<Item=ChangedKey<'a>> + 'a>;
+	/// Number of the block which changes are collected, converted into `u128`. `None` if the
+	/// source doesn't know the block.
+	fn block_number(&self) -> Option<u128> {
+		None
+	}
+	/// Largest extrinsic index that has been used in the block, if it exceeds the max number
+	/// of extrinsics in the block. `None` if the source doesn't know that.
+	fn excess_extrinsic_index(&self) -> Option<u32> {
+		None
+	}
+	/// Returns true if nothing has been changed in the block. The default implementation
+	/// doesn't know that, so it always returns false.
+	fn is_empty(&self) -> bool {
+		false
+	}
+}
+
+impl ChangesSource for OverlayedChanges {
+	fn changed_children(&self) -> Vec<ChildInfo> {
+		self.children().map(|(_, child_info)| child_info.clone()).collect()
+	}
+
+	fn changed_keys<'a>(&'a self, child_info: Option<&ChildInfo>) -> Box<dyn Iterator<Item=ChangedKey<'a>> + 'a> {
+		// the final value is stored in the change itself => no need to look it up in the overlay again
+		let into_changed_key = |(key, value): (&'a StorageKey, &'a OverlayedValue)| ChangedKey {
+			key: &key[..],
+			extrinsics: value.sorted_extrinsics().into(),
+			final_value_is_some: value.value().is_some(),
+		};
+		match child_info {
+			Some(child_info) => match self.child_changes(child_info.storage_key()) {
+				Some((changes, _)) => Box::new(changes.map(into_changed_key)),
+				None => Box::new(std::iter::empty()),
+			},
+			None => Box::new(self.changes().map(into_changed_key)),
+		}
+	}
+
+	fn block_number(&self) -> Option<u128> {
+		self.extrinsic_changes().block::<u128>()
+	}
+
+	fn excess_extrinsic_index(&self) -> Option<u32> {
+		self.extrinsic_changes().excess_extrinsic_index()
+	}
+
+	fn is_empty(&self) -> bool {
+		OverlayedChanges::is_empty(self) && self.extrinsic_changes().is_empty()
+	}
+}
+
+/// Change of the single key, collected by `CollectedChanges`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CollectedChange {
+	extrinsics: BTreeSet<u32>,
+	final_value_is_some: bool,
+}
+
+/// Changes that have been collected without executing the block in the `OverlayedChanges`,
+/// e.g. by tracing the block execution.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CollectedChanges {
+	top: BTreeMap<StorageKey, CollectedChange>,
+	children: BTreeMap<StorageKey, (ChildInfo, BTreeMap<StorageKey, CollectedChange>)>,
+}
+
+impl CollectedChanges {
+	/// Note that the key of the top-level (if `child_info` is `None`) or child storage has been
+	/// changed by the extrinsic. Changes must be noted in the order they've been made, so that
+	/// `value_is_some` of the last change tells whether the key has a value at the end of the block.
+	pub fn note_change(
+		&mut self,
+		child_info: Option<&ChildInfo>,
+		key: StorageKey,
+		extrinsic: u32,
+		value_is_some: bool,
+	) {
+		let changes = match child_info {
+			Some(child_info) => &mut self.children
+				.entry(child_info.storage_key().to_vec())
+				.or_insert_with(|| (child_info.clone(), BTreeMap::new()))
+				.1,
+			None => &mut self.top,
+		};
+		let change = changes.entry(key).or_default();
+		change.extrinsics.insert(extrinsic);
+		change.final_value_is_some = value_is_some;
+	}
+}
+
+impl ChangesSource for CollectedChanges {
+	fn changed_children(&self) -> Vec<ChildInfo> {
+		self.children.values().map(|(child_info, _)| child_info.clone()).collect()
+	}
+
+	fn changed_keys<'a>(&'a self, child_info: Option<&ChildInfo>) -> Box<dyn Iterator<Item=ChangedKey<'a>> + 'a> {
+		let changes = match child_info {
+			Some(child_info) => match self.children.get(child_info.storage_key()) {
+				Some((_, changes)) => changes,
+				None => return Box::new(std::iter::empty()),
+			},
+			None => &self.top,
+		};
+		Box::new(changes.iter().map(|(key, change)| ChangedKey {
+			key: &key[..],
+			extrinsics: change.extrinsics.iter().cloned().map(ExtrinsicId).collect(),
+			final_value_is_some: change.final_value_is_some,
+		}))
+	}
+
+	fn is_empty(&self) -> bool {
+		self.top.is_empty() && self.children.is_empty()
+	}
+}
@@ -0,0 +1,131 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consistency check of the changes trie input against the storage delta of the block.
+//!
+//! Keys that are changed in the block are tracked separately for the changes trie (see
+//! `ExtrinsicChanges`) and for the storage root. If tracking misses a write, the changes trie
+//! silently lacks the key. The check derives the changed keys from the storage delta instead
+//! and compares them with keys of the changes trie input.
+
+use std::collections::BTreeSet;
+use sp_core::hexdisplay::HexDisplay;
+use crate::StorageKey;
+use crate::changes_trie::BlockNumber;
+use crate::changes_trie::input::InputPair;
+
+/// Keys of the storage delta don't match keys of the changes trie input.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+	"changes trie input doesn't match the storage delta: keys {} are missing from the input, \
+	keys {} are missing from the delta",
+	display_keys(.missing_from_input),
+	display_keys(.missing_from_delta),
+)]
+pub struct ConsistencyError {
+	/// Keys that have been changed according to the delta, but are not in the input.
+	pub missing_from_input: Vec<StorageKey>,
+	/// Keys that are in the input, but haven't been changed according to the delta.
+	pub missing_from_delta: Vec<StorageKey>,
+}
+
+/// Check that keys of the top-level `ExtrinsicIndex` pairs of the `input` are exactly the keys
+/// of the storage `delta`.
+///
+/// Keys that are deleted by the delta, but haven't existed before the block (according to
+/// `backend_existed`) are temporary values, so they're not expected to be in the input. Other
+/// changes filters aren't applied, so the caller must exclude keys that are never included
+/// into the changes trie from the delta.
+pub fn check_against_delta<'a, Number: BlockNumber>(
+	input: &[InputPair<Number>],
+	delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	backend_existed: impl Fn(&[u8]) -> bool,
+) -> Result<(), ConsistencyError> {
+	let input_keys = input.iter()
+		.filter_map(|pair| match pair {
+			InputPair::ExtrinsicIndex(index, _) => Some(&index.key[..]),
+			_ => None,
+		})
+		.collect::<BTreeSet<_>>();
+	let delta_keys = delta
+		.filter(|(key, value)| value.is_some() || backend_existed(key))
+		.map(|(key, _)| key)
+		.collect::<BTreeSet<_>>();
+
+	let missing_from_input = delta_keys.difference(&input_keys).map(|key| key.to_vec()).collect::<Vec<_>>();
+	let missing_from_delta = input_keys.difference(&delta_keys).map(|key| key.to_vec()).collect::<Vec<_>>();
+	if missing_from_input.is_empty() && missing_from_delta.is_empty() {
+		return Ok(());
+	}
+
+	Err(ConsistencyError { missing_from_input, missing_from_delta })
+}
+
+/// Format keys for the error message.
+fn display_keys(keys: &[StorageKey]) -> String {
+	let keys = keys.iter().map(|key| format!("{}", HexDisplay::from(key))).collect::<Vec<_>>();
+	format!("[{}]", keys.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::changes_trie::input::ExtrinsicIndex;
+	use super::*;
+
+	fn extrinsic_pair(key: u8) -> InputPair<u64> {
+		InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![key] }, vec![0].into())
+	}
+
+	#[test]
+	fn matching_input_and_delta_are_consistent() {
+		let delta = vec![(vec![1], Some(vec![1])), (vec![2], None), (vec![3], None)];
+		let delta = delta.iter().map(|(key, value)| (&key[..], value.as_deref()));
+		// key 3 is a temporary value => it isn't in the input
+		let existed = |key: &[u8]| key == [2];
+		assert_eq!(check_against_delta(&[extrinsic_pair(1), extrinsic_pair(2)], delta, existed), Ok(()));
+	}
+
+	#[test]
+	fn mismatching_keys_are_reported() {
+		let delta = vec![(vec![1], Some(vec![1])), (vec![2], None), (vec![4], None)];
+		let delta = delta.iter().map(|(key, value)| (&key[..], value.as_deref()));
+		// key 2 has been deleted, but it isn't in the input, and key 3 has never been changed
+		let existed = |key: &[u8]| key == [2];
+		let input = vec![
+			extrinsic_pair(1),
+			extrinsic_pair(3),
+			InputPair::ChildIndex(
+				crate::changes_trie::input::ChildIndex {
+					block: 1,
+					storage_key: sp_core::storage::PrefixedStorageKey::new(vec![5]),
+				},
+				vec![0; 32],
+			),
+		];
+
+		let error = check_against_delta(&input, delta, existed).unwrap_err();
+		assert_eq!(error, ConsistencyError {
+			missing_from_input: vec![vec![2]],
+			missing_from_delta: vec![vec![3]],
+		});
+		assert_eq!(
+			error.to_string(),
+			"changes trie input doesn't match the storage delta: keys [02] are missing from the input, \
+			keys [03] are missing from the delta",
+		);
+	}
+}
@@ -0,0 +1,187 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries digest input cache.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use sp_core::storage::PrefixedStorageKey;
+use crate::StorageKey;
+
+/// Storage of the changed key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyKind {
+	/// The key has been changed in the top-level storage.
+	Top,
+	/// The key has been changed in the child storage, identified by the key.
+	Child(PrefixedStorageKey),
+}
+
+/// Changed keys of the single changes trie.
+pub type DigestCacheEntry = Arc<Vec<(StorageKey, KeyKind)>>;
+
+/// Changes trie digest input cache.
+///
+/// Holds keys that have been read from changes tries of digest input blocks, so that
+/// the same tries aren't read again when higher level digest is built. Entries are
+/// indexed by block number and hold the changes trie root they have been read from,
+/// so entries of non-canonical blocks are never returned.
+///
+/// At most `capacity` entries are held. When the cache is full, the least recently
+/// used entry is evicted.
+pub struct DigestCache<H, N> {
+	capacity: usize,
+	data: Mutex<DigestCacheData<H, N>>,
+}
+
+struct DigestCacheData<H, N> {
+	/// Incremented on every access. Used to find the least recently used entry.
+	tick: u64,
+	/// Map of block number => (changes trie root, changed keys, last access tick).
+	entries: HashMap<N, (H, DigestCacheEntry, u64)>,
+}
+
+impl<H, N> DigestCache<H, N>
+	where
+		N: Eq + ::std::hash::Hash + Clone,
+		H: Eq + Clone,
+{
+	/// Create new digest input cache that holds at most `capacity` entries.
+	pub fn new(capacity: usize) -> Self {
+		DigestCache {
+			capacity,
+			data: Mutex::new(DigestCacheData {
+				tick: 0,
+				entries: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Get number of cached entries.
+	pub fn len(&self) -> usize {
+		self.data.lock().entries.len()
+	}
+
+	/// Returns true if there are no cached entries.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Get changed keys of the block, if they have been read from the trie with given root.
+	pub fn get(&self, block: &N, root: &H) -> Option<DigestCacheEntry> {
+		let mut data = self.data.lock();
+		data.tick += 1;
+		let tick = data.tick;
+		match data.entries.get_mut(block) {
+			Some((entry_root, keys, last_access)) if entry_root == root => {
+				*last_access = tick;
+				Some(keys.clone())
+			},
+			_ => None,
+		}
+	}
+
+	/// Insert changed keys of the block with given changes trie root.
+	pub fn insert(&self, block: N, root: H, keys: Vec<(StorageKey, KeyKind)>) {
+		if self.capacity == 0 {
+			return;
+		}
+
+		let mut data = self.data.lock();
+		data.tick += 1;
+		let tick = data.tick;
+		if !data.entries.contains_key(&block) && data.entries.len() >= self.capacity {
+			let least_recently_used = data.entries.iter()
+				.min_by_key(|(_, (_, _, last_access))| *last_access)
+				.map(|(block, _)| block.clone());
+			if let Some(least_recently_used) = least_recently_used {
+				data.entries.remove(&least_recently_used);
+			}
+		}
+		data.entries.insert(block, (root, Arc::new(keys), tick));
+	}
+
+	/// Remove all entries that aren't matching changes trie roots of the canonical chain.
+	///
+	/// Should be called on reorgs. The `roots` function returns changes trie root of the
+	/// canonical block with given number.
+	pub fn retain_canonical(&self, roots: impl Fn(N) -> Option<H>) {
+		self.data.lock().entries
+			.retain(|block, (root, _, _)| roots(block.clone()).as_ref() == Some(&*root));
+	}
+
+	/// Remove all entries.
+	pub fn clear(&self) {
+		self.data.lock().entries.clear();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn keys(key: u8) -> Vec<(StorageKey, KeyKind)> {
+		vec![(vec![key], KeyKind::Top)]
+	}
+
+	#[test]
+	fn entry_is_returned_only_for_the_same_root() {
+		let cache = DigestCache::<u32, u32>::new(4);
+		cache.insert(1, 100, keys(1));
+
+		assert_eq!(cache.get(&1, &100).map(|keys| (*keys).clone()), Some(keys(1)));
+		assert_eq!(cache.get(&1, &101), None);
+		assert_eq!(cache.get(&2, &100), None);
+	}
+
+	#[test]
+	fn least_recently_used_entry_is_evicted() {
+		let cache = DigestCache::<u32, u32>::new(2);
+		cache.insert(1, 100, keys(1));
+		cache.insert(2, 200, keys(2));
+		assert!(cache.get(&1, &100).is_some());
+
+		cache.insert(3, 300, keys(3));
+		assert_eq!(cache.len(), 2);
+		assert!(cache.get(&1, &100).is_some());
+		assert!(cache.get(&2, &200).is_none());
+		assert!(cache.get(&3, &300).is_some());
+
+		let cache = DigestCache::<u32, u32>::new(0);
+		cache.insert(1, 100, keys(1));
+		assert!(cache.is_empty());
+	}
+
+	#[test]
+	fn non_canonical_entries_are_removed() {
+		let cache = DigestCache::<u32, u32>::new(4);
+		cache.insert(1, 100, keys(1));
+		cache.insert(2, 200, keys(2));
+		cache.insert(3, 300, keys(3));
+
+		// block#2 has been reorged and block#3 has been retracted
+		cache.retain_canonical(|block| match block {
+			1 => Some(100),
+			2 => Some(201),
+			_ => None,
+		});
+
+		assert_eq!(cache.len(), 1);
+		assert!(cache.get(&1, &100).is_some());
+	}
+}
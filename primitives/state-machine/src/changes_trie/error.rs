@@ -0,0 +1,194 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes trie related errors.
+
+/// Changes trie error.
+///
+/// Allows callers to distinguish between data that is expectedly missing from the
+/// changes tries storage (e.g. because it has been pruned or not yet synced) and
+/// failures of the underlying storage.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error<Number: std::fmt::Debug + std::fmt::Display> {
+	/// Changes trie root for the given block is not in the changes tries storage.
+	#[error("no changes trie root for block {0}")]
+	StorageRootMissing(Number),
+	/// Changes trie roots of the given digest input blocks are not in the changes tries storage.
+	#[error("no changes trie roots for digest input blocks {0:?}")]
+	DigestInputRootsMissing(Vec<Number>),
+	/// Digest has been built without some of its input blocks, so changes of these blocks
+	/// can't be found using the digest.
+	#[error("digest of block {block} has been built without input blocks {missing_blocks:?}")]
+	DegradedDigest {
+		/// Digest block.
+		block: Number,
+		/// Input blocks that haven't been included into the digest.
+		missing_blocks: Vec<Number>,
+	},
+	/// Changes have been collected for other block than the block of the changes trie.
+	#[error("extrinsics changes have been collected for other block than {0}")]
+	StaleExtrinsicChanges(Number),
+	/// State backend has failed to read data.
+	#[error("{0}")]
+	Backend(String),
+	/// Changed child storage has unknown type.
+	#[error("unknown type of child storage key {}", sp_core::hexdisplay::HexDisplay::from(.0))]
+	UnknownChildStorageKey(Vec<u8>),
+	/// Existence of the key can't be checked, because trie node is missing from the backend
+	/// (e.g. the backend has been created from the proof that doesn't contain the node).
+	#[error("trie node that is required to check existence of key {0:?} is missing")]
+	MissingProofNode(Vec<u8>),
+	/// State of the given block is not available (e.g. it has been pruned).
+	#[error("state of block {0} is not available")]
+	StatePruned(Number),
+	/// Changes tries storage has failed to read data.
+	#[error("{0}")]
+	Storage(String),
+	/// Changes tries storage doesn't know the number of the block with given hash.
+	#[error("can't find associated number for block {}", sp_core::hexdisplay::HexDisplay::from(.0))]
+	UnknownBlock(Vec<u8>),
+	/// Changes trie node with given hash is required, but it isn't included into the proof.
+	#[error("trie node {} is not included into the proof", sp_core::hexdisplay::HexDisplay::from(.0))]
+	MissingTrieNode(Vec<u8>),
+	/// Changes trie configuration, stored in the state, can't be decoded.
+	#[error("invalid changes trie configuration in storage: {0}")]
+	InvalidStoredConfiguration(codec::Error),
+	/// Changes trie configuration is invalid.
+	#[error("invalid changes trie configuration: {0:?}")]
+	InvalidConfiguration(crate::changes_trie::Configuration),
+	/// Changes tries are enabled, but extrinsics indices of changes have not been collected.
+	#[error("extrinsics indices of storage changes have not been collected")]
+	ExtrinsicsNotCollected,
+	/// Requested blocks range is invalid.
+	#[error("invalid changes range: {begin}..{end}/{max}")]
+	InvalidRange {
+		/// First block of the range.
+		begin: Number,
+		/// Last block of the range.
+		end: Number,
+		/// Best known block.
+		max: Number,
+	},
+	/// Requested blocks range is not covered by the changes trie configuration.
+	#[error(
+		"changes trie range is not covered by configuration: {begin}..{end}/{zero}..{}",
+		.config_end.as_ref().map(|end| end.to_string()).unwrap_or_else(|| "None".into()),
+	)]
+	RangeNotCovered {
+		/// First block of the range.
+		begin: Number,
+		/// Last block of the range.
+		end: Number,
+		/// Zero block of the configuration.
+		zero: Number,
+		/// End block of the configuration, if any.
+		config_end: Option<Number>,
+	},
+	/// Changes trie input contains multiple pairs with the same trie key.
+	#[error("changes trie input contains multiple pairs for key {}", sp_core::hexdisplay::HexDisplay::from(.0))]
+	DuplicateInputKey(Vec<u8>),
+	/// Changes trie input, that must be sorted by trie keys, has the pair after the pair with
+	/// the greater trie key.
+	#[error("changes trie input isn't sorted: pair for key {} is out of order", sp_core::hexdisplay::HexDisplay::from(.0))]
+	UnsortedInputKey(Vec<u8>),
+	/// Changes trie input contains pair of other block.
+	#[error(
+		"changes trie input pair for key {} belongs to block {block} instead of {expected}",
+		sp_core::hexdisplay::HexDisplay::from(.key),
+	)]
+	InvalidInputBlock {
+		/// Storage key of the pair.
+		key: Vec<u8>,
+		/// Block of the pair.
+		block: Number,
+		/// Block the changes trie is built for.
+		expected: Number,
+	},
+	/// Changes trie input contains digest pairs, but digest isn't built at the block.
+	#[error("changes trie input of block {0} contains digest pairs, but digest isn't built at this block")]
+	UnexpectedDigestBlock(Number),
+	/// Digest lists the block that it can't legitimately list: the block isn't below the
+	/// digest block or precedes the range, covered by the digest.
+	#[error("digest of block {digest_block} lists block {listed} that it doesn't cover")]
+	InvalidDigestBlock {
+		/// Digest block.
+		digest_block: Number,
+		/// Block, listed by the digest.
+		listed: Number,
+	},
+	/// Digest is built at the block, but there are no digest input blocks.
+	#[error("digest is built at block {0}, but there are no digest input blocks")]
+	MissingDigestBlock(Number),
+	/// Memory, occupied by the changes trie input, has exceeded the limit.
+	#[error("changes trie input occupies {used} bytes, which exceeds the limit of {limit} bytes")]
+	MemoryLimitExceeded {
+		/// Estimated number of bytes that have been occupied when the limit has been checked.
+		used: usize,
+		/// The limit.
+		limit: usize,
+	},
+	/// Key has been changed by more extrinsics than the changes trie may list, or by the
+	/// extrinsic with index that exceeds the max number of extrinsics in the block.
+	#[error(
+		"key {} has been changed by too many extrinsics: {count}",
+		sp_core::hexdisplay::HexDisplay::from(.key),
+	)]
+	TooManyExtrinsics {
+		/// The changed key.
+		key: Vec<u8>,
+		/// Number of extrinsics that have changed the key, or the number of extrinsics in the
+		/// block that is implied by the largest extrinsic index.
+		count: u32,
+	},
+	/// Changes trie contains data that can't be decoded.
+	#[error("{0}")]
+	Decode(#[from] codec::Error),
+	/// Changes trie value lists more items than it may legitimately list.
+	#[error("changes trie value lists {len} items, but at most {max_len} items are allowed")]
+	ValueTooLarge {
+		/// Number of items, read from the length prefix of the value.
+		len: u32,
+		/// Max number of items.
+		max_len: u32,
+	},
+	/// Changes trie value lists items that aren't sorted in ascending order or aren't unique.
+	#[error("changes trie value lists items that aren't sorted or aren't unique")]
+	NonCanonicalValue,
+	/// Key changes query limits don't allow to read a single changes trie.
+	#[error("key changes query limits are too low to make any progress")]
+	KeyChangesLimitsTooLow,
+	/// Key changes query cursor points outside of the queried range.
+	#[error("invalid key changes query cursor")]
+	InvalidKeyChangesCursor,
+	/// Changes trie contains key that can't be decoded.
+	#[error(
+		"changes trie of block {block} contains invalid key {}",
+		sp_core::hexdisplay::HexDisplay::from(.key),
+	)]
+	InvalidInputKey {
+		/// Block of the changes trie.
+		block: Number,
+		/// Raw trie key.
+		key: Vec<u8>,
+	},
+	/// Changes trie input doesn't match the storage delta of the block.
+	#[error("{0}")]
+	InconsistentWithDelta(crate::changes_trie::consistency::ConsistencyError),
+	/// Changes trie build has been cancelled by the caller.
+	#[error("changes trie build has been cancelled")]
+	Cancelled,
+}
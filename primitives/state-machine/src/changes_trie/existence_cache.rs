@@ -0,0 +1,105 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries keys existence cache.
+
+use std::collections::{HashMap, HashSet};
+use sp_core::storage::ChildInfo;
+use crate::StorageKey;
+
+/// Cache that is consulted before checking existence of keys in the state backend.
+///
+/// When changes trie is built, existence of every key that has been deleted in the
+/// overlay is checked in the backend, so that temporary values (keys that have been
+/// created and then deleted within the same block) are not included into the trie.
+/// Block builders may back this cache with e.g. bloom filter of the state trie keys to
+/// skip backend lookups of keys that definitely do not exist.
+pub trait ExistenceCache: Send + Sync {
+	/// Returns false if the key definitely does not exist in the backend state. Returns
+	/// true if the key may exist => the backend must be asked.
+	fn may_exist(&self, child_info: Option<&ChildInfo>, key: &[u8]) -> bool;
+}
+
+/// Existence cache that knows nothing about the state => every key is checked in the backend.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoExistenceCache;
+
+impl ExistenceCache for NoExistenceCache {
+	fn may_exist(&self, _child_info: Option<&ChildInfo>, _key: &[u8]) -> bool {
+		true
+	}
+}
+
+/// In-memory existence cache that holds all keys of the state.
+///
+/// Keys that are not in the cache are considered non-existent in the backend state.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryExistenceCache {
+	top: HashSet<StorageKey>,
+	children: HashMap<StorageKey, HashSet<StorageKey>>,
+}
+
+impl InMemoryExistenceCache {
+	/// Create new empty cache.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Remember that the key exists in the (child) storage.
+	pub fn insert(&mut self, child_info: Option<&ChildInfo>, key: StorageKey) {
+		match child_info {
+			Some(child_info) => self.children
+				.entry(child_info.storage_key().to_vec())
+				.or_default()
+				.insert(key),
+			None => self.top.insert(key),
+		};
+	}
+}
+
+impl ExistenceCache for InMemoryExistenceCache {
+	fn may_exist(&self, child_info: Option<&ChildInfo>, key: &[u8]) -> bool {
+		match child_info {
+			Some(child_info) => self.children
+				.get(child_info.storage_key())
+				.map(|keys| keys.contains(key))
+				.unwrap_or(false),
+			None => self.top.contains(key),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn in_memory_cache_knows_inserted_keys_only() {
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let other_child_info = ChildInfo::new_default(b"other_storage_key");
+		let mut cache = InMemoryExistenceCache::new();
+		cache.insert(None, vec![1]);
+		cache.insert(Some(&child_info), vec![2]);
+
+		assert!(cache.may_exist(None, &[1]));
+		assert!(!cache.may_exist(None, &[2]));
+		assert!(cache.may_exist(Some(&child_info), &[2]));
+		assert!(!cache.may_exist(Some(&child_info), &[1]));
+		assert!(!cache.may_exist(Some(&other_child_info), &[2]));
+		assert!(NoExistenceCache.may_exist(None, &[2]));
+	}
+}
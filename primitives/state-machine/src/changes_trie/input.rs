@@ -16,46 +16,200 @@
 // limitations under the License.
 
 //! Different types of changes trie input pairs.
+//!
+//! Input pairs are inserted into changes tries as `(trie_key, trie_value)` pairs. The byte
+//! layout of these pairs is a part of the consensus and is stable. Current layout (version 1)
+//! of trie keys is:
+//!
+//! - `ExtrinsicIndex`: `0x01 ++ SCALE(block) ++ SCALE(key)`;
+//! - `DigestIndex`: `0x02 ++ SCALE(block) ++ SCALE(key)`;
+//! - `ChildIndex`: `0x03 ++ SCALE(block) ++ SCALE(storage_key)`;
+//! - `MissingBlocks`: `0x04 ++ SCALE(block)`;
+//!
+//! where keys are SCALE-encoded as byte vectors (compact length followed by the bytes).
+//! Trie values are:
+//!
+//! - `ExtrinsicIndex`: `SCALE(Vec<u32>)` of extrinsic indices. Changes that have been made
+//!   outside of extrinsics are attributed to the reserved indices (see `ExtrinsicId`);
+//! - `DigestIndex`: `SCALE(Vec<Number>)` of block numbers, or the bitmap of block numbers (see
+//!   below);
+//! - `ChildIndex`: `SCALE(Vec<u8>)` of the child changes trie root;
+//! - `MissingBlocks`: `SCALE(Vec<Number>)` of digest input blocks that have been skipped.
+//!
+//! The `MissingBlocks` pair is only inserted into the top-level trie of the digest block that
+//! has been built without some of its input blocks (see `MissingRootPolicy::SkipAndRecord`).
+//!
+//! When `record_deletions` is configured, `ExtrinsicIndex` values of keys that have existed before
+//! the block and don't exist after it also list the reserved `ExtrinsicId::KEY_DELETION` index.
+//! Values of other keys are encoded the same way as without the flag (see `ChangeKind`).
+//!
+//! Lists of extrinsic indices and block numbers are always sorted in ascending order and have
+//! no duplicates, so the same changes always produce the same trie. Values that aren't in this
+//! canonical form are rejected when decoded.
+//!
+//! When `digest_value_threshold` is configured, `DigestIndex` values that list more blocks than
+//! the threshold are stored as `0x00 ++ SCALE(last) ++ SCALE(Vec<u8>)`, where bit `i` (least
+//! significant bit first) of the byte vector is set if block `last - i` is listed. The bitmap
+//! is only used when it is shorter than the explicit list. Bit `0` of the canonical bitmap is
+//! always set and its last byte is never zero. Since the explicit empty list is encoded as the
+//! single `0x00` byte, both forms could be told apart by the value length.
+//!
+//! The encoded `InputPair` is the SCALE encoding of the `(trie_key, trie_value)` tuple.
 
-use codec::{Decode, Encode, Input, Output, Error};
+use codec::{Compact, Decode, Encode, Input, Output, Error};
 use crate::{
-	StorageKey, StorageValue,
-	changes_trie::BlockNumber
+	StorageKey, StorageValue, ExtrinsicId,
+	changes_trie::{BlockNumber, HeapSize, Error as ChangesTrieError},
+	changes_trie_keys::decode_all,
 };
-use sp_core::storage::PrefixedStorageKey;
 
-/// Key of { changed key => set of extrinsic indices } mapping.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct ExtrinsicIndex<Number: BlockNumber> {
-	/// Block at which this key has been inserted in the trie.
-	pub block: Number,
-	/// Storage key this node is responsible for.
-	pub key: StorageKey,
-}
+pub use crate::changes_trie_keys::{
+	ExtrinsicIndex, DigestIndex, ChildIndex, MissingBlocksIndex, InputKey,
+	EXTRINSIC_INDEX_KEY_PREFIX, DIGEST_INDEX_KEY_PREFIX, CHILD_INDEX_KEY_PREFIX, MISSING_BLOCKS_KEY_PREFIX,
+};
 
 /// Value of { changed key => set of extrinsic indices } mapping.
-pub type ExtrinsicIndexValue = Vec<u32>;
+pub type ExtrinsicIndexValue = ExtrinsicList;
 
-/// Key of { changed key => block/digest block numbers } mapping.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct DigestIndex<Number: BlockNumber> {
-	/// Block at which this key has been inserted in the trie.
-	pub block: Number,
-	/// Storage key this node is responsible for.
-	pub key: StorageKey,
+/// List of items, sorted in ascending order and without duplicates.
+///
+/// This is the canonical form of the lists that are stored in the changes trie. Lists are
+/// canonicalized when created from arbitrary items and are validated when decoded.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CanonicalList<T>(Vec<T>);
+
+/// Sorted list of unique extrinsic indices.
+pub type ExtrinsicList = CanonicalList<ExtrinsicId>;
+
+/// Sorted list of unique block numbers.
+pub type BlockList<Number> = CanonicalList<Number>;
+
+impl<T> Default for CanonicalList<T> {
+	fn default() -> Self {
+		CanonicalList(Vec::new())
+	}
+}
+
+impl<T: Ord> CanonicalList<T> {
+	/// Insert item into the list, unless it is already there.
+	///
+	/// Items are usually inserted in ascending order, so this is normally a push.
+	pub fn insert(&mut self, item: T) {
+		if self.0.last().map(|last| *last < item).unwrap_or(true) {
+			self.0.push(item);
+		} else if let Err(index) = self.0.binary_search(&item) {
+			self.0.insert(index, item);
+		}
+	}
+
+	/// Returns true if items are sorted in ascending order and there are no duplicates.
+	pub fn is_canonical(items: &[T]) -> bool {
+		items.windows(2).all(|pair| pair[0] < pair[1])
+	}
+
+	/// Get list items.
+	pub fn into_inner(self) -> Vec<T> {
+		self.0
+	}
+}
+
+impl<T: Ord> From<Vec<T>> for CanonicalList<T> {
+	fn from(mut items: Vec<T>) -> Self {
+		if !Self::is_canonical(&items) {
+			items.sort_unstable();
+			items.dedup();
+		}
+		CanonicalList(items)
+	}
+}
+
+impl From<Vec<u32>> for ExtrinsicList {
+	fn from(items: Vec<u32>) -> Self {
+		items.into_iter().map(ExtrinsicId).collect::<Vec<_>>().into()
+	}
+}
+
+impl ExtrinsicList {
+	/// Kind of the change of the key, that has been changed by extrinsics of this list.
+	///
+	/// `records_deletions` is the `record_deletions` flag of the configuration that the changes
+	/// trie has been built with. Without the flag, lists aren't marked and the kind is unknown.
+	pub fn change_kind(&self, records_deletions: bool) -> ChangeKind {
+		match records_deletions {
+			true if self.binary_search(&ExtrinsicId::KEY_DELETION).is_ok() => ChangeKind::Deleted,
+			true => ChangeKind::Updated,
+			false => ChangeKind::Unknown,
+		}
+	}
+}
+
+/// Kind of the change of the key at the block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+	/// The key has been changed and it hasn't been deleted at the end of the block.
+	Updated,
+	/// The key has existed before the block and it has been deleted in the block.
+	Deleted,
+	/// Deletions aren't recorded by the changes trie configuration.
+	Unknown,
+}
+
+impl<T: Ord> std::iter::FromIterator<T> for CanonicalList<T> {
+	fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+		iter.into_iter().collect::<Vec<_>>().into()
+	}
+}
+
+impl<T> std::ops::Deref for CanonicalList<T> {
+	type Target = [T];
+
+	fn deref(&self) -> &[T] {
+		&self.0
+	}
 }
 
-/// Key of { childtrie key => Childchange trie } mapping.
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ChildIndex<Number: BlockNumber> {
-	/// Block at which this key has been inserted in the trie.
-	pub block: Number,
-	/// Storage key this node is responsible for.
-	pub storage_key: PrefixedStorageKey,
+impl<T> IntoIterator for CanonicalList<T> {
+	type Item = T;
+	type IntoIter = std::vec::IntoIter<T>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.0.into_iter()
+	}
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for CanonicalList<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+impl<T: Encode> Encode for CanonicalList<T> {
+	fn size_hint(&self) -> usize {
+		self.0.size_hint()
+	}
+
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		self.0.encode_to(dest)
+	}
+}
+
+impl<T: Encode> codec::EncodeLike for CanonicalList<T> {}
+
+impl<T: Decode + Ord> Decode for CanonicalList<T> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let items = Vec::<T>::decode(input)?;
+		if !Self::is_canonical(&items) {
+			return Err("Changes trie value list is not sorted or has duplicates".into());
+		}
+		Ok(CanonicalList(items))
+	}
 }
 
 /// Value of { changed key => block/digest block numbers } mapping.
-pub type DigestIndexValue<Number> = Vec<Number>;
+pub type DigestIndexValue<Number> = BlockList<Number>;
+
+/// Value of the list of digest input blocks that haven't been included into the digest.
+pub type MissingBlocksValue<Number> = BlockList<Number>;
 
 /// Value of { changed key => block/digest block numbers } mapping.
 /// That is the root of the child change trie.
@@ -70,17 +224,46 @@ pub enum InputPair<Number: BlockNumber> {
 	DigestIndex(DigestIndex<Number>, DigestIndexValue<Number>),
 	/// Element of { childtrie key => Childchange trie } where key has been changed } element mapping.
 	ChildIndex(ChildIndex<Number>, ChildIndexValue),
+	/// Digest input blocks whose changes tries roots were missing when the digest has been built.
+	MissingBlocks(MissingBlocksIndex<Number>, MissingBlocksValue<Number>),
 }
 
-/// Single input key of changes trie.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum InputKey<Number: BlockNumber> {
-	/// Key of { key => set of extrinsics where key has been changed } element mapping.
-	ExtrinsicIndex(ExtrinsicIndex<Number>),
-	/// Key of { key => set of blocks/digest blocks where key has been changed } element mapping.
-	DigestIndex(DigestIndex<Number>),
-	/// Key of { childtrie key => Childchange trie } where key has been changed } element mapping.
-	ChildIndex(ChildIndex<Number>),
+/// Kind of the changes trie key.
+///
+/// Kinds are ordered by the first byte of their keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyKind {
+	/// Key of the `ExtrinsicIndex` pair.
+	ExtrinsicIndex,
+	/// Key of the `DigestIndex` pair.
+	DigestIndex,
+	/// Key of the `ChildIndex` pair.
+	ChildIndex,
+	/// Key of the `MissingBlocks` pair.
+	MissingBlocks,
+}
+
+impl KeyKind {
+	/// First byte of keys of this kind.
+	pub fn prefix_byte(&self) -> u8 {
+		match *self {
+			KeyKind::ExtrinsicIndex => EXTRINSIC_INDEX_KEY_PREFIX,
+			KeyKind::DigestIndex => DIGEST_INDEX_KEY_PREFIX,
+			KeyKind::ChildIndex => CHILD_INDEX_KEY_PREFIX,
+			KeyKind::MissingBlocks => MISSING_BLOCKS_KEY_PREFIX,
+		}
+	}
+
+	/// Kind of keys that start with given byte.
+	pub fn from_prefix_byte(byte: u8) -> Option<Self> {
+		match byte {
+			EXTRINSIC_INDEX_KEY_PREFIX => Some(KeyKind::ExtrinsicIndex),
+			DIGEST_INDEX_KEY_PREFIX => Some(KeyKind::DigestIndex),
+			CHILD_INDEX_KEY_PREFIX => Some(KeyKind::ChildIndex),
+			MISSING_BLOCKS_KEY_PREFIX => Some(KeyKind::MissingBlocks),
+			_ => None,
+		}
+	}
 }
 
 impl<Number: BlockNumber> InputPair<Number> {
@@ -89,18 +272,159 @@ impl<Number: BlockNumber> InputPair<Number> {
 		match *self {
 			InputPair::ExtrinsicIndex(ref key, _) => Some(&key.key),
 			InputPair::DigestIndex(ref key, _) => Some(&key.key),
-			InputPair::ChildIndex(_, _) => None,
+			InputPair::ChildIndex(_, _) | InputPair::MissingBlocks(_, _) => None,
+		}
+	}
+}
+
+impl<Number: BlockNumber> InputPair<Number> {
+	/// Encode key of this pair, exactly as it is inserted into the changes trie.
+	pub fn key_encoded(&self) -> Vec<u8> {
+		let mut key = Vec::with_capacity(self.encoded_key_len());
+		self.encode_key_to(&mut key);
+		key
+	}
+
+	/// Encode value of this pair, exactly as it is inserted into the changes trie.
+	pub fn value_encoded(&self) -> Vec<u8> {
+		let mut value = Vec::new();
+		self.encode_value_to(&mut value);
+		value
+	}
+
+	/// Append encoded key of this pair to the `dest`.
+	///
+	/// Allows to reuse the same buffer when many pairs are inserted into the trie.
+	pub fn encode_key_to(&self, dest: &mut Vec<u8>) {
+		match *self {
+			InputPair::ExtrinsicIndex(ref key, _) => key.encode_to(dest),
+			InputPair::DigestIndex(ref key, _) => key.encode_to(dest),
+			InputPair::ChildIndex(ref key, _) => key.encode_to(dest),
+			InputPair::MissingBlocks(ref key, _) => key.encode_to(dest),
+		}
+	}
+
+	/// Append encoded value of this pair to the `dest`.
+	///
+	/// Allows to reuse the same buffer when many pairs are inserted into the trie.
+	pub fn encode_value_to(&self, dest: &mut Vec<u8>) {
+		match *self {
+			InputPair::ExtrinsicIndex(_, ref value) => value.encode_to(dest),
+			InputPair::DigestIndex(_, ref value) => value.encode_to(dest),
+			InputPair::ChildIndex(_, ref value) => value.encode_to(dest),
+			InputPair::MissingBlocks(_, ref value) => value.encode_to(dest),
+		}
+	}
+
+	/// Length of the encoded key of this pair.
+	pub fn encoded_key_len(&self) -> usize {
+		let (block, key) = match *self {
+			InputPair::ExtrinsicIndex(ref key, _) => (&key.block, &key.key[..]),
+			InputPair::DigestIndex(ref key, _) => (&key.block, &key.key[..]),
+			InputPair::ChildIndex(ref key, _) => (&key.block, &key.storage_key[..]),
+			InputPair::MissingBlocks(ref key, _) => return 1 + key.block.encoded_size(),
+		};
+		1 + block.encoded_size() + Compact(key.len() as u32).encoded_size() + key.len()
+	}
+
+	/// Decode pair from the key and the value of the changes trie.
+	pub fn decode_from_trie_pair(trie_key: &[u8], trie_value: &[u8]) -> Result<Self, Error> {
+		Ok(match InputKey::decode_from_trie_key(trie_key)? {
+			InputKey::ExtrinsicIndex(key) => InputPair::ExtrinsicIndex(key, decode_all(trie_value)?),
+			InputKey::DigestIndex(key) => InputPair::DigestIndex(key, match trie_value.split_first() {
+				Some((&BLOCK_BITMAP_MARKER, bitmap)) if !bitmap.is_empty() =>
+					decode_block_bitmap::<Number>(bitmap, u32::max_value())
+						.map_err(|_| Error::from("Invalid DigestIndex blocks bitmap"))?,
+				_ => decode_all(trie_value)?,
+			}),
+			InputKey::ChildIndex(key) => InputPair::ChildIndex(key, decode_all(trie_value)?),
+			InputKey::MissingBlocks(key) => InputPair::MissingBlocks(key, decode_all(trie_value)?),
+		})
+	}
+}
+
+impl<Number: BlockNumber> InputPair<Number> {
+	/// Kind of the key, block and the storage key that pairs are ordered by.
+	fn order_key(&self) -> (KeyKind, &Number, &[u8]) {
+		match *self {
+			InputPair::ExtrinsicIndex(ref key, _) => (KeyKind::ExtrinsicIndex, &key.block, &key.key[..]),
+			InputPair::DigestIndex(ref key, _) => (KeyKind::DigestIndex, &key.block, &key.key[..]),
+			InputPair::ChildIndex(ref key, _) => (KeyKind::ChildIndex, &key.block, &key.storage_key[..]),
+			InputPair::MissingBlocks(ref key, _) => (KeyKind::MissingBlocks, &key.block, &[][..]),
+		}
+	}
+}
+
+/// Pairs are ordered by the kind of the key (`ExtrinsicIndex` pairs go first, then `DigestIndex`,
+/// `ChildIndex` and `MissingBlocks` pairs), then by the block and then by the storage key in
+/// the trie order (see `cmp_trie_keys`). Pairs with the same key are ordered by the encoded value.
+///
+/// So pairs of the single changes trie are ordered by their trie keys. Input of the single
+/// changes trie is prepared in this order (see `prepare_input_iter`).
+impl<Number: BlockNumber> Ord for InputPair<Number> {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		let (kind, block, key) = self.order_key();
+		let (other_kind, other_block, other_key) = other.order_key();
+		kind.cmp(&other_kind)
+			.then_with(|| block.cmp(other_block))
+			.then_with(|| cmp_trie_keys(key, other_key))
+			.then_with(|| self.value_encoded().cmp(&other.value_encoded()))
+	}
+}
+
+/// Compare storage keys in the order of their SCALE encodings, i.e. in the order of trie keys
+/// of the changes trie pairs (of the same kind and block) they are included into.
+///
+/// Encoded keys start with their compact length, so this isn't the lexicographic order.
+pub(crate) fn cmp_trie_keys(key: &[u8], other_key: &[u8]) -> std::cmp::Ordering {
+	if key.len() == other_key.len() {
+		return key.cmp(other_key);
+	}
+
+	// compact encodings of different lengths are never prefixes of each other
+	Compact(key.len() as u32).using_encoded(|len| Compact(other_key.len() as u32)
+		.using_encoded(|other_len| len.cmp(other_len)))
+}
+
+impl<Number: BlockNumber> PartialOrd for InputPair<Number> {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<Number: BlockNumber> HeapSize for InputPair<Number> {
+	fn heap_size(&self) -> usize {
+		match *self {
+			InputPair::ExtrinsicIndex(ref key, ref value) =>
+				key.key.capacity() + value.0.capacity() * std::mem::size_of::<ExtrinsicId>(),
+			InputPair::DigestIndex(ref key, ref value) =>
+				key.key.capacity() + value.0.capacity() * std::mem::size_of::<Number>(),
+			InputPair::ChildIndex(ref key, ref value) =>
+				key.storage_key.capacity() + value.capacity(),
+			InputPair::MissingBlocks(_, ref value) =>
+				value.0.capacity() * std::mem::size_of::<Number>(),
 		}
 	}
 }
 
 impl<Number: BlockNumber> Into<(StorageKey, StorageValue)> for InputPair<Number> {
 	fn into(self) -> (StorageKey, StorageValue) {
-		match self {
-			InputPair::ExtrinsicIndex(key, value) => (key.encode(), value.encode()),
-			InputPair::DigestIndex(key, value) => (key.encode(), value.encode()),
-			InputPair::ChildIndex(key, value) => (key.encode(), value.encode()),
-		}
+		(self.key_encoded(), self.value_encoded())
+	}
+}
+
+impl<Number: BlockNumber> Encode for InputPair<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		self.key_encoded().encode_to(dest);
+		self.value_encoded().encode_to(dest);
+	}
+}
+
+impl<Number: BlockNumber> Decode for InputPair<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let trie_key: Vec<u8> = Decode::decode(input)?;
+		let trie_value: Vec<u8> = Decode::decode(input)?;
+		InputPair::decode_from_trie_pair(&trie_key, &trie_value)
 	}
 }
 
@@ -110,87 +434,312 @@ impl<Number: BlockNumber> Into<InputKey<Number>> for InputPair<Number> {
 			InputPair::ExtrinsicIndex(key, _) => InputKey::ExtrinsicIndex(key),
 			InputPair::DigestIndex(key, _) => InputKey::DigestIndex(key),
 			InputPair::ChildIndex(key, _) => InputKey::ChildIndex(key),
+			InputPair::MissingBlocks(key, _) => InputKey::MissingBlocks(key),
 		}
 	}
 }
 
-impl<Number: BlockNumber> ExtrinsicIndex<Number> {
-	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
-		let mut prefix = vec![1];
-		prefix.extend(block.encode());
-		prefix
-	}
+impl<Number: BlockNumber> codec::EncodeLike for InputPair<Number> {}
+
+/// Split the changes trie key into its kind, block and the storage key (the prefixed storage
+/// key of the child storage for `ChildIndex` keys). `MissingBlocks` keys have no storage key,
+/// so the empty key is returned for them.
+///
+/// Block is decoded as `Number`, so the same type that has been used to build the changes
+/// trie must be used here. Returns `None` if the key isn't a valid changes trie key.
+pub fn classify_key<Number: BlockNumber>(trie_key: &[u8]) -> Option<(KeyKind, Number, Vec<u8>)> {
+	classify_key_ref(trie_key).map(|(kind, block, key)| (kind, block, key.to_vec()))
 }
 
-impl<Number: BlockNumber> Encode for ExtrinsicIndex<Number> {
-	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
-		dest.push_byte(1);
-		self.block.encode_to(dest);
-		self.key.encode_to(dest);
+/// Same as `classify_key`, but the storage key is borrowed from the `trie_key`.
+pub fn classify_key_ref<Number: BlockNumber>(trie_key: &[u8]) -> Option<(KeyKind, Number, &[u8])> {
+	let (prefix_byte, mut rest) = trie_key.split_first()?;
+	let kind = KeyKind::from_prefix_byte(*prefix_byte)?;
+	let block = Number::decode(&mut rest).ok()?;
+	if kind == KeyKind::MissingBlocks {
+		return if rest.is_empty() { Some((kind, block, rest)) } else { None };
+	}
+	let key_len = <Compact<u32>>::decode(&mut rest).ok()?.0;
+	if rest.len() != key_len as usize {
+		return None;
 	}
+	Some((kind, block, rest))
 }
 
-impl<Number: BlockNumber> codec::EncodeLike for ExtrinsicIndex<Number> {}
+/// Decode value of the ExtrinsicIndex pair, that may list at most `max_len` extrinsics.
+///
+/// The length prefix is checked before the list is allocated, so values that are coming from
+/// untrusted sources (e.g. proofs) can't force large allocations. Lists that aren't in the
+/// canonical form are rejected with `Error::NonCanonicalValue`.
+pub fn decode_extrinsic_list<Number: BlockNumber>(
+	value: &[u8],
+	max_len: u32,
+) -> Result<ExtrinsicIndexValue, ChangesTrieError<Number>> {
+	decode_bounded_list(value, max_len)
+}
 
-impl<Number: BlockNumber> DigestIndex<Number> {
-	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
-		let mut prefix = vec![2];
-		prefix.extend(block.encode());
-		prefix
+/// Decode value of the DigestIndex pair, that may list at most `max_len` blocks. Both explicit
+/// lists and bitmaps are accepted.
+///
+/// The length prefix is checked before the list is allocated, so values that are coming from
+/// untrusted sources (e.g. proofs) can't force large allocations. Lists that aren't in the
+/// canonical form are rejected with `Error::NonCanonicalValue`.
+pub fn decode_block_list<Number: BlockNumber>(
+	value: &[u8],
+	max_len: u32,
+) -> Result<DigestIndexValue<Number>, ChangesTrieError<Number>> {
+	match value.split_first() {
+		Some((&BLOCK_BITMAP_MARKER, bitmap)) if !bitmap.is_empty() => decode_block_bitmap(bitmap, max_len),
+		_ => decode_bounded_list(value, max_len),
 	}
 }
 
-
-impl<Number: BlockNumber> Encode for DigestIndex<Number> {
-	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
-		dest.push_byte(2);
-		self.block.encode_to(dest);
-		self.key.encode_to(dest);
+/// Encode value of the DigestIndex pair into `dest`, using the bitmap if the list has more
+/// than `threshold` blocks and the bitmap is shorter than the explicit list.
+pub fn encode_block_list_to<Number: BlockNumber>(
+	list: &BlockList<Number>,
+	threshold: u32,
+	dest: &mut Vec<u8>,
+) {
+	if list.len() > threshold as usize {
+		if let Some(bitmap) = block_bitmap(list) {
+			let bitmap_len = 1 + list[list.len() - 1].encoded_size() + bitmap.encoded_size();
+			if bitmap_len < list.encoded_size() {
+				dest.push(BLOCK_BITMAP_MARKER);
+				list[list.len() - 1].encode_to(dest);
+				bitmap.encode_to(dest);
+				return;
+			}
+		}
 	}
+
+	list.encode_to(dest)
 }
 
-impl<Number: BlockNumber> ChildIndex<Number> {
-	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
-		let mut prefix = vec![3];
-		prefix.extend(block.encode());
-		prefix
+/// First byte of the bitmap-encoded block list.
+const BLOCK_BITMAP_MARKER: u8 = 0;
+
+/// Bitmap of blocks of the non-empty list, counting back from the last block. Returns `None`
+/// if the list is empty or the distance between blocks doesn't fit `u32`.
+fn block_bitmap<Number: BlockNumber>(list: &[Number]) -> Option<Vec<u8>> {
+	let last = list.last()?;
+	let mut bitmap = Vec::new();
+	for block in list {
+		let offset: u32 = (last.clone() - block.clone()).try_into().ok()?;
+		let (byte, bit) = ((offset / 8) as usize, offset % 8);
+		if bitmap.len() <= byte {
+			bitmap.resize(byte + 1, 0);
+		}
+		bitmap[byte] |= 1 << bit;
 	}
+	Some(bitmap)
 }
 
-impl<Number: BlockNumber> Encode for ChildIndex<Number> {
-	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
-		dest.push_byte(3);
-		self.block.encode_to(dest);
-		self.storage_key.encode_to(dest);
+/// Decode bitmap-encoded value of the DigestIndex pair (without the marker byte), that may
+/// list at most `max_len` blocks.
+///
+/// The bitmap is never longer than the explicit list of `max_len` blocks would be.
+fn decode_block_bitmap<Number: BlockNumber>(
+	mut value: &[u8],
+	max_len: u32,
+) -> Result<BlockList<Number>, ChangesTrieError<Number>> {
+	let last = Number::decode(&mut value)?;
+	let bitmap_len = <Compact<u32>>::decode(&mut value)?.0;
+	let max_bitmap_len = max_len.saturating_mul(last.encoded_size() as u32);
+	if bitmap_len > max_bitmap_len {
+		return Err(ChangesTrieError::ValueTooLarge { len: bitmap_len, max_len: max_bitmap_len });
+	}
+	if value.len() != bitmap_len as usize {
+		return Err(Error::from("Invalid length of the changes trie blocks bitmap").into());
 	}
+	if value.first().map(|first| first & 1 == 0).unwrap_or(true) || value.last() == Some(&0) {
+		return Err(ChangesTrieError::NonCanonicalValue);
+	}
+	let len = value.iter().map(|byte| byte.count_ones()).sum::<u32>();
+	if len > max_len {
+		return Err(ChangesTrieError::ValueTooLarge { len, max_len });
+	}
+
+	let mut list = Vec::with_capacity(len as usize);
+	for (index, byte) in value.iter().enumerate() {
+		for bit in 0..8u32 {
+			if byte & (1 << bit) != 0 {
+				let offset = Number::from(index as u32 * 8 + bit);
+				list.push(last.checked_sub(&offset).ok_or(ChangesTrieError::NonCanonicalValue)?);
+			}
+		}
+	}
+	list.reverse();
+	Ok(CanonicalList(list))
 }
 
-impl<Number: BlockNumber> codec::EncodeLike for DigestIndex<Number> {}
+/// Decode canonical list of at most `max_len` items.
+fn decode_bounded_list<T: Decode + Ord, Number: BlockNumber>(
+	mut value: &[u8],
+	max_len: u32,
+) -> Result<CanonicalList<T>, ChangesTrieError<Number>> {
+	let len = <Compact<u32>>::decode(&mut value)?.0;
+	if len > max_len {
+		return Err(ChangesTrieError::ValueTooLarge { len, max_len });
+	}
 
-impl<Number: BlockNumber> Decode for InputKey<Number> {
-	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
-		match input.read_byte()? {
-			1 => Ok(InputKey::ExtrinsicIndex(ExtrinsicIndex {
-				block: Decode::decode(input)?,
-				key: Decode::decode(input)?,
-			})),
-			2 => Ok(InputKey::DigestIndex(DigestIndex {
-				block: Decode::decode(input)?,
-				key: Decode::decode(input)?,
-			})),
-			3 => Ok(InputKey::ChildIndex(ChildIndex {
-				block: Decode::decode(input)?,
-				storage_key: PrefixedStorageKey::new(Decode::decode(input)?),
-			})),
-			_ => Err("Invalid input key variant".into()),
+	let mut list = Vec::with_capacity(len as usize);
+	for _ in 0..len {
+		let item = T::decode(&mut value)?;
+		if list.last().map(|last| *last >= item).unwrap_or(false) {
+			return Err(ChangesTrieError::NonCanonicalValue);
 		}
+		list.push(item);
 	}
+	Ok(CanonicalList(list))
 }
 
 #[cfg(test)]
 mod tests {
+	use sp_core::storage::PrefixedStorageKey;
 	use super::*;
 
+	#[test]
+	fn lists_are_decoded_with_bounded_length() {
+		let extrinsics = vec![1u32, 2].encode();
+		assert_eq!(decode_extrinsic_list::<u64>(&extrinsics, 2), Ok(vec![1, 2].into()));
+		assert_eq!(
+			decode_extrinsic_list::<u64>(&extrinsics, 1),
+			Err(ChangesTrieError::ValueTooLarge { len: 2, max_len: 1 }),
+		);
+		assert!(decode_extrinsic_list::<u64>(&extrinsics[..5], 2).is_err());
+
+		let blocks = vec![4u64, 8].encode();
+		assert_eq!(decode_block_list::<u64>(&blocks, 8), Ok(vec![4, 8].into()));
+
+		// forged length prefix doesn't cause allocation
+		assert_eq!(
+			decode_block_list::<u64>(&Compact(u32::max_value()).encode(), 8),
+			Err(ChangesTrieError::ValueTooLarge { len: u32::max_value(), max_len: 8 }),
+		);
+	}
+
+	#[test]
+	fn long_block_lists_are_encoded_as_bitmaps() {
+		let encode = |list: &BlockList<u64>, threshold| {
+			let mut encoded = Vec::new();
+			encode_block_list_to(list, threshold, &mut encoded);
+			encoded
+		};
+
+		// key has been changed in every block of the 64-blocks digest
+		let every_block: BlockList<u64> = (1..=64).collect();
+		let bitmap = encode(&every_block, 8);
+		assert_eq!(every_block.encode().len(), 2 + 64 * 8);
+		assert_eq!(bitmap.len(), 1 + 8 + 1 + 8);
+		assert_eq!(&bitmap[..10], &[0, 64, 0, 0, 0, 0, 0, 0, 0, Compact(8u32).encode()[0]]);
+		assert_eq!(decode_block_list::<u64>(&bitmap, 64), Ok(every_block.clone()));
+		assert_eq!(decode_block_list::<u64>(&every_block.encode(), 64), Ok(every_block.clone()));
+
+		// short lists, and lists that are shorter than bitmaps, are listed explicitly
+		assert_eq!(encode(&every_block, 64), every_block.encode());
+		let sparse: BlockList<u64> = vec![1, 1000, 2000].into();
+		assert_eq!(encode(&sparse, 2), sparse.encode());
+
+		let pair = InputPair::DigestIndex(DigestIndex { block: 65u64, key: vec![42] }, every_block.clone());
+		assert_eq!(InputPair::decode_from_trie_pair(&pair.key_encoded(), &bitmap), Ok(pair));
+
+		// bitmaps are bounded and canonical
+		assert_eq!(
+			decode_block_list::<u64>(&bitmap, 63),
+			Err(ChangesTrieError::ValueTooLarge { len: 64, max_len: 63 }),
+		);
+		let mut trailing_zero = bitmap.clone();
+		trailing_zero[9] = Compact(9u32).encode()[0];
+		trailing_zero.push(0);
+		assert_eq!(decode_block_list::<u64>(&trailing_zero, 64), Err(ChangesTrieError::NonCanonicalValue));
+		let mut last_not_listed = bitmap.clone();
+		last_not_listed[10] = 0xfe;
+		assert_eq!(decode_block_list::<u64>(&last_not_listed, 64), Err(ChangesTrieError::NonCanonicalValue));
+		let mut before_zero = bitmap.clone();
+		before_zero[1] = 10;
+		assert_eq!(decode_block_list::<u64>(&before_zero, 64), Err(ChangesTrieError::NonCanonicalValue));
+		assert_eq!(decode_block_list::<u64>(&[0], 64), Ok(Vec::new().into()));
+	}
+
+	#[test]
+	fn lists_are_canonical() {
+		let list: ExtrinsicList = vec![3, 1, 1, 2].into();
+		assert_eq!(list.into_inner(), vec![ExtrinsicId(1), ExtrinsicId(2), ExtrinsicId(3)]);
+
+		let mut list: BlockList<u64> = vec![4].into();
+		list.insert(8);
+		list.insert(2);
+		list.insert(8);
+		assert_eq!(list.encode(), vec![2u64, 4, 8].encode());
+
+		for non_canonical in vec![vec![3u32, 1, 1], vec![1, 1], vec![2, 1]] {
+			let encoded = non_canonical.encode();
+			assert!(ExtrinsicList::decode(&mut &encoded[..]).is_err());
+			assert_eq!(decode_extrinsic_list::<u64>(&encoded, 8), Err(ChangesTrieError::NonCanonicalValue));
+		}
+		assert_eq!(ExtrinsicList::decode(&mut &vec![1u32, 3].encode()[..]), Ok(vec![1, 3].into()));
+	}
+
+	#[test]
+	fn change_kinds_are_decoded_from_both_encodings() {
+		use crate::{KEY_DELETION_EXTRINSIC_INDEX, BLOCK_FINALIZATION_EXTRINSIC_INDEX};
+
+		let updated = decode_extrinsic_list::<u64>(&vec![0u32, 2].encode(), 8).unwrap();
+		let deleted = decode_extrinsic_list::<u64>(&vec![0u32, 2, KEY_DELETION_EXTRINSIC_INDEX].encode(), 8).unwrap();
+		assert_eq!(updated.change_kind(true), ChangeKind::Updated);
+		assert_eq!(deleted.change_kind(true), ChangeKind::Deleted);
+		assert_eq!(updated.change_kind(false), ChangeKind::Unknown);
+		assert_eq!(deleted.change_kind(false), ChangeKind::Unknown);
+
+		// the marker is ordered before indices of the system changes
+		let list: ExtrinsicList = vec![ExtrinsicId::BLOCK_FINALIZATION, ExtrinsicId::KEY_DELETION, ExtrinsicId(1)].into();
+		assert_eq!(list.encode(), vec![1u32, KEY_DELETION_EXTRINSIC_INDEX, BLOCK_FINALIZATION_EXTRINSIC_INDEX].encode());
+		assert_eq!(list.change_kind(true), ChangeKind::Deleted);
+
+		let pair = InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3u64, key: vec![42] }, deleted);
+		assert_eq!(InputPair::decode_from_trie_pair(&pair.key_encoded(), &pair.value_encoded()), Ok(pair));
+	}
+
+	#[test]
+	fn key_neutral_prefixes_are_stable() {
+		assert_eq!(ExtrinsicIndex::key_neutral_prefix(0u64), vec![1, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(ExtrinsicIndex::key_neutral_prefix(777u64), vec![1, 9, 3, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(DigestIndex::key_neutral_prefix(16u64), vec![2, 16, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(
+			DigestIndex::key_neutral_prefix(0x0102030405060708u64),
+			vec![2, 8, 7, 6, 5, 4, 3, 2, 1],
+		);
+		assert_eq!(ChildIndex::key_neutral_prefix(777u64), vec![3, 9, 3, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(MissingBlocksIndex { block: 777u64 }.encode(), vec![4, 9, 3, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(ExtrinsicIndex::key_neutral_prefix(777u32), vec![1, 9, 3, 0, 0]);
+	}
+
+	#[test]
+	fn keys_are_classified() {
+		let key = ExtrinsicIndex { block: 777u64, key: vec![42, 43] }.encode();
+		assert_eq!(classify_key::<u64>(&key), Some((KeyKind::ExtrinsicIndex, 777, vec![42, 43])));
+		assert!(key.starts_with(&ExtrinsicIndex::key_neutral_prefix(777u64)));
+
+		let key = DigestIndex { block: 16u64, key: vec![] }.encode();
+		assert_eq!(classify_key::<u64>(&key), Some((KeyKind::DigestIndex, 16, vec![])));
+
+		let storage_key = PrefixedStorageKey::new(b"child".to_vec());
+		let key = ChildIndex { block: 4u64, storage_key: storage_key.clone() }.encode();
+		assert_eq!(classify_key::<u64>(&key), Some((KeyKind::ChildIndex, 4, storage_key.into_inner())));
+
+		let key = MissingBlocksIndex { block: 12u64 }.encode();
+		assert_eq!(classify_key::<u64>(&key), Some((KeyKind::MissingBlocks, 12, vec![])));
+		assert_eq!(classify_key::<u64>(&[&key[..], &[0][..]].concat()), None);
+
+		// unknown kind, truncated key and trailing bytes
+		let key = ExtrinsicIndex { block: 777u64, key: vec![42] }.encode();
+		assert_eq!(classify_key::<u64>(&[&[5][..], &key[1..]].concat()), None);
+		assert_eq!(classify_key::<u64>(&key[..key.len() - 1]), None);
+		assert_eq!(classify_key::<u64>(&[&key[..], &[0][..]].concat()), None);
+		assert_eq!(classify_key::<u64>(&[]), None);
+	}
+
 	#[test]
 	fn extrinsic_index_serialized_and_deserialized() {
 		let original = ExtrinsicIndex { block: 777u64, key: vec![42] };
@@ -206,4 +755,128 @@ mod tests {
 		let deserialized: InputKey<u64> = Decode::decode(&mut &serialized[..]).unwrap();
 		assert_eq!(InputKey::DigestIndex(original), deserialized);
 	}
+
+	#[test]
+	fn input_pairs_encoding_is_stable() {
+		let extrinsic_pair = InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 777u64, key: vec![42] }, vec![0, 5].into());
+		assert_eq!(extrinsic_pair.key_encoded(), vec![1, 9, 3, 0, 0, 0, 0, 0, 0, 4, 42]);
+		assert_eq!(extrinsic_pair.value_encoded(), vec![8, 0, 0, 0, 0, 5, 0, 0, 0]);
+
+		let digest_pair = InputPair::DigestIndex(DigestIndex { block: 1u64, key: vec![] }, vec![u64::max_value()].into());
+		assert_eq!(digest_pair.key_encoded(), vec![2, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(digest_pair.value_encoded(), vec![4, 255, 255, 255, 255, 255, 255, 255, 255]);
+
+		let child_pair = InputPair::ChildIndex(
+			ChildIndex { block: 2u64, storage_key: PrefixedStorageKey::new(vec![7, 8]) },
+			vec![9, 9],
+		);
+		assert_eq!(child_pair.key_encoded(), vec![3, 2, 0, 0, 0, 0, 0, 0, 0, 8, 7, 8]);
+		assert_eq!(child_pair.value_encoded(), vec![8, 9, 9]);
+		assert_eq!(child_pair.encode(), vec![48, 3, 2, 0, 0, 0, 0, 0, 0, 0, 8, 7, 8, 12, 8, 9, 9]);
+
+		let missing_pair = InputPair::MissingBlocks(MissingBlocksIndex { block: 12u64 }, vec![9].into());
+		assert_eq!(missing_pair.key_encoded(), vec![4, 12, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(missing_pair.value_encoded(), vec![4, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+		let mut key = vec![0xff];
+		let mut value = vec![0xff];
+		for pair in &[extrinsic_pair, digest_pair, child_pair, missing_pair] {
+			key.truncate(1);
+			value.truncate(1);
+			pair.encode_key_to(&mut key);
+			pair.encode_value_to(&mut value);
+			assert_eq!(key[1..], pair.key_encoded()[..]);
+			assert_eq!(value[1..], pair.value_encoded()[..]);
+			assert_eq!(pair.encoded_key_len(), pair.key_encoded().len());
+		}
+	}
+
+	#[test]
+	fn input_pairs_are_decoded_from_trie_pairs() {
+		// compact length boundaries + large key
+		let keys = vec![
+			vec![],
+			vec![1],
+			vec![2; 63],
+			vec![3; 64],
+			vec![4; 16384],
+			vec![5; 1024 * 1024],
+		];
+		let blocks = vec![0u64, 1, u64::max_value()];
+
+		let mut pairs = Vec::new();
+		for key in &keys {
+			for block in &blocks {
+				pairs.push(InputPair::ExtrinsicIndex(
+					ExtrinsicIndex { block: *block, key: key.clone() },
+					vec![0, u32::max_value()].into(),
+				));
+				pairs.push(InputPair::DigestIndex(
+					DigestIndex { block: *block, key: key.clone() },
+					vec![0, *block].into(),
+				));
+				pairs.push(InputPair::ChildIndex(
+					ChildIndex { block: *block, storage_key: PrefixedStorageKey::new(key.clone()) },
+					vec![0xff; 32],
+				));
+			}
+		}
+		for block in &blocks {
+			pairs.push(InputPair::MissingBlocks(MissingBlocksIndex { block: *block }, vec![0, *block].into()));
+		}
+		pairs.push(InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 0, key: vec![] }, ExtrinsicList::default()));
+
+		for pair in pairs {
+			let (trie_key, trie_value): (StorageKey, StorageValue) = pair.clone().into();
+			assert_eq!(InputPair::decode_from_trie_pair(&trie_key, &trie_value), Ok(pair.clone()));
+			assert_eq!(InputKey::decode_from_trie_key(&trie_key), Ok(pair.clone().into()));
+			assert_eq!(InputPair::decode(&mut &pair.encode()[..]), Ok(pair.clone()));
+
+			let mut trie_key_with_trailing_bytes = trie_key.clone();
+			trie_key_with_trailing_bytes.push(0);
+			assert!(InputKey::<u64>::decode_from_trie_key(&trie_key_with_trailing_bytes).is_err());
+			assert!(InputKey::<u64>::decode_from_trie_key(&trie_key[..trie_key.len() - 1]).is_err());
+		}
+	}
+
+	#[test]
+	fn typed_keys_are_not_decoded_from_other_keys() {
+		let digest_key = DigestIndex { block: 1u64, key: vec![42] }.encode();
+		assert_eq!(
+			DigestIndex::<u64>::decode(&mut &digest_key[..]),
+			Ok(DigestIndex { block: 1u64, key: vec![42] }),
+		);
+		assert!(ExtrinsicIndex::<u64>::decode(&mut &digest_key[..]).is_err());
+		assert!(ChildIndex::<u64>::decode(&mut &digest_key[..]).is_err());
+		assert!(MissingBlocksIndex::<u64>::decode(&mut &digest_key[..]).is_err());
+	}
+
+	#[test]
+	fn input_pairs_are_ordered_by_trie_keys() {
+		let extrinsic_pair = |key: Vec<u8>| InputPair::ExtrinsicIndex(
+			ExtrinsicIndex { block: 1u64, key },
+			vec![0].into(),
+		);
+		let mut pairs = vec![
+			InputPair::MissingBlocks(MissingBlocksIndex { block: 1u64 }, vec![0].into()),
+			InputPair::ChildIndex(ChildIndex { block: 1u64, storage_key: PrefixedStorageKey::new(vec![1]) }, vec![]),
+			InputPair::DigestIndex(DigestIndex { block: 1u64, key: vec![1] }, vec![0].into()),
+			extrinsic_pair(vec![0; 64]),
+			extrinsic_pair(vec![1; 63]),
+			extrinsic_pair(vec![2]),
+			extrinsic_pair(vec![1, 1]),
+			extrinsic_pair(vec![1; 16384]),
+		];
+		pairs.sort();
+
+		let trie_keys = pairs.iter().map(|pair| pair.key_encoded()).collect::<Vec<_>>();
+		let mut sorted_trie_keys = trie_keys.clone();
+		sorted_trie_keys.sort();
+		assert_eq!(trie_keys, sorted_trie_keys);
+		// keys are ordered by their compact lengths first
+		assert_eq!(
+			pairs.iter().filter_map(|pair| pair.key()).map(|key| key.len()).collect::<Vec<_>>(),
+			vec![64, 16384, 1, 2, 63, 1],
+		);
+	}
 }
@@ -49,38 +49,84 @@
 //! Changes trie only contains the top level storage changes. Sub-level changes
 //! are propagated through its storage root on the top level storage.
 
+pub mod audit;
+pub mod bench_utils;
 mod build;
 mod build_cache;
-mod build_iterator;
+pub mod build_iterator;
+mod changed_keys;
+mod changes_filter;
+mod changes_source;
 mod changes_iterator;
-mod input;
+pub mod consistency;
+mod digest_cache;
+mod error;
+mod existence_cache;
+pub mod input;
+mod pending_digest;
+#[cfg(test)]
+mod property_tests;
 mod prune;
+mod reader;
+mod rebuild;
+mod snapshot;
 mod storage;
 mod surface_iterator;
 
 pub use self::build_cache::{BuildCache, CachedBuildData, CacheAction};
-pub use self::storage::InMemoryStorage;
+pub use self::build_iterator::{digest_build_iterator, digest_parent_blocks, DigestBuildIterator};
+pub use self::build::{
+	prepare_input, prepare_input_with_limit, prepare_input_with_limit_from_source, InputParts,
+	compute_root_from_parts, verify_digest_pairs_against_proof,
+};
+pub(crate) use self::build::{prepare_extrinsics_input_pairs, ExtrinsicsInput};
+pub use self::changed_keys::{enumerate_changed_keys, enumerate_changed_keys_with_prefix};
+pub use self::changes_filter::{ChangesFilter, DefaultChangesFilter, AllChangesFilter};
+pub use self::changes_source::{ChangesSource, ChangedKey, CollectedChanges};
+pub use self::digest_cache::{DigestCache, DigestCacheEntry, KeyKind};
+pub use self::error::Error;
+pub use self::input::ChangeKind;
+pub use self::existence_cache::{ExistenceCache, NoExistenceCache, InMemoryExistenceCache};
+pub use self::storage::{
+	InMemoryStorage, InMemoryStorageBuilder, ProofRecorder, ProofCheckStorage, CachedStorage, OverlayedChangesTrieStorage,
+	KvBackend, DbColumns, DbStorage, DbStorageTransaction, InMemoryKvBackend,
+	RootsJournal, verify_root_proof,
+};
 pub use self::changes_iterator::{
-	key_changes, key_changes_proof,
+	key_changes, key_changes_with_values, KeyChangesQuery, key_change_kinds, StateProvider, key_changes_proof,
 	key_changes_proof_check, key_changes_proof_check_with_db,
+	KeyChangesLimits, KeyChangesCursor, KeyChangesPage,
+	key_changes_limited, key_changes_proof_limited, key_changes_proof_check_limited,
+	KeyChangesResult, key_changes_partial, key_changes_proof_partial, key_changes_proof_check_partial,
+	key_changed_since, key_changed_since_proof, key_changed_since_proof_check,
+	KeysChanges, keys_changes, keys_changes_proof, keys_changes_proof_check,
 };
-pub use self::prune::prune;
+pub use self::pending_digest::PendingDigest;
+pub use self::prune::{prune, oldest_non_prunable_block};
+pub use self::reader::Reader;
+pub use self::rebuild::{rebuild_range, BlockChanges};
+pub use self::snapshot::{ChangesTrieSnapshot, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use hash_db::{Hasher, Prefix};
 use num_traits::{One, Zero};
 use codec::{Decode, Encode};
 use sp_core;
-use sp_core::storage::PrefixedStorageKey;
+use sp_core::storage::{PrefixedStorageKey, well_known_keys::EXTRINSIC_INDEX};
 use sp_trie::{MemoryDB, DBValue, TrieMut};
 use sp_trie::trie_types::TrieDBMut;
+use trie_db::{trie_visit, ChildReference, ProcessEncodedNode};
 use crate::{
 	StorageKey,
 	backend::Backend,
+	node_cache::NodeCache,
 	overlayed_changes::OverlayedChanges,
 	changes_trie::{
-		build::prepare_input,
+		build::{prepare_input_iter, digest_level_at_block, ExtrinsicsLimits, InputContext, InputValidator},
 		build_cache::{IncompleteCachedBuildData, IncompleteCacheAction},
 	},
 };
@@ -88,7 +134,7 @@ use crate::{
 /// Requirements for block number that can be used with changes tries.
 pub trait BlockNumber:
 	Send + Sync + 'static +
-	std::fmt::Display +
+	std::fmt::Display + std::fmt::Debug +
 	Clone +
 	From<u32> + TryInto<u32> + One + Zero +
 	PartialEq + Ord +
@@ -103,7 +149,7 @@ pub trait BlockNumber:
 
 impl<T> BlockNumber for T where T:
 	Send + Sync + 'static +
-	std::fmt::Display +
+	std::fmt::Display + std::fmt::Debug +
 	Clone +
 	From<u32> + TryInto<u32> + One + Zero +
 	PartialEq + Ord +
@@ -116,6 +162,34 @@ impl<T> BlockNumber for T where T:
 	Decode + Encode,
 {}
 
+/// Convert block number into `u128`, so it could be stored and compared without knowing the
+/// block number type. Returns `None` if the number doesn't fit into `u128`.
+pub(crate) fn block_number_into_u128<Number: BlockNumber>(mut number: Number) -> Option<u128> {
+	let base = Number::from(1 << 16);
+	let mut result = 0u128;
+	let mut shift = 0u32;
+	while !number.is_zero() {
+		let digit: u32 = (number.clone() % base.clone()).try_into().ok()?;
+		result |= (digit as u128).checked_shl(shift)?;
+		number = number / base.clone();
+		shift += 16;
+	}
+	Some(result)
+}
+
+/// Convert `u128`, produced by the `block_number_into_u128`, back into the block number.
+/// Returns `None` if the number doesn't fit into the block number type.
+pub(crate) fn block_number_from_u128<Number: BlockNumber>(number: u128) -> Option<Number> {
+	let base = Number::from(1 << 16);
+	let mut result = Number::zero();
+	for shift in (0..128).step_by(16).rev() {
+		let digit = ((number >> shift) & 0xffff) as u32;
+		// the lowest 16 bits are zero after the multiplication, so the addition can't overflow
+		result = result.checked_mul(&base)? + Number::from(digit);
+	}
+	Some(result)
+}
+
 /// Block identifier that could be used to determine fork of this block.
 #[derive(Debug)]
 pub struct AnchorBlockId<Hash: std::fmt::Debug, Number: BlockNumber> {
@@ -135,18 +209,177 @@ pub struct State<'a, H, Number> {
 	pub zero: Number,
 	/// Underlying changes tries storage reference.
 	pub storage: &'a dyn Storage<H, Number>,
+	/// Cache that is consulted before checking existence of deleted keys in the backend.
+	pub existence_cache: &'a dyn ExistenceCache,
+	/// Parameters of the changes trie build.
+	pub params: BuildParams<'a>,
 }
 
-/// Changes trie storage. Provides access to trie roots and trie nodes.
+/// Parameters of the changes trie build.
+#[derive(Clone, Copy)]
+pub struct BuildParams<'a> {
+	/// If true, keys of digest input tries that can't be decoded are skipped (and counted in
+	/// `BuildStats::invalid_input_keys`). Otherwise, build fails with `InvalidInputKey` error.
+	pub tolerate_invalid_keys: bool,
+	/// Filter that decides whether the changed key is included into the changes trie. Skipped
+	/// keys are counted in `BuildStats::skipped_transient`.
+	///
+	/// The filter affects the changes trie root, so all nodes must use the same filter.
+	pub changes_filter: &'a dyn ChangesFilter,
+	/// Changes trie root of blocks where nothing has been changed.
+	///
+	/// The policy affects block headers, so all nodes must use the same policy.
+	pub empty_block_policy: EmptyBlockPolicy,
+	/// What to do when changes trie roots of some digest input blocks are missing.
+	pub on_missing_root: MissingRootPolicy,
+	/// Max number of extrinsics in the block. If the key has been changed by the extrinsic
+	/// with larger index, build fails with `TooManyExtrinsics` error.
+	///
+	/// The limit affects validity of blocks, so all nodes must use the same limit.
+	pub max_extrinsics_per_block: u32,
+	/// Max number of extrinsics that may change the single key. If the key has been changed
+	/// by more extrinsics, build fails with `TooManyExtrinsics` error.
+	///
+	/// The limit affects validity of blocks, so all nodes must use the same limit.
+	pub max_extrinsics_per_key: u32,
+	/// Flag that is checked while the digest input is read from changes tries of digest input
+	/// blocks. Once it is set, the build fails with `Cancelled` error.
+	pub cancel: Option<&'a AtomicBool>,
+	/// Check keys of the top-level changes trie against the storage delta of the block (see
+	/// `consistency::check_against_delta`). On mismatch, the build fails with
+	/// `InconsistentWithDelta` error. The check is meant for debugging, since it keeps the
+	/// whole input in memory and reads the backend once per deleted key.
+	pub check_against_delta: bool,
+}
+
+impl<'a> Default for BuildParams<'a> {
+	fn default() -> Self {
+		BuildParams {
+			tolerate_invalid_keys: false,
+			changes_filter: &DefaultChangesFilter,
+			empty_block_policy: Default::default(),
+			on_missing_root: Default::default(),
+			max_extrinsics_per_block: u32::MAX,
+			max_extrinsics_per_key: u32::MAX,
+			cancel: None,
+			check_against_delta: false,
+		}
+	}
+}
+
+impl<'a> BuildParams<'a> {
+	/// Limits of extrinsics that are listed by the changes trie.
+	pub(crate) fn extrinsics_limits(&self) -> ExtrinsicsLimits {
+		ExtrinsicsLimits {
+			max_per_block: self.max_extrinsics_per_block,
+			max_per_key: self.max_extrinsics_per_key,
+		}
+	}
+}
+
+impl<'a> std::fmt::Debug for BuildParams<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("BuildParams")
+			.field("tolerate_invalid_keys", &self.tolerate_invalid_keys)
+			.field("empty_block_policy", &self.empty_block_policy)
+			.field("on_missing_root", &self.on_missing_root)
+			.field("max_extrinsics_per_block", &self.max_extrinsics_per_block)
+			.field("max_extrinsics_per_key", &self.max_extrinsics_per_key)
+			.field("cancellable", &self.cancel.is_some())
+			.field("check_against_delta", &self.check_against_delta)
+			.finish()
+	}
+}
+
+/// Changes trie root of the block where nothing has been changed, i.e. of the block with
+/// empty changes trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyBlockPolicy {
+	/// Block has no changes trie root.
+	///
+	/// Digest input blocks without roots are treated as blocks without changes. Key changes
+	/// queries require roots of all blocks, so the roots storage should report `empty_root()`
+	/// for such blocks if they need to be queried.
+	OmitRoot,
+	/// Block has the root of the empty changes trie.
+	EmptyTrieRoot,
+	/// Block has the same changes trie root as its parent. The root of the parent block is read
+	/// from the changes tries storage. If parent has no changes trie root, root of the empty
+	/// changes trie is used.
+	///
+	/// Digest input blocks that have the same root as their parents are treated as blocks
+	/// without changes.
+	ReuseParentRoot,
+}
+
+impl Default for EmptyBlockPolicy {
+	fn default() -> Self {
+		EmptyBlockPolicy::EmptyTrieRoot
+	}
+}
+
+/// What to do when changes trie roots of some digest input blocks are missing from the storage
+/// (e.g. because they have been pruned).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingRootPolicy {
+	/// Fail with `DigestInputRootsMissing` error, listing all blocks without roots.
+	Fail,
+	/// Build the digest without these blocks and list them in the `MissingBlocks` pair of
+	/// the top-level changes trie.
+	///
+	/// The degraded digest has a different root than the digest that is built from all input
+	/// blocks, so this policy must never be used to build changes tries that are checked
+	/// against block headers by other nodes. Key changes queries that are walking the degraded
+	/// digest fail with `DegradedDigest` error, if missing blocks are within the queried range.
+	SkipAndRecord,
+}
+
+impl Default for MissingRootPolicy {
+	fn default() -> Self {
+		MissingRootPolicy::Fail
+	}
+}
+
+/// Outcome of the changes trie build.
+pub struct ChangesTrieOutcome<H: Hasher, Number: BlockNumber> {
+	/// Changes trie root of the block. `None` if the root is omitted.
+	pub root: Option<H::Out>,
+	/// Nodes of the changes trie and the action that must be applied to the build cache.
+	/// `None` if no changes trie has been built for the block.
+	pub transaction: Option<(MemoryDB<H>, CacheAction<H::Out, Number>)>,
+	/// True if nothing has been changed in the block.
+	pub was_empty: bool,
+}
+
+/// Changes trie roots storage. Provides access to changes trie roots of the chain.
+///
+/// Roots are always resolved relative to the anchor block, so forks are handled
+/// without parsing headers. The trait is object safe: builder, pruning and query code
+/// only use it as `&dyn RootsStorage`, so the client may back it by its headers database.
+///
+/// Implementations must be safe to share between threads: all methods take `&self`, and the
+/// same storage may be used by the block builder and by several query threads at once, so
+/// any mutable state must be guarded internally (see `CachedStorage`).
 pub trait RootsStorage<H: Hasher, Number: BlockNumber>: Send + Sync {
 	/// Resolve hash of the block into anchor.
-	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, String>;
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>>;
 	/// Get changes trie root for the block with given number which is an ancestor (or the block
 	/// itself) of the anchor_block (i.e. anchor_block.number >= block).
-	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, String>;
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>>;
+	/// Get changes trie roots for the blocks with given numbers which are ancestors (or the block
+	/// itself) of the anchor_block. Roots are returned in the same order as blocks.
+	fn roots(
+		&self,
+		anchor: &AnchorBlockId<H::Out, Number>,
+		blocks: &[Number],
+	) -> Result<Vec<Option<H::Out>>, Error<Number>> {
+		blocks.iter().map(|block| self.root(anchor, block.clone())).collect()
+	}
 }
 
 /// Changes trie storage. Provides access to trie roots and trie nodes.
+///
+/// The same concurrency contract as for `RootsStorage` applies.
 pub trait Storage<H: Hasher, Number: BlockNumber>: RootsStorage<H, Number> {
 	/// Casts from self reference to RootsStorage reference.
 	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number>;
@@ -157,8 +390,48 @@ pub trait Storage<H: Hasher, Number: BlockNumber>: RootsStorage<H, Number> {
 		root: &H::Out,
 		functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
 	) -> bool;
+	/// Get cache of keys, read from changes tries of digest input blocks.
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		None
+	}
+	/// Get keys that have been changed in recent blocks, noted right after these blocks have been built.
+	fn pending_digest(&self) -> Option<&PendingDigest<Number>> {
+		None
+	}
+	/// Get cache of trie nodes, shared by readers of digest input tries.
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		None
+	}
+	/// Get storage of trie nodes of the changes trie of given block.
+	///
+	/// Storages that shard trie nodes by block may route reads of this trie to the shard of
+	/// the block. If `None` is returned, nodes are read using `get`.
+	fn as_trie_backend_storage(&self, _block: &Number) -> Option<BlockTrieStorage<'_, H>> {
+		None
+	}
 	/// Get a trie node.
-	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String>;
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>>;
+}
+
+/// Storage of trie nodes of the changes trie of the single block.
+pub type BlockTrieStorage<'a, H> = Box<dyn crate::TrieBackendStorage<H, Overlay = MemoryDB<H>> + 'a>;
+
+impl<'a, H: Hasher> crate::TrieBackendStorage<H> for BlockTrieStorage<'a, H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		(**self).get(key, prefix)
+	}
+}
+
+/// Get storage of trie nodes of the changes trie of given block, falling back to
+/// `Storage::get` if the storage doesn't provide block-specific access to nodes.
+pub(crate) fn block_trie_storage<'a, H: Hasher, Number: BlockNumber>(
+	storage: &'a dyn Storage<H, Number>,
+	block: &Number,
+) -> BlockTrieStorage<'a, H> {
+	storage.as_trie_backend_storage(block)
+		.unwrap_or_else(|| Box::new(TrieBackendStorageAdapter(storage)))
 }
 
 /// Changes trie storage -> trie backend essence adapter.
@@ -168,13 +441,172 @@ impl<'a, H: Hasher, N: BlockNumber> crate::TrieBackendStorage<H> for TrieBackend
 	type Overlay = sp_trie::MemoryDB<H>;
 
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
-		self.0.get(key, prefix)
+		self.0.get(key, prefix).map_err(|e| e.to_string())
+	}
+}
+
+/// Changes trie storage that counts trie nodes reads.
+struct ReadsCountingStorage<'a, H: Hasher, Number: BlockNumber> {
+	storage: &'a dyn Storage<H, Number>,
+	reads: AtomicU64,
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> ReadsCountingStorage<'a, H, Number> {
+	fn new(storage: &'a dyn Storage<H, Number>) -> Self {
+		ReadsCountingStorage {
+			storage,
+			reads: AtomicU64::new(0),
+		}
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> RootsStorage<H, Number> for ReadsCountingStorage<'a, H, Number> {
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		self.storage.root(anchor, block)
+	}
+
+	fn roots(
+		&self,
+		anchor: &AnchorBlockId<H::Out, Number>,
+		blocks: &[Number],
+	) -> Result<Vec<Option<H::Out>>, Error<Number>> {
+		self.storage.roots(anchor, blocks)
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> Storage<H, Number> for ReadsCountingStorage<'a, H, Number> {
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		root: &H::Out,
+		functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		self.storage.with_cached_changed_keys(root, functor)
+	}
+
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		self.storage.digest_cache()
+	}
+
+	fn pending_digest(&self) -> Option<&PendingDigest<Number>> {
+		self.storage.pending_digest()
+	}
+
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		self.storage.node_cache()
+	}
+
+	fn as_trie_backend_storage(&self, block: &Number) -> Option<BlockTrieStorage<'_, H>> {
+		let storage = self.storage.as_trie_backend_storage(block)?;
+		Some(Box::new(ReadsCountingTrieStorage { storage, reads: &self.reads }))
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		self.reads.fetch_add(1, Ordering::Relaxed);
+		self.storage.get(key, prefix)
 	}
 }
 
+/// Storage of trie nodes of the single changes trie that counts nodes reads.
+struct ReadsCountingTrieStorage<'a, H: Hasher> {
+	storage: BlockTrieStorage<'a, H>,
+	reads: &'a AtomicU64,
+}
+
+impl<'a, H: Hasher> crate::TrieBackendStorage<H> for ReadsCountingTrieStorage<'a, H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		self.reads.fetch_add(1, Ordering::Relaxed);
+		self.storage.get(key, prefix)
+	}
+}
+
+/// Statistics of the changes trie build.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildStats {
+	/// Number of ExtrinsicIndex pairs, inserted into top-level and child changes tries.
+	pub extrinsic_pairs: usize,
+	/// Number of DigestIndex pairs, inserted into top-level and child changes tries.
+	pub digest_pairs: usize,
+	/// Number of digest input blocks whose changed keys have been collected.
+	pub digest_blocks_walked: u64,
+	/// Number of digest input blocks that have been skipped, because their roots are missing.
+	pub missing_digest_input_blocks: u64,
+	/// Number of trie nodes that have been read from the changes tries storage.
+	pub storage_reads: u64,
+	/// Total size of encoded keys and values of all inserted pairs.
+	pub encoded_input_bytes: usize,
+	/// Number of digest input tries keys that can't be decoded and have been skipped.
+	pub invalid_input_keys: u64,
+	/// Number of digest input tries nodes that have been found in the storage node cache.
+	pub node_cache_hits: u64,
+	/// Number of digest input tries nodes that haven't been found in the storage node cache.
+	pub node_cache_misses: u64,
+	/// Number of changed keys that have been skipped by the changes filter.
+	pub skipped_transient: u64,
+	/// Keys that have been changed in the block, as they're included into the changes trie.
+	pub changed_keys: ChangedKeys,
+	/// Time spent on building the changes trie.
+	pub elapsed: Duration,
+}
+
+/// Keys that have been changed in the block, as they're included into its changes trie.
+///
+/// Keys are taken from the ExtrinsicIndex pairs, so changes that are skipped by the changes
+/// filter (e.g. transient values) aren't listed, exactly as light clients won't find them
+/// in the changes trie.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangedKeys {
+	/// Changed keys of the top-level storage.
+	pub top: BTreeSet<StorageKey>,
+	/// Changed keys of child storages, grouped by the child storage key.
+	pub children: BTreeMap<PrefixedStorageKey, BTreeSet<StorageKey>>,
+	/// Number of changed keys that have been skipped by the changes filter.
+	pub filtered_out: usize,
+}
+
 /// Changes trie configuration.
 pub type Configuration = sp_core::ChangesTrieConfiguration;
 
+/// Access to the changes trie configuration, stored under the `CHANGES_TRIE_CONFIG` key.
+pub trait ConfigurationExt: Sized {
+	/// Read configuration from the state. Returns `Ok(None)` if changes tries are disabled.
+	///
+	/// Returns `Error::InvalidStoredConfiguration` if the stored value can't be decoded.
+	fn from_storage<H: Hasher, B: Backend<H>, Number: BlockNumber>(backend: &B) -> Result<Option<Self>, Error<Number>>;
+	/// Storage key and value that need to be written to activate this configuration.
+	fn to_storage_delta(&self) -> (StorageKey, Option<Vec<u8>>);
+}
+
+impl ConfigurationExt for Configuration {
+	fn from_storage<H: Hasher, B: Backend<H>, Number: BlockNumber>(backend: &B) -> Result<Option<Self>, Error<Number>> {
+		backend.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG)
+			.map_err(|e| Error::Backend(format!("{}", e)))?
+			.map(|config| Configuration::decode(&mut &config[..]).map_err(Error::InvalidStoredConfiguration))
+			.transpose()
+	}
+
+	fn to_storage_delta(&self) -> (StorageKey, Option<Vec<u8>>) {
+		(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec(), Some(self.encode()))
+	}
+}
+
+/// Estimation of the heap memory that is owned by the value.
+///
+/// Used to limit memory that is spent on preparing the changes trie input.
+pub trait HeapSize {
+	/// Estimated number of heap bytes, owned by the value.
+	fn heap_size(&self) -> usize;
+}
+
 /// Blocks range where configuration has been constant.
 #[derive(Clone)]
 pub struct ConfigurationRange<'a, N> {
@@ -197,8 +629,22 @@ impl<'a, H, Number> State<'a, H, Number> {
 			config,
 			zero,
 			storage,
+			existence_cache: &NoExistenceCache,
+			params: Default::default(),
 		}
 	}
+
+	/// Use given existence cache when changes trie is built.
+	pub fn with_existence_cache(mut self, existence_cache: &'a dyn ExistenceCache) -> Self {
+		self.existence_cache = existence_cache;
+		self
+	}
+
+	/// Use given parameters when changes trie is built.
+	pub fn with_build_params(mut self, params: BuildParams<'a>) -> Self {
+		self.params = params;
+		self
+	}
 }
 
 impl<'a, H, Number: Clone> Clone for State<'a, H, Number> {
@@ -207,16 +653,27 @@ impl<'a, H, Number: Clone> Clone for State<'a, H, Number> {
 			config: self.config.clone(),
 			zero: self.zero.clone(),
 			storage: self.storage,
+			existence_cache: self.existence_cache,
+			params: self.params,
 		}
 	}
 }
 
+/// Root of the changes trie that has no input pairs.
+///
+/// Tries with this root don't need to be read, because no keys have been changed in their
+/// blocks.
+pub fn empty_root<H: Hasher>() -> H::Out {
+	sp_trie::empty_trie_root::<sp_trie::Layout<H>>()
+}
+
 /// Create state where changes tries are disabled.
 pub fn disabled_state<'a, H, Number>() -> Option<State<'a, H, Number>> {
 	None
 }
 
-/// Compute the changes trie root and transaction for given block.
+/// Compute the changes trie root and transaction for given block, applying the
+/// `EmptyBlockPolicy` of the state. Build statistics are collected if `stats` is `Some`.
 /// Returns Err(()) if unknown `parent_hash` has been passed.
 /// Returns Ok(None) if there's no data to perform computation.
 /// Panics if background storage returns an error OR if insert to MemoryDB fails.
@@ -226,23 +683,11 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 	changes: &OverlayedChanges,
 	parent_hash: H::Out,
 	panic_on_storage_error: bool,
-) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, ()>
+	stats: &mut Option<BuildStats>,
+) -> Result<Option<ChangesTrieOutcome<H, Number>>, ()>
 	where
 		H::Out: Ord + 'static + Encode,
 {
-	/// Panics when `res.is_err() && panic`, otherwise it returns `Err(())` on an error.
-	fn maybe_panic<R, E: std::fmt::Debug>(
-		res: std::result::Result<R, E>,
-		panic: bool,
-	) -> std::result::Result<R, ()> {
-		res.map(Ok)
-			.unwrap_or_else(|e| if panic {
-				panic!("changes trie: storage access is not allowed to fail within runtime: {:?}", e)
-			} else {
-				Err(())
-			})
-	}
-
 	// when storage isn't provided, changes tries aren't created
 	let state = match state {
 		Some(state) => state,
@@ -251,10 +696,147 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 
 	// build_anchor error should not be considered fatal
 	let parent = state.storage.build_anchor(parent_hash).map_err(|_| ())?;
-	let block = parent.number.clone() + One::one();
+
+	// storage errors are considered fatal (similar to situations when runtime fetches values from storage)
+	try_build_changes_trie_outcome_with_stats(backend, Some(state), changes, &parent, stats)
+		.map_err(|e| if panic_on_storage_error {
+			panic!("changes trie: storage access is not allowed to fail within runtime: {:?}", e)
+		})
+}
+
+/// Same as `try_build_changes_trie`, but applies the `EmptyBlockPolicy` of the state when
+/// nothing has been changed in the block.
+pub fn try_build_changes_trie_outcome<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+) -> Result<Option<ChangesTrieOutcome<H, Number>>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	try_build_changes_trie_outcome_with_stats(backend, state, changes, parent, &mut None)
+}
+
+/// Same as `try_build_changes_trie_outcome`, but also collects build statistics if `stats`
+/// is `Some`.
+pub fn try_build_changes_trie_outcome_with_stats<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+	stats: &mut Option<BuildStats>,
+) -> Result<Option<ChangesTrieOutcome<H, Number>>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	let state = match state {
+		Some(state) => state,
+		None => return Ok(None),
+	};
+	try_build_changes_trie_with_stats(backend, Some(state), changes, parent, stats)?
+		.map(|built| apply_empty_block_policy(state, parent, built))
+		.transpose()
+}
+
+/// Turn the built changes trie into the build outcome, applying the `EmptyBlockPolicy` of the
+/// state if nothing has been changed in the block.
+pub(crate) fn apply_empty_block_policy<'a, H: Hasher, Number: BlockNumber>(
+	state: &'a State<'a, H, Number>,
+	parent: &AnchorBlockId<H::Out, Number>,
+	(mdb, root, cache_action): (MemoryDB<H>, H::Out, CacheAction<H::Out, Number>),
+) -> Result<ChangesTrieOutcome<H, Number>, Error<Number>> {
+	let was_empty = root == empty_root::<H>();
+	if !was_empty {
+		return Ok(ChangesTrieOutcome { root: Some(root), transaction: Some((mdb, cache_action)), was_empty });
+	}
+
+	let outcome_root = match state.params.empty_block_policy {
+		EmptyBlockPolicy::OmitRoot => None,
+		EmptyBlockPolicy::EmptyTrieRoot => Some(root),
+		EmptyBlockPolicy::ReuseParentRoot => Some(state.storage
+			.root(parent, parent.number.clone())?
+			.unwrap_or(root)),
+	};
+	// nodes of the empty trie are only needed when its root is used
+	let transaction = if outcome_root == Some(root) { Some((mdb, cache_action)) } else { None };
+	Ok(ChangesTrieOutcome { root: outcome_root, transaction, was_empty })
+}
+
+/// Compute the changes trie root and transaction for the child block of given `parent`.
+/// Returns Ok(None) if there's no data to perform computation.
+/// Unlike `build_changes_trie`, never panics and returns all storage errors to the caller.
+pub fn try_build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	try_build_changes_trie_with_stats(backend, state, changes, parent, &mut None)
+}
+
+/// Same as `try_build_changes_trie`, but also collects build statistics if `stats` is `Some`.
+/// Nothing is spent on statistics if `stats` is `None`.
+pub fn try_build_changes_trie_with_stats<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+	stats: &mut Option<BuildStats>,
+) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	build_changes_trie_nodes(backend, state, changes, parent, stats, None)
+}
+
+/// Same as `try_build_changes_trie`, but passes every node of the changes trie (and of child
+/// changes tries) to the `sink` as soon as it is final, instead of collecting nodes in the
+/// memory database.
+///
+/// Input pairs of every trie are prepared in the order of trie keys, so the nodes are built
+/// bottom-up right from the input and only the nodes on the path to the last inserted key are
+/// kept in memory. This is useful when blocks with huge number of changes (e.g. migrations) are
+/// imported. The first error returned by the `sink` aborts the build.
+pub fn try_build_changes_trie_streaming<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+	sink: &mut dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>>,
+) -> Result<Option<(H::Out, CacheAction<H::Out, Number>)>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	build_changes_trie_nodes(backend, state, changes, parent, &mut None, Some(sink))
+		.map(|built| built.map(|(_, root, cache_action)| (root, cache_action)))
+}
+
+/// Build the changes trie, inserting its nodes either into the memory database, or (if
+/// `sink` is `Some`) passing them to the sink. In the latter case, returned database is empty.
+fn build_changes_trie_nodes<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+	stats: &mut Option<BuildStats>,
+	sink: Option<&mut dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>>>,
+) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	// when storage isn't provided, changes tries aren't created
+	let state = match state {
+		Some(state) => state,
+		None => return Ok(None),
+	};
 
 	// prepare configuration range - we already know zero block. Current block may be the end block if configuration
 	// has been changed in this block
+	let block = parent.number.clone() + One::one();
 	let is_config_changed = match changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG) {
 		Some(Some(new_config)) => new_config != &state.config.encode()[..],
 		Some(None) => true,
@@ -263,82 +845,426 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 	let config_range = ConfigurationRange {
 		config: &state.config,
 		zero: state.zero.clone(),
-		end: if is_config_changed { Some(block.clone()) } else { None },
+		end: if is_config_changed { Some(block) } else { None },
 	};
 
-	// storage errors are considered fatal (similar to situations when runtime fetches values from storage)
-	let (input_pairs, child_input_pairs, digest_input_blocks) = maybe_panic(
-		prepare_input::<B, H, Number>(
-			backend,
-			state.storage,
-			config_range.clone(),
-			changes,
-			&parent,
-		),
-		panic_on_storage_error,
+	let delta = if state.params.check_against_delta { Some(changes) } else { None };
+	build_changes_trie_nodes_from_source(backend, state, config_range, changes, delta, parent, stats, sink).map(Some)
+}
+
+/// Build the changes trie of given changes, inserting its nodes either into the memory database,
+/// or (if `sink` is `Some`) passing them to the sink.
+///
+/// If `delta` is `Some`, keys of the top-level changes trie are checked against it (see
+/// `BuildParams::check_against_delta`).
+pub(crate) fn build_changes_trie_nodes_from_source<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: &'a State<'a, H, Number>,
+	config_range: ConfigurationRange<'a, Number>,
+	changes: &dyn ChangesSource,
+	delta: Option<&OverlayedChanges>,
+	parent: &AnchorBlockId<H::Out, Number>,
+	stats: &mut Option<BuildStats>,
+	mut sink: Option<&mut dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>>>,
+) -> Result<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>), Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	let started = stats.as_ref().map(|_| Instant::now());
+	let node_cache = stats.as_ref().and_then(|_| state.storage.node_cache());
+	let node_cache_lookups = |node_cache: &NodeCache<H>| (node_cache.hits(), node_cache.misses());
+	let node_cache_lookups_before = node_cache.as_deref().map(node_cache_lookups);
+	let counting_storage = stats.as_ref().map(|_| ReadsCountingStorage::new(state.storage));
+	let storage = match counting_storage.as_ref() {
+		Some(counting_storage) => counting_storage as &dyn Storage<H, Number>,
+		None => state.storage,
+	};
+
+	let block = parent.number.clone() + One::one();
+	let invalid_input_keys = AtomicU64::new(0);
+	let skipped_keys = AtomicU64::new(0);
+
+	let is_digest_block = digest_level_at_block(config_range.clone(), block.clone()).is_some();
+	let (input_pairs, child_input_pairs, digest_input_blocks) = prepare_input_iter::<B, H, Number>(
+		backend,
+		storage,
+		&state.params,
+		config_range.clone(),
+		changes,
+		parent,
+		InputContext {
+			existence_cache: Some(state.existence_cache),
+			memory_limit: None,
+			invalid_keys: if state.params.tolerate_invalid_keys { Some(&invalid_input_keys) } else { None },
+			skipped_keys: stats.as_ref().map(|_| &skipped_keys),
+			cancel: state.params.cancel,
+		},
 	)?;
+	let digest_blocks_walked = digest_input_blocks.len() as u64;
 
 	// prepare cached data
 	let mut cache_action = prepare_cached_build_data(config_range, block.clone());
 	let needs_changed_keys = cache_action.collects_changed_keys();
 	cache_action = cache_action.set_digest_input_blocks(digest_input_blocks);
 
+	let digest_value_threshold = state.config.digest_value_threshold;
 	let mut mdb = MemoryDB::default();
 	let mut child_roots = Vec::with_capacity(child_input_pairs.len());
 	for (child_index, input_pairs) in child_input_pairs {
 		let mut not_empty = false;
+		let mut storage_changed_keys = HashSet::new();
 		let mut root = Default::default();
-		{
-			let mut trie = TrieDBMut::<H>::new(&mut mdb, &mut root);
-			let mut storage_changed_keys = HashSet::new();
-			for input_pair in input_pairs {
+		let root: H::Out = build_trie_nodes(
+			TrieNodes::new(&mut mdb, &mut root, sink.as_deref_mut()),
+			InputValidator::sorted(block.clone(), is_digest_block),
+			digest_value_threshold,
+			stats,
+			input_pairs,
+			|stats, input_pair| {
 				if needs_changed_keys {
 					if let Some(key) = input_pair.key() {
 						storage_changed_keys.insert(key.to_vec());
 					}
 				}
-
-				let (key, value) = input_pair.into();
+				if let (Some(stats), input::InputPair::ExtrinsicIndex(index, _)) = (stats.as_mut(), input_pair) {
+					stats.changed_keys.children
+						.entry(child_index.storage_key.clone())
+						.or_default()
+						.insert(index.key.clone());
+				}
 				not_empty = true;
-				maybe_panic(trie.insert(&key, &value), panic_on_storage_error)?;
-			}
+			},
+		)?;
 
-			cache_action = cache_action.insert(
-				Some(child_index.storage_key.clone()),
-				storage_changed_keys,
-			);
-		}
+		cache_action = cache_action.insert(
+			Some(child_index.storage_key.clone()),
+			storage_changed_keys,
+		);
 		if not_empty {
 			child_roots.push(input::InputPair::ChildIndex(child_index, root.as_ref().to_vec()));
 		}
 	}
-	let mut root = Default::default();
-	{
-		let mut trie = TrieDBMut::<H>::new(&mut mdb, &mut root);
-		for (key, value) in child_roots.into_iter().map(Into::into) {
-			maybe_panic(trie.insert(&key, &value), panic_on_storage_error)?;
-		}
 
-		let mut storage_changed_keys = HashSet::new();
-		for input_pair in input_pairs {
+	let mut storage_changed_keys = HashSet::new();
+	let mut extrinsics_input = Vec::new();
+	let mut root = Default::default();
+	let root: H::Out = build_trie_nodes(
+		TrieNodes::new(&mut mdb, &mut root, sink.as_deref_mut()),
+		InputValidator::sorted(block.clone(), is_digest_block),
+		digest_value_threshold,
+		stats,
+		merge_input(input_pairs, child_roots.into_iter().map(Ok)),
+		|stats, input_pair| {
 			if needs_changed_keys {
 				if let Some(key) = input_pair.key() {
 					storage_changed_keys.insert(key.to_vec());
 				}
 			}
+			if let (Some(stats), input::InputPair::ExtrinsicIndex(index, _)) = (stats.as_mut(), input_pair) {
+				stats.changed_keys.top.insert(index.key.clone());
+			}
+			if delta.is_some() && matches!(input_pair, input::InputPair::ExtrinsicIndex(..)) {
+				extrinsics_input.push(input_pair.clone());
+			}
+		},
+	)?;
 
-			let (key, value) = input_pair.into();
-			maybe_panic(trie.insert(&key, &value), panic_on_storage_error)?;
-		}
+	if let Some(delta) = delta {
+		check_input_against_delta(backend, &state.config, delta, &extrinsics_input)?;
+	}
 
-		cache_action = cache_action.insert(
-			None,
-			storage_changed_keys,
-		);
+	cache_action = cache_action.insert(
+		None,
+		storage_changed_keys,
+	);
+
+	if let Some(stats) = stats.as_mut() {
+		stats.digest_blocks_walked = digest_blocks_walked - stats.missing_digest_input_blocks;
+		stats.invalid_input_keys = invalid_input_keys.load(Ordering::Relaxed);
+		stats.skipped_transient = skipped_keys.load(Ordering::Relaxed);
+		stats.changed_keys.filtered_out = stats.skipped_transient as usize;
+		// lookups of other users of the shared cache may also be counted here
+		let lookups = node_cache.as_deref().map(node_cache_lookups);
+		if let (Some((hits, misses)), Some((hits_before, misses_before))) = (lookups, node_cache_lookups_before) {
+			stats.node_cache_hits = hits.saturating_sub(hits_before);
+			stats.node_cache_misses = misses.saturating_sub(misses_before);
+		}
+		stats.storage_reads = counting_storage.as_ref()
+			.map(|counting_storage| counting_storage.reads.load(Ordering::Relaxed))
+			.unwrap_or_default();
+		stats.elapsed = started.map(|started| started.elapsed()).unwrap_or_default();
 	}
 
 	let cache_action = cache_action.complete(block, &root);
-	Ok(Some((mdb, root, cache_action)))
+	Ok((mdb, root, cache_action))
+}
+
+/// Check keys of the top-level `ExtrinsicIndex` input pairs against the storage delta of
+/// `changes`. Keys that are never included into the changes trie (ignored and untracked keys,
+/// and the extrinsic index itself) are excluded from the delta.
+fn check_input_against_delta<B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	config: &Configuration,
+	changes: &OverlayedChanges,
+	extrinsics_input: &[input::InputPair<Number>],
+) -> Result<(), Error<Number>> {
+	let delta = changes.effective_changes()
+		.filter(|(key, _)| *key != EXTRINSIC_INDEX)
+		.filter(|(key, _)| !config.is_ignored_key(key))
+		.filter(|(key, _)| !changes.untracked_prefixes().iter().any(|prefix| key.starts_with(prefix)));
+	// if existence can't be checked, the key is expected in the input
+	let backend_existed = |key: &[u8]| backend.exists_storage(key).unwrap_or(true);
+	consistency::check_against_delta(extrinsics_input, delta, backend_existed)
+		.map_err(Error::InconsistentWithDelta)
+}
+
+/// Same as `try_build_changes_trie`, but fails if changes tries are enabled and the
+/// extrinsics indices of changes have not been collected. Changes trie that is built out
+/// of such changes doesn't contain any changes, which usually signals a bug in the caller.
+pub fn try_build_changes_trie_strict<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	if state.is_some() && !changes.collects_extrinsics() {
+		return Err(Error::ExtrinsicsNotCollected);
+	}
+
+	try_build_changes_trie(backend, state, changes, parent)
+}
+
+/// Result of the comparison of computed changes trie root and the root that is expected
+/// by the block header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootCheck<Hash> {
+	/// Computed root matches the expected root. This includes the case when changes tries
+	/// are disabled and no root is expected.
+	Match,
+	/// Computed root differs from the expected root.
+	Mismatch {
+		/// Root that has been computed.
+		computed: Hash,
+		/// Root that is expected by the header.
+		expected: Hash,
+	},
+	/// Changes tries are disabled, but the header expects the root.
+	ExpectedButDisabled {
+		/// Root that is expected by the header.
+		expected: Hash,
+	},
+	/// Changes tries are enabled, but the header doesn't expect the root.
+	ComputedButNotExpected {
+		/// Root that has been computed.
+		computed: Hash,
+	},
+	/// Nothing has been changed in the block and the root is omitted according to
+	/// `EmptyBlockPolicy::OmitRoot`, but the header expects the root.
+	ExpectedButOmitted {
+		/// Root that is expected by the header.
+		expected: Hash,
+	},
+}
+
+impl<Hash> RootCheck<Hash> {
+	/// Returns true if computed root matches the expected root.
+	pub fn is_match(&self) -> bool {
+		match *self {
+			RootCheck::Match => true,
+			_ => false,
+		}
+	}
+}
+
+/// Compute the changes trie root for the child block of given `parent` and compare it with
+/// the root, expected by the block header. The `EmptyBlockPolicy` of the state is applied
+/// to the computed root.
+///
+/// Unlike skipping the verification when changes tries are disabled, this reports headers
+/// that expect the root as `RootCheck::ExpectedButDisabled`.
+pub fn compute_and_check_root<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	state: Option<&'a State<'a, H, Number>>,
+	changes: &OverlayedChanges,
+	parent: &AnchorBlockId<H::Out, Number>,
+	expected: Option<H::Out>,
+) -> Result<RootCheck<H::Out>, Error<Number>>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	let outcome = try_build_changes_trie_outcome(backend, state, changes, parent)?;
+	let is_enabled = outcome.is_some();
+	let computed = outcome.and_then(|outcome| outcome.root);
+	Ok(match (computed, expected) {
+		(Some(computed), Some(expected)) if computed == expected => RootCheck::Match,
+		(Some(computed), Some(expected)) => RootCheck::Mismatch { computed, expected },
+		(Some(computed), None) => RootCheck::ComputedButNotExpected { computed },
+		(None, Some(expected)) if is_enabled => RootCheck::ExpectedButOmitted { expected },
+		(None, Some(expected)) => RootCheck::ExpectedButDisabled { expected },
+		(None, None) => RootCheck::Match,
+	})
+}
+
+/// Encode input pair into the `key` and `value` buffers, noting it in the build statistics.
+/// DigestIndex values are encoded according to the `digest_value_threshold` of configuration.
+///
+/// Previous content of buffers is discarded, but their capacity is reused.
+fn encode_input_pair<Number: BlockNumber>(
+	stats: &mut Option<BuildStats>,
+	digest_value_threshold: Option<u32>,
+	pair: &input::InputPair<Number>,
+	key: &mut Vec<u8>,
+	value: &mut Vec<u8>,
+) {
+	key.clear();
+	key.reserve(pair.encoded_key_len());
+	pair.encode_key_to(key);
+	value.clear();
+	match (pair, digest_value_threshold) {
+		(input::InputPair::DigestIndex(_, ref blocks), Some(threshold)) =>
+			input::encode_block_list_to(blocks, threshold, value),
+		_ => pair.encode_value_to(value),
+	}
+
+	let stats = match stats.as_mut() {
+		Some(stats) => stats,
+		None => return,
+	};
+
+	match *pair {
+		input::InputPair::ExtrinsicIndex(_, _) => stats.extrinsic_pairs += 1,
+		input::InputPair::DigestIndex(_, _) => stats.digest_pairs += 1,
+		input::InputPair::ChildIndex(_, _) => (),
+		input::InputPair::MissingBlocks(_, ref blocks) => stats.missing_digest_input_blocks += blocks.len() as u64,
+	}
+	stats.encoded_input_bytes += key.len() + value.len();
+}
+
+/// Build the single (top-level or child) changes trie from its input pairs, returning its root.
+///
+/// Pairs must be sorted in `InputPair` order (i.e. by trie keys). Every pair is validated and
+/// passed to the `note_pair` before it is encoded and inserted into the trie.
+fn build_trie_nodes<H: Hasher, Number: BlockNumber>(
+	trie: TrieNodes<H, Number>,
+	mut validator: InputValidator<Number>,
+	digest_value_threshold: Option<u32>,
+	stats: &mut Option<BuildStats>,
+	input_pairs: impl Iterator<Item=Result<input::InputPair<Number>, Error<Number>>>,
+	mut note_pair: impl FnMut(&mut Option<BuildStats>, &input::InputPair<Number>),
+) -> Result<H::Out, Error<Number>> {
+	trie.build(input_pairs.map(|input_pair| {
+		let input_pair = input_pair?;
+		validator.validate(&input_pair)?;
+		note_pair(stats, &input_pair);
+
+		let mut key = Vec::new();
+		let mut value = Vec::new();
+		encode_input_pair(stats, digest_value_threshold, &input_pair, &mut key, &mut value);
+		Ok((key, value))
+	}))
+}
+
+/// Merge two iterators of input pairs, sorted in `InputPair` order, into the single sorted
+/// iterator. Errors are yielded as soon as they're met.
+fn merge_input<Number: BlockNumber>(
+	input: impl Iterator<Item=Result<input::InputPair<Number>, Error<Number>>>,
+	other_input: impl Iterator<Item=Result<input::InputPair<Number>, Error<Number>>>,
+) -> impl Iterator<Item=Result<input::InputPair<Number>, Error<Number>>> {
+	let mut input = input.peekable();
+	let mut other_input = other_input.peekable();
+	std::iter::from_fn(move || match (input.peek(), other_input.peek()) {
+		(Some(Ok(pair)), Some(Ok(other_pair))) if other_pair < pair => other_input.next(),
+		(Some(Ok(_)), Some(Err(_))) | (None, _) => other_input.next(),
+		(Some(_), _) => input.next(),
+	})
+}
+
+/// Nodes of the single (top-level or child) changes trie that is being built.
+enum TrieNodes<'a, 's, H: Hasher, Number: BlockNumber> {
+	/// Pairs are inserted into the trie, which keeps its nodes in the memory database.
+	Memory(TrieDBMut<'a, H>),
+	/// Trie is built bottom-up from the sorted pairs and every node is passed to the sink as
+	/// soon as it is final. Only nodes on the path to the last inserted key are kept in memory.
+	Sink(&'a mut (dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>> + 's)),
+}
+
+impl<'a, 's, H: Hasher, Number: BlockNumber> TrieNodes<'a, 's, H, Number> {
+	/// Start building the trie.
+	fn new(
+		mdb: &'a mut MemoryDB<H>,
+		root: &'a mut H::Out,
+		sink: Option<&'a mut (dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>> + 's)>,
+	) -> Self {
+		match sink {
+			Some(sink) => TrieNodes::Sink(sink),
+			None => TrieNodes::Memory(TrieDBMut::<H>::new(mdb, root)),
+		}
+	}
+
+	/// Insert encoded pairs, sorted by key, into the trie and return its root. The first error
+	/// aborts the build.
+	fn build(
+		self,
+		pairs: impl Iterator<Item=Result<(Vec<u8>, Vec<u8>), Error<Number>>>,
+	) -> Result<H::Out, Error<Number>> {
+		match self {
+			TrieNodes::Memory(mut trie) => {
+				for pair in pairs {
+					let (key, value) = pair?;
+					trie.insert(&key, &value).map_err(|e| Error::Storage(format!("{:?}", e)))?;
+				}
+				Ok(*trie.root())
+			},
+			TrieNodes::Sink(sink) => {
+				let mut input_result = Ok(());
+				let pairs = pairs.map_while(|pair| match pair {
+					Ok(pair) => Some(pair),
+					Err(e) => {
+						input_result = Err(e);
+						None
+					},
+				});
+
+				let mut processor = SinkProcessor::<H, Number> { sink, root: None, result: Ok(()) };
+				trie_visit::<sp_trie::Layout<H>, _, _, _, _>(pairs, &mut processor);
+				input_result?;
+				processor.result?;
+				Ok(processor.root.unwrap_or_else(empty_root::<H>))
+			},
+		}
+	}
+}
+
+/// Processor of encoded trie nodes that passes them to the sink.
+struct SinkProcessor<'a, 's, H: Hasher, Number: BlockNumber> {
+	sink: &'a mut (dyn FnMut(H::Out, Vec<u8>) -> Result<(), Error<Number>> + 's),
+	root: Option<H::Out>,
+	/// Result of the first failed sink call. Nodes aren't passed to the sink after that.
+	result: Result<(), Error<Number>>,
+}
+
+impl<'a, 's, H: Hasher, Number: BlockNumber> ProcessEncodedNode<H::Out> for SinkProcessor<'a, 's, H, Number> {
+	fn process(&mut self, _prefix: Prefix, encoded_node: Vec<u8>, is_root: bool) -> ChildReference<H::Out> {
+		// nodes that are shorter than hash are inlined into their parent nodes
+		let len = encoded_node.len();
+		if !is_root && len < H::LENGTH {
+			let mut inline = H::Out::default();
+			inline.as_mut()[..len].copy_from_slice(&encoded_node);
+			return ChildReference::Inline(inline, len);
+		}
+
+		let hash = H::hash(&encoded_node);
+		if is_root {
+			self.root = Some(hash);
+		}
+		if self.result.is_ok() {
+			self.result = (self.sink)(hash, encoded_node);
+		}
+		ChildReference::Hash(hash)
+	}
 }
 
 /// Prepare empty cached build data for given block.
@@ -370,18 +1296,352 @@ fn prepare_cached_build_data<Number: BlockNumber>(
 
 #[cfg(test)]
 mod tests {
+	use sp_core::Blake2Hasher;
+	use sp_core::storage::ChildInfo;
+	use crate::InMemoryBackend;
 	use super::*;
 
+	fn build_root(changes: &OverlayedChanges) -> <Blake2Hasher as Hasher>::Out {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let state = State::new(Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() }, 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		try_build_changes_trie(&backend, Some(&state), changes, &parent).unwrap().unwrap().1
+	}
+
+	fn new_changes() -> OverlayedChanges {
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes
+	}
+
+	#[test]
+	fn computed_root_is_checked_against_expected_root() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let state = State::new(Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() }, 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let mut changes = new_changes();
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![1], Some(vec![1]));
+		let root = build_root(&changes);
+		let other_root = Blake2Hasher::hash(b"other");
+
+		let check = |state, expected| compute_and_check_root(&backend, state, &changes, &parent, expected).unwrap();
+		assert_eq!(check(Some(&state), Some(root)), RootCheck::Match);
+		assert_eq!(check(None, None), RootCheck::Match);
+		assert_eq!(
+			check(Some(&state), Some(other_root)),
+			RootCheck::Mismatch { computed: root, expected: other_root },
+		);
+		assert_eq!(check(None, Some(root)), RootCheck::ExpectedButDisabled { expected: root });
+		assert_eq!(check(Some(&state), None), RootCheck::ComputedButNotExpected { computed: root });
+	}
+
+	#[test]
+	fn input_is_checked_against_storage_delta() {
+		let backend: InMemoryBackend<Blake2Hasher> = vec![(vec![3], vec![3])]
+			.into_iter().collect::<BTreeMap<_, _>>().into();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ignored_prefixes: vec![vec![9]], ..Default::default() };
+		let state = State::new(config.clone(), 0, &storage)
+			.with_build_params(BuildParams { check_against_delta: true, ..Default::default() });
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let mut changes = new_changes();
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![1], Some(vec![1]));
+		changes.set_storage(vec![3], None);
+		changes.set_storage(vec![9, 1], Some(vec![1]));
+		// temporary value isn't in the changes trie
+		changes.set_storage(vec![4], Some(vec![4]));
+		changes.set_storage(vec![4], None);
+		assert!(try_build_changes_trie(&backend, Some(&state), &changes, &parent).is_ok());
+
+		// the write that hasn't been attributed to any extrinsic is missed by the changes trie
+		changes.set_collect_extrinsics(false);
+		changes.set_storage(vec![2], Some(vec![2]));
+		assert_eq!(
+			try_build_changes_trie(&backend, Some(&state), &changes, &parent).map(|_| ()),
+			Err(Error::InconsistentWithDelta(consistency::ConsistencyError {
+				missing_from_input: vec![vec![2]],
+				missing_from_delta: Vec::new(),
+			})),
+		);
+
+		// the check is opt-in
+		let state = State::new(config, 0, &storage);
+		assert!(try_build_changes_trie(&backend, Some(&state), &changes, &parent).is_ok());
+	}
+
+	#[test]
+	fn empty_block_policy_is_applied_to_block_without_changes() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let parent_root = Blake2Hasher::hash(b"parent");
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::with_blocks(vec![(1, parent_root)]);
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let state_with = |empty_block_policy| State::new(config.clone(), 0, &storage)
+			.with_build_params(BuildParams { empty_block_policy, ..Default::default() });
+		let changes = new_changes();
+		let build = |empty_block_policy, parent_number| {
+			let state = state_with(empty_block_policy);
+			let parent = AnchorBlockId { hash: Default::default(), number: parent_number };
+			let outcome = try_build_changes_trie_outcome(&backend, Some(&state), &changes, &parent)
+				.unwrap()
+				.unwrap();
+			assert!(outcome.was_empty);
+			// the same policy is applied when the root is checked
+			assert_eq!(
+				compute_and_check_root(&backend, Some(&state), &changes, &parent, outcome.root),
+				Ok(RootCheck::Match),
+			);
+			(outcome.root, outcome.transaction.is_some())
+		};
+
+		let empty_root = empty_root::<Blake2Hasher>();
+		assert_eq!(build(EmptyBlockPolicy::EmptyTrieRoot, 1), (Some(empty_root), true));
+		assert_eq!(build(EmptyBlockPolicy::OmitRoot, 1), (None, false));
+		assert_eq!(build(EmptyBlockPolicy::ReuseParentRoot, 1), (Some(parent_root), false));
+		// parent has no changes trie root => root of the empty trie is used
+		assert_eq!(build(EmptyBlockPolicy::ReuseParentRoot, 0), (Some(empty_root), true));
+
+		let parent = AnchorBlockId { hash: Default::default(), number: 1 };
+		let check = |empty_block_policy, expected| {
+			compute_and_check_root(&backend, Some(&state_with(empty_block_policy)), &changes, &parent, expected)
+		};
+		assert_eq!(
+			check(EmptyBlockPolicy::OmitRoot, Some(empty_root)),
+			Ok(RootCheck::ExpectedButOmitted { expected: empty_root }),
+		);
+		assert_eq!(
+			check(EmptyBlockPolicy::EmptyTrieRoot, None),
+			Ok(RootCheck::ComputedButNotExpected { computed: empty_root }),
+		);
+
+		// policy isn't applied to blocks with changes
+		let mut changes = new_changes();
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![1], Some(vec![1]));
+		let state = state_with(EmptyBlockPolicy::OmitRoot);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let outcome = try_build_changes_trie_outcome(&backend, Some(&state), &changes, &parent).unwrap().unwrap();
+		assert!(!outcome.was_empty);
+		assert!(outcome.transaction.is_some());
+		assert_eq!(outcome.root, Some(build_root(&changes)));
+	}
+
+	#[test]
+	fn changes_trie_is_not_built_when_disabled() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let parent = AnchorBlockId { hash: Default::default(), number: 0u64 };
+		assert!(try_build_changes_trie(&backend, None, &new_changes(), &parent).unwrap().is_none());
+	}
+
+	#[test]
+	fn strict_build_fails_when_extrinsics_are_not_collected() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let state = State::new(Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() }, 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let mut changes = OverlayedChanges::default();
+		changes.set_storage(vec![1], Some(vec![1]));
+
+		// changes tries are disabled
+		assert!(try_build_changes_trie_strict(&backend, None, &changes, &parent).unwrap().is_none());
+		// extrinsics are not collected
+		assert!(try_build_changes_trie(&backend, Some(&state), &changes, &parent).unwrap().is_some());
+		assert_eq!(
+			try_build_changes_trie_strict(&backend, Some(&state), &changes, &parent).err(),
+			Some(Error::ExtrinsicsNotCollected),
+		);
+		// extrinsics are collected
+		changes.set_collect_extrinsics(true);
+		assert!(try_build_changes_trie_strict(&backend, Some(&state), &changes, &parent).unwrap().is_some());
+	}
+
+	#[test]
+	fn changes_trie_contains_top_and_child_changes_of_the_same_extrinsic() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut changes = new_changes();
+		changes.set_extrinsic_index(0).unwrap();
+		changes.set_storage(vec![1], Some(vec![1]));
+		changes.set_child_storage(&child_info, vec![2], Some(vec![2]));
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let state = State::new(config.clone(), 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (mdb, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+			.unwrap().unwrap();
+		storage.insert(1, root, mdb);
+
+		let key_changes_at_block1 = |storage_key: Option<&PrefixedStorageKey>, key: &[u8]| key_changes::<Blake2Hasher, u64>(
+			ConfigurationRange { config: &config, zero: 0, end: None },
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 1 },
+			1,
+			storage_key,
+			key,
+		).and_then(|changes| changes.collect::<Result<Vec<_>, _>>());
+		let child_storage_key = child_info.prefixed_storage_key();
+		assert_eq!(key_changes_at_block1(None, &[1]), Ok(vec![(1, 0)]));
+		assert_eq!(key_changes_at_block1(Some(&child_storage_key), &[2]), Ok(vec![(1, 0)]));
+		assert_eq!(key_changes_at_block1(None, &[2]), Ok(vec![]));
+		assert_eq!(key_changes_at_block1(Some(&child_storage_key), &[1]), Ok(vec![]));
+	}
+
+	#[test]
+	fn changes_trie_root_is_the_same_as_root_of_separately_encoded_pairs() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut changes = new_changes();
+		for i in 0u32..64 {
+			// keys of different length, so the reused buffers are both grown and shrunk
+			let key = vec![i as u8; 1 + (i as usize * 7) % 40];
+			changes.set_extrinsic_index(i % 5).unwrap();
+			changes.set_storage(key.clone(), Some(vec![1]));
+			changes.set_child_storage(&child_info, key, Some(vec![2]));
+		}
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (input_pairs, child_input_pairs, _) = build::prepare_input(
+			&backend,
+			&storage,
+			ConfigurationRange { config: &config, zero: 0, end: None },
+			&changes,
+			&parent,
+			1,
+		).unwrap();
+		let mut mdb = MemoryDB::<Blake2Hasher>::default();
+		let child_roots = child_input_pairs.into_iter().map(|(child_index, input_pairs)| {
+			let root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+				&mut mdb,
+				input_pairs.map(Into::into),
+			).unwrap();
+			input::InputPair::ChildIndex(child_index, root.as_ref().to_vec())
+		}).collect::<Vec<_>>();
+		let naive_root = crate::backend::insert_into_memory_db::<Blake2Hasher, _>(
+			&mut mdb,
+			child_roots.into_iter().chain(input_pairs).map(Into::into),
+		).unwrap();
+
+		assert_eq!(build_root(&changes), naive_root);
+	}
+
+	#[test]
+	fn streaming_build_produces_the_same_trie() {
+		use rand::{Rng, SeedableRng, rngs::StdRng};
+
+		let child_info = ChildInfo::new_default(b"child");
+		let mut rng = StdRng::seed_from_u64(42);
+		let mut changes = new_changes();
+		for _ in 0..50_000 {
+			let key_len = rng.gen_range(1, 100);
+			let key = (0..key_len).map(|_| rng.gen::<u8>()).collect::<Vec<_>>();
+			changes.set_extrinsic_index(rng.gen_range(0, 100)).unwrap();
+			if rng.gen_bool(0.8) {
+				changes.set_storage(key, Some(vec![1]));
+			} else {
+				changes.set_child_storage(&child_info, key, Some(vec![2]));
+			}
+		}
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let state = State::new(Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() }, 0, &storage);
+		let parent = AnchorBlockId { hash: Default::default(), number: 0 };
+		let (mut mdb, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+			.unwrap().unwrap();
+		let mut streamed_nodes = HashMap::new();
+		let (streamed_root, _) = try_build_changes_trie_streaming(&backend, Some(&state), &changes, &parent, &mut |hash, node| {
+			streamed_nodes.insert(hash, node);
+			Ok(())
+		}).unwrap().unwrap();
+
+		let nodes = mdb.drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(hash, (node, _))| (hash, node))
+			.collect::<HashMap<_, _>>();
+		assert_eq!(streamed_root, root);
+		assert_eq!(streamed_nodes.len(), nodes.len());
+		assert!(streamed_nodes == nodes);
+
+		// the first sink error aborts the build
+		let mut sink_calls = 0;
+		let result = try_build_changes_trie_streaming(&backend, Some(&state), &changes, &parent, &mut |_, _| {
+			sink_calls += 1;
+			Err(Error::Storage("sink is full".into()))
+		});
+		assert_eq!(result.map(|_| ()), Err(Error::Storage("sink is full".into())));
+		assert_eq!(sink_calls, 1);
+	}
+
+	#[test]
+	fn changes_trie_root_does_not_depend_on_changes_order() {
+		let child_info = ChildInfo::new_default(b"child");
+
+		let mut changes1 = new_changes();
+		changes1.set_extrinsic_index(0).unwrap();
+		changes1.set_storage(vec![1], Some(vec![1]));
+		changes1.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		changes1.set_extrinsic_index(1).unwrap();
+		changes1.set_storage(vec![2], Some(vec![2]));
+		changes1.set_extrinsic_index(2).unwrap();
+		changes1.set_storage(vec![3], Some(vec![3]));
+		changes1.set_child_storage(&child_info, vec![2], Some(vec![2]));
+
+		let mut changes2 = new_changes();
+		changes2.set_extrinsic_index(2).unwrap();
+		changes2.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		changes2.set_storage(vec![3], Some(vec![3]));
+		changes2.set_extrinsic_index(1).unwrap();
+		changes2.set_storage(vec![2], Some(vec![2]));
+		changes2.set_extrinsic_index(0).unwrap();
+		changes2.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		changes2.set_storage(vec![1], Some(vec![1]));
+
+		assert_eq!(build_root(&changes1), build_root(&changes2));
+	}
+
+	#[test]
+	fn changes_trie_root_does_not_depend_on_transactions() {
+		let mut changes1 = new_changes();
+		changes1.set_extrinsic_index(0).unwrap();
+		changes1.set_storage(vec![1], Some(vec![1]));
+		changes1.set_extrinsic_index(1).unwrap();
+		changes1.set_storage(vec![1], Some(vec![2]));
+		changes1.set_storage(vec![2], Some(vec![2]));
+
+		let mut changes2 = new_changes();
+		changes2.start_transaction();
+		changes2.set_extrinsic_index(0).unwrap();
+		changes2.set_storage(vec![1], Some(vec![1]));
+		changes2.start_transaction();
+		changes2.set_extrinsic_index(1).unwrap();
+		changes2.set_storage(vec![1], Some(vec![2]));
+		changes2.commit_transaction().unwrap();
+		changes2.start_transaction();
+		changes2.set_storage(vec![3], Some(vec![3]));
+		changes2.rollback_transaction().unwrap();
+		changes2.commit_transaction().unwrap();
+		changes2.set_storage(vec![2], Some(vec![2]));
+
+		assert_eq!(build_root(&changes1), build_root(&changes2));
+	}
+
 	#[test]
 	fn cache_is_cleared_when_digests_are_disabled() {
-		let config = Configuration { digest_interval: 0, digest_levels: 0 };
+		let config = Configuration { digest_interval: 0, digest_levels: 0, ..Default::default() };
 		let config_range = ConfigurationRange { zero: 0, end: None, config: &config };
 		assert_eq!(prepare_cached_build_data(config_range, 8u32), IncompleteCacheAction::Clear);
 	}
 
 	#[test]
 	fn build_data_is_cached_when_digests_are_enabled() {
-		let config = Configuration { digest_interval: 8, digest_levels: 2 };
+		let config = Configuration { digest_interval: 8, digest_levels: 2, ..Default::default() };
 		let config_range = ConfigurationRange { zero: 0, end: None, config: &config };
 		assert!(prepare_cached_build_data(config_range.clone(), 4u32).collects_changed_keys());
 		assert!(prepare_cached_build_data(config_range.clone(), 7u32).collects_changed_keys());
@@ -390,15 +1650,43 @@ mod tests {
 
 	#[test]
 	fn cache_is_cleared_when_digests_are_enabled_and_top_level_digest_is_built() {
-		let config = Configuration { digest_interval: 8, digest_levels: 2 };
+		let config = Configuration { digest_interval: 8, digest_levels: 2, ..Default::default() };
 		let config_range = ConfigurationRange { zero: 0, end: None, config: &config };
 		assert_eq!(prepare_cached_build_data(config_range, 64u32), IncompleteCacheAction::Clear);
 	}
 
 	#[test]
 	fn cache_is_cleared_when_end_block_of_configuration_is_built() {
-		let config = Configuration { digest_interval: 8, digest_levels: 2 };
+		let config = Configuration { digest_interval: 8, digest_levels: 2, ..Default::default() };
 		let config_range = ConfigurationRange { zero: 0, end: Some(4u32), config: &config };
 		assert_eq!(prepare_cached_build_data(config_range.clone(), 4u32), IncompleteCacheAction::Clear);
 	}
+
+	#[test]
+	fn configuration_is_read_from_storage() {
+		let config = Configuration::new(4, 2);
+		let backend_with = |value: Option<Vec<u8>>| InMemoryBackend::<Blake2Hasher>::from(
+			value.into_iter()
+				.map(|value| (sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec(), value))
+				.collect::<std::collections::BTreeMap<_, _>>()
+		);
+
+		// changes tries are disabled
+		assert_eq!(Configuration::from_storage::<_, _, u64>(&backend_with(None)), Ok(None));
+
+		// changes tries are enabled
+		let (key, value) = config.to_storage_delta();
+		assert_eq!(key, sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec());
+		assert_eq!(Configuration::from_storage::<_, _, u64>(&backend_with(value)), Ok(Some(config.clone())));
+
+		// the value is corrupted
+		match Configuration::from_storage::<_, _, u64>(&backend_with(Some(vec![1, 2, 3]))) {
+			Err(Error::InvalidStoredConfiguration(_)) => (),
+			result => panic!("unexpected result: {:?}", result),
+		}
+
+		// configuration hash is the hash of encoded configuration
+		assert_eq!(config.hash::<Blake2Hasher>(), Blake2Hasher::hash(&config.encode()));
+		assert_ne!(config.hash::<Blake2Hasher>(), Configuration::new(4, 3).hash::<Blake2Hasher>());
+	}
 }
@@ -0,0 +1,195 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries digest input, maintained incrementally.
+
+use std::collections::{BTreeMap, BTreeSet};
+use num_traits::CheckedAdd;
+use parking_lot::Mutex;
+use crate::changes_trie::{BlockNumber, ConfigurationRange};
+use crate::changes_trie::build_iterator::digest_parent_blocks;
+use crate::changes_trie::build::{DigestBuildBlockKeys, digest_input_blocks, digest_input_maps};
+use crate::changes_trie::input::{ChildIndex, InputPair};
+
+/// Keys that have been changed in recent blocks, noted right after these blocks have been built.
+///
+/// When digest is built, changed keys of digest input blocks are taken from the pending digest
+/// instead of reading them from changes tries. Blocks are identified by number only, so the
+/// pending digest must be purged on reorgs (see `purge_from`) and pruned on finality
+/// (see `prune_finalized`).
+pub struct PendingDigest<Number> {
+	blocks: Mutex<BTreeMap<Number, DigestBuildBlockKeys>>,
+}
+
+impl<Number: BlockNumber> Default for PendingDigest<Number> {
+	fn default() -> Self {
+		PendingDigest {
+			blocks: Mutex::new(BTreeMap::new()),
+		}
+	}
+}
+
+impl<Number: BlockNumber> PendingDigest<Number> {
+	/// Create new empty pending digest.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Get number of noted blocks.
+	pub fn len(&self) -> usize {
+		self.blocks.lock().len()
+	}
+
+	/// Returns true if no blocks are noted.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Note input pairs of the top-level and child changes tries of the block that has just been built.
+	pub fn note_block(
+		&self,
+		block: Number,
+		input_pairs: &[InputPair<Number>],
+		children_input_pairs: &[(ChildIndex<Number>, Vec<InputPair<Number>>)],
+	) {
+		let keys = |input_pairs: &[InputPair<Number>]| input_pairs.iter()
+			.filter_map(|pair| pair.key())
+			.map(|key| key.to_vec())
+			.collect::<BTreeSet<_>>();
+
+		let mut block_keys = DigestBuildBlockKeys::default();
+		block_keys.top = keys(input_pairs);
+		for (child_index, input_pairs) in children_input_pairs {
+			let child_keys = keys(input_pairs);
+			if !child_keys.is_empty() {
+				block_keys.children.insert(child_index.storage_key.clone(), child_keys);
+			}
+		}
+
+		self.note_block_keys(block, block_keys);
+	}
+
+	/// Note changed keys of the block.
+	pub(crate) fn note_block_keys(&self, block: Number, block_keys: DigestBuildBlockKeys) {
+		self.blocks.lock().insert(block, block_keys);
+	}
+
+	/// Forget all blocks starting from given block. Should be called on reorgs.
+	pub fn purge_from(&self, block: &Number) {
+		self.blocks.lock().split_off(block);
+	}
+
+	/// Forget finalized blocks that won't be taken by any future digest. Should be called on
+	/// finality.
+	///
+	/// The block is kept only while the first digest block that includes it is not finalized.
+	/// Blocks that are not included into any digest (e.g. because of configuration change) are
+	/// forgotten as soon as they're finalized.
+	pub fn prune_finalized(&self, config: ConfigurationRange<Number>, finalized: &Number)
+		where
+			Number: CheckedAdd,
+	{
+		self.blocks.lock().retain(|block, _| block > finalized
+			|| digest_parent_blocks(config.clone(), block.clone())
+				.next()
+				.map(|digest_block| digest_block > *finalized)
+				.unwrap_or(false)
+		);
+	}
+
+	/// Take DigestIndex pairs of the top-level and child changes tries, if digest is built at
+	/// given block and changed keys of all its input blocks have been noted.
+	///
+	/// Pairs are the same as pairs that are built by reading changes tries of digest input blocks.
+	pub fn take_if_digest_block(
+		&self,
+		config: ConfigurationRange<Number>,
+		block: Number,
+	) -> Option<(Vec<InputPair<Number>>, Vec<(ChildIndex<Number>, Vec<InputPair<Number>>)>)> {
+		let digest_input_blocks = digest_input_blocks(config, block.clone());
+		if digest_input_blocks.is_empty() {
+			return None;
+		}
+
+		let digest_input_blocks_keys = self.take_blocks(&digest_input_blocks)?;
+		let (map, child_map) = digest_input_maps(&block, &digest_input_blocks, digest_input_blocks_keys, None)
+			.expect("memory is not limited; qed");
+		Some((
+			map.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)).collect(),
+			child_map.into_iter().map(|(child_index, pairs)| (
+				child_index,
+				pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)).collect(),
+			)).collect(),
+		))
+	}
+
+	/// Take changed keys of all given blocks. Returns None (and keeps all blocks) if some
+	/// block hasn't been noted.
+	pub(crate) fn take_blocks(&self, blocks: &[Number]) -> Option<Vec<DigestBuildBlockKeys>> {
+		let mut noted_blocks = self.blocks.lock();
+		if !blocks.iter().all(|block| noted_blocks.contains_key(block)) {
+			return None;
+		}
+
+		Some(blocks.iter().filter_map(|block| noted_blocks.remove(block)).collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::ChangesTrieConfiguration;
+	use super::*;
+
+	fn noted_blocks(pending_digest: &PendingDigest<u64>) -> Vec<u64> {
+		pending_digest.blocks.lock().keys().cloned().collect()
+	}
+
+	fn pending_digest(blocks: impl Iterator<Item=u64>) -> PendingDigest<u64> {
+		let pending_digest = PendingDigest::new();
+		for block in blocks {
+			pending_digest.note_block_keys(block, Default::default());
+		}
+		pending_digest
+	}
+
+	#[test]
+	fn prune_finalized_keeps_blocks_required_by_non_finalized_digests() {
+		let config = ChangesTrieConfiguration::new(4, 2);
+		let config_range = ConfigurationRange { config: &config, zero: 0, end: None };
+		let pending_digest = pending_digest(1..=6);
+
+		pending_digest.prune_finalized(config_range.clone(), &3);
+		assert_eq!(noted_blocks(&pending_digest), vec![1, 2, 3, 4, 5, 6]);
+
+		// blocks 1..3 are only required by L1 digest at block 4, block 4 is required by L2 digest at block 16
+		pending_digest.prune_finalized(config_range.clone(), &5);
+		assert_eq!(noted_blocks(&pending_digest), vec![4, 5, 6]);
+
+		pending_digest.prune_finalized(config_range, &16);
+		assert!(pending_digest.is_empty());
+	}
+
+	#[test]
+	fn prune_finalized_forgets_blocks_not_included_in_digests() {
+		let config = ChangesTrieConfiguration::new(0, 0);
+		let config_range = ConfigurationRange { config: &config, zero: 0, end: None };
+		let pending_digest = pending_digest(1..=6);
+
+		pending_digest.prune_finalized(config_range, &5);
+		assert_eq!(noted_blocks(&pending_digest), vec![6]);
+	}
+}
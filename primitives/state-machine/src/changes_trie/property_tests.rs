@@ -0,0 +1,195 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Randomized (but deterministic) property tests of the digest structure and of the
+//! changes tries build and query paths.
+
+use std::collections::{BTreeMap, BTreeSet};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use sp_core::Blake2Hasher;
+use crate::InMemoryBackend;
+use crate::overlayed_changes::OverlayedChanges;
+use crate::changes_trie::{
+	AnchorBlockId, Configuration, ConfigurationRange, InMemoryStorage, State,
+	digest_build_iterator, digest_parent_blocks, key_changes, try_build_changes_trie,
+};
+
+/// Max block number that is used in digest structure properties.
+const MAX_BLOCK: u64 = 1_000_000;
+
+fn random_config(rng: &mut StdRng, max_interval: u32) -> Configuration {
+	Configuration {
+		digest_interval: rng.gen_range(2, max_interval + 1),
+		digest_levels: rng.gen_range(1, 5),
+		..Default::default()
+	}
+}
+
+fn config_range<'a>(config: &'a Configuration, zero: u64) -> ConfigurationRange<'a, u64> {
+	ConfigurationRange {
+		config,
+		zero,
+		end: None,
+	}
+}
+
+/// Returns random digest block of given configuration.
+fn random_digest_block(rng: &mut StdRng, config: &Configuration, zero: u64) -> u64 {
+	let interval = config.digest_interval as u64;
+	zero + interval * rng.gen_range(1, MAX_BLOCK / interval + 1)
+}
+
+/// Blocks that must be included into the digest of given level, built at given block: for every
+/// level, blocks between the previous digest of this level and the block, with the step of the
+/// previous level digest.
+fn expected_digest_input_blocks(config: &Configuration, block: u64, level: u32) -> Vec<u64> {
+	let interval = config.digest_interval as u64;
+	let mut blocks = (0..level)
+		.flat_map(|level| {
+			let step = interval.pow(level);
+			(1..interval).map(move |i| block - i * step)
+		})
+		.collect::<Vec<_>>();
+	blocks.sort();
+	blocks
+}
+
+#[test]
+fn digest_includes_blocks_of_its_span() {
+	let mut rng = StdRng::seed_from_u64(0);
+	for _ in 0..1_000 {
+		let config = random_config(&mut rng, 32);
+		let zero = rng.gen_range(0, 1_000);
+		let block = random_digest_block(&mut rng, &config, zero);
+		let context = format!("config: {:?}, zero: {}, block: {}", config, zero, block);
+
+		let (level, _, step) = config.digest_level_at_block(zero, block)
+			.unwrap_or_else(|| panic!("digest isn't built at digest block; {}", context));
+		assert_eq!(step, config.digest_interval.pow(level - 1), "{}", context);
+
+		let input = digest_build_iterator(config_range(&config, zero), block).collect::<Vec<_>>();
+		assert!(input.windows(2).all(|w| w[0] < w[1]), "blocks are not ascending: {:?}; {}", input, context);
+		assert!(input.iter().all(|b| *b > zero && *b < block), "digest includes {:?}; {}", input, context);
+		assert_eq!(input, expected_digest_input_blocks(&config, block, level), "{}", context);
+
+		// L2+ digest includes exactly the L1 digest blocks of its L2 span (higher level digests
+		// that are before this span are also L1 digests)
+		if level > 1 {
+			let interval = config.digest_interval as u64;
+			let span = interval.pow(2);
+			let l1_digests = input.iter()
+				.filter(|b| **b > block - span && (*b - zero) % interval == 0)
+				.cloned()
+				.collect::<Vec<_>>();
+			let expected = (block - span + 1..block).filter(|b| (b - zero) % interval == 0).collect::<Vec<_>>();
+			assert_eq!(l1_digests, expected, "{}", context);
+		}
+	}
+}
+
+#[test]
+fn every_block_is_included_into_single_digest() {
+	let mut rng = StdRng::seed_from_u64(1);
+	for _ in 0..200 {
+		// small intervals, so that all digest blocks that could include the block are checked
+		let config = random_config(&mut rng, 8);
+		let zero = rng.gen_range(0, 1_000);
+		let block = zero + rng.gen_range(1, MAX_BLOCK);
+		let context = format!("config: {:?}, zero: {}, block: {}", config, zero, block);
+
+		let interval = config.digest_interval as u64;
+		let max_digest_interval = config.max_digest_interval() as u64;
+		let first_digest_block = block - (block - zero) % interval + interval;
+		let including_digests = (0..)
+			.map(|i| first_digest_block + i * interval)
+			.take_while(|digest_block| *digest_block <= block + max_digest_interval)
+			.filter(|digest_block| {
+				digest_build_iterator(config_range(&config, zero), *digest_block).any(|b| b == block)
+			})
+			.collect::<Vec<_>>();
+
+		let is_max_level_digest = (block - zero) % max_digest_interval == 0;
+		let parent = digest_parent_blocks(config_range(&config, zero), block).next();
+		match is_max_level_digest {
+			true => {
+				assert_eq!(including_digests, Vec::<u64>::new(), "{}", context);
+				assert_eq!(parent, None, "{}", context);
+			},
+			false => {
+				assert_eq!(including_digests.len(), 1, "included into {:?}; {}", including_digests, context);
+				assert_eq!(parent, Some(including_digests[0]), "{}", context);
+			},
+		}
+	}
+}
+
+#[test]
+fn key_changes_are_found_in_incrementally_built_tries() {
+	let mut rng = StdRng::seed_from_u64(2);
+	for _ in 0..10 {
+		let config = random_config(&mut rng, 4);
+		let blocks = rng.gen_range(1, 100u64);
+		let keys = 4u8;
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let mut expected = BTreeMap::<u8, BTreeSet<(u64, u32)>>::new();
+		for block in 1..=blocks {
+			let mut changes = OverlayedChanges::default();
+			changes.set_collect_extrinsics(true);
+			for extrinsic in 0..rng.gen_range(0, 4) {
+				changes.set_extrinsic_index(extrinsic).unwrap();
+				for key in 0..keys {
+					if rng.gen_range(0, 4) == 0 {
+						changes.set_storage(vec![key], Some(vec![block as u8]));
+						expected.entry(key).or_default().insert((block, extrinsic));
+					}
+				}
+			}
+
+			let state = State::new(config.clone(), 0, &storage);
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let (trie, root, _) = try_build_changes_trie(&backend, Some(&state), &changes, &parent)
+				.unwrap_or_else(|e| panic!("failed to build block {}: {:?}; config: {:?}", block, e, config))
+				.expect("state is provided; qed");
+			storage.insert(block, root, trie);
+		}
+
+		let end = AnchorBlockId { hash: Default::default(), number: blocks };
+		for key in 0..keys {
+			let actual = key_changes::<Blake2Hasher, u64>(
+				config_range(&config, 0),
+				&storage,
+				1,
+				&end,
+				blocks,
+				None,
+				&[key],
+			)
+				.and_then(|changes| changes.collect::<Result<BTreeSet<_>, _>>())
+				.unwrap_or_else(|e| panic!("query has failed: {:?}; config: {:?}, key: {}", e, config, key));
+			assert_eq!(
+				actual,
+				expected.remove(&key).unwrap_or_default(),
+				"config: {:?}, blocks: {}, key: {}",
+				config,
+				blocks,
+				key,
+			);
+		}
+	}
+}
@@ -23,7 +23,7 @@ use log::warn;
 use num_traits::One;
 use crate::proving_backend::ProvingBackendRecorder;
 use crate::trie_backend_essence::TrieBackendEssence;
-use crate::changes_trie::{AnchorBlockId, Storage, BlockNumber};
+use crate::changes_trie::{AnchorBlockId, ConfigurationRange, Storage, BlockNumber};
 use crate::changes_trie::storage::TrieBackendAdapter;
 use crate::changes_trie::input::{ChildIndex, InputKey};
 use codec::{Decode, Codec};
@@ -66,7 +66,7 @@ pub fn prune<H: Hasher, Number: BlockNumber, F: FnMut(H::Out)>(
 			);
 			let child_prefix = ChildIndex::key_neutral_prefix(block.clone());
 			let mut children_roots = Vec::new();
-			trie_storage.for_key_values_with_prefix(&child_prefix, |mut key, mut value| {
+			trie_storage.try_for_key_values_with_prefix(&child_prefix, |mut key, mut value| {
 				if let Ok(InputKey::ChildIndex::<Number>(_trie_key)) = Decode::decode(&mut key) {
 					if let Ok(value) = <Vec<u8>>::decode(&mut value) {
 						let mut trie_root = <H as Hasher>::Out::default();
@@ -74,6 +74,9 @@ pub fn prune<H: Hasher, Number: BlockNumber, F: FnMut(H::Out)>(
 						children_roots.push(trie_root);
 					}
 				}
+			}).unwrap_or_else(|error| {
+				// prune the tries we've been able to read
+				warn!(target: "trie", "Failed to read changes trie from DB: {}", error);
 			});
 
 			children_roots
@@ -86,6 +89,36 @@ pub fn prune<H: Hasher, Number: BlockNumber, F: FnMut(H::Out)>(
 	}
 }
 
+/// Returns the oldest block which changes trie must be kept when `min_blocks_to_keep`
+/// last blocks are preserved.
+///
+/// Changes tries are pruned by whole max-level digest ranges, because lower-level digests
+/// are required to drill down into blocks covered by the higher-level digest. So this is the
+/// first block of the max-level digest range that includes `current_block - min_blocks_to_keep`.
+pub fn oldest_non_prunable_block<Number: BlockNumber>(
+	config: ConfigurationRange<Number>,
+	min_blocks_to_keep: Number,
+	current_block: Number,
+) -> Number {
+	// the first changes trie that uses this configuration is built at zero + 1
+	let first_block = config.zero.clone() + One::one();
+	let oldest_kept_block = match current_block.checked_sub(&min_blocks_to_keep) {
+		Some(oldest_kept_block) if oldest_kept_block > first_block => oldest_kept_block,
+		_ => return first_block,
+	};
+
+	// all changes tries of deactivated configuration could be pruned
+	if let Some(end) = config.end.as_ref() {
+		if oldest_kept_block > *end {
+			return end.clone() + One::one();
+		}
+	}
+
+	config.config.next_max_level_digest_range(config.zero.clone(), oldest_kept_block.clone())
+		.map(|(begin, _)| begin)
+		.unwrap_or(oldest_kept_block)
+}
+
 // Prune a trie.
 fn prune_trie<H: Hasher, Number: BlockNumber, F: FnMut(H::Out)>(
 	storage: &dyn Storage<H, Number>,
@@ -117,6 +150,7 @@ mod tests {
 	use sp_trie::MemoryDB;
 	use sp_core::H256;
 	use crate::backend::insert_into_memory_db;
+	use crate::changes_trie::Configuration;
 	use crate::changes_trie::storage::InMemoryStorage;
 	use codec::Encode;
 	use sp_runtime::traits::BlakeTwo256;
@@ -135,6 +169,60 @@ mod tests {
 		pruned_trie_nodes
 	}
 
+	fn oldest_non_prunable(
+		digest_interval: u32,
+		digest_levels: u32,
+		zero: u64,
+		end: Option<u64>,
+		min_blocks_to_keep: u64,
+		current_block: u64,
+	) -> u64 {
+		let config = Configuration { digest_interval, digest_levels, ..Default::default() };
+		oldest_non_prunable_block(
+			ConfigurationRange { config: &config, zero, end },
+			min_blocks_to_keep,
+			current_block,
+		)
+	}
+
+	#[test]
+	fn oldest_non_prunable_block_works_when_digests_are_disabled() {
+		assert_eq!(oldest_non_prunable(0, 0, 0, None, 8, 5), 1);
+		assert_eq!(oldest_non_prunable(0, 0, 0, None, 8, 30), 22);
+		assert_eq!(oldest_non_prunable(4, 0, 0, None, 8, 30), 22);
+		assert_eq!(oldest_non_prunable(0, 0, 10, None, 8, 30), 22);
+		assert_eq!(oldest_non_prunable(0, 0, 10, Some(20), 8, 30), 21);
+	}
+
+	#[test]
+	fn oldest_non_prunable_block_works_with_single_level_digests() {
+		assert_eq!(oldest_non_prunable(4, 1, 0, None, 8, 12), 1);
+		assert_eq!(oldest_non_prunable(4, 1, 0, None, 8, 13), 5);
+		assert_eq!(oldest_non_prunable(4, 1, 0, None, 8, 16), 5);
+		assert_eq!(oldest_non_prunable(4, 1, 0, None, 8, 17), 9);
+	}
+
+	#[test]
+	fn oldest_non_prunable_block_works_with_multi_level_digests() {
+		// L2 digest covers 16 blocks => L1 digests at 4, 8, 12 are kept while L2 digest at 16 is kept
+		assert_eq!(oldest_non_prunable(4, 2, 0, None, 8, 5), 1);
+		assert_eq!(oldest_non_prunable(4, 2, 0, None, 8, 24), 1);
+		assert_eq!(oldest_non_prunable(4, 2, 0, None, 8, 25), 17);
+		assert_eq!(oldest_non_prunable(4, 2, 0, None, 8, 30), 17);
+		assert_eq!(oldest_non_prunable(4, 2, 0, None, 8, 41), 33);
+		assert_eq!(oldest_non_prunable(2, 3, 0, None, 0, 10), 9);
+		assert_eq!(oldest_non_prunable(2, 3, 0, None, 0, 8), 1);
+	}
+
+	#[test]
+	fn oldest_non_prunable_block_respects_configuration_range() {
+		// max-level digest ranges are 8..=11, 12..=15, ...
+		assert_eq!(oldest_non_prunable(2, 2, 7, Some(20), 8, 10), 8);
+		assert_eq!(oldest_non_prunable(2, 2, 7, Some(20), 8, 20), 12);
+		assert_eq!(oldest_non_prunable(2, 2, 7, Some(20), 8, 28), 20);
+		assert_eq!(oldest_non_prunable(2, 2, 7, Some(20), 8, 40), 21);
+	}
+
 	#[test]
 	fn prune_works() {
 		fn prepare_storage() -> InMemoryStorage<BlakeTwo256, u64> {
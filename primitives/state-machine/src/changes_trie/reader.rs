@@ -0,0 +1,304 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed access to the single changes trie.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use codec::{Decode, Encode};
+use hash_db::Hasher;
+use sp_core::storage::PrefixedStorageKey;
+use crate::StorageKey;
+use crate::changes_trie::{Storage, BlockNumber, Error, BlockTrieStorage, block_trie_storage};
+use crate::changes_trie::input::{
+	InputKey, ExtrinsicIndex, ExtrinsicIndexValue, DigestIndex, DigestIndexValue, ChildIndex,
+	MissingBlocksIndex, MissingBlocksValue, KeyKind, classify_key_ref, decode_block_list,
+};
+use crate::node_cache::NodeCache;
+use crate::trie_backend_essence::TrieBackendEssence;
+
+/// Reader of the single (top-level or child) changes trie.
+///
+/// Handles encoding of the changes trie keys and decoding of the values, so that the
+/// trie could be read without knowing the details of changes trie input encoding.
+///
+/// By default, keys that can't be decoded are reported as `InvalidInputKey` errors.
+pub struct Reader<'a, H: Hasher, Number: BlockNumber> {
+	storage: &'a dyn Storage<H, Number>,
+	block: Number,
+	trie: TrieBackendEssence<BlockTrieStorage<'a, H>, H>,
+	invalid_keys: Option<&'a AtomicU64>,
+	cancel: Option<&'a AtomicBool>,
+	node_cache: Option<Arc<NodeCache<H>>>,
+}
+
+/// Number of changed keys that are read between two checks of the cancellation flag.
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+impl<'a, H: Hasher, Number: BlockNumber> Reader<'a, H, Number> where H::Out: Encode {
+	/// Create reader of the changes trie of given block, with given root.
+	///
+	/// Trie nodes are read from the storage, returned by `Storage::as_trie_backend_storage`.
+	pub fn new(storage: &'a dyn Storage<H, Number>, block: Number, root: H::Out) -> Self {
+		Reader {
+			storage,
+			trie: TrieBackendEssence::new(block_trie_storage(storage, &block), root),
+			block,
+			invalid_keys: None,
+			cancel: None,
+			node_cache: None,
+		}
+	}
+
+	/// Look up trie nodes in the shared `node_cache` before reading them from the storage.
+	pub fn with_node_cache(mut self, node_cache: Arc<NodeCache<H>>) -> Self {
+		self.trie = TrieBackendEssence::with_cache(
+			block_trie_storage(self.storage, &self.block),
+			*self.trie.root(),
+			node_cache.clone(),
+		);
+		self.node_cache = Some(node_cache);
+		self
+	}
+
+	/// Skip keys that can't be decoded, counting them in `invalid_keys` instead of failing.
+	pub fn tolerate_invalid_keys(mut self, invalid_keys: &'a AtomicU64) -> Self {
+		self.invalid_keys = Some(invalid_keys);
+		self
+	}
+
+	/// Stop reading changed keys with `Cancelled` error once the `cancel` flag is set.
+	pub fn cancellable(mut self, cancel: &'a AtomicBool) -> Self {
+		self.cancel = Some(cancel);
+		self
+	}
+
+	/// Block of the changes trie.
+	pub fn block(&self) -> &Number {
+		&self.block
+	}
+
+	/// Create reader of the child changes trie with given root, built at the same block.
+	pub fn child(&self, root: H::Out) -> Self {
+		let reader = Reader {
+			invalid_keys: self.invalid_keys,
+			cancel: self.cancel,
+			..Reader::new(self.storage, self.block.clone(), root)
+		};
+		match self.node_cache.clone() {
+			Some(node_cache) => reader.with_node_cache(node_cache),
+			None => reader,
+		}
+	}
+
+	/// Get indices of extrinsics that have changed the key in this block.
+	pub fn extrinsic_changes(&self, key: &[u8]) -> Result<Option<ExtrinsicIndexValue>, Error<Number>> {
+		self.read(&ExtrinsicIndex { block: self.block.clone(), key: key.to_vec() }.encode())
+	}
+
+	/// Get numbers of digest input blocks where the key has been changed.
+	///
+	/// Both explicit lists and bitmaps of blocks are decoded.
+	pub fn digest_blocks(&self, key: &[u8]) -> Result<Option<DigestIndexValue<Number>>, Error<Number>> {
+		let trie_key = DigestIndex { block: self.block.clone(), key: key.to_vec() }.encode();
+		match self.trie.storage(&trie_key).map_err(Error::Storage)? {
+			Some(value) => decode_block_list(&value, u32::max_value()).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	/// Get root of the child changes trie of the child storage with given key.
+	pub fn child_root(&self, storage_key: &PrefixedStorageKey) -> Result<Option<H::Out>, Error<Number>> {
+		let child_index = ChildIndex { block: self.block.clone(), storage_key: storage_key.clone() };
+		self.read::<Vec<u8>>(&child_index.encode())?
+			.map(|root| decode_root::<H, Number>(&root))
+			.transpose()
+	}
+
+	/// Get digest input blocks that haven't been included into the digest of this block.
+	pub fn missing_blocks(&self) -> Result<Option<MissingBlocksValue<Number>>, Error<Number>> {
+		self.read(&MissingBlocksIndex { block: self.block.clone() }.encode())
+	}
+
+	/// Call `f` for every child changes trie of this block.
+	pub fn for_child_roots(&self, mut f: impl FnMut(PrefixedStorageKey, H::Out)) -> Result<(), Error<Number>> {
+		let mut result = Ok(());
+		self.trie.try_for_key_values_with_prefix(&ChildIndex::key_neutral_prefix(self.block.clone()), |key, mut value| {
+			if result.is_err() {
+				return;
+			}
+			let decoded = <Vec<u8>>::decode(&mut value).map_err(Into::into)
+				.and_then(|value| decode_root::<H, Number>(&value));
+			match (InputKey::<Number>::decode(&mut &key[..]), decoded) {
+				(Ok(InputKey::ChildIndex(trie_key)), Ok(root)) => f(trie_key.storage_key, root),
+				(Ok(InputKey::ChildIndex(_)), Err(error)) => result = Err(error),
+				_ => result = self.invalid_key(key),
+			}
+		}).map_err(Error::Storage)?;
+		result
+	}
+
+	/// Call `f` for every key that has been changed in this block (`ExtrinsicIndex` entries)
+	/// and for every key that has been changed in digest input blocks (`DigestIndex` entries).
+	///
+	/// Only kinds of the trie keys are checked, so the full `InputKey` isn't decoded here.
+	/// If the reader is cancellable, the cancellation flag is checked every
+	/// `CANCEL_CHECK_INTERVAL` keys.
+	pub fn for_changed_keys(&self, mut f: impl FnMut(StorageKey)) -> Result<(), Error<Number>> {
+		let kinds = [
+			(KeyKind::ExtrinsicIndex, ExtrinsicIndex::key_neutral_prefix(self.block.clone())),
+			(KeyKind::DigestIndex, DigestIndex::key_neutral_prefix(self.block.clone())),
+		];
+		let mut read_keys = 0usize;
+		for (kind, prefix) in kinds.iter() {
+			let mut result = Ok(());
+			self.trie.try_for_keys_with_prefix(prefix, |key| {
+				read_keys += 1;
+				if read_keys % CANCEL_CHECK_INTERVAL == 0 && self.is_cancelled() {
+					result = Err(Error::Cancelled);
+					return false;
+				}
+
+				match classify_key_ref::<Number>(key) {
+					Some((key_kind, _, storage_key)) if key_kind == *kind => f(storage_key.to_vec()),
+					_ => result = self.invalid_key(key),
+				}
+				result.is_ok()
+			}).map_err(Error::Storage)?;
+			result?;
+		}
+		Ok(())
+	}
+
+	/// Returns true if the cancellation flag is set.
+	fn is_cancelled(&self) -> bool {
+		self.cancel.map(|cancel| cancel.load(Ordering::Relaxed)).unwrap_or(false)
+	}
+
+	/// Handle the key that can't be decoded: count it, or return an error if invalid keys
+	/// aren't tolerated.
+	fn invalid_key(&self, key: &[u8]) -> Result<(), Error<Number>> {
+		match self.invalid_keys {
+			Some(invalid_keys) => {
+				invalid_keys.fetch_add(1, Ordering::Relaxed);
+				Ok(())
+			},
+			None => Err(Error::InvalidInputKey { block: self.block.clone(), key: key.to_vec() }),
+		}
+	}
+
+	/// Read and decode value of given trie key.
+	fn read<V: Decode>(&self, trie_key: &[u8]) -> Result<Option<V>, Error<Number>> {
+		match self.trie.storage(trie_key).map_err(Error::Storage)? {
+			Some(value) => Ok(Some(V::decode(&mut &value[..])?)),
+			None => Ok(None),
+		}
+	}
+}
+
+/// Convert encoded changes trie root into the hash.
+///
+/// Roots are read from the storage, so the root of unexpected length is a decode error.
+fn decode_root<H: Hasher, Number: BlockNumber>(encoded: &[u8]) -> Result<H::Out, Error<Number>> {
+	let mut root = H::Out::default();
+	if root.as_ref().len() != encoded.len() {
+		return Err(Error::Decode("Invalid length of the child changes trie root".into()));
+	}
+	root.as_mut().copy_from_slice(encoded);
+	Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::Blake2Hasher;
+	use sp_core::storage::ChildInfo;
+	use crate::changes_trie::{AnchorBlockId, RootsStorage, InMemoryStorage};
+	use crate::changes_trie::input::InputPair;
+	use super::*;
+
+	#[test]
+	fn reader_decodes_changes_trie_entries() {
+		let child_key = ChildInfo::new_default(b"storage_key").prefixed_storage_key();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::with_inputs(vec![
+			(4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![100] }, vec![0, 2, 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![100] }, vec![1, 3].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![101] }, vec![2].into()),
+				InputPair::MissingBlocks(MissingBlocksIndex { block: 4 }, vec![3].into()),
+			]),
+		], vec![(child_key.clone(), vec![
+			(4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![102] }, vec![1].into()),
+			]),
+		])]);
+		let anchor = AnchorBlockId { hash: Default::default(), number: 4 };
+		let root = storage.root(&anchor, 4).unwrap().unwrap();
+		let reader = Reader::new(&storage, 4, root);
+
+		assert_eq!(reader.extrinsic_changes(&[100]), Ok(Some(vec![0, 2, 3].into())));
+		assert_eq!(reader.extrinsic_changes(&[101]), Ok(None));
+		assert_eq!(reader.digest_blocks(&[100]), Ok(Some(vec![1, 3].into())));
+		assert_eq!(reader.digest_blocks(&[101]), Ok(Some(vec![2].into())));
+		assert_eq!(reader.digest_blocks(&[102]), Ok(None));
+		assert_eq!(reader.missing_blocks(), Ok(Some(vec![3].into())));
+
+		let mut changed_keys = Vec::new();
+		reader.for_changed_keys(|key| changed_keys.push(key)).unwrap();
+		assert_eq!(changed_keys, vec![vec![100], vec![100], vec![101]]);
+
+		let mut children_roots = Vec::new();
+		reader.for_child_roots(|storage_key, root| children_roots.push((storage_key, root))).unwrap();
+		let child_root = reader.child_root(&child_key).unwrap().unwrap();
+		assert_eq!(children_roots, vec![(child_key, child_root)]);
+
+		let child_reader = reader.child(child_root);
+		assert_eq!(child_reader.extrinsic_changes(&[102]), Ok(Some(vec![1].into())));
+		assert_eq!(child_reader.extrinsic_changes(&[100]), Ok(None));
+		assert_eq!(child_reader.missing_blocks(), Ok(None));
+	}
+
+	#[test]
+	fn reader_stops_reading_changed_keys_when_cancelled() {
+		let keys_count = 2 * CANCEL_CHECK_INTERVAL as u32;
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::with_inputs(vec![
+			(4, (0..keys_count)
+				.map(|key| InputPair::ExtrinsicIndex(
+					ExtrinsicIndex { block: 4, key: key.encode() },
+					vec![0].into(),
+				))
+				.collect()),
+		], vec![]);
+		let anchor = AnchorBlockId { hash: Default::default(), number: 4 };
+		let root = storage.root(&anchor, 4).unwrap().unwrap();
+
+		let cancel = AtomicBool::new(false);
+		let mut changed_keys = 0;
+		Reader::new(&storage, 4, root).cancellable(&cancel)
+			.for_changed_keys(|_| changed_keys += 1)
+			.unwrap();
+		assert_eq!(changed_keys, keys_count);
+
+		cancel.store(true, Ordering::Relaxed);
+		let mut changed_keys = 0;
+		assert_eq!(
+			Reader::new(&storage, 4, root).cancellable(&cancel)
+				.for_changed_keys(|_| changed_keys += 1),
+			Err(Error::Cancelled),
+		);
+		assert_eq!(changed_keys, CANCEL_CHECK_INTERVAL as u32 - 1);
+	}
+}
@@ -0,0 +1,416 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rebuilding changes tries of historical blocks.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use codec::Codec;
+use hash_db::{Hasher, Prefix};
+use num_traits::One;
+use parking_lot::RwLock;
+use sp_core::storage::{ChildInfo, ChildType, PrefixedStorageKey};
+use sp_trie::{MemoryDB, DBValue};
+use crate::{StorageKey, StorageValue, InMemoryBackend};
+use crate::node_cache::NodeCache;
+use crate::changes_trie::{
+	AnchorBlockId, BlockNumber, BlockTrieStorage, BuildCache, BuildParams, CollectedChanges,
+	ConfigurationRange, DigestCache, Error, KeyKind, RootsStorage, State, Storage,
+	apply_empty_block_policy, build_changes_trie_nodes_from_source,
+};
+
+/// Changes of the single block, used to rebuild its changes trie.
+///
+/// Changes are supplied by replaying the block. Keys that have been created and then deleted
+/// within the block must not be included, because changes tries never contain such keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockChanges<Number> {
+	/// Number of the block.
+	pub block: Number,
+	/// Keys that have been changed by every extrinsic of the block. Changes that have been made
+	/// outside of extrinsics are attributed to the reserved extrinsic indices.
+	pub extrinsics: Vec<(u32, Vec<(StorageKey, KeyKind)>)>,
+	/// Changed keys that have existed before the block and have no value after it. All other
+	/// changed keys must have a value after the block.
+	pub deleted: Vec<(StorageKey, KeyKind)>,
+}
+
+/// Rebuild changes tries of the range of blocks.
+///
+/// Used to backfill changes tries after they have been enabled on the existing chain. Blocks
+/// must be given in ascending order. Tries are built the same way as on block import, using
+/// given build `params`. Every built trie is passed to the `sink` along with its block number
+/// and root, and `on_progress` is called after every processed block. The `sink` isn't called
+/// for blocks that have no root according to the `EmptyBlockPolicy`.
+///
+/// Roots and changed keys of freshly built tries are kept in memory until they're no longer
+/// required by digests, so the `sink` isn't required to commit tries to the `storage` before
+/// next block is processed. Blocks that already have changes trie roots in the `storage` (as
+/// seen from the `anchor` block) are skipped, so the interrupted backfill could be resumed.
+/// Changed keys of such blocks are read from the `storage` when they're included into digest.
+pub fn rebuild_range<H, Number>(
+	config: ConfigurationRange<Number>,
+	storage: &dyn Storage<H, Number>,
+	params: BuildParams,
+	anchor: &AnchorBlockId<H::Out, Number>,
+	blocks: impl IntoIterator<Item=BlockChanges<Number>>,
+	mut sink: impl FnMut(Number, H::Out, MemoryDB<H>),
+	mut on_progress: impl FnMut(&Number),
+) -> Result<(), Error<Number>>
+	where
+		H: Hasher,
+		H::Out: Ord + Codec + 'static,
+		Number: BlockNumber,
+{
+	let rebuild_storage = RebuildStorage {
+		storage,
+		anchor,
+		max_digest_interval: config.config.max_digest_interval(),
+		roots: RwLock::new(BTreeMap::new()),
+		cache: RwLock::new(BuildCache::new()),
+	};
+	let state = State::new(config.config.clone(), config.zero.clone(), &rebuild_storage)
+		.with_build_params(params);
+	for changes in blocks {
+		let block = changes.block.clone();
+		let is_rebuilt = storage.root(anchor, block.clone())?.is_some();
+		if !is_rebuilt {
+			// roots of previous blocks are resolved relative to the `anchor` by the storage
+			let parent = AnchorBlockId { hash: anchor.hash, number: block.clone() - One::one() };
+			let (source, backend) = block_changes_source::<H, _>(changes)?;
+			let built = build_changes_trie_nodes_from_source(
+				&backend,
+				&state,
+				config.clone(),
+				&source,
+				None,
+				&parent,
+				&mut None,
+				None,
+			)?;
+			let outcome = apply_empty_block_policy(&state, &parent, built)?;
+			let mdb = match outcome.transaction {
+				Some((mdb, cache_action)) => {
+					rebuild_storage.cache.write().perform(cache_action);
+					mdb
+				},
+				None => MemoryDB::default(),
+			};
+			if let Some(root) = outcome.root {
+				rebuild_storage.roots.write().insert(block.clone(), root);
+				sink(block.clone(), root, mdb);
+			}
+			rebuild_storage.prune(&block);
+		}
+		on_progress(&block);
+	}
+
+	Ok(())
+}
+
+/// Convert changes of the block into the source of the changes trie input and the backend that
+/// only has deleted keys, so that deleted keys could be told apart from the temporary keys.
+fn block_changes_source<H: Hasher, Number: BlockNumber>(
+	changes: BlockChanges<Number>,
+) -> Result<(CollectedChanges, InMemoryBackend<H>), Error<Number>>
+	where
+		H::Out: Ord + Codec,
+{
+	let child_info = |kind: &KeyKind| match kind {
+		KeyKind::Top => Ok(None),
+		KeyKind::Child(storage_key) => match ChildType::from_prefixed_key(storage_key) {
+			Some((ChildType::ParentKeyId, storage_key)) => Ok(Some(ChildInfo::new_default(storage_key))),
+			None => Err(Error::UnknownChildStorageKey(storage_key.clone().into_inner())),
+		},
+	};
+	let storage_key = |kind: &KeyKind| match kind {
+		KeyKind::Top => None,
+		KeyKind::Child(storage_key) => Some(storage_key.clone()),
+	};
+
+	let mut deleted = HashSet::new();
+	let mut deleted_state = HashMap::<_, BTreeMap<StorageKey, StorageValue>>::new();
+	deleted_state.insert(None, BTreeMap::new());
+	for (key, kind) in changes.deleted {
+		deleted_state.entry(child_info(&kind)?)
+			.or_default()
+			// empty values are never stored in the trie
+			.insert(key.clone(), vec![0]);
+		deleted.insert((storage_key(&kind), key));
+	}
+
+	let mut source = CollectedChanges::default();
+	for (extrinsic, keys) in changes.extrinsics {
+		for (key, kind) in keys {
+			let value_is_some = !deleted.contains(&(storage_key(&kind), key.clone()));
+			source.note_change(child_info(&kind)?.as_ref(), key, extrinsic, value_is_some);
+		}
+	}
+
+	Ok((source, deleted_state.into()))
+}
+
+/// Changes tries storage that serves roots and changed keys of the rebuilt tries, that may be
+/// not yet committed to the underlying storage.
+///
+/// Roots of previous blocks are always resolved relative to the anchor of the rebuilt range.
+struct RebuildStorage<'a, H: Hasher, Number: BlockNumber> {
+	storage: &'a dyn Storage<H, Number>,
+	anchor: &'a AnchorBlockId<H::Out, Number>,
+	max_digest_interval: u32,
+	roots: RwLock<BTreeMap<Number, H::Out>>,
+	cache: RwLock<BuildCache<H::Out, Number>>,
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> RebuildStorage<'a, H, Number> {
+	/// Forget roots that aren't required to build digests (or to reuse parent root) at blocks
+	/// after given block.
+	fn prune(&self, block: &Number) {
+		if let Some(first_required) = block.checked_sub(&self.max_digest_interval.into()) {
+			let mut roots = self.roots.write();
+			*roots = roots.split_off(&first_required);
+		}
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> RootsStorage<H, Number> for RebuildStorage<'a, H, Number> {
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, _anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		match self.roots.read().get(&block) {
+			Some(root) => Ok(Some(*root)),
+			None => self.storage.root(self.anchor, block),
+		}
+	}
+}
+
+impl<'a, H: Hasher, Number: BlockNumber> Storage<H, Number> for RebuildStorage<'a, H, Number> {
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		root: &H::Out,
+		functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		self.cache.read().with_changed_keys(root, functor)
+			|| self.storage.with_cached_changed_keys(root, functor)
+	}
+
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		self.storage.digest_cache()
+	}
+
+	// the pending digest of the underlying storage holds keys of the recently imported blocks
+	// => it is never used here
+
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		self.storage.node_cache()
+	}
+
+	fn as_trie_backend_storage(&self, block: &Number) -> Option<BlockTrieStorage<'_, H>> {
+		self.storage.as_trie_backend_storage(block)
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		self.storage.get(key, prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::Blake2Hasher;
+	use sp_core::storage::ChildInfo;
+	use crate::InMemoryBackend;
+	use crate::overlayed_changes::OverlayedChanges;
+	use crate::changes_trie::{Configuration, EmptyBlockPolicy, InMemoryStorage, try_build_changes_trie};
+	use super::*;
+
+	type Root = <Blake2Hasher as Hasher>::Out;
+
+	fn config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 2, ignored_prefixes: vec![b":hot:".to_vec()], ..Default::default() }
+	}
+
+	fn configuration_range<'a>(config: &'a Configuration) -> ConfigurationRange<'a, u64> {
+		ConfigurationRange { config, zero: 0, end: None }
+	}
+
+	fn child_info() -> ChildInfo {
+		ChildInfo::new_default(b"storage_key")
+	}
+
+	fn block_changes(block: u64) -> BlockChanges<u64> {
+		let mut extrinsics = vec![
+			(0, vec![(vec![(block % 3) as u8], KeyKind::Top), (b":hot:".to_vec(), KeyKind::Top)]),
+			(1, vec![(vec![10 + (block % 5) as u8], KeyKind::Top)]),
+		];
+		if block % 2 == 0 {
+			let child_change = (vec![(block % 7) as u8], KeyKind::Child(child_info().prefixed_storage_key()));
+			extrinsics[1].1.push(child_change);
+		}
+		if block % 3 == 0 {
+			// the same key is changed by several extrinsics
+			extrinsics[0].1.push((vec![42], KeyKind::Top));
+			extrinsics[1].1.push((vec![42], KeyKind::Top));
+		}
+		BlockChanges { block, extrinsics, deleted: Vec::new() }
+	}
+
+	/// Same as `block_changes`, but also deletes the key that has existed before the block.
+	fn block_changes_with_deletion(block: u64) -> BlockChanges<u64> {
+		let mut changes = block_changes(block);
+		let deleted_key = (vec![200 + block as u8], KeyKind::Top);
+		changes.extrinsics[1].1.push(deleted_key.clone());
+		changes.deleted.push(deleted_key);
+		changes
+	}
+
+	/// Build changes tries of blocks 1..=16 one after another, as they're built on import.
+	fn build_incrementally(
+		config: &Configuration,
+		block_changes: fn(u64) -> BlockChanges<u64>,
+	) -> Vec<(u64, Root, MemoryDB<Blake2Hasher>)> {
+		// keys that are deleted by `block_changes_with_deletion`
+		let backend: InMemoryBackend<Blake2Hasher> = (201..=216u8)
+			.map(|key| (vec![key], vec![key]))
+			.collect::<BTreeMap<_, _>>()
+			.into();
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::new();
+		let mut tries = Vec::new();
+		for block in 1..=16u64 {
+			let changes = block_changes(block);
+			let mut overlay = OverlayedChanges::default();
+			overlay.set_collect_extrinsics(true);
+			for (extrinsic, keys) in changes.extrinsics {
+				overlay.set_extrinsic_index(extrinsic).unwrap();
+				for (key, kind) in keys {
+					let value = if changes.deleted.contains(&(key.clone(), kind.clone())) {
+						None
+					} else {
+						Some(vec![1])
+					};
+					match kind {
+						KeyKind::Top => overlay.set_storage(key, value),
+						KeyKind::Child(_) => overlay.set_child_storage(&child_info(), key, value),
+					}
+				}
+			}
+
+			let state = State::new(config.clone(), 0, &storage);
+			let parent = AnchorBlockId { hash: Default::default(), number: block - 1 };
+			let (mdb, root, _) = try_build_changes_trie(&backend, Some(&state), &overlay, &parent)
+				.unwrap()
+				.unwrap();
+			storage.insert(block, root, mdb.clone());
+			tries.push((block, root, mdb));
+		}
+		tries
+	}
+
+	fn rebuild(
+		config: &Configuration,
+		storage: &InMemoryStorage<Blake2Hasher, u64>,
+		blocks: impl Iterator<Item=BlockChanges<u64>>,
+	) -> (Vec<(u64, Root)>, Vec<u64>) {
+		let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+		let mut rebuilt = Vec::new();
+		let mut processed = Vec::new();
+		rebuild_range::<Blake2Hasher, u64>(
+			configuration_range(config),
+			storage,
+			Default::default(),
+			&anchor,
+			blocks,
+			|block, root, _| rebuilt.push((block, root)),
+			|block| processed.push(*block),
+		).unwrap();
+		(rebuilt, processed)
+	}
+
+	#[test]
+	fn rebuilt_tries_match_incrementally_built_tries() {
+		let roots = build_incrementally(&config(), block_changes).into_iter()
+			.map(|(block, root, _)| (block, root))
+			.collect::<Vec<_>>();
+
+		let (rebuilt, processed) = rebuild(&config(), &InMemoryStorage::new(), (1..=16).map(block_changes));
+		assert_eq!(rebuilt, roots);
+		assert_eq!(processed, (1..=16).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn rebuilt_tries_match_incrementally_built_tries_when_deletions_are_recorded() {
+		let recording_config = Configuration { record_deletions: true, ..config() };
+		let roots = build_incrementally(&recording_config, block_changes_with_deletion).into_iter()
+			.map(|(block, root, _)| (block, root))
+			.collect::<Vec<_>>();
+
+		let blocks = || (1..=16).map(block_changes_with_deletion);
+		let (rebuilt, _) = rebuild(&recording_config, &InMemoryStorage::new(), blocks());
+		assert_eq!(rebuilt, roots);
+
+		// deletions are recorded => roots differ from roots of tries without deletions
+		let (rebuilt_without_deletions, _) = rebuild(&config(), &InMemoryStorage::new(), blocks());
+		assert!(rebuilt.iter().zip(rebuilt_without_deletions).all(|(a, b)| a.1 != b.1));
+	}
+
+	#[test]
+	fn rebuild_applies_empty_block_policy() {
+		let params = BuildParams { empty_block_policy: EmptyBlockPolicy::OmitRoot, ..Default::default() };
+		let anchor = AnchorBlockId { hash: Default::default(), number: 3 };
+		let mut rebuilt = Vec::new();
+		let blocks = vec![
+			block_changes(1),
+			BlockChanges { block: 2, extrinsics: Vec::new(), deleted: Vec::new() },
+			block_changes(3),
+		];
+		rebuild_range::<Blake2Hasher, u64>(
+			configuration_range(&Configuration::new(4, 2)),
+			&InMemoryStorage::new(),
+			params,
+			&anchor,
+			blocks,
+			|block, _, _| rebuilt.push(block),
+			|_| (),
+		).unwrap();
+		assert_eq!(rebuilt, vec![1, 3]);
+	}
+
+	#[test]
+	fn rebuild_is_resumed_from_partially_backfilled_range() {
+		let tries = build_incrementally(&config(), block_changes);
+		let roots = tries.iter().map(|(block, root, _)| (*block, *root)).collect::<Vec<_>>();
+		let storage = InMemoryStorage::new();
+		for (block, root, mdb) in tries.into_iter().take(6) {
+			storage.insert(block, root, mdb);
+		}
+
+		// already backfilled blocks are skipped
+		let (rebuilt, processed) = rebuild(&config(), &storage, (1..=16).map(block_changes));
+		assert_eq!(rebuilt, roots[6..].to_vec());
+		assert_eq!(processed, (1..=16).collect::<Vec<_>>());
+
+		// keys of already backfilled digest input blocks are read from the storage
+		let (rebuilt, processed) = rebuild(&config(), &storage, (7..=16).map(block_changes));
+		assert_eq!(rebuilt, roots[6..].to_vec());
+		assert_eq!(processed, (7..=16).collect::<Vec<_>>());
+	}
+}
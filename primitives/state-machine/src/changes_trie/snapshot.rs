@@ -0,0 +1,143 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Portable snapshots of changes tries.
+//!
+//! Snapshot holds roots of changes tries of the canonical chain and all trie nodes, so that
+//! changes tries of the real chain could be captured once and then loaded into the
+//! `InMemoryStorage` by tests and benchmarks.
+//!
+//! Encoded snapshot starts with the `SNAPSHOT_MAGIC` bytes and the SCALE-encoded version of
+//! the snapshot format. Snapshots of other versions are rejected when decoded.
+
+use codec::{Decode, Encode};
+
+/// First bytes of every encoded changes trie snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"chts";
+
+/// Current version of the changes trie snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Snapshot of changes tries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangesTrieSnapshot<Hash, Number> {
+	/// Changes tries roots of the canonical chain, ordered by block number.
+	pub roots: Vec<(Number, Hash)>,
+	/// Encoded nodes of all changes tries, ordered by encoding.
+	pub nodes: Vec<Vec<u8>>,
+}
+
+impl<Hash: Encode + Decode, Number: Encode + Decode> ChangesTrieSnapshot<Hash, Number> {
+	/// Encode snapshot, prefixed with the format header.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut encoded = SNAPSHOT_MAGIC.to_vec();
+		SNAPSHOT_VERSION.encode_to(&mut encoded);
+		(&self.roots, &self.nodes).encode_to(&mut encoded);
+		encoded
+	}
+
+	/// Decode snapshot, encoded with `encode`.
+	///
+	/// Fails if the format header is missing, or the snapshot has been encoded using other
+	/// version of the format.
+	pub fn decode(encoded: &[u8]) -> Result<Self, String> {
+		if !encoded.starts_with(&SNAPSHOT_MAGIC) {
+			return Err("Not a changes trie snapshot".into());
+		}
+		let mut input = &encoded[SNAPSHOT_MAGIC.len()..];
+		let version = u32::decode(&mut input)
+			.map_err(|e| format!("Failed to decode changes trie snapshot version: {}", e))?;
+		if version != SNAPSHOT_VERSION {
+			return Err(format!(
+				"Unsupported changes trie snapshot version {} (expected {})",
+				version,
+				SNAPSHOT_VERSION,
+			));
+		}
+
+		let (roots, nodes) = <(Vec<(Number, Hash)>, Vec<Vec<u8>>)>::decode(&mut input)
+			.map_err(|e| format!("Failed to decode changes trie snapshot: {}", e))?;
+		if !input.is_empty() {
+			return Err("Trailing bytes after the changes trie snapshot".into());
+		}
+		Ok(ChangesTrieSnapshot { roots, nodes })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::H256;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::changes_trie::{
+		AnchorBlockId, Configuration, ConfigurationRange, InMemoryStorage, RootsStorage, key_changes,
+	};
+	use crate::changes_trie::input::{InputPair, ExtrinsicIndex, DigestIndex};
+	use super::*;
+
+	#[test]
+	fn storage_is_restored_from_snapshot() {
+		let storage = InMemoryStorage::<BlakeTwo256, u64>::with_inputs(vec![
+			(1, vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: vec![42] }, vec![0].into())]),
+			(2, vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 2, key: vec![42] }, vec![1].into())]),
+			(3, vec![InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 3, key: vec![43] }, vec![0].into())]),
+			(4, vec![
+				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 4, key: vec![42] }, vec![2].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![42] }, vec![1, 2].into()),
+				InputPair::DigestIndex(DigestIndex { block: 4, key: vec![43] }, vec![3].into()),
+			]),
+		], vec![]);
+		let snapshot = storage.export();
+		assert_eq!(snapshot.roots.len(), 4);
+
+		let encoded = snapshot.encode();
+		let decoded = ChangesTrieSnapshot::<H256, u64>::decode(&encoded).unwrap();
+		assert_eq!(decoded, snapshot);
+		let restored = InMemoryStorage::<BlakeTwo256, u64>::from_snapshot(decoded);
+		assert_eq!(restored.export(), snapshot);
+
+		let config = Configuration { digest_interval: 4, digest_levels: 1, ..Default::default() };
+		let anchor = AnchorBlockId { hash: Default::default(), number: 4 };
+		for storage in vec![&storage, &restored] {
+			assert_eq!(storage.roots(&anchor, &[1, 2, 3, 4]).unwrap().len(), 4);
+			let changes = key_changes::<BlakeTwo256, u64>(
+				ConfigurationRange { config: &config, zero: 0, end: None },
+				storage, 1, &anchor, 4, None, &[42],
+			).and_then(|changes| changes.collect::<Result<Vec<_>, _>>());
+			assert_eq!(changes, Ok(vec![(4, 2), (2, 1), (1, 0)]));
+		}
+	}
+
+	#[test]
+	fn snapshots_of_other_versions_are_rejected() {
+		let snapshot = ChangesTrieSnapshot::<H256, u64> {
+			roots: vec![(1, H256::repeat_byte(1))],
+			nodes: vec![vec![1, 2, 3]],
+		};
+		let encoded = snapshot.encode();
+		assert_eq!(&encoded[..4], b"chts");
+
+		let mut other_version = encoded.clone();
+		other_version[4] = 2;
+		assert_eq!(
+			ChangesTrieSnapshot::<H256, u64>::decode(&other_version),
+			Err("Unsupported changes trie snapshot version 2 (expected 1)".into()),
+		);
+		assert!(ChangesTrieSnapshot::<H256, u64>::decode(&encoded[4..]).is_err());
+		assert!(ChangesTrieSnapshot::<H256, u64>::decode(&encoded[..encoded.len() - 1]).is_err());
+		assert!(ChangesTrieSnapshot::<H256, u64>::decode(&[&encoded[..], &[0][..]].concat()).is_err());
+	}
+}
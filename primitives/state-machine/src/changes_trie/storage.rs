@@ -17,27 +17,50 @@
 
 //! Changes trie storage utilities.
 
-use std::collections::{BTreeMap, HashSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashSet, HashMap};
+use num_traits::One;
 use hash_db::{Hasher, Prefix, EMPTY_PREFIX};
 use sp_core::storage::PrefixedStorageKey;
 use sp_trie::DBValue;
-use sp_trie::MemoryDB;
+use sp_trie::{MemoryDB, Layout, Recorder, TrieMut, read_trie_value, read_trie_value_with};
+use sp_trie::trie_types::TrieDBMut;
 use parking_lot::RwLock;
 use crate::{
 	StorageKey,
-	trie_backend_essence::TrieBackendStorage,
-	changes_trie::{BuildCache, RootsStorage, Storage, AnchorBlockId, BlockNumber},
+	trie_backend_essence::{TrieBackendStorage, Ephemeral},
+	changes_trie::{
+		BuildCache, DigestCache, PendingDigest, RootsStorage, Storage, AnchorBlockId, BlockNumber, empty_root,
+		ChangesTrieSnapshot, Error,
+	},
+	node_cache::NodeCache,
 };
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[cfg(test)]
 use crate::backend::insert_into_memory_db;
-#[cfg(test)]
 use crate::changes_trie::input::{InputPair, ChildIndex};
 
+/// Identifier of the fork in the `InMemoryStorage`.
+pub type ForkId = u32;
+
+/// Fork of the `InMemoryStorage` that is canonical by default.
+pub const MAIN_FORK: ForkId = 0;
+
 /// In-memory implementation of changes trie storage.
+///
+/// Roots of changes tries could be inserted into multiple forks. Every fork except the main
+/// fork starts at its lowest block. Roots of blocks below this block are read from the main fork.
+/// Roots are read from the fork that contains the anchor block (block hash is the changes trie
+/// root here). If no fork contains the anchor block, roots are read from the canonical fork.
+///
+/// Anchors are built for blocks of the canonical chain, whose changes trie root is equal to the
+/// block hash, unless the hash has been inserted with `insert_block_hash`.
 pub struct InMemoryStorage<H: Hasher, Number: BlockNumber> {
 	data: RwLock<InMemoryStorageData<H, Number>>,
 	cache: BuildCache<H::Out, Number>,
+	digest_cache: Option<DigestCache<H::Out, Number>>,
+	pending_digest: Option<PendingDigest<Number>>,
+	node_cache: Option<Arc<NodeCache<H>>>,
 }
 
 /// Adapter for using changes trie storage as a TrieBackendEssence' storage.
@@ -47,19 +70,79 @@ pub struct TrieBackendAdapter<'a, H: Hasher, Number: BlockNumber> {
 }
 
 struct InMemoryStorageData<H: Hasher, Number: BlockNumber> {
-	roots: BTreeMap<Number, H::Out>,
+	forks: BTreeMap<ForkId, BTreeMap<Number, H::Out>>,
+	canonical_fork: ForkId,
+	block_hashes: HashMap<H::Out, Number>,
 	mdb: MemoryDB<H>,
 }
 
+impl<H: Hasher, Number: BlockNumber> InMemoryStorageData<H, Number> {
+	fn new(roots: BTreeMap<Number, H::Out>, mdb: MemoryDB<H>) -> Self {
+		InMemoryStorageData {
+			forks: vec![(MAIN_FORK, roots)].into_iter().collect(),
+			canonical_fork: MAIN_FORK,
+			block_hashes: HashMap::new(),
+			mdb,
+		}
+	}
+
+	/// Returns roots of given fork, unless it is the main fork.
+	fn fork_roots(&self, fork: ForkId) -> Option<&BTreeMap<Number, H::Out>> {
+		if fork == MAIN_FORK {
+			return None;
+		}
+
+		self.forks.get(&fork)
+	}
+
+	/// Returns fork of the canonical chain, unless it is the main fork.
+	fn canonical_fork_roots(&self) -> Option<&BTreeMap<Number, H::Out>> {
+		self.fork_roots(self.canonical_fork)
+	}
+
+	/// Returns root of the given block of given fork.
+	fn fork_root(&self, fork: ForkId, block: &Number) -> Option<H::Out> {
+		if let Some(fork) = self.fork_roots(fork) {
+			if let Some(root) = fork.get(block) {
+				return Some(*root);
+			}
+			if fork.keys().next().map(|fork_begin| block >= fork_begin).unwrap_or(false) {
+				return None;
+			}
+		}
+
+		self.forks.get(&MAIN_FORK).and_then(|roots| roots.get(block)).cloned()
+	}
+
+	/// Returns fork that contains the anchor block. The canonical fork is preferred if
+	/// multiple forks contain the anchor block. If there's no such fork, returns the canonical fork.
+	fn anchor_fork(&self, anchor: &AnchorBlockId<H::Out, Number>) -> ForkId {
+		std::iter::once(self.canonical_fork)
+			.chain(self.forks.keys().cloned().filter(|fork| *fork != self.canonical_fork))
+			.find(|fork| self.fork_root(*fork, &anchor.number).as_ref() == Some(&anchor.hash))
+			.unwrap_or(self.canonical_fork)
+	}
+
+	/// Iterate over all roots of the canonical chain.
+	fn canonical_roots(&self) -> impl Iterator<Item=(&Number, &H::Out)> {
+		let fork = self.canonical_fork_roots();
+		let fork_begin = fork.and_then(|fork| fork.keys().next());
+		self.forks.get(&MAIN_FORK).into_iter()
+			.flat_map(|roots| roots.iter())
+			.take_while(move |(block, _)| fork_begin.map(|fork_begin| *block < fork_begin).unwrap_or(true))
+			.chain(fork.into_iter().flat_map(|roots| roots.iter()))
+	}
+}
+
 impl<H: Hasher, Number: BlockNumber> InMemoryStorage<H, Number> {
 	/// Creates storage from given in-memory database.
 	pub fn with_db(mdb: MemoryDB<H>) -> Self {
 		Self {
-			data: RwLock::new(InMemoryStorageData {
-				roots: BTreeMap::new(),
-				mdb,
-			}),
+			data: RwLock::new(InMemoryStorageData::new(BTreeMap::new(), mdb)),
 			cache: BuildCache::new(),
+			digest_cache: None,
+			pending_digest: None,
+			node_cache: None,
 		}
 	}
 
@@ -70,13 +153,7 @@ impl<H: Hasher, Number: BlockNumber> InMemoryStorage<H, Number> {
 
 	/// Creates storage with given proof.
 	pub fn with_proof(proof: Vec<Vec<u8>>) -> Self {
-		use hash_db::HashDB;
-
- 		let mut proof_db = MemoryDB::<H>::default();
-		for item in proof {
-			proof_db.insert(EMPTY_PREFIX, &item);
-		}
-		Self::with_db(proof_db)
+		Self::with_db(proof_db(proof))
 	}
 
 	/// Get mutable cache reference.
@@ -84,21 +161,66 @@ impl<H: Hasher, Number: BlockNumber> InMemoryStorage<H, Number> {
 		&mut self.cache
 	}
 
+	/// Set cache of keys, read from changes tries of digest input blocks.
+	pub fn set_digest_cache(&mut self, digest_cache: Option<DigestCache<H::Out, Number>>) {
+		self.digest_cache = digest_cache;
+	}
+
+	/// Set keys that have been changed in recent blocks.
+	pub fn set_pending_digest(&mut self, pending_digest: Option<PendingDigest<Number>>) {
+		self.pending_digest = pending_digest;
+	}
+
+	/// Set cache of trie nodes, shared by readers of digest input tries.
+	pub fn set_node_cache(&mut self, node_cache: Option<Arc<NodeCache<H>>>) {
+		self.node_cache = node_cache;
+	}
+
 	/// Create the storage with given blocks.
 	pub fn with_blocks(blocks: Vec<(Number, H::Out)>) -> Self {
 		Self {
-			data: RwLock::new(InMemoryStorageData {
-				roots: blocks.into_iter().collect(),
-				mdb: MemoryDB::default(),
-			}),
+			data: RwLock::new(InMemoryStorageData::new(blocks.into_iter().collect(), MemoryDB::default())),
 			cache: BuildCache::new(),
+			digest_cache: None,
+			pending_digest: None,
+			node_cache: None,
 		}
 	}
 
+	/// Create the storage with given inputs of the main fork.
+	///
+	/// Panics if the same block is listed twice in the top inputs or in inputs of the same child.
 	#[cfg(test)]
 	pub fn with_inputs(
+		top_inputs: Vec<(Number, Vec<InputPair<Number>>)>,
+		children_inputs: Vec<(PrefixedStorageKey, Vec<(Number, Vec<InputPair<Number>>)>)>,
+	) -> Self {
+		let mut builder = InMemoryStorageBuilder::new();
+		for (block, pairs) in top_inputs {
+			builder = builder.block(block, pairs);
+		}
+		for (storage_key, child_inputs) in children_inputs {
+			for (block, pairs) in child_inputs {
+				builder = builder.child_block(storage_key.clone(), block, pairs);
+			}
+		}
+		builder.build()
+	}
+
+	/// Create the storage with given inputs of the main fork and given inputs of other forks.
+	#[cfg(test)]
+	pub fn with_forked_inputs(
+		top_inputs: Vec<(Number, Vec<InputPair<Number>>)>,
+		children_inputs: Vec<(PrefixedStorageKey, Vec<(Number, Vec<InputPair<Number>>)>)>,
+		forks_inputs: Vec<(ForkId, Vec<(Number, Vec<InputPair<Number>>)>)>,
+	) -> Self {
+		Self::from_inputs(top_inputs, children_inputs, forks_inputs)
+	}
+
+	fn from_inputs(
 		mut top_inputs: Vec<(Number, Vec<InputPair<Number>>)>,
 		children_inputs: Vec<(PrefixedStorageKey, Vec<(Number, Vec<InputPair<Number>>)>)>,
+		forks_inputs: Vec<(ForkId, Vec<(Number, Vec<InputPair<Number>>)>)>,
 	) -> Self {
 		let mut mdb = MemoryDB::default();
 		let mut roots = BTreeMap::new();
@@ -128,12 +250,25 @@ impl<H: Hasher, Number: BlockNumber> InMemoryStorage<H, Number> {
 			}
 		}
 
+		let mut forks = BTreeMap::new();
+		for (fork, fork_inputs) in forks_inputs {
+			let fork_roots: &mut BTreeMap<_, _> = forks.entry(fork).or_default();
+			for (block, pairs) in fork_inputs {
+				let root = insert_into_memory_db::<H, _>(&mut mdb, pairs.into_iter().map(Into::into));
+				if let Some(root) = root {
+					fork_roots.insert(block, root);
+				}
+			}
+		}
+
+		let mut data = InMemoryStorageData::new(roots, mdb);
+		data.forks.extend(forks);
 		InMemoryStorage {
-			data: RwLock::new(InMemoryStorageData {
-				roots,
-				mdb,
-			}),
+			data: RwLock::new(data),
 			cache: BuildCache::new(),
+			digest_cache: None,
+			pending_digest: None,
+			node_cache: None,
 		}
 	}
 
@@ -155,24 +290,231 @@ impl<H: Hasher, Number: BlockNumber> InMemoryStorage<H, Number> {
 		self.data.into_inner().mdb
 	}
 
-	/// Insert changes trie for given block.
+	/// Export roots of the canonical chain and all trie nodes into the portable snapshot.
+	///
+	/// Only roots of the canonical chain are exported, but nodes of all forks are included.
+	pub fn export(&self) -> ChangesTrieSnapshot<H::Out, Number> {
+		let data = self.data.read();
+		let roots = data.canonical_roots()
+			.map(|(block, root)| (block.clone(), *root))
+			.collect();
+		let mut nodes = data.mdb.clone().drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(_, (node, _))| node)
+			.collect::<Vec<_>>();
+		nodes.sort();
+		ChangesTrieSnapshot { roots, nodes }
+	}
+
+	/// Create storage from the snapshot, exported with `export`.
+	pub fn from_snapshot(snapshot: ChangesTrieSnapshot<H::Out, Number>) -> Self {
+		Self {
+			data: RwLock::new(InMemoryStorageData::new(
+				snapshot.roots.into_iter().collect(),
+				proof_db(snapshot.nodes),
+			)),
+			cache: BuildCache::new(),
+			digest_cache: None,
+			pending_digest: None,
+			node_cache: None,
+		}
+	}
+
+	/// Insert changes trie for given block of the canonical chain.
 	pub fn insert(&self, block: Number, changes_trie_root: H::Out, trie: MemoryDB<H>) {
+		let fork = self.data.read().canonical_fork;
+		self.insert_at(fork, block, changes_trie_root, trie);
+	}
+
+	/// Insert changes trie of given block of the canonical chain, built by the changes trie builder.
+	///
+	/// Unlike `insert`, panics if the root of this block is already known to the storage.
+	pub fn insert_built(&self, block: Number, changes_trie_root: H::Out, trie: MemoryDB<H>) {
+		let known_root = {
+			let data = self.data.read();
+			data.fork_root(data.canonical_fork, &block)
+		};
+		if let Some(known_root) = known_root {
+			panic!(
+				"Changes trie root of block {} is already inserted: {:?}. Refusing to replace it with {:?}",
+				block,
+				known_root,
+				changes_trie_root,
+			);
+		}
+		self.insert(block, changes_trie_root, trie);
+	}
+
+	/// Insert changes trie for given block of given fork.
+	pub fn insert_at(&self, fork: ForkId, block: Number, changes_trie_root: H::Out, trie: MemoryDB<H>) {
 		let mut data = self.data.write();
-		data.roots.insert(block, changes_trie_root);
+		data.forks.entry(fork).or_default().insert(block, changes_trie_root);
 		data.mdb.consolidate(trie);
 	}
+
+	/// Remember number of the block with given hash. The number is used when anchor is built for
+	/// this block.
+	pub fn insert_block_hash(&self, hash: H::Out, block: Number) {
+		self.data.write().block_hashes.insert(hash, block);
+	}
+
+	/// Make given fork canonical. Roots storage answers for the canonical chain only.
+	pub fn switch_fork(&self, fork: ForkId) {
+		self.data.write().canonical_fork = fork;
+	}
+
+	/// Remove changes trie root of given block of the canonical chain.
+	///
+	/// Trie nodes are kept in the storage.
+	pub fn remove(&self, block: &Number) {
+		let mut data = self.data.write();
+		let fork = match data.canonical_fork_roots().map(|fork| fork.contains_key(block)) {
+			Some(true) => data.canonical_fork,
+			_ => MAIN_FORK,
+		};
+		if let Some(roots) = data.forks.get_mut(&fork) {
+			roots.remove(block);
+		}
+	}
+
+	/// Remove all changes tries roots and nodes from the storage.
+	pub fn clear(&self) {
+		let mut data = self.data.write();
+		data.forks.values_mut().for_each(|roots| roots.clear());
+		data.mdb = MemoryDB::default();
+	}
+}
+
+/// Builder of the `InMemoryStorage` from changes tries inputs.
+///
+/// Every block may only be added once (once per child trie for child inputs), so that inputs
+/// of the same block are never silently replaced.
+pub struct InMemoryStorageBuilder<H: Hasher, Number: BlockNumber> {
+	top_inputs: BTreeMap<Number, Vec<InputPair<Number>>>,
+	children_inputs: BTreeMap<PrefixedStorageKey, BTreeMap<Number, Vec<InputPair<Number>>>>,
+	require_contiguous: bool,
+	_hasher: std::marker::PhantomData<H>,
+}
+
+impl<H: Hasher, Number: BlockNumber> InMemoryStorageBuilder<H, Number> {
+	/// Create new empty builder.
+	pub fn new() -> Self {
+		InMemoryStorageBuilder {
+			top_inputs: BTreeMap::new(),
+			children_inputs: BTreeMap::new(),
+			require_contiguous: false,
+			_hasher: Default::default(),
+		}
+	}
+
+	/// Add top-level changes trie input of given block.
+	///
+	/// Panics if input of this block has already been added.
+	pub fn block(mut self, block: Number, pairs: Vec<InputPair<Number>>) -> Self {
+		if self.top_inputs.contains_key(&block) {
+			panic!("Changes trie input of block {} is added twice", block);
+		}
+		self.top_inputs.insert(block, pairs);
+		self
+	}
+
+	/// Add changes trie input of given child trie at given block.
+	///
+	/// Panics if input of this child trie at this block has already been added.
+	pub fn child_block(
+		mut self,
+		storage_key: PrefixedStorageKey,
+		block: Number,
+		pairs: Vec<InputPair<Number>>,
+	) -> Self {
+		let child_inputs = self.children_inputs.entry(storage_key.clone()).or_default();
+		if child_inputs.contains_key(&block) {
+			panic!(
+				"Changes trie input of child {} at block {} is added twice",
+				sp_core::hexdisplay::HexDisplay::from(&storage_key.into_inner()),
+				block,
+			);
+		}
+		child_inputs.insert(block, pairs);
+		self
+	}
+
+	/// Require added blocks to form a contiguous range. `build` panics if there are gaps.
+	pub fn require_contiguous(mut self) -> Self {
+		self.require_contiguous = true;
+		self
+	}
+
+	/// Build the storage.
+	pub fn build(self) -> InMemoryStorage<H, Number> {
+		if self.require_contiguous {
+			let blocks = self.top_inputs.keys()
+				.chain(self.children_inputs.values().flat_map(|inputs| inputs.keys()))
+				.collect::<BTreeSet<_>>();
+			let mut blocks = blocks.into_iter();
+			if let Some(mut prev_block) = blocks.next() {
+				for block in blocks {
+					if *block != prev_block.clone() + One::one() {
+						panic!(
+							"Changes trie inputs are not contiguous: block {} is followed by block {}",
+							prev_block,
+							block,
+						);
+					}
+					prev_block = block;
+				}
+			}
+		}
+
+		InMemoryStorage::from_inputs(
+			self.top_inputs.into_iter().collect(),
+			self.children_inputs.into_iter()
+				.map(|(storage_key, inputs)| (storage_key, inputs.into_iter().collect()))
+				.collect(),
+			Vec::new(),
+		)
+	}
+}
+
+impl<H: Hasher, Number: BlockNumber> Default for InMemoryStorageBuilder<H, Number> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<H: Hasher, Number: BlockNumber> Default for InMemoryStorage<H, Number> {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl<H: Hasher, Number: BlockNumber> RootsStorage<H, Number> for InMemoryStorage<H, Number> {
-	fn build_anchor(&self, parent_hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, String> {
-		self.data.read().roots.iter()
+	fn build_anchor(&self, parent_hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		let data = self.data.read();
+		if let Some(number) = data.block_hashes.get(&parent_hash) {
+			return Ok(AnchorBlockId { hash: parent_hash, number: number.clone() });
+		}
+
+		let anchor = data.canonical_roots()
 			.find(|(_, v)| **v == parent_hash)
 			.map(|(k, _)| AnchorBlockId { hash: parent_hash, number: k.clone() })
-			.ok_or_else(|| format!("Can't find associated number for block {:?}", parent_hash))
+			.ok_or_else(|| Error::UnknownBlock(parent_hash.as_ref().to_vec()));
+		anchor
 	}
 
-	fn root(&self, _anchor_block: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, String> {
-		Ok(self.data.read().roots.get(&block).cloned())
+	fn root(&self, anchor_block: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		let data = self.data.read();
+		Ok(data.fork_root(data.anchor_fork(anchor_block), &block))
+	}
+
+	fn roots(
+		&self,
+		anchor_block: &AnchorBlockId<H::Out, Number>,
+		blocks: &[Number],
+	) -> Result<Vec<Option<H::Out>>, Error<Number>> {
+		let data = self.data.read();
+		let fork = data.anchor_fork(anchor_block);
+		Ok(blocks.iter().map(|block| data.fork_root(fork, block)).collect())
 	}
 }
 
@@ -189,8 +531,20 @@ impl<H: Hasher, Number: BlockNumber> Storage<H, Number> for InMemoryStorage<H, N
 		self.cache.with_changed_keys(root, functor)
 	}
 
-	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
-		MemoryDB::<H>::get(&self.data.read().mdb, key, prefix)
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		self.digest_cache.as_ref()
+	}
+
+	fn pending_digest(&self) -> Option<&PendingDigest<Number>> {
+		self.pending_digest.as_ref()
+	}
+
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		self.node_cache.clone()
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		MemoryDB::<H>::get(&self.data.read().mdb, key, prefix).map_err(Error::Storage)
 	}
 }
 
@@ -208,6 +562,1075 @@ impl<'a, H, Number> TrieBackendStorage<H> for TrieBackendAdapter<'a, H, Number>
 	type Overlay = MemoryDB<H>;
 
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		self.storage.get(key, prefix).map_err(|e| e.to_string())
+	}
+}
+
+/// Changes trie storage that records all trie nodes that are read from the inner storage.
+///
+/// Recorded nodes could be used to construct `ProofCheckStorage` that replays the same reads
+/// without the inner storage.
+pub struct ProofRecorder<S, H: Hasher> {
+	storage: S,
+	proof: RwLock<HashMap<H::Out, DBValue>>,
+}
+
+impl<S, H: Hasher> ProofRecorder<S, H> {
+	/// Create recorder that wraps given storage.
+	pub fn new(storage: S) -> Self {
+		ProofRecorder {
+			storage,
+			proof: RwLock::new(HashMap::new()),
+		}
+	}
+
+	/// Consume self and return the inner storage.
+	pub fn into_inner(self) -> S {
+		self.storage
+	}
+
+	/// Get all trie nodes that have been read so far.
+	pub fn extract_proof(&self) -> Vec<Vec<u8>> {
+		self.proof.read().values().cloned().collect()
+	}
+
+	/// Record trie node that has been read from the inner storage.
+	fn record<E>(
+		&self,
+		key: &H::Out,
+		value: Result<Option<DBValue>, E>,
+	) -> Result<Option<DBValue>, E> {
+		if let Ok(Some(value)) = value.as_ref() {
+			self.proof.write().insert(*key, value.clone());
+		}
+		value
+	}
+}
+
+impl<S, H, Number> RootsStorage<H, Number> for ProofRecorder<S, H>
+	where
+		S: RootsStorage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		self.storage.root(anchor, block)
+	}
+
+	fn roots(
+		&self,
+		anchor: &AnchorBlockId<H::Out, Number>,
+		blocks: &[Number],
+	) -> Result<Vec<Option<H::Out>>, Error<Number>> {
+		self.storage.roots(anchor, blocks)
+	}
+}
+
+impl<S, H, Number> Storage<H, Number> for ProofRecorder<S, H>
+	where
+		S: Storage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		_root: &H::Out,
+		_functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		// cached keys are not read from the trie => the cache is bypassed to record trie nodes
+		false
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		self.record(key, self.storage.get(key, prefix))
+	}
+}
+
+impl<S, H> TrieBackendStorage<H> for ProofRecorder<S, H>
+	where
+		S: TrieBackendStorage<H>,
+		H: Hasher,
+{
+	type Overlay = S::Overlay;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		self.record(key, self.storage.get(key, prefix))
+	}
+}
+
+/// Changes trie storage that answers all requests using the proof, recorded by `ProofRecorder`.
+///
+/// Reading a trie node that isn't included into the proof is an error.
+pub struct ProofCheckStorage<H: Hasher, Number: BlockNumber> {
+	proof_db: MemoryDB<H>,
+	root: Box<dyn Fn(Number) -> Result<Option<H::Out>, String> + Send + Sync>,
+}
+
+impl<H: Hasher, Number: BlockNumber> ProofCheckStorage<H, Number> {
+	/// Create storage from the proof and the function that returns changes trie root of the block.
+	pub fn new(
+		proof: Vec<Vec<u8>>,
+		root: impl Fn(Number) -> Result<Option<H::Out>, String> + Send + Sync + 'static,
+	) -> Self {
+		ProofCheckStorage {
+			proof_db: proof_db(proof),
+			root: Box::new(root),
+		}
+	}
+}
+
+impl<H: Hasher, Number: BlockNumber> RootsStorage<H, Number> for ProofCheckStorage<H, Number> {
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		Err(Error::UnknownBlock(hash.as_ref().to_vec()))
+	}
+
+	fn root(&self, _anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		(self.root)(block).map_err(Error::Storage)
+	}
+}
+
+impl<H: Hasher, Number: BlockNumber> Storage<H, Number> for ProofCheckStorage<H, Number> {
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		_root: &H::Out,
+		_functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		false
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		match MemoryDB::<H>::get(&self.proof_db, key, prefix).map_err(Error::Storage)? {
+			Some(value) => Ok(Some(value)),
+			None => Err(Error::MissingTrieNode(key.as_ref().to_vec())),
+		}
+	}
+}
+
+/// Number of shards of the `CachedStorage` roots cache.
+const CACHED_STORAGE_SHARDS: usize = 16;
+
+/// Changes trie storage that caches changes tries roots and trie nodes, read from the inner
+/// storage.
+///
+/// The storage is meant to be shared by the thread that builds changes tries and threads that
+/// are querying them. The roots cache is split into shards, each guarded by its own lock, so
+/// that concurrent readers rarely wait for each other. Trie nodes are cached in the LRU
+/// `NodeCache`, which is also returned by `Storage::node_cache`, so trie backends that are
+/// reading changes tries from this storage share the same cache.
+///
+/// Trie nodes are cached by their hash, so cached nodes are never stale. Cached roots become
+/// stale when blocks are reverted or a fork becomes canonical: `invalidate_from` must be called
+/// after the inner storage has been updated, or `invalidate_fork` if only roots that have been
+/// read using the single anchor block are affected. Lookups that have been started before the
+/// invalidation never put their results into the cache. Missing roots are never cached.
+///
+/// The inner storage is never accessed while the cache shard is locked.
+pub struct CachedStorage<S, H: Hasher, Number: BlockNumber> {
+	storage: S,
+	/// Incremented on every invalidation.
+	epoch: AtomicU64,
+	root_hits: AtomicU64,
+	root_misses: AtomicU64,
+	roots: Vec<RwLock<HashMap<(H::Out, Number, Number), H::Out>>>,
+	nodes: Arc<NodeCache<H>>,
+}
+
+impl<S, H: Hasher, Number: BlockNumber> CachedStorage<S, H, Number> {
+	/// Create storage that wraps given storage and caches at most `max_nodes` trie nodes.
+	pub fn new(storage: S, max_nodes: usize) -> Self {
+		CachedStorage {
+			storage,
+			epoch: AtomicU64::new(0),
+			root_hits: AtomicU64::new(0),
+			root_misses: AtomicU64::new(0),
+			roots: (0..CACHED_STORAGE_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+			nodes: Arc::new(NodeCache::new(max_nodes)),
+		}
+	}
+
+	/// Get reference to the inner storage.
+	pub fn inner(&self) -> &S {
+		&self.storage
+	}
+
+	/// Consume self and return the inner storage.
+	pub fn into_inner(self) -> S {
+		self.storage
+	}
+
+	/// Number of roots that have been served from the cache.
+	pub fn root_hits(&self) -> u64 {
+		self.root_hits.load(Ordering::Relaxed)
+	}
+
+	/// Number of roots that have been read from the inner storage.
+	pub fn root_misses(&self) -> u64 {
+		self.root_misses.load(Ordering::Relaxed)
+	}
+
+	/// Forget cached roots of the given block and all blocks after it, and all roots that have
+	/// been read using anchors at these blocks.
+	pub fn invalidate_from(&self, block: &Number) {
+		self.epoch.fetch_add(1, Ordering::SeqCst);
+		for shard in &self.roots {
+			shard.write().retain(|(_, anchor, root_block), _| anchor < block && root_block < block);
+		}
+	}
+
+	/// Forget cached roots that have been read using the anchor block with given hash.
+	pub fn invalidate_fork(&self, anchor_hash: &H::Out) {
+		self.epoch.fetch_add(1, Ordering::SeqCst);
+		self.roots[shard_index(anchor_hash)].write().retain(|(hash, _, _), _| hash != anchor_hash);
+	}
+
+	/// Get cached root of given block, or read it from the inner storage.
+	fn cached_root(
+		&self,
+		anchor: &AnchorBlockId<H::Out, Number>,
+		block: Number,
+		read: impl FnOnce(Number) -> Result<Option<H::Out>, Error<Number>>,
+	) -> Result<Option<H::Out>, Error<Number>> {
+		let key = (anchor.hash, anchor.number.clone(), block);
+		let shard = &self.roots[shard_index(&key.0)];
+		if let Some(root) = shard.read().get(&key) {
+			self.root_hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(Some(*root));
+		}
+
+		self.root_misses.fetch_add(1, Ordering::Relaxed);
+		let epoch = self.epoch.load(Ordering::SeqCst);
+		let root = read(key.2.clone())?;
+		if let Some(root) = root.as_ref() {
+			let mut shard = shard.write();
+			// the shard is locked here, so it'll be cleared if invalidation starts right now
+			if self.epoch.load(Ordering::SeqCst) == epoch {
+				shard.insert(key, *root);
+			}
+		}
+		Ok(root)
+	}
+
+	/// Get cached trie node, or read it from the inner storage.
+	fn cached_node(
+		&self,
+		key: &H::Out,
+		read: impl FnOnce() -> Result<Option<DBValue>, Error<Number>>,
+	) -> Result<Option<DBValue>, Error<Number>> {
+		if let Some(node) = self.nodes.get(key) {
+			return Ok(Some(node));
+		}
+
+		let node = read()?;
+		if let Some(node) = node.as_ref() {
+			self.nodes.insert(*key, node.clone());
+		}
+		Ok(node)
+	}
+}
+
+/// Index of the `CachedStorage` roots cache shard for given hash.
+fn shard_index<Hash: AsRef<[u8]>>(hash: &Hash) -> usize {
+	hash.as_ref().last().map(|byte| *byte as usize % CACHED_STORAGE_SHARDS).unwrap_or(0)
+}
+
+impl<S, H, Number> RootsStorage<H, Number> for CachedStorage<S, H, Number>
+	where
+		S: RootsStorage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		self.cached_root(anchor, block, |block| self.storage.root(anchor, block))
+	}
+}
+
+impl<S, H, Number> Storage<H, Number> for CachedStorage<S, H, Number>
+	where
+		S: Storage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		root: &H::Out,
+		functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		self.storage.with_cached_changed_keys(root, functor)
+	}
+
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		self.storage.digest_cache()
+	}
+
+	fn pending_digest(&self) -> Option<&PendingDigest<Number>> {
+		self.storage.pending_digest()
+	}
+
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		Some(self.nodes.clone())
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		self.cached_node(key, || self.storage.get(key, prefix))
+	}
+}
+
+/// Changes trie storage that layers changes tries of not yet committed blocks over the inner
+/// storage.
+///
+/// When several blocks are built speculatively on top of each other, changes tries of these
+/// blocks aren't in the inner storage yet, but they're required to build digests of following
+/// blocks. Roots and nodes of overlayed tries are read before the inner storage is consulted.
+pub struct OverlayedChangesTrieStorage<S, H: Hasher, Number: BlockNumber> {
+	storage: S,
+	overlay: RwLock<BTreeMap<Number, (H::Out, MemoryDB<H>)>>,
+}
+
+impl<S, H: Hasher, Number: BlockNumber> OverlayedChangesTrieStorage<S, H, Number> {
+	/// Create storage without overlayed changes tries.
+	pub fn new(storage: S) -> Self {
+		OverlayedChangesTrieStorage {
+			storage,
+			overlay: RwLock::new(BTreeMap::new()),
+		}
+	}
+
+	/// Get reference to the inner storage.
+	pub fn inner(&self) -> &S {
+		&self.storage
+	}
+
+	/// Add changes trie of the not yet committed block, replacing previous trie of this block.
+	pub fn insert(&self, block: Number, root: H::Out, trie: MemoryDB<H>) {
+		self.overlay.write().insert(block, (root, trie));
+	}
+
+	/// Remove changes trie of the block from the overlay, returning its root and nodes. These
+	/// should be inserted into the inner storage (e.g. using `DbStorageTransaction::insert_trie`)
+	/// once the block is committed.
+	pub fn commit(&self, block: &Number) -> Option<(H::Out, MemoryDB<H>)> {
+		self.overlay.write().remove(block)
+	}
+
+	/// Forget changes tries of the abandoned block and all blocks that have been built on top
+	/// of it.
+	pub fn revert(&self, block: &Number) {
+		self.overlay.write().split_off(block);
+	}
+
+	/// Get numbers of blocks whose changes tries are in the overlay.
+	pub fn overlayed_blocks(&self) -> Vec<Number> {
+		self.overlay.read().keys().cloned().collect()
+	}
+}
+
+impl<S, H, Number> RootsStorage<H, Number> for OverlayedChangesTrieStorage<S, H, Number>
+	where
+		S: RootsStorage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		self.storage.build_anchor(hash)
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		match self.overlay.read().get(&block) {
+			Some((root, _)) => Ok(Some(*root)),
+			None => self.storage.root(anchor, block),
+		}
+	}
+}
+
+impl<S, H, Number> Storage<H, Number> for OverlayedChangesTrieStorage<S, H, Number>
+	where
+		S: Storage<H, Number>,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		root: &H::Out,
+		functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		self.storage.with_cached_changed_keys(root, functor)
+	}
+
+	fn digest_cache(&self) -> Option<&DigestCache<H::Out, Number>> {
+		self.storage.digest_cache()
+	}
+
+	// pending digest of the inner storage may have noted other versions of overlayed blocks,
+	// so keys of digest input blocks are always read from their tries
+
+	fn node_cache(&self) -> Option<Arc<NodeCache<H>>> {
+		self.storage.node_cache()
+	}
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		for (_, trie) in self.overlay.read().values() {
+			if let Some(node) = hash_db::HashDB::get(trie, key, prefix) {
+				return Ok(Some(node));
+			}
+		}
 		self.storage.get(key, prefix)
 	}
 }
+
+/// Minimal key-value database that may be used by the `DbStorage`.
+pub trait KvBackend: Send + Sync {
+	/// Get value of the key from given column.
+	fn get(&self, col: u32, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+	/// Check if the key exists in given column.
+	fn contains(&self, col: u32, key: &[u8]) -> Result<bool, String> {
+		self.get(col, key).map(|value| value.is_some())
+	}
+}
+
+/// Columns of the key-value database that are used by the `DbStorage`.
+///
+/// The layout is:
+/// - `roots`: big-endian encoded block number => changes trie root, plus the root of the
+///   roots journal trie (see `RootsJournal`);
+/// - `nodes`: trie node hash => trie node;
+/// - `block_numbers`: block hash => big-endian encoded block number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbColumns {
+	/// Column with changes tries roots.
+	pub roots: u32,
+	/// Column with changes tries nodes.
+	pub nodes: u32,
+	/// Column with numbers of blocks.
+	pub block_numbers: u32,
+}
+
+impl Default for DbColumns {
+	fn default() -> Self {
+		DbColumns {
+			roots: 0,
+			nodes: 1,
+			block_numbers: 2,
+		}
+	}
+}
+
+/// Changes trie storage over the key-value database.
+///
+/// Roots are indexed by block number, so only roots of the canonical chain are available.
+pub struct DbStorage<DB, H> {
+	db: DB,
+	columns: DbColumns,
+	_hasher: std::marker::PhantomData<H>,
+}
+
+impl<DB: KvBackend, H: Hasher> DbStorage<DB, H> {
+	/// Create storage over given database, using given columns.
+	pub fn new(db: DB, columns: DbColumns) -> Self {
+		DbStorage {
+			db,
+			columns,
+			_hasher: Default::default(),
+		}
+	}
+
+	/// Get reference to the underlying database.
+	pub fn db(&self) -> &DB {
+		&self.db
+	}
+
+	/// Get the journal of changes tries roots, that has been committed by the
+	/// `DbStorageTransaction::insert_roots_journal`. The journal is empty if it has never been
+	/// committed.
+	pub fn roots_journal(&self) -> Result<RootsJournal<H, &Self>, String> {
+		let root = match self.db.get(self.columns.roots, ROOTS_JOURNAL_KEY)? {
+			Some(root) => decode_hash::<H>(&root)?,
+			None => empty_root::<H>(),
+		};
+		Ok(RootsJournal::new(self, root))
+	}
+}
+
+impl<DB, H, Number> RootsStorage<H, Number> for DbStorage<DB, H>
+	where
+		DB: KvBackend,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn build_anchor(&self, hash: H::Out) -> Result<AnchorBlockId<H::Out, Number>, Error<Number>> {
+		match self.db.get(self.columns.block_numbers, hash.as_ref()).map_err(Error::Storage)? {
+			Some(number) => Ok(AnchorBlockId { hash, number: decode_block_number(&number)? }),
+			None => Err(Error::UnknownBlock(hash.as_ref().to_vec())),
+		}
+	}
+
+	fn root(&self, anchor: &AnchorBlockId<H::Out, Number>, block: Number) -> Result<Option<H::Out>, Error<Number>> {
+		if block > anchor.number {
+			return Ok(None);
+		}
+
+		self.db.get(self.columns.roots, &block_number_key(&block)).map_err(Error::Storage)?
+			.map(|root| decode_hash::<H>(&root).map_err(Error::Storage))
+			.transpose()
+	}
+}
+
+impl<DB, H, Number> Storage<H, Number> for DbStorage<DB, H>
+	where
+		DB: KvBackend,
+		H: Hasher,
+		Number: BlockNumber,
+{
+	fn as_roots_storage(&self) -> &dyn RootsStorage<H, Number> {
+		self
+	}
+
+	fn with_cached_changed_keys(
+		&self,
+		_root: &H::Out,
+		_functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+	) -> bool {
+		false
+	}
+
+	fn get(&self, key: &H::Out, _prefix: Prefix) -> Result<Option<DBValue>, Error<Number>> {
+		self.db.get(self.columns.nodes, key.as_ref()).map_err(Error::Storage)
+	}
+}
+
+impl<DB: KvBackend, H: Hasher> TrieBackendStorage<H> for DbStorage<DB, H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, _prefix: Prefix) -> Result<Option<DBValue>, String> {
+		self.db.get(self.columns.nodes, key.as_ref())
+	}
+}
+
+impl<'a, DB: KvBackend, H: Hasher> TrieBackendStorage<H> for &'a DbStorage<DB, H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, _prefix: Prefix) -> Result<Option<DBValue>, String> {
+		self.db.get(self.columns.nodes, key.as_ref())
+	}
+}
+
+/// Key of the roots journal trie root in the `roots` column. Keys of roots are encoded
+/// block numbers, which are shorter than this key.
+const ROOTS_JOURNAL_KEY: &[u8] = b":changes_trie_roots_journal";
+
+/// Journal of changes tries roots: the trie that maps big-endian encoded block number to the
+/// changes trie root of this block.
+///
+/// The root of the journal is the single commitment to all changes tries roots, so light
+/// clients could verify the changes trie root of any block with the proof, generated by the
+/// `prove_root`.
+pub struct RootsJournal<H: Hasher, C> {
+	storage: C,
+	root: H::Out,
+}
+
+impl<H, C> RootsJournal<H, C>
+	where
+		H: Hasher,
+		C: TrieBackendStorage<H, Overlay = MemoryDB<H>>,
+{
+	/// Create journal with given root, which nodes are read from the `storage`.
+	pub fn new(storage: C, root: H::Out) -> Self {
+		RootsJournal { storage, root }
+	}
+
+	/// Current root of the journal.
+	pub fn root(&self) -> &H::Out {
+		&self.root
+	}
+
+	/// Append changes trie root of given block to the journal. Returns the new root of the journal
+	/// and nodes of the updated journal trie. Nodes must be written to the storage before the
+	/// journal is read or updated again.
+	pub fn append<Number: BlockNumber>(
+		&mut self,
+		block: Number,
+		root: H::Out,
+	) -> Result<(H::Out, MemoryDB<H>), String> {
+		let mut nodes = MemoryDB::default();
+		let mut journal_root = self.root;
+		{
+			let mut ephemeral = Ephemeral::new(&self.storage, &mut nodes);
+			// the empty trie root node isn't written to the storage
+			let mut trie = if journal_root == empty_root::<H>() {
+				TrieDBMut::<H>::new(&mut ephemeral, &mut journal_root)
+			} else {
+				TrieDBMut::<H>::from_existing(&mut ephemeral, &mut journal_root)
+					.map_err(|e| format!("{:?}", e))?
+			};
+			trie.insert(&block_number_key(&block), root.as_ref())
+				.map_err(|e| format!("{:?}", e))?;
+		}
+
+		self.root = journal_root;
+		Ok((journal_root, nodes))
+	}
+
+	/// Generate proof of the changes trie root of given block.
+	pub fn prove_root<Number: BlockNumber>(&self, block: Number) -> Result<Vec<Vec<u8>>, String> {
+		let mut read_overlay = MemoryDB::default();
+		let ephemeral = Ephemeral::new(&self.storage, &mut read_overlay);
+		let mut proof_recorder = Recorder::<H::Out>::default();
+		read_trie_value_with::<Layout<H>, _, _>(
+			&ephemeral,
+			&self.root,
+			&block_number_key(&block),
+			&mut proof_recorder,
+		).map_err(|e| format!("{:?}", e))?;
+		Ok(proof_recorder.drain()
+			.into_iter()
+			.map(|n| n.data.to_vec())
+			.collect())
+	}
+}
+
+/// Check the proof, generated by the `RootsJournal::prove_root`. Returns true if the proof
+/// proves that the journal with `journal_root` has `expected_root` as the changes trie root of
+/// given block.
+pub fn verify_root_proof<H: Hasher, Number: BlockNumber>(
+	journal_root: &H::Out,
+	block: Number,
+	expected_root: &H::Out,
+	proof: Vec<Vec<u8>>,
+) -> Result<bool, String> {
+	let proof_db = proof_db::<H>(proof);
+	let root = read_trie_value::<Layout<H>, _>(&proof_db, journal_root, &block_number_key(&block))
+		.map_err(|e| format!("{:?}", e))?;
+	Ok(root.as_ref().map(|root| &root[..]) == Some(expected_root.as_ref()))
+}
+
+/// Changes that need to be written to the key-value database atomically to make
+/// changes tries available through the `DbStorage`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DbStorageTransaction {
+	columns: DbColumns,
+	changes: Vec<(u32, Vec<u8>, Vec<u8>)>,
+}
+
+impl DbStorageTransaction {
+	/// Create empty transaction that writes to given columns.
+	pub fn new(columns: DbColumns) -> Self {
+		DbStorageTransaction {
+			columns,
+			changes: Vec::new(),
+		}
+	}
+
+	/// Insert changes trie root and nodes of the built changes trie of given block.
+	pub fn insert_trie<H: Hasher, Number: BlockNumber>(
+		&mut self,
+		block: Number,
+		root: H::Out,
+		trie: MemoryDB<H>,
+	) {
+		self.insert_root::<H, _>(block, root);
+		self.insert_nodes(trie);
+	}
+
+	/// Insert changes trie root of given block.
+	pub fn insert_root<H: Hasher, Number: BlockNumber>(&mut self, block: Number, root: H::Out) {
+		self.changes.push((self.columns.roots, block_number_key(&block), root.as_ref().to_vec()));
+	}
+
+	/// Insert changes tries nodes.
+	pub fn insert_nodes<H: Hasher>(&mut self, mut nodes: MemoryDB<H>) {
+		let column = self.columns.nodes;
+		self.changes.extend(nodes.drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(key, (value, _))| (column, key.as_ref().to_vec(), value)));
+	}
+
+	/// Insert the root and nodes of the roots journal, returned by the `RootsJournal::append`.
+	pub fn insert_roots_journal<H: Hasher>(&mut self, journal_root: H::Out, nodes: MemoryDB<H>) {
+		self.changes.push((self.columns.roots, ROOTS_JOURNAL_KEY.to_vec(), journal_root.as_ref().to_vec()));
+		self.insert_nodes(nodes);
+	}
+
+	/// Insert number of the block with given hash. The number is used when anchor is built for
+	/// this block.
+	pub fn insert_block_hash<H: Hasher, Number: BlockNumber>(&mut self, hash: H::Out, block: Number) {
+		self.changes.push((self.columns.block_numbers, hash.as_ref().to_vec(), block_number_key(&block)));
+	}
+
+	/// Consume self and return all (column, key, value) pairs that need to be written.
+	pub fn into_changes(self) -> Vec<(u32, Vec<u8>, Vec<u8>)> {
+		self.changes
+	}
+}
+
+/// In-memory key-value database.
+#[derive(Debug, Default)]
+pub struct InMemoryKvBackend {
+	data: RwLock<HashMap<(u32, Vec<u8>), Vec<u8>>>,
+}
+
+impl InMemoryKvBackend {
+	/// Write all changes of the transaction.
+	pub fn commit(&self, transaction: DbStorageTransaction) {
+		let mut data = self.data.write();
+		for (col, key, value) in transaction.into_changes() {
+			data.insert((col, key), value);
+		}
+	}
+}
+
+impl KvBackend for InMemoryKvBackend {
+	fn get(&self, col: u32, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+		Ok(self.data.read().get(&(col, key.to_vec())).cloned())
+	}
+}
+
+/// Key of the block in the database: encoded block number in big-endian, so that keys of
+/// fixed-size numbers are ordered by number.
+fn block_number_key<Number: BlockNumber>(block: &Number) -> Vec<u8> {
+	let mut key = block.encode();
+	key.reverse();
+	key
+}
+
+/// Decode block number from the database key.
+fn decode_block_number<Number: BlockNumber>(key: &[u8]) -> Result<Number, Error<Number>> {
+	let mut encoded = key.to_vec();
+	encoded.reverse();
+	Ok(Number::decode(&mut &encoded[..])?)
+}
+
+/// Decode hash from the database value.
+fn decode_hash<H: Hasher>(value: &[u8]) -> Result<H::Out, String> {
+	let mut hash = H::Out::default();
+	if hash.as_ref().len() != value.len() {
+		return Err(format!("Invalid changes trie root {}", sp_core::hexdisplay::HexDisplay::from(&value)));
+	}
+	hash.as_mut().copy_from_slice(value);
+	Ok(hash)
+}
+
+/// Create in-memory database of proof nodes.
+fn proof_db<H: Hasher>(proof: Vec<Vec<u8>>) -> MemoryDB<H> {
+	use hash_db::HashDB;
+
+	let mut proof_db = MemoryDB::<H>::default();
+	for item in proof {
+		proof_db.insert(EMPTY_PREFIX, &item);
+	}
+	proof_db
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::H256;
+	use sp_runtime::traits::BlakeTwo256;
+	use super::*;
+
+	fn anchor(number: u64) -> AnchorBlockId<H256, u64> {
+		AnchorBlockId { hash: Default::default(), number }
+	}
+
+	fn prepare_storage() -> InMemoryStorage<BlakeTwo256, u64> {
+		InMemoryStorage::with_blocks(vec![
+			(1, H256::repeat_byte(1)),
+			(2, H256::repeat_byte(2)),
+			(3, H256::repeat_byte(3)),
+		])
+	}
+
+	#[test]
+	fn roots_are_read_from_canonical_fork() {
+		let storage = prepare_storage();
+		storage.insert_at(1, 2, H256::repeat_byte(22), Default::default());
+
+		let roots = |storage: &InMemoryStorage<BlakeTwo256, u64>| storage.roots(&anchor(3), &[1, 2, 3]).unwrap();
+		assert_eq!(roots(&storage), vec![
+			Some(H256::repeat_byte(1)),
+			Some(H256::repeat_byte(2)),
+			Some(H256::repeat_byte(3)),
+		]);
+
+		// fork#1 starts at block 2 => block 1 is read from the main fork
+		storage.switch_fork(1);
+		assert_eq!(roots(&storage), vec![Some(H256::repeat_byte(1)), Some(H256::repeat_byte(22)), None]);
+		assert_eq!(storage.build_anchor(H256::repeat_byte(22)).unwrap().number, 2);
+		assert!(storage.build_anchor(H256::repeat_byte(2)).is_err());
+
+		// new blocks are inserted into the canonical fork
+		storage.insert(3, H256::repeat_byte(33), Default::default());
+		assert_eq!(storage.root(&anchor(3), 3).unwrap(), Some(H256::repeat_byte(33)));
+
+		storage.switch_fork(MAIN_FORK);
+		assert_eq!(storage.root(&anchor(3), 3).unwrap(), Some(H256::repeat_byte(3)));
+	}
+
+	fn extrinsic_pair(block: u64, key: u8) -> InputPair<u64> {
+		InputPair::ExtrinsicIndex(
+			crate::changes_trie::input::ExtrinsicIndex { block, key: vec![key] },
+			vec![0].into(),
+		)
+	}
+
+	#[test]
+	fn builder_adds_child_roots_to_top_inputs() {
+		let child_key = PrefixedStorageKey::new(b":child_storage:default:child".to_vec());
+		let storage = InMemoryStorageBuilder::<BlakeTwo256, u64>::new()
+			.block(1, vec![extrinsic_pair(1, 1)])
+			.child_block(child_key.clone(), 2, vec![extrinsic_pair(2, 2)])
+			.require_contiguous()
+			.build();
+		let expected = InMemoryStorage::<BlakeTwo256, u64>::with_inputs(
+			vec![(1, vec![extrinsic_pair(1, 1)])],
+			vec![(child_key, vec![(2, vec![extrinsic_pair(2, 2)])])],
+		);
+		assert!(storage.root(&anchor(2), 2).unwrap().is_some());
+		assert_eq!(storage.export(), expected.export());
+	}
+
+	#[test]
+	#[should_panic(expected = "Changes trie input of block 1 is added twice")]
+	fn builder_rejects_duplicate_blocks() {
+		InMemoryStorageBuilder::<BlakeTwo256, u64>::new()
+			.block(1, vec![extrinsic_pair(1, 1)])
+			.block(1, vec![extrinsic_pair(1, 2)]);
+	}
+
+	#[test]
+	#[should_panic(expected = "block 1 is followed by block 3")]
+	fn builder_rejects_gaps_when_contiguity_is_required() {
+		InMemoryStorageBuilder::<BlakeTwo256, u64>::new()
+			.block(1, vec![extrinsic_pair(1, 1)])
+			.block(3, vec![extrinsic_pair(3, 1)])
+			.require_contiguous()
+			.build();
+	}
+
+	#[test]
+	fn builder_accepts_gaps_by_default() {
+		let storage = InMemoryStorageBuilder::<BlakeTwo256, u64>::new()
+			.block(3, vec![extrinsic_pair(3, 1)])
+			.block(1, vec![extrinsic_pair(1, 1)])
+			.build();
+		assert!(storage.root(&anchor(3), 1).unwrap().is_some());
+		assert!(storage.root(&anchor(3), 3).unwrap().is_some());
+	}
+
+	#[test]
+	#[should_panic(expected = "Changes trie root of block 2 is already inserted")]
+	fn insert_built_refuses_to_replace_root() {
+		let storage = prepare_storage();
+		storage.insert_built(4, H256::repeat_byte(4), Default::default());
+		storage.insert_built(2, H256::repeat_byte(22), Default::default());
+	}
+
+	#[test]
+	fn roots_are_read_from_anchor_fork() {
+		let storage = prepare_storage();
+		storage.insert_at(1, 2, H256::repeat_byte(22), Default::default());
+		storage.insert_at(1, 3, H256::repeat_byte(33), Default::default());
+
+		let fork_anchor = AnchorBlockId { hash: H256::repeat_byte(33), number: 3 };
+		let main_anchor = AnchorBlockId { hash: H256::repeat_byte(3), number: 3 };
+		for _ in 0..2 {
+			assert_eq!(storage.roots(&fork_anchor, &[1, 2, 3]).unwrap(), vec![
+				Some(H256::repeat_byte(1)),
+				Some(H256::repeat_byte(22)),
+				Some(H256::repeat_byte(33)),
+			]);
+			assert_eq!(storage.root(&main_anchor, 2).unwrap(), Some(H256::repeat_byte(2)));
+
+			// unknown anchor => canonical fork is used
+			storage.switch_fork(1);
+		}
+		assert_eq!(storage.root(&anchor(3), 2).unwrap(), Some(H256::repeat_byte(22)));
+	}
+
+	#[test]
+	fn remove_and_clear_work() {
+		let storage = prepare_storage();
+
+		storage.remove(&2);
+		assert_eq!(storage.roots(&anchor(3), &[1, 2, 3]).unwrap(), vec![
+			Some(H256::repeat_byte(1)),
+			None,
+			Some(H256::repeat_byte(3)),
+		]);
+
+		storage.clear();
+		assert_eq!(storage.roots(&anchor(3), &[1, 2, 3]).unwrap(), vec![None, None, None]);
+	}
+
+	#[test]
+	fn roots_journal_proves_roots() {
+		let storage = DbStorage::<_, BlakeTwo256>::new(InMemoryKvBackend::default(), DbColumns::default());
+		for block in 1..=16u64 {
+			let root = H256::repeat_byte(block as u8);
+			let (journal_root, nodes) = storage.roots_journal().unwrap().append(block, root).unwrap();
+			let mut transaction = DbStorageTransaction::new(DbColumns::default());
+			transaction.insert_root::<BlakeTwo256, _>(block, root);
+			transaction.insert_roots_journal::<BlakeTwo256>(journal_root, nodes);
+			storage.db().commit(transaction);
+		}
+
+		let journal = storage.roots_journal().unwrap();
+		let journal_root = *journal.root();
+		let proof = journal.prove_root(7u64).unwrap();
+		assert_eq!(verify_root_proof::<BlakeTwo256, _>(&journal_root, 7u64, &H256::repeat_byte(7), proof.clone()), Ok(true));
+		assert_eq!(verify_root_proof::<BlakeTwo256, _>(&journal_root, 7u64, &H256::repeat_byte(8), proof.clone()), Ok(false));
+		assert_ne!(verify_root_proof::<BlakeTwo256, _>(&journal_root, 8u64, &H256::repeat_byte(8), proof.clone()), Ok(true));
+
+		// every node of the tampered proof has a different hash, so it isn't found by the checker
+		for index in 0..proof.len() {
+			let mut tampered_proof = proof.clone();
+			let last_byte = tampered_proof[index].len() - 1;
+			tampered_proof[index][last_byte] ^= 1;
+			assert!(verify_root_proof::<BlakeTwo256, _>(&journal_root, 7u64, &H256::repeat_byte(7), tampered_proof).is_err());
+		}
+	}
+
+	#[test]
+	fn cached_storage_caches_roots_until_invalidated() {
+		let storage = CachedStorage::new(prepare_storage(), 1024);
+		assert_eq!(storage.root(&anchor(4), 3), Ok(Some(H256::repeat_byte(3))));
+
+		// cached root is served until it is invalidated
+		storage.inner().insert(3, H256::repeat_byte(33), MemoryDB::default());
+		assert_eq!(storage.root(&anchor(4), 3), Ok(Some(H256::repeat_byte(3))));
+		storage.invalidate_from(&5);
+		assert_eq!(storage.root(&anchor(4), 3), Ok(Some(H256::repeat_byte(3))));
+		// roots that have been read using invalidated anchors are forgotten too
+		storage.invalidate_from(&4);
+		assert_eq!(storage.root(&anchor(4), 3), Ok(Some(H256::repeat_byte(33))));
+
+		// missing roots aren't cached
+		assert_eq!(storage.root(&anchor(5), 5), Ok(None));
+		storage.inner().insert(5, H256::repeat_byte(5), MemoryDB::default());
+		assert_eq!(storage.root(&anchor(5), 5), Ok(Some(H256::repeat_byte(5))));
+
+		// nodes are read from the inner storage once
+		let mut mdb = MemoryDB::<BlakeTwo256>::default();
+		let node = hash_db::HashDB::insert(&mut mdb, EMPTY_PREFIX, b"node");
+		storage.inner().insert(6, node, mdb);
+		assert_eq!(storage.get(&node, EMPTY_PREFIX), Ok(Some(b"node".to_vec())));
+		storage.inner().clear();
+		assert_eq!(storage.get(&node, EMPTY_PREFIX), Ok(Some(b"node".to_vec())));
+	}
+
+	#[test]
+	fn cached_storage_counts_root_hits_and_misses() {
+		let storage = CachedStorage::new(prepare_storage(), 1024);
+		assert_eq!(storage.roots(&anchor(3), &[1, 2, 3]), Ok(vec![
+			Some(H256::repeat_byte(1)),
+			Some(H256::repeat_byte(2)),
+			Some(H256::repeat_byte(3)),
+		]));
+		assert_eq!(storage.root(&anchor(3), 2), Ok(Some(H256::repeat_byte(2))));
+		assert_eq!((storage.root_hits(), storage.root_misses()), (1, 3));
+
+		// cached root is served until the fork is invalidated
+		storage.inner().insert(2, H256::repeat_byte(22), MemoryDB::default());
+		assert_eq!(storage.root(&anchor(3), 2), Ok(Some(H256::repeat_byte(2))));
+		storage.invalidate_fork(&anchor(3).hash);
+		assert_eq!(storage.root(&anchor(3), 2), Ok(Some(H256::repeat_byte(22))));
+		assert_eq!((storage.root_hits(), storage.root_misses()), (2, 4));
+
+		// roots that have been read using other anchors are kept
+		let other_anchor = AnchorBlockId { hash: H256::repeat_byte(4), number: 4 };
+		assert_eq!(storage.root(&other_anchor, 1), Ok(Some(H256::repeat_byte(1))));
+		storage.invalidate_fork(&anchor(3).hash);
+		assert_eq!(storage.root(&other_anchor, 1), Ok(Some(H256::repeat_byte(1))));
+		assert_eq!((storage.root_hits(), storage.root_misses()), (3, 5));
+	}
+
+	#[test]
+	fn cached_storage_never_serves_invalidated_roots() {
+		use std::sync::atomic::AtomicBool;
+
+		const ROUNDS: u64 = 200;
+		const BLOCKS: u64 = 8;
+
+		// root of the block, inserted at given round of reorgs
+		let root = |round: u64, block: u64| H256::from_low_u64_be(round * 100 + block);
+		// blocks starting from this one are replaced at given round
+		let reorg_from = |round: u64| 1 + round % BLOCKS;
+
+		let storage = Arc::new(CachedStorage::new(InMemoryStorage::<BlakeTwo256, u64>::new(), 1024));
+		for block in 1..=BLOCKS {
+			storage.inner().insert(block, root(0, block), MemoryDB::default());
+		}
+		let published_round = Arc::new(AtomicU64::new(0));
+		let done = Arc::new(AtomicBool::new(false));
+
+		let queries = (0..4).map(|_| {
+			let storage = storage.clone();
+			let published_round = published_round.clone();
+			let done = done.clone();
+			std::thread::spawn(move || {
+				while !done.load(Ordering::SeqCst) {
+					let round = published_round.load(Ordering::SeqCst);
+					for block in 1..=BLOCKS {
+						// the last round (up to published) that has replaced root of this block
+						let min_round = (0..=round).rev()
+							.find(|round| *round == 0 || reorg_from(*round) <= block)
+							.unwrap();
+						let served = storage.root(&anchor(BLOCKS), block).unwrap().unwrap();
+						let served_round = served.to_low_u64_be() / 100;
+						assert_eq!(served.to_low_u64_be() % 100, block);
+						assert!(
+							served_round >= min_round,
+							"root of block {} from round {} is served after round {}",
+							block, served_round, round,
+						);
+					}
+				}
+			})
+		}).collect::<Vec<_>>();
+
+		let builder = {
+			let storage = storage.clone();
+			let published_round = published_round.clone();
+			std::thread::spawn(move || {
+				for round in 1..=ROUNDS {
+					for block in reorg_from(round)..=BLOCKS {
+						storage.inner().insert(block, root(round, block), MemoryDB::default());
+					}
+					storage.invalidate_from(&reorg_from(round));
+					published_round.store(round, Ordering::SeqCst);
+				}
+			})
+		};
+
+		builder.join().unwrap();
+		done.store(true, Ordering::SeqCst);
+		for query in queries {
+			query.join().unwrap();
+		}
+		for block in 1..=BLOCKS {
+			assert_eq!(storage.root(&anchor(BLOCKS), block), Ok(Some(root(ROUNDS, block))));
+		}
+	}
+}
@@ -22,7 +22,7 @@
 //! search for gems.
 
 use num_traits::One;
-use crate::changes_trie::{ConfigurationRange, BlockNumber};
+use crate::changes_trie::{ConfigurationRange, BlockNumber, Error};
 
 /// Returns surface iterator for given range of blocks.
 ///
@@ -33,7 +33,7 @@ pub fn surface_iterator<'a, Number: BlockNumber>(
 	max: Number,
 	begin: Number,
 	end: Number,
-) -> Result<SurfaceIterator<'a, Number>, String> {
+) -> Result<SurfaceIterator<'a, Number>, Error<Number>> {
 	let (current, current_begin, digest_step, digest_level) = lower_bound_max_digest(
 		config.clone(),
 		max.clone(),
@@ -51,6 +51,10 @@ pub fn surface_iterator<'a, Number: BlockNumber>(
 	})
 }
 
+/// Position of the `SurfaceIterator`: (current point, first block of the current digest range,
+/// digest step, digest level). `None` if the iterator is exhausted.
+pub(crate) type SurfacePosition<Number> = Option<(Number, Number, u32, Option<u32>)>;
+
 /// Surface iterator - only traverses top-level digests from given range and tries to find
 /// all valid digest changes.
 ///
@@ -67,8 +71,58 @@ pub struct SurfaceIterator<'a, Number: BlockNumber> {
 	digest_level: Option<u32>,
 }
 
+impl<'a, Number: BlockNumber> SurfaceIterator<'a, Number> {
+	/// Returns configuration range the iterator walks over.
+	pub(crate) fn config(&self) -> &ConfigurationRange<'a, Number> {
+		&self.config
+	}
+
+	/// Returns the first block of the range.
+	pub(crate) fn begin(&self) -> &Number {
+		&self.begin
+	}
+
+	/// Returns number of the best block, known to caller.
+	pub(crate) fn max_block(&self) -> &Number {
+		&self.max
+	}
+
+	/// Returns current position of the iterator.
+	pub(crate) fn position(&self) -> SurfacePosition<Number> {
+		self.current.clone().map(|current| (
+			current,
+			self.current_begin.clone(),
+			self.digest_step,
+			self.digest_level,
+		))
+	}
+
+	/// Create iterator that starts at given position, previously returned by `position`.
+	pub(crate) fn resume(
+		config: ConfigurationRange<'a, Number>,
+		begin: Number,
+		max: Number,
+		position: SurfacePosition<Number>,
+	) -> Self {
+		let (current, current_begin, digest_step, digest_level) = match position {
+			Some((current, current_begin, digest_step, digest_level)) =>
+				(Some(current), current_begin, digest_step, digest_level),
+			None => (None, begin.clone(), 1, None),
+		};
+		SurfaceIterator {
+			config,
+			begin,
+			max,
+			current,
+			current_begin,
+			digest_step,
+			digest_level,
+		}
+	}
+}
+
 impl<'a, Number: BlockNumber> Iterator for SurfaceIterator<'a, Number> {
-	type Item = Result<(Number, Option<u32>), String>;
+	type Item = Result<(Number, Option<u32>), Error<Number>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		let current = self.current.clone()?;
@@ -112,16 +166,17 @@ fn lower_bound_max_digest<'a, Number: BlockNumber>(
 	max: Number,
 	begin: Number,
 	end: Number,
-) -> Result<(Number, Number, u32, Option<u32>), String> {
+) -> Result<(Number, Number, u32, Option<u32>), Error<Number>> {
 	if end > max || begin > end {
-		return Err(format!("invalid changes range: {}..{}/{}", begin, end, max));
+		return Err(Error::InvalidRange { begin, end, max });
 	}
 	if begin <= config.zero || config.end.as_ref().map(|config_end| end > *config_end).unwrap_or(false) {
-		return Err(format!("changes trie range is not covered by configuration: {}..{}/{}..{}",
-			begin, end, config.zero, match config.end.as_ref() {
-				Some(config_end) => format!("{}", config_end),
-				None => "None".into(),
-			}));
+		return Err(Error::RangeNotCovered {
+			begin,
+			end,
+			zero: config.zero,
+			config_end: config.end,
+		});
 	}
 
 	let mut digest_level = 0u32;
@@ -209,7 +264,7 @@ mod tests {
 
 	#[test]
 	fn lower_bound_max_digest_works() {
-		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
 
 		// when config activates at 0
 		assert_eq!(
@@ -226,7 +281,7 @@ mod tests {
 
 	#[test]
 	fn surface_iterator_works() {
-		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
 
 		// when config activates at 0
 		assert_eq!(
@@ -270,7 +325,7 @@ mod tests {
 
 	#[test]
 	fn surface_iterator_works_with_skewed_digest() {
-		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let config = Configuration { digest_interval: 4, digest_levels: 2, ..Default::default() };
 		let mut config_range = configuration_range(&config, 0u64);
 
 		// when config activates at 0 AND ends at 170
@@ -0,0 +1,277 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keys of changes trie input pairs.
+//!
+//! Unlike the rest of the changes trie code, key types don't depend on `std`, so the runtime
+//! could compute the changes trie key of the storage item. The byte layout of keys is
+//! described in the `changes_trie::input` module, which re-exports all items of this module.
+
+use codec::{Decode, Encode, Input, Output, Error};
+use sp_core::storage::PrefixedStorageKey;
+use sp_std::{vec, vec::Vec};
+use crate::StorageKey;
+
+/// Key of { changed key => set of extrinsic indices } mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtrinsicIndex<Number> {
+	/// Block at which this key has been inserted in the trie.
+	pub block: Number,
+	/// Storage key this node is responsible for.
+	pub key: StorageKey,
+}
+
+/// Key of { changed key => block/digest block numbers } mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestIndex<Number> {
+	/// Block at which this key has been inserted in the trie.
+	pub block: Number,
+	/// Storage key this node is responsible for.
+	pub key: StorageKey,
+}
+
+/// Key of { childtrie key => Childchange trie } mapping.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Clone, PartialOrd, Ord))]
+pub struct ChildIndex<Number> {
+	/// Block at which this key has been inserted in the trie.
+	pub block: Number,
+	/// Storage key this node is responsible for.
+	pub storage_key: PrefixedStorageKey,
+}
+
+/// Key of the list of digest input blocks that haven't been included into the digest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingBlocksIndex<Number> {
+	/// Block at which this key has been inserted in the trie.
+	pub block: Number,
+}
+
+/// Single input key of changes trie.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Clone))]
+pub enum InputKey<Number> {
+	/// Key of { key => set of extrinsics where key has been changed } element mapping.
+	ExtrinsicIndex(ExtrinsicIndex<Number>),
+	/// Key of { key => set of blocks/digest blocks where key has been changed } element mapping.
+	DigestIndex(DigestIndex<Number>),
+	/// Key of { childtrie key => Childchange trie } where key has been changed } element mapping.
+	ChildIndex(ChildIndex<Number>),
+	/// Key of the list of digest input blocks that haven't been included into the digest.
+	MissingBlocks(MissingBlocksIndex<Number>),
+}
+
+/// First byte of keys of all `ExtrinsicIndex` pairs.
+pub const EXTRINSIC_INDEX_KEY_PREFIX: u8 = 1;
+/// First byte of keys of all `DigestIndex` pairs.
+pub const DIGEST_INDEX_KEY_PREFIX: u8 = 2;
+/// First byte of keys of all `ChildIndex` pairs.
+pub const CHILD_INDEX_KEY_PREFIX: u8 = 3;
+/// First byte of keys of all `MissingBlocks` pairs.
+pub const MISSING_BLOCKS_KEY_PREFIX: u8 = 4;
+
+impl<Number: Decode> InputKey<Number> {
+	/// Decode key from the key of the changes trie. Fails if the key has trailing bytes.
+	pub fn decode_from_trie_key(trie_key: &[u8]) -> Result<Self, Error> {
+		decode_all(trie_key)
+	}
+}
+
+impl<Number: Encode> Encode for InputKey<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		match *self {
+			InputKey::ExtrinsicIndex(ref key) => key.encode_to(dest),
+			InputKey::DigestIndex(ref key) => key.encode_to(dest),
+			InputKey::ChildIndex(ref key) => key.encode_to(dest),
+			InputKey::MissingBlocks(ref key) => key.encode_to(dest),
+		}
+	}
+}
+
+impl<Number: Encode> ExtrinsicIndex<Number> {
+	/// Prefix of keys of all ExtrinsicIndex pairs of given block.
+	///
+	/// The prefix is `EXTRINSIC_INDEX_KEY_PREFIX ++ SCALE(block)`, so all keys that have been
+	/// changed in the block could be found by iterating the trie keys with this prefix.
+	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
+		let mut prefix = vec![EXTRINSIC_INDEX_KEY_PREFIX];
+		prefix.extend(block.encode());
+		prefix
+	}
+}
+
+impl<Number: Encode> Encode for ExtrinsicIndex<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		dest.push_byte(EXTRINSIC_INDEX_KEY_PREFIX);
+		self.block.encode_to(dest);
+		self.key.encode_to(dest);
+	}
+}
+
+impl<Number: Encode> codec::EncodeLike for ExtrinsicIndex<Number> {}
+
+impl<Number: Encode> DigestIndex<Number> {
+	/// Prefix of keys of all DigestIndex pairs of given block.
+	///
+	/// The prefix is `DIGEST_INDEX_KEY_PREFIX ++ SCALE(block)`, so all keys that have been
+	/// changed in the digest input blocks could be found by iterating the trie keys with this prefix.
+	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
+		let mut prefix = vec![DIGEST_INDEX_KEY_PREFIX];
+		prefix.extend(block.encode());
+		prefix
+	}
+}
+
+impl<Number: Encode> Encode for DigestIndex<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		dest.push_byte(DIGEST_INDEX_KEY_PREFIX);
+		self.block.encode_to(dest);
+		self.key.encode_to(dest);
+	}
+}
+
+impl<Number: Encode> ChildIndex<Number> {
+	/// Prefix of keys of all ChildIndex pairs of given block.
+	///
+	/// The prefix is `CHILD_INDEX_KEY_PREFIX ++ SCALE(block)`, so roots of all child changes
+	/// tries could be found by iterating the trie keys with this prefix.
+	pub fn key_neutral_prefix(block: Number) -> Vec<u8> {
+		let mut prefix = vec![CHILD_INDEX_KEY_PREFIX];
+		prefix.extend(block.encode());
+		prefix
+	}
+}
+
+impl<Number: Encode> Encode for ChildIndex<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		dest.push_byte(CHILD_INDEX_KEY_PREFIX);
+		self.block.encode_to(dest);
+		self.storage_key.encode_to(dest);
+	}
+}
+
+impl<Number: Encode> Encode for MissingBlocksIndex<Number> {
+	fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+		dest.push_byte(MISSING_BLOCKS_KEY_PREFIX);
+		self.block.encode_to(dest);
+	}
+}
+
+impl<Number: Encode> codec::EncodeLike for DigestIndex<Number> {}
+
+impl<Number: Encode> codec::EncodeLike for ChildIndex<Number> {}
+
+impl<Number: Encode> codec::EncodeLike for MissingBlocksIndex<Number> {}
+
+impl<Number: Encode> codec::EncodeLike for InputKey<Number> {}
+
+impl<Number: Decode> Decode for InputKey<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		match input.read_byte()? {
+			EXTRINSIC_INDEX_KEY_PREFIX => Ok(InputKey::ExtrinsicIndex(ExtrinsicIndex {
+				block: Decode::decode(input)?,
+				key: Decode::decode(input)?,
+			})),
+			DIGEST_INDEX_KEY_PREFIX => Ok(InputKey::DigestIndex(DigestIndex {
+				block: Decode::decode(input)?,
+				key: Decode::decode(input)?,
+			})),
+			CHILD_INDEX_KEY_PREFIX => Ok(InputKey::ChildIndex(ChildIndex {
+				block: Decode::decode(input)?,
+				storage_key: PrefixedStorageKey::new(Decode::decode(input)?),
+			})),
+			MISSING_BLOCKS_KEY_PREFIX => Ok(InputKey::MissingBlocks(MissingBlocksIndex {
+				block: Decode::decode(input)?,
+			})),
+			_ => Err("Invalid input key variant".into()),
+		}
+	}
+}
+
+impl<Number: Decode> Decode for ExtrinsicIndex<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		match InputKey::decode(input)? {
+			InputKey::ExtrinsicIndex(key) => Ok(key),
+			_ => Err("Input key is not an ExtrinsicIndex".into()),
+		}
+	}
+}
+
+impl<Number: Decode> Decode for DigestIndex<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		match InputKey::decode(input)? {
+			InputKey::DigestIndex(key) => Ok(key),
+			_ => Err("Input key is not a DigestIndex".into()),
+		}
+	}
+}
+
+impl<Number: Decode> Decode for ChildIndex<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		match InputKey::decode(input)? {
+			InputKey::ChildIndex(key) => Ok(key),
+			_ => Err("Input key is not a ChildIndex".into()),
+		}
+	}
+}
+
+impl<Number: Decode> Decode for MissingBlocksIndex<Number> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		match InputKey::decode(input)? {
+			InputKey::MissingBlocks(key) => Ok(key),
+			_ => Err("Input key is not a MissingBlocks".into()),
+		}
+	}
+}
+
+/// Decode value from given bytes, failing if there are trailing bytes.
+pub(crate) fn decode_all<T: Decode>(mut encoded: &[u8]) -> Result<T, Error> {
+	let decoded = T::decode(&mut encoded)?;
+	if !encoded.is_empty() {
+		return Err("Trailing bytes after the encoded changes trie input".into());
+	}
+	Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Encoded `ExtrinsicIndex { block: 777u64, key: vec![42] }`. The module doesn't depend on
+	/// `std`, so builds with and without `std` must produce these bytes.
+	const EXTRINSIC_INDEX_GOLDEN: [u8; 11] = [1, 9, 3, 0, 0, 0, 0, 0, 0, 4, 42];
+
+	#[test]
+	fn keys_encoding_matches_golden_vectors() {
+		let extrinsic_index = ExtrinsicIndex { block: 777u64, key: vec![42] };
+		assert_eq!(extrinsic_index.encode(), EXTRINSIC_INDEX_GOLDEN);
+		assert_eq!(ExtrinsicIndex::<u64>::decode(&mut &EXTRINSIC_INDEX_GOLDEN[..]), Ok(extrinsic_index.clone()));
+		assert_eq!(
+			InputKey::<u64>::decode_from_trie_key(&EXTRINSIC_INDEX_GOLDEN),
+			Ok(InputKey::ExtrinsicIndex(extrinsic_index)),
+		);
+		assert!(EXTRINSIC_INDEX_GOLDEN.starts_with(&ExtrinsicIndex::key_neutral_prefix(777u64)));
+
+		assert_eq!(ExtrinsicIndex { block: 777u32, key: vec![42, 43] }.encode(), vec![1, 9, 3, 0, 0, 8, 42, 43]);
+		assert_eq!(DigestIndex { block: 16u64, key: vec![] }.encode(), vec![2, 16, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(
+			ChildIndex { block: 4u32, storage_key: PrefixedStorageKey::new(b"child".to_vec()) }.encode(),
+			vec![3, 4, 0, 0, 0, 20, 99, 104, 105, 108, 100],
+		);
+		assert_eq!(MissingBlocksIndex { block: 12u64 }.encode(), vec![4, 12, 0, 0, 0, 0, 0, 0, 0]);
+	}
+}
@@ -32,13 +32,19 @@ use sp_externalities::{
 };
 use codec::{Decode, Encode, EncodeAppend};
 
-use sp_std::{fmt, any::{Any, TypeId}, vec::Vec, vec, boxed::Box, cmp::Ordering};
+use sp_std::{fmt, any::{Any, TypeId}, vec::Vec, vec, boxed::Box, cmp::Ordering, collections::btree_map::BTreeMap};
 use crate::{warn, trace, log_error};
 #[cfg(feature = "std")]
-use crate::changes_trie::State as ChangesTrieState;
+use crate::{
+	ChangesTrieTransaction, changes_trie::State as ChangesTrieState,
+	observer::{ExecutionObserver, ReadSource},
+	overlayed_changes::ExtrinsicId,
+};
+#[cfg(feature = "std")]
+use sp_core::storage::well_known_keys::EXTRINSIC_INDEX;
 use crate::StorageTransactionCache;
 #[cfg(feature = "std")]
-use std::error;
+use std::{error, time::Instant};
 
 const EXT_NOT_ALLOWED_TO_FAIL: &str = "Externalities not allowed to fail within runtime";
 const BENCHMARKING_FN: &str = "\
@@ -89,6 +95,16 @@ impl<B: error::Error, E: error::Error> error::Error for Error<B, E> {
 	}
 }
 
+/// Root of the changed child storage, computed by the backend.
+struct CachedChildRoot<H: Hasher, Transaction> {
+	/// Root of the child storage.
+	root: H::Out,
+	/// True if the child storage is empty.
+	is_empty: bool,
+	/// Transaction of the child storage. Taken when the storage root is computed.
+	transaction: Option<Transaction>,
+}
+
 /// Wraps a read-only backend, call executor, and current overlayed changes.
 pub struct Ext<'a, H, N, B>
 	where
@@ -102,6 +118,9 @@ pub struct Ext<'a, H, N, B>
 	backend: &'a B,
 	/// The cache for the storage transactions.
 	storage_transaction_cache: &'a mut StorageTransactionCache<B::Transaction, H, N>,
+	/// Roots of the changed child storages. The root is only valid if the child storage hasn't
+	/// been changed since it has been computed (see `OverlayedChanges::is_child_dirty`).
+	child_roots: BTreeMap<StorageKey, CachedChildRoot<H, B::Transaction>>,
 	/// Changes trie state to read from.
 	#[cfg(feature = "std")]
 	changes_trie_state: Option<ChangesTrieState<'a, H, N>>,
@@ -112,6 +131,9 @@ pub struct Ext<'a, H, N, B>
 	/// Extensions registered with this instance.
 	#[cfg(feature = "std")]
 	extensions: Option<OverlayedExtensions<'a>>,
+	/// Observer of the execution. Nothing is measured if it isn't set.
+	#[cfg(feature = "std")]
+	observer: Option<&'a dyn ExecutionObserver>,
 }
 
 
@@ -133,6 +155,7 @@ impl<'a, H, N, B> Ext<'a, H, N, B>
 			backend,
 			id: 0,
 			storage_transaction_cache,
+			child_roots: BTreeMap::new(),
 			_phantom: Default::default(),
 		}
 	}
@@ -151,18 +174,131 @@ impl<'a, H, N, B> Ext<'a, H, N, B>
 			backend,
 			changes_trie_state,
 			storage_transaction_cache,
+			child_roots: BTreeMap::new(),
 			id: rand::random(),
 			_phantom: Default::default(),
 			extensions: extensions.map(OverlayedExtensions::new),
+			observer: None,
 		}
 	}
 
+	/// Notify the given observer about reads and writes of the top-level storage, storage
+	/// roots and changes tries that are computed by this `Ext`.
+	#[cfg(feature = "std")]
+	pub fn with_observer(mut self, observer: Option<&'a dyn ExecutionObserver>) -> Self {
+		self.observer = observer;
+		self
+	}
+
 	/// Invalidates the currently cached storage root and the db transaction.
 	///
 	/// Called when there are changes that likely will invalidate the storage root.
 	fn mark_dirty(&mut self) {
 		self.storage_transaction_cache.reset();
 	}
+
+	/// Returns the extrinsic index, set by the runtime, if it exceeds the max number of
+	/// extrinsics in the block, so the changes trie of the block couldn't be built.
+	#[cfg(feature = "std")]
+	fn excess_extrinsic_index(&self, value: Option<&[u8]>) -> Option<u32> {
+		let max_extrinsics = self.changes_trie_state.as_ref()?.params.max_extrinsics_per_block;
+
+		value
+			.and_then(|value| u32::decode(&mut &value[..]).ok())
+			.and_then(|index| ExtrinsicId(index).extrinsic_index())
+			.filter(|index| *index >= max_extrinsics)
+	}
+
+	/// Get root of the child storage that has been changed in the overlay. Returns `None` if
+	/// there are no changes of the child storage.
+	///
+	/// The root is only computed by the backend if the child storage has been changed since its
+	/// root has been computed for the last time, or if the `transaction` is required and the
+	/// cached one has already been taken.
+	fn changed_child_root(&mut self, child_info: &ChildInfo, with_transaction: bool) -> Option<(H::Out, bool)>
+		where H::Out: Ord,
+	{
+		let storage_key = child_info.storage_key();
+		if !self.overlay.is_child_dirty(storage_key) {
+			if let Some(cached) = self.child_roots.get(storage_key) {
+				if self.overlay.child_changes(storage_key).is_some()
+					&& (!with_transaction || cached.transaction.is_some())
+				{
+					return Some((cached.root, cached.is_empty));
+				}
+			}
+		}
+
+		let (root, is_empty, transaction) = {
+			let (delta, info) = self.overlay.effective_child_changes(storage_key)?;
+			self.backend.child_storage_root(info, delta)
+		};
+		self.overlay.mark_child_root_computed(storage_key);
+		self.child_roots.insert(storage_key.to_vec(), CachedChildRoot {
+			root,
+			is_empty,
+			transaction: Some(transaction),
+		});
+		Some((root, is_empty))
+	}
+
+	/// Take the changes trie transaction that has been computed by the last
+	/// `storage_changes_root` call.
+	///
+	/// Returns `None` if the root hasn't been computed since the last change, or if there's no
+	/// changes trie for the block. The computed root stays cached, so the transaction is computed
+	/// again only if it is required to drain the storage changes.
+	#[cfg(feature = "std")]
+	pub fn take_changes_trie_transaction(&mut self) -> Option<ChangesTrieTransaction<H, N>> {
+		self.storage_transaction_cache.changes_trie_transaction.take().flatten()
+	}
+}
+
+#[cfg(feature = "std")]
+impl<'a, H, N, B> Ext<'a, H, N, B>
+where
+	H: Hasher,
+	H::Out: Ord + 'static + codec::Codec,
+	B: Backend<H>,
+	N: crate::changes_trie::BlockNumber,
+{
+	/// Same as `storage_changes_root`, but the changes trie is built for the block
+	/// `parent_block + 1`, regardless of the block number the overlay has been prepared for.
+	///
+	/// Fails if the changes trie root has already been computed for other block, or if
+	/// `parent_block` isn't the number of the block with `parent_hash`.
+	pub fn storage_changes_root_at(&mut self, parent_hash: &[u8], parent_block: N) -> Result<Option<Vec<u8>>, ()> {
+		let block = parent_block.clone() + num_traits::One::one();
+		let is_other_block = self.overlay.extrinsic_changes().block::<N>()
+			.map(|prepared_block| prepared_block != block)
+			.unwrap_or(false);
+		if is_other_block && self.storage_transaction_cache.changes_trie_transaction_storage_root.is_some() {
+			warn!(
+				target: "state",
+				"Refuse to build changes trie of block {}: it has already been built for other block",
+				block,
+			);
+			return Err(());
+		}
+
+		if let Some(ref state) = self.changes_trie_state {
+			let anchor = Decode::decode(&mut &parent_hash[..])
+				.map_err(|_| ())
+				.and_then(|parent_hash| state.storage.build_anchor(parent_hash).map_err(|_| ()))?;
+			if anchor.number != parent_block {
+				warn!(
+					target: "state",
+					"Refuse to build changes trie of block {}: parent block number is {}",
+					block,
+					anchor.number,
+				);
+				return Err(());
+			}
+		}
+
+		self.overlay.set_block_number(block);
+		self.storage_changes_root(parent_hash)
+	}
 }
 
 #[cfg(test)]
@@ -178,7 +314,7 @@ where
 
 		self.backend.pairs().iter()
 			.map(|&(ref k, ref v)| (k.to_vec(), Some(v.to_vec())))
-			.chain(self.overlay.changes().map(|(k, v)| (k.clone(), v.value().cloned())))
+			.chain(self.overlay.effective_changes().map(|(k, v)| (k.to_vec(), v.map(|v| v.to_vec()))))
 			.collect::<HashMap<_, _>>()
 			.into_iter()
 			.filter_map(|(k, maybe_val)| maybe_val.map(|val| (k, val)))
@@ -199,8 +335,21 @@ where
 
 	fn storage(&self, key: &[u8]) -> Option<StorageValue> {
 		let _guard = guard();
-		let result = self.overlay.storage(key).map(|x| x.map(|x| x.to_vec())).unwrap_or_else(||
+		self.overlay.record_read(key);
+		#[cfg(feature = "std")]
+		let started = self.observer.map(|_| Instant::now());
+		let overlay_value = self.overlay.storage(key);
+		#[cfg(feature = "std")]
+		let source = match overlay_value {
+			Some(_) => ReadSource::Overlay,
+			None => ReadSource::Backend,
+		};
+		let result = overlay_value.map(|x| x.map(|x| x.to_vec())).unwrap_or_else(||
 			self.backend.storage(key).expect(EXT_NOT_ALLOWED_TO_FAIL));
+		#[cfg(feature = "std")]
+		if let (Some(observer), Some(started)) = (self.observer, started) {
+			observer.read(key, source, started.elapsed());
+		}
 
 		// NOTE: be careful about touching the key names – used outside substrate!
 		trace!(
@@ -222,6 +371,7 @@ where
 
 	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
 		let _guard = guard();
+		self.overlay.record_read(key);
 		let result = self.overlay
 			.storage(key)
 			.map(|x| x.map(|x| H::hash(x)))
@@ -241,6 +391,7 @@ where
 		key: &[u8],
 	) -> Option<StorageValue> {
 		let _guard = guard();
+		self.overlay.record_child_read(child_info, key);
 		let result = self.overlay
 			.child_storage(child_info, key)
 			.map(|x| x.map(|x| x.to_vec()))
@@ -265,6 +416,7 @@ where
 		key: &[u8],
 	) -> Option<Vec<u8>> {
 		let _guard = guard();
+		self.overlay.record_child_read(child_info, key);
 		let result = self.overlay
 			.child_storage(child_info, key)
 			.map(|x| x.map(|x| H::hash(x)))
@@ -285,6 +437,7 @@ where
 
 	fn exists_storage(&self, key: &[u8]) -> bool {
 		let _guard = guard();
+		self.overlay.record_read(key);
 		let result = match self.overlay.storage(key) {
 			Some(x) => x.is_some(),
 			_ => self.backend.exists_storage(key).expect(EXT_NOT_ALLOWED_TO_FAIL),
@@ -305,6 +458,7 @@ where
 		key: &[u8],
 	) -> bool {
 		let _guard = guard();
+		self.overlay.record_child_read(child_info, key);
 
 		let result = match self.overlay.child_storage(child_info, key) {
 			Some(x) => x.is_some(),
@@ -410,6 +564,17 @@ where
 			warn!(target: "trie", "Refuse to directly set child storage key");
 			return;
 		}
+		#[cfg(feature = "std")]
+		if key == EXTRINSIC_INDEX {
+			if let Some(index) = self.excess_extrinsic_index(value.as_deref()) {
+				warn!(
+					target: "runtime",
+					"Extrinsic index {} is above the max number of extrinsics in the block, changes trie won't be built",
+					index,
+				);
+				self.overlay.note_excess_extrinsic_index(index);
+			}
+		}
 
 		// NOTE: be careful about touching the key names – used outside substrate!
 		trace!(
@@ -427,6 +592,10 @@ where
 		);
 
 		self.mark_dirty();
+		#[cfg(feature = "std")]
+		if let Some(observer) = self.observer {
+			observer.write(&key);
+		}
 		self.overlay.set_storage(key, value);
 	}
 
@@ -511,6 +680,10 @@ where
 
 		let _guard = guard();
 		self.mark_dirty();
+		#[cfg(feature = "std")]
+		if let Some(observer) = self.observer {
+			observer.write(&key);
+		}
 
 		let backend = &mut self.backend;
 		let current_value = self.overlay.value_mut_or_insert_with(
@@ -530,7 +703,32 @@ where
 			return root.encode();
 		}
 
-		let root = self.overlay.storage_root(self.backend, self.storage_transaction_cache);
+		#[cfg(feature = "std")]
+		let started = self.observer.map(|_| Instant::now());
+		// roots of child storages that haven't been changed since their roots have been read
+		// are taken from the cache
+		let changed_children = self.overlay.children()
+			.map(|(_, child_info)| child_info.clone())
+			.collect::<Vec<_>>();
+		for child_info in &changed_children {
+			self.changed_child_root(child_info, true);
+		}
+		let child_roots = &mut self.child_roots;
+		let root = self.overlay.storage_root_with_child_roots(
+			self.backend,
+			self.storage_transaction_cache,
+			|child_info| {
+				let cached = child_roots.get_mut(child_info.storage_key())
+					.expect("roots of all changed child storages are computed above; qed");
+				let transaction = cached.transaction.take()
+					.expect("transactions of all changed child storages are computed above; qed");
+				(cached.root, cached.is_empty, transaction)
+			},
+		);
+		#[cfg(feature = "std")]
+		if let (Some(observer), Some(started)) = (self.observer, started) {
+			observer.storage_root(started.elapsed());
+		}
 		trace!(target: "state", "{:04x}: Root {}", self.id, HexDisplay::from(&root.as_ref()));
 		root.encode()
 	}
@@ -556,14 +754,9 @@ where
 			);
 			root.encode()
 		} else {
-			let root = if let Some((changes, info)) = self.overlay.child_changes(storage_key) {
-				let delta = changes.map(|(k, v)| (k.as_ref(), v.value().map(AsRef::as_ref)));
-				Some(self.backend.child_storage_root(info, delta))
-			} else {
-				None
-			};
+			let root = self.changed_child_root(child_info, false);
 
-			if let Some((root, is_empty, _)) = root {
+			if let Some((root, is_empty)) = root {
 				let root = root.encode();
 				// We store update in the overlay in order to be able to use 'self.storage_transaction'
 				// cache. This is brittle as it rely on Ext only querying the trie backend for
@@ -648,9 +841,10 @@ where
 				root,
 			);
 
-			Ok(Some(root.encode()))
+			Ok(root.map(|root| root.encode()))
 		} else {
-			let root = self.overlay.changes_trie_root(
+			let mut stats = self.observer.map(|_| Default::default());
+			let root = self.overlay.changes_trie_root_with_stats(
 				self.backend,
 				self.changes_trie_state.as_ref(),
 				Decode::decode(&mut parent_hash).map_err(|e|
@@ -662,7 +856,13 @@ where
 				)?,
 				true,
 				self.storage_transaction_cache,
+				&mut stats,
 			);
+			match (self.observer, stats) {
+				(Some(observer), Some(stats)) if self.changes_trie_state.is_some() && root.is_ok() =>
+					observer.changes_trie_build(&stats),
+				_ => (),
+			}
 
 			trace!(
 				target: "state",
@@ -922,13 +1122,21 @@ mod tests {
 		storage::{
 			Storage,
 			StorageChild,
+			PrefixedStorageKey,
 			well_known_keys::EXTRINSIC_INDEX,
 		},
 	};
 	use crate::{
 		changes_trie::{
+			AnchorBlockId as ChangesTrieAnchorBlockId,
+			BuildParams as ChangesTrieBuildParams,
 			Configuration as ChangesTrieConfiguration,
+			EmptyBlockPolicy,
+			Error as ChangesTrieError,
 			InMemoryStorage as TestChangesTrieStorage,
+			RootsStorage as ChangesTrieRootsStorage,
+			Storage as ChangesTrieStorage,
+			input::{InputPair, ExtrinsicIndex},
 		}, InMemoryBackend,
 	};
 
@@ -938,7 +1146,7 @@ mod tests {
 	fn prepare_overlay_with_changes() -> OverlayedChanges {
 		let mut changes = OverlayedChanges::default();
 		changes.set_collect_extrinsics(true);
-		changes.set_extrinsic_index(1);
+		changes.set_extrinsic_index(1).unwrap();
 		changes.set_storage(vec![1], Some(vec![100]));
 		changes.set_storage(EXTRINSIC_INDEX.to_vec(), Some(3u32.encode()));
 		changes.set_offchain_storage(b"k1", Some(b"v1"));
@@ -950,9 +1158,43 @@ mod tests {
 		ChangesTrieConfiguration {
 			digest_interval: 0,
 			digest_levels: 0,
+			..Default::default()
 		}
 	}
 
+	#[test]
+	fn observer_is_notified_about_execution_events() {
+		use crate::observer::{ReadSource, tests::{Event, RecordingObserver}};
+
+		let mut overlay = prepare_overlay_with_changes();
+		let mut cache = StorageTransactionCache::default();
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]);
+		let state = Some(ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage));
+		let backend = TestBackend::default();
+		let observer = RecordingObserver::default();
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None)
+			.with_observer(Some(&observer));
+
+		ext.storage(&[1]);
+		ext.storage(&[2]);
+		ext.set_storage(vec![2], vec![20]);
+		ext.storage_append(vec![3], 1u8.encode());
+		ext.storage_root();
+		// the root is cached until the storage is changed
+		ext.storage_root();
+		ext.storage_changes_root(&H256::default().encode()).unwrap();
+		drop(ext);
+
+		assert_eq!(*observer.events.lock(), vec![
+			Event::Read(vec![1], ReadSource::Overlay),
+			Event::Read(vec![2], ReadSource::Backend),
+			Event::Write(vec![2]),
+			Event::Write(vec![3]),
+			Event::StorageRoot,
+			Event::ChangesTrieBuild,
+		]);
+	}
+
 	#[test]
 	fn storage_changes_root_is_none_when_storage_is_not_provided() {
 		let mut overlay = prepare_overlay_with_changes();
@@ -981,10 +1223,44 @@ mod tests {
 		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None);
 		assert_eq!(
 			ext.storage_changes_root(&H256::default().encode()).unwrap(),
-			Some(hex!("bb0c2ef6e1d36d5490f9766cfcc7dfe2a6ca804504c3bb206053890d6dd02376").to_vec()),
+			Some(hex!("733f847650e6fc3b07a996deb55102d333c4418be87a3dccc58ea3bb81b0fca9").to_vec()),
 		);
 	}
 
+	#[test]
+	fn storage_changes_root_block_number_may_be_overridden() {
+		let mut overlay = prepare_overlay_with_changes();
+		// the block builder has guessed the wrong number of the block
+		overlay.set_block_number(42u64);
+		let mut cache = StorageTransactionCache::default();
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]);
+		let state = Some(ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage));
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None);
+		assert_eq!(
+			ext.storage_changes_root_at(&H256::default().encode(), 99).unwrap(),
+			Some(hex!("733f847650e6fc3b07a996deb55102d333c4418be87a3dccc58ea3bb81b0fca9").to_vec()),
+		);
+
+		// the root has been built for block#100 => it can't be rebuilt for other block
+		assert_eq!(ext.storage_changes_root_at(&H256::default().encode(), 100), Err(()));
+		drop(ext);
+		assert_eq!(overlay.extrinsic_changes().block(), Some(100u64));
+	}
+
+	#[test]
+	fn storage_changes_root_fails_when_parent_block_number_mismatches() {
+		let mut overlay = prepare_overlay_with_changes();
+		let mut cache = StorageTransactionCache::default();
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]);
+		let state = Some(ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage));
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None);
+		assert_eq!(ext.storage_changes_root_at(&H256::default().encode(), 98), Err(()));
+		drop(ext);
+		assert_eq!(overlay.extrinsic_changes().block::<u64>(), None);
+	}
+
 	#[test]
 	fn storage_changes_root_is_some_when_extrinsic_changes_are_empty() {
 		let mut overlay = prepare_overlay_with_changes();
@@ -997,10 +1273,154 @@ mod tests {
 		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None);
 		assert_eq!(
 			ext.storage_changes_root(&H256::default().encode()).unwrap(),
-			Some(hex!("96f5aae4690e7302737b6f9b7f8567d5bbb9eac1c315f80101235a92d9ec27f4").to_vec()),
+			Some(hex!("03170a2e7597b7b7e3d84c05391d139a62b157e78786d8c082f29dcf4c111314").to_vec()),
 		);
 	}
 
+	#[test]
+	fn storage_changes_root_of_empty_block_follows_empty_block_policy() {
+		let parent_root = H256::repeat_byte(99);
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, parent_root)]);
+		let backend = TestBackend::default();
+		let changes_root = |empty_block_policy| {
+			let mut overlay = OverlayedChanges::default();
+			overlay.set_collect_extrinsics(true);
+			let mut cache = StorageTransactionCache::default();
+			let state = ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage)
+				.with_build_params(ChangesTrieBuildParams { empty_block_policy, ..Default::default() });
+			let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, Some(state), None);
+			ext.storage_changes_root(&parent_root.encode()).unwrap()
+		};
+
+		assert_eq!(changes_root(EmptyBlockPolicy::OmitRoot), None);
+		assert_eq!(
+			changes_root(EmptyBlockPolicy::EmptyTrieRoot),
+			Some(crate::changes_trie::empty_root::<Blake2Hasher>().encode()),
+		);
+		assert_eq!(changes_root(EmptyBlockPolicy::ReuseParentRoot), Some(parent_root.encode()));
+	}
+
+	#[test]
+	fn extrinsic_index_above_the_limit_fails_changes_trie_build() {
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]);
+		let backend = TestBackend::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		let mut cache = StorageTransactionCache::default();
+		let params = ChangesTrieBuildParams { max_extrinsics_per_block: 2, ..Default::default() };
+		let state = ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage)
+			.with_build_params(params);
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, Some(state), None);
+
+		ext.place_storage(EXTRINSIC_INDEX.to_vec(), Some(1u32.encode()));
+		ext.set_storage(vec![10], vec![10]);
+		ext.place_storage(EXTRINSIC_INDEX.to_vec(), Some(2u32.encode()));
+		ext.set_storage(vec![20], vec![20]);
+
+		// the write is never dropped
+		assert_eq!(ext.storage(EXTRINSIC_INDEX), Some(2u32.encode()));
+		ext.place_storage(EXTRINSIC_INDEX.to_vec(), None);
+		drop(ext);
+
+		assert_eq!(overlay.extrinsic_changes().excess_extrinsic_index(), Some(2));
+		assert_eq!(
+			overlay.changes().find(|(key, _)| *key == &vec![20]).map(|(_, value)| value.sorted_extrinsics()),
+			Some(vec![2]),
+		);
+
+		// but the changes trie of the block can't be built
+		let state = ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage)
+			.with_build_params(params);
+		let parent = ChangesTrieAnchorBlockId { hash: Default::default(), number: 99 };
+		assert_eq!(
+			crate::changes_trie::try_build_changes_trie_outcome(&backend, Some(&state), &overlay, &parent)
+				.map(|_| ()),
+			Err(ChangesTrieError::TooManyExtrinsics { key: EXTRINSIC_INDEX.to_vec(), count: 3 }),
+		);
+	}
+
+	/// Changes trie storage that counts anchors that are built, i.e. changes tries builds.
+	struct BuildsCountingStorage {
+		inner: TestChangesTrieStorage<Blake2Hasher, u64>,
+		builds: std::sync::atomic::AtomicUsize,
+	}
+
+	impl ChangesTrieRootsStorage<Blake2Hasher, u64> for BuildsCountingStorage {
+		fn build_anchor(&self, hash: H256) -> Result<ChangesTrieAnchorBlockId<H256, u64>, ChangesTrieError<u64>> {
+			self.builds.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+			self.inner.build_anchor(hash)
+		}
+
+		fn root(&self, anchor: &ChangesTrieAnchorBlockId<H256, u64>, block: u64) -> Result<Option<H256>, ChangesTrieError<u64>> {
+			self.inner.root(anchor, block)
+		}
+
+		fn roots(&self, anchor: &ChangesTrieAnchorBlockId<H256, u64>, blocks: &[u64]) -> Result<Vec<Option<H256>>, ChangesTrieError<u64>> {
+			self.inner.roots(anchor, blocks)
+		}
+	}
+
+	impl ChangesTrieStorage<Blake2Hasher, u64> for BuildsCountingStorage {
+		fn as_roots_storage(&self) -> &dyn ChangesTrieRootsStorage<Blake2Hasher, u64> {
+			self
+		}
+
+		fn with_cached_changed_keys(
+			&self,
+			root: &H256,
+			functor: &mut dyn FnMut(&std::collections::HashMap<Option<PrefixedStorageKey>, std::collections::HashSet<StorageKey>>),
+		) -> bool {
+			self.inner.with_cached_changed_keys(root, functor)
+		}
+
+		fn get(&self, key: &H256, prefix: hash_db::Prefix) -> Result<Option<sp_trie::DBValue>, ChangesTrieError<u64>> {
+			self.inner.get(key, prefix)
+		}
+	}
+
+	#[test]
+	fn storage_changes_root_is_cached_until_storage_is_changed() {
+		let mut overlay = prepare_overlay_with_changes();
+		let mut cache = StorageTransactionCache::default();
+		let storage = BuildsCountingStorage {
+			inner: TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]),
+			builds: Default::default(),
+		};
+		let builds = || storage.builds.load(std::sync::atomic::Ordering::Relaxed);
+		let state = Some(ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage));
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, state, None);
+
+		let root = ext.storage_changes_root(&H256::default().encode()).unwrap();
+		assert!(root.is_some());
+		assert_eq!(ext.storage_changes_root(&H256::default().encode()).unwrap(), root);
+		assert_eq!(builds(), 1);
+
+		// the transaction may be taken without recomputing the root
+		assert!(ext.take_changes_trie_transaction().is_some());
+		assert!(ext.take_changes_trie_transaction().is_none());
+		assert_eq!(ext.storage_changes_root(&H256::default().encode()).unwrap(), root);
+		assert_eq!(builds(), 1);
+
+		ext.set_storage(vec![2], vec![200]);
+		assert_ne!(ext.storage_changes_root(&H256::default().encode()).unwrap(), root);
+		assert_eq!(builds(), 2);
+	}
+
+	#[test]
+	fn changes_limit_exceeded_is_recorded_on_set_storage() {
+		let mut overlay = prepare_overlay_with_changes();
+		let estimated_trie_size = overlay.extrinsic_changes().estimated_trie_size();
+		overlay.set_changes_limit(Some(estimated_trie_size));
+		let mut cache = StorageTransactionCache::default();
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut cache, &backend, None, None);
+
+		assert!(!ext.overlay.changes_limit_exceeded());
+		ext.set_storage(vec![2], vec![102]);
+		assert!(ext.overlay.changes_limit_exceeded());
+	}
+
 	#[test]
 	fn next_storage_key_works() {
 		let mut cache = StorageTransactionCache::default();
@@ -1154,6 +1574,45 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn clear_prefix_records_every_deleted_key() {
+		let prefixed = |key: u8| vec![1, key];
+		let backend: TestBackend = Storage {
+			top: (100..=105).map(|key| (prefixed(key), vec![255]))
+				.chain(std::iter::once((vec![2, 100], vec![255])))
+				.collect(),
+			children_default: map![],
+		}.into();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		let mut cache = StorageTransactionCache::default();
+
+		overlay.set_extrinsic_index(0).unwrap();
+		TestExt::new(&mut overlay, &mut cache, &backend, None, None)
+			.set_storage(prefixed(200), vec![200]);
+		overlay.set_extrinsic_index(1).unwrap();
+		assert_eq!(
+			TestExt::new(&mut overlay, &mut cache, &backend, None, None).clear_prefix(&[1], None),
+			(true, 6),
+		);
+
+		// the key that has only existed in the overlay isn't included
+		let (top, _) = crate::changes_trie::prepare_extrinsics_input_pairs::<_, Blake2Hasher, u64>(
+			&backend,
+			&crate::changes_trie::NoExistenceCache,
+			&Default::default(),
+			&1,
+			&changes_trie_config(),
+			&overlay,
+		).unwrap();
+		assert_eq!(
+			top,
+			(100..=105)
+				.map(|key| InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1, key: prefixed(key) }, vec![1].into()))
+				.collect::<Vec<_>>(),
+		);
+	}
+
 	#[test]
 	fn clear_prefix_cannot_delete_a_child_root() {
 		let child_info = ChildInfo::new_default(b"Child1");
@@ -1211,4 +1670,137 @@ mod tests {
 
 		assert_eq!(Vec::<u32>::decode(&mut &data[..]).unwrap(), vec![1, 2]);
 	}
+
+	/// Backend that records storage keys of child storages whose roots are computed.
+	#[derive(Debug, Default)]
+	struct ChildRootsCountingBackend {
+		inner: TestBackend,
+		child_roots: std::cell::RefCell<Vec<Vec<u8>>>,
+	}
+
+	impl Backend<Blake2Hasher> for ChildRootsCountingBackend {
+		type Error = crate::DefaultError;
+		type Transaction = sp_trie::MemoryDB<Blake2Hasher>;
+		type TrieBackendStorage = sp_trie::MemoryDB<Blake2Hasher>;
+
+		fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+			self.inner.storage(key)
+		}
+
+		fn child_storage(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> Result<Option<Vec<u8>>, Self::Error> {
+			self.inner.child_storage(child_info, key)
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+			self.inner.next_storage_key(key)
+		}
+
+		fn next_child_storage_key(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> Result<Option<StorageKey>, Self::Error> {
+			self.inner.next_child_storage_key(child_info, key)
+		}
+
+		fn apply_to_key_values_while<F: FnMut(Vec<u8>, Vec<u8>) -> bool>(
+			&self,
+			child_info: Option<&ChildInfo>,
+			prefix: Option<&[u8]>,
+			start_at: Option<&[u8]>,
+			f: F,
+			allow_missing: bool,
+		) -> Result<bool, Self::Error> {
+			self.inner.apply_to_key_values_while(child_info, prefix, start_at, f, allow_missing)
+		}
+
+		fn apply_to_keys_while<F: FnMut(&[u8]) -> bool>(
+			&self,
+			child_info: Option<&ChildInfo>,
+			prefix: Option<&[u8]>,
+			f: F,
+		) {
+			self.inner.apply_to_keys_while(child_info, prefix, f)
+		}
+
+		fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+			self.inner.for_key_values_with_prefix(prefix, f)
+		}
+
+		fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+			&self,
+			child_info: &ChildInfo,
+			prefix: &[u8],
+			f: F,
+		) {
+			self.inner.for_child_keys_with_prefix(child_info, prefix, f)
+		}
+
+		fn storage_root<'a>(
+			&self,
+			delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		) -> (H256, Self::Transaction) {
+			self.inner.storage_root(delta)
+		}
+
+		fn child_storage_root<'a>(
+			&self,
+			child_info: &ChildInfo,
+			delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		) -> (H256, bool, Self::Transaction) {
+			self.child_roots.borrow_mut().push(child_info.storage_key().to_vec());
+			self.inner.child_storage_root(child_info, delta)
+		}
+
+		fn pairs(&self) -> Vec<(StorageKey, Vec<u8>)> {
+			self.inner.pairs()
+		}
+
+		fn register_overlay_stats(&self, _stats: &crate::stats::StateMachineStats) { }
+
+		fn usage_info(&self) -> crate::UsageInfo {
+			crate::UsageInfo::empty()
+		}
+	}
+
+	#[test]
+	fn child_storage_roots_are_computed_lazily() {
+		let child_a = ChildInfo::new_default(b"child_a");
+		let child_b = ChildInfo::new_default(b"child_b");
+		let mut overlay = OverlayedChanges::default();
+		let mut cache = StorageTransactionCache::default();
+		let backend = ChildRootsCountingBackend::default();
+		let child_roots = || backend.child_roots.borrow().clone();
+		let mut ext = Ext::<Blake2Hasher, u64, _>::new(&mut overlay, &mut cache, &backend, None, None);
+
+		ext.set_child_storage(&child_b, vec![1], vec![1]);
+		let root_b = ext.child_storage_root(&child_b);
+		assert_eq!(child_roots(), vec![b"child_b".to_vec()]);
+
+		// changes of other child storage don't invalidate the root
+		ext.set_child_storage(&child_a, vec![2], vec![2]);
+		assert_eq!(ext.child_storage_root(&child_b), root_b);
+		assert_eq!(ext.child_storage_root(&child_b), root_b);
+		assert_eq!(child_roots(), vec![b"child_b".to_vec()]);
+
+		// storage root reuses the cached root, but computes the root of the changed child storage
+		let root = ext.storage_root();
+		assert_eq!(child_roots(), vec![b"child_b".to_vec(), b"child_a".to_vec()]);
+
+		// rolled back changes invalidate the root
+		ext.storage_start_transaction();
+		ext.set_child_storage(&child_b, vec![3], vec![3]);
+		assert_ne!(ext.child_storage_root(&child_b), root_b);
+		ext.storage_rollback_transaction().unwrap();
+		assert_eq!(ext.child_storage_root(&child_b), root_b);
+		assert_eq!(child_roots().len(), 4);
+
+		drop(ext);
+		let mut cache = StorageTransactionCache::<_, Blake2Hasher, u64>::default();
+		assert_eq!(overlay.storage_root(&backend.inner, &mut cache).encode(), root);
+	}
 }
@@ -18,14 +18,199 @@
 //! State machine in memory backend.
 
 use crate::{
-	StorageKey, StorageValue, StorageCollection, trie_backend::TrieBackend, backend::Backend,
+	StorageKey, StorageValue, StorageCollection, DefaultError,
+	trie_backend::TrieBackend, trie_backend_essence::TrieBackendStorage, backend::Backend,
 };
-use std::collections::{BTreeMap, HashMap};
-use hash_db::Hasher;
-use sp_trie::{MemoryDB, empty_trie_root, Layout};
-use codec::Codec;
+use std::{sync::Arc, collections::{BTreeMap, HashMap, HashSet}};
+use hash_db::{Hasher, HashDB, Prefix, EMPTY_PREFIX};
+use sp_trie::{MemoryDB, DBValue, empty_trie_root, Layout};
+use codec::{Codec, Decode, Encode};
 use sp_core::storage::{ChildInfo, Storage};
 
+/// Trie nodes storage of the in-memory backend.
+///
+/// Nodes are kept in layers that are shared between all snapshots of the backend, so updating
+/// the backend or taking its snapshot costs the size of the update, not the size of the state.
+/// The newest layer is merged into the previous one once it has grown to at least half of its
+/// size, so the number of layers stays logarithmic in the number of nodes.
+pub struct SharedMemoryDB<H: Hasher> {
+	/// Layers of nodes (from the oldest to the newest) with the number of entries in every layer.
+	layers: Vec<(Arc<MemoryDB<H>>, usize)>,
+}
+
+impl<H: Hasher> SharedMemoryDB<H> {
+	/// Merge trie nodes of the transaction into the storage.
+	///
+	/// Snapshots that share layers with this storage are not affected.
+	pub fn consolidate(&mut self, transaction: MemoryDB<H>) {
+		let transaction_size = transaction.keys().len();
+		if transaction_size == 0 {
+			return;
+		}
+
+		let unique_newest_layer = self.layers.last_mut()
+			.and_then(|(layer, size)| Arc::get_mut(layer).map(|layer| (layer, size)));
+		match unique_newest_layer {
+			Some((layer, size)) => {
+				layer.consolidate(transaction);
+				*size += transaction_size;
+			},
+			None => self.layers.push((Arc::new(transaction), transaction_size)),
+		}
+
+		self.compact();
+	}
+
+	/// Merge newest layers while the newest layer is at least half of the previous one.
+	fn compact(&mut self) {
+		while self.layers.len() > 1 {
+			let newer_size = self.layers[self.layers.len() - 1].1;
+			let older_size = self.layers[self.layers.len() - 2].1;
+			if newer_size * 2 < older_size {
+				break;
+			}
+
+			let (newer, newer_size) = self.layers.pop().expect("there are at least two layers; qed");
+			let (older, older_size) = self.layers.last_mut().expect("there are at least two layers; qed");
+			let newer = Arc::try_unwrap(newer).unwrap_or_else(|newer| (*newer).clone());
+			Arc::make_mut(older).consolidate(newer);
+			*older_size += newer_size;
+		}
+	}
+}
+
+impl<H: Hasher> Clone for SharedMemoryDB<H> {
+	fn clone(&self) -> Self {
+		SharedMemoryDB {
+			layers: self.layers.clone(),
+		}
+	}
+}
+
+impl<H: Hasher> Default for SharedMemoryDB<H> {
+	fn default() -> Self {
+		SharedMemoryDB {
+			layers: Vec::new(),
+		}
+	}
+}
+
+impl<H: Hasher> TrieBackendStorage<H> for SharedMemoryDB<H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, DefaultError> {
+		// the same node may be inserted and removed in different layers => sum reference counts
+		let mut total_rc = 0;
+		let mut value = None;
+		for (layer, _) in &self.layers {
+			if let Some((layer_value, rc)) = layer.raw(key, prefix) {
+				total_rc += rc;
+				if rc > 0 {
+					value = Some(layer_value);
+				}
+			}
+		}
+
+		Ok(if total_rc > 0 { value.cloned() } else { None })
+	}
+}
+
+/// Storage of trie nodes of the in-memory backend.
+pub trait InMemoryBackendStorage<H: Hasher>: TrieBackendStorage<H, Overlay = MemoryDB<H>> + Clone + Default {
+	/// Merge trie nodes of the transaction into the storage.
+	fn consolidate(&mut self, transaction: MemoryDB<H>);
+
+	/// Get all trie nodes of the storage, ordered by encoding.
+	fn nodes(&self) -> Vec<DBValue>;
+}
+
+impl<H: Hasher> InMemoryBackendStorage<H> for MemoryDB<H> {
+	fn consolidate(&mut self, transaction: MemoryDB<H>) {
+		MemoryDB::consolidate(self, transaction)
+	}
+
+	fn nodes(&self) -> Vec<DBValue> {
+		let mut nodes = self.clone().drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(_, (node, _))| node)
+			.collect::<Vec<_>>();
+		nodes.sort();
+		nodes
+	}
+}
+
+impl<H: Hasher> InMemoryBackendStorage<H> for SharedMemoryDB<H> {
+	fn consolidate(&mut self, transaction: MemoryDB<H>) {
+		SharedMemoryDB::consolidate(self, transaction)
+	}
+
+	fn nodes(&self) -> Vec<DBValue> {
+		let keys = self.layers.iter()
+			.flat_map(|(layer, _)| layer.keys().into_iter().map(|(key, _)| key))
+			.collect::<HashSet<_>>();
+		let mut nodes = keys.into_iter()
+			.filter_map(|key| TrieBackendStorage::get(self, &key, EMPTY_PREFIX).ok().flatten())
+			.collect::<Vec<_>>();
+		nodes.sort();
+		nodes
+	}
+}
+
+/// First bytes of every encoded state snapshot.
+pub const STATE_SNAPSHOT_MAGIC: [u8; 4] = *b"stsn";
+
+/// Current version of the state snapshot format.
+pub const STATE_SNAPSHOT_VERSION: u32 = 1;
+
+/// Portable snapshot of the in-memory state: the state root and all trie nodes.
+///
+/// Encoded snapshot starts with `STATE_SNAPSHOT_MAGIC` and the SCALE-encoded version of the
+/// snapshot format. Snapshots of other versions are rejected when decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSnapshot<Hash> {
+	/// Root of the state trie.
+	pub root: Hash,
+	/// Encoded nodes of the state trie and child tries, ordered by encoding.
+	pub nodes: Vec<Vec<u8>>,
+}
+
+impl<Hash: Encode + Decode> StateSnapshot<Hash> {
+	/// Encode snapshot, prefixed with the format header.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut encoded = STATE_SNAPSHOT_MAGIC.to_vec();
+		STATE_SNAPSHOT_VERSION.encode_to(&mut encoded);
+		(&self.root, &self.nodes).encode_to(&mut encoded);
+		encoded
+	}
+
+	/// Decode snapshot, encoded with `encode`.
+	///
+	/// Fails if the format header is missing, or the snapshot has been encoded using other
+	/// version of the format.
+	pub fn decode(encoded: &[u8]) -> Result<Self, String> {
+		if !encoded.starts_with(&STATE_SNAPSHOT_MAGIC) {
+			return Err("Not a state snapshot".into());
+		}
+		let mut input = &encoded[STATE_SNAPSHOT_MAGIC.len()..];
+		let version = u32::decode(&mut input)
+			.map_err(|e| format!("Failed to decode state snapshot version: {}", e))?;
+		if version != STATE_SNAPSHOT_VERSION {
+			return Err(format!(
+				"Unsupported state snapshot version {} (expected {})",
+				version,
+				STATE_SNAPSHOT_VERSION,
+			));
+		}
+
+		let (root, nodes) = <(Hash, Vec<Vec<u8>>)>::decode(&mut input)
+			.map_err(|e| format!("Failed to decode state snapshot: {}", e))?;
+		if !input.is_empty() {
+			return Err("Trailing bytes after the state snapshot".into());
+		}
+		Ok(StateSnapshot { root, nodes })
+	}
+}
+
 /// Create a new empty instance of in-memory backend.
 pub fn new_in_mem<H: Hasher>() -> TrieBackend<MemoryDB<H>, H>
 where
@@ -35,7 +220,28 @@ where
 	TrieBackend::new(db, empty_trie_root::<Layout<H>>())
 }
 
-impl<H: Hasher> TrieBackend<MemoryDB<H>, H>
+/// Create a new empty instance of in-memory backend, which shares trie nodes with its snapshots.
+pub fn new_shared_in_mem<H: Hasher>() -> TrieBackend<SharedMemoryDB<H>, H>
+where
+	H::Out: Codec + Ord,
+{
+	let db = SharedMemoryDB::default();
+	TrieBackend::new(db, empty_trie_root::<Layout<H>>())
+}
+
+impl<H: Hasher> TrieBackend<SharedMemoryDB<H>, H>
+where
+	H::Out: Codec + Ord,
+{
+	/// Take a snapshot of the state.
+	///
+	/// The snapshot shares trie nodes with this backend and isn't affected by its updates.
+	pub fn snapshot(&self) -> Self {
+		self.clone()
+	}
+}
+
+impl<H: Hasher, S: InMemoryBackendStorage<H>> TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
@@ -87,33 +293,55 @@ where
 	pub fn eq(&self, other: &Self) -> bool {
 		self.root() == other.root()
 	}
+
+	/// Export the state root and all trie nodes into the portable snapshot.
+	///
+	/// Nodes of previous versions of the state, that are still held by the backend, are
+	/// exported too.
+	pub fn export_state(&self) -> StateSnapshot<H::Out> {
+		StateSnapshot {
+			root: *self.root(),
+			nodes: self.backend_storage().nodes(),
+		}
+	}
+
+	/// Create backend from the snapshot, exported with `export_state`.
+	pub fn from_state(snapshot: StateSnapshot<H::Out>) -> Self {
+		let mut nodes = MemoryDB::default();
+		for node in snapshot.nodes {
+			HashDB::insert(&mut nodes, EMPTY_PREFIX, &node);
+		}
+		let mut db = S::default();
+		db.consolidate(nodes);
+		TrieBackend::new(db, snapshot.root)
+	}
 }
 
-impl<H: Hasher> Clone for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> Clone for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
 	fn clone(&self) -> Self {
-		TrieBackend::new(self.backend_storage().clone(), self.root().clone())
+		TrieBackend::new(self.backend_storage().clone(), *self.root())
 	}
 }
 
-impl<H: Hasher> Default for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> Default for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
 	fn default() -> Self {
-		new_in_mem()
+		TrieBackend::new(S::default(), empty_trie_root::<Layout<H>>())
 	}
 }
 
-impl<H: Hasher> From<HashMap<Option<ChildInfo>, BTreeMap<StorageKey, StorageValue>>>
-	for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> From<HashMap<Option<ChildInfo>, BTreeMap<StorageKey, StorageValue>>>
+	for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
 	fn from(inner: HashMap<Option<ChildInfo>, BTreeMap<StorageKey, StorageValue>>) -> Self {
-		let mut backend = new_in_mem();
+		let mut backend = Self::default();
 		backend.insert(
 			inner.into_iter().map(|(k, m)| (k, m.into_iter().map(|(k, v)| (k, Some(v))).collect())),
 		);
@@ -121,7 +349,7 @@ where
 	}
 }
 
-impl<H: Hasher> From<Storage> for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> From<Storage> for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
@@ -133,7 +361,7 @@ where
 	}
 }
 
-impl<H: Hasher> From<BTreeMap<StorageKey, StorageValue>> for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> From<BTreeMap<StorageKey, StorageValue>> for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
@@ -144,8 +372,8 @@ where
 	}
 }
 
-impl<H: Hasher> From<Vec<(Option<ChildInfo>, StorageCollection)>>
-	for TrieBackend<MemoryDB<H>, H>
+impl<H: Hasher, S: InMemoryBackendStorage<H>> From<Vec<(Option<ChildInfo>, StorageCollection)>>
+	for TrieBackend<S, H>
 where
 	H::Out: Codec + Ord,
 {
@@ -166,6 +394,37 @@ where
 	}
 }
 
+impl<H: Hasher, S: InMemoryBackendStorage<H>> std::iter::FromIterator<(StorageKey, StorageValue)> for TrieBackend<S, H>
+where
+	H::Out: Codec + Ord,
+{
+	fn from_iter<T: IntoIterator<Item = (StorageKey, StorageValue)>>(iter: T) -> Self {
+		iter.into_iter().collect::<BTreeMap<_, _>>().into()
+	}
+}
+
+impl<H: Hasher, S: InMemoryBackendStorage<H>> Extend<(Option<ChildInfo>, StorageCollection)> for TrieBackend<S, H>
+where
+	H::Out: Codec + Ord,
+{
+	fn extend<T: IntoIterator<Item = (Option<ChildInfo>, StorageCollection)>>(&mut self, iter: T) {
+		self.insert(iter)
+	}
+}
+
+impl<H: Hasher, S: InMemoryBackendStorage<H>> IntoIterator for TrieBackend<S, H>
+where
+	H::Out: Codec + Ord,
+{
+	type Item = (StorageKey, StorageValue);
+	type IntoIter = std::vec::IntoIter<(StorageKey, StorageValue)>;
+
+	/// Iterate over top-level key-value pairs, in the key order.
+	fn into_iter(self) -> Self::IntoIter {
+		self.pairs().into_iter()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -202,4 +461,152 @@ mod tests {
 		assert_eq!(storage.child_storage(&child_info, &b"2"[..]), Ok(Some(b"3".to_vec())));
 		assert_eq!(storage.child_storage(&child_info, &b"1"[..]), Ok(Some(b"3".to_vec())));
 	}
+
+	#[test]
+	fn snapshots_are_not_affected_by_updates() {
+		use codec::Encode;
+
+		let mut snapshots = vec![new_shared_in_mem::<BlakeTwo256>()];
+		for block in 0..1000u32 {
+			let mut changes = (0..9u8)
+				.map(|key| (vec![key], Some(block.encode())))
+				.collect::<Vec<_>>();
+			changes.push(((1000 + block).encode(), Some(vec![1])));
+
+			let snapshot = snapshots.last().expect("there's at least one snapshot").snapshot();
+			snapshots.push(snapshot.update(vec![(None, changes)]));
+		}
+
+		// number of layers is logarithmic in the number of nodes
+		assert!(snapshots.last().unwrap().backend_storage().layers.len() <= 20);
+
+		for (block, snapshot) in snapshots.iter().enumerate().skip(1).step_by(111) {
+			let block = block as u32 - 1;
+			assert_eq!(snapshot.storage(&[0]).unwrap(), Some(block.encode()));
+			assert!(snapshot.exists_storage(&(1000 + block).encode()).unwrap());
+			assert!(!snapshot.exists_storage(&(1000 + block + 1).encode()).unwrap());
+			assert_eq!(snapshot.pairs().len(), 9 + block as usize + 1);
+			assert_ne!(
+				snapshot.storage_root(std::iter::empty()).0,
+				snapshots[block as usize].storage_root(std::iter::empty()).0,
+			);
+		}
+	}
+
+	#[test]
+	fn pairs_are_iterated_page_by_page() {
+		let pairs = (0..10_000u32)
+			.map(|key| (key.to_be_bytes().to_vec(), key.to_le_bytes().to_vec()))
+			.collect::<Vec<_>>();
+		let backend = pairs.iter().cloned().collect::<TrieBackend<SharedMemoryDB<BlakeTwo256>, BlakeTwo256>>();
+
+		let mut iterated = Vec::new();
+		let mut start_key = None;
+		loop {
+			let mut page = Vec::new();
+			start_key = backend.for_pairs(start_key.as_deref(), 100, |key, value| {
+				page.push((key.to_vec(), value.to_vec()));
+				true
+			}).unwrap();
+			assert_eq!(page.len(), 100);
+			iterated.extend(page);
+
+			// the last page ends exactly at the last key, so the cursor is only
+			// cleared when there's nothing left to iterate
+			if start_key.is_none() {
+				break;
+			}
+			assert_eq!(start_key, Some(pairs[iterated.len()].0.clone()));
+		}
+		assert_eq!(iterated, pairs);
+		assert_eq!(
+			backend.for_pairs(Some(&pairs[9_999].0), 1, |_, _| true),
+			Ok(None),
+		);
+
+		// iteration stops early if the callback asks for it
+		let mut visited = 0;
+		let next_key = backend.for_pairs(Some(&pairs[10].0), 100, |_, _| {
+			visited += 1;
+			visited < 3
+		}).unwrap();
+		assert_eq!(visited, 3);
+		assert_eq!(next_key, Some(pairs[13].0.clone()));
+
+		// start key doesn't have to exist
+		let mut first = None;
+		backend.for_pairs(Some(&[0, 0, 0, 5, 0][..]), 1, |key, _| {
+			first = Some(key.to_vec());
+			true
+		}).unwrap();
+		assert_eq!(first, Some(pairs[6].0.clone()));
+	}
+
+	#[test]
+	fn pairs_do_not_depend_on_insertion_order() {
+		let pairs = (0..100u8).map(|key| (vec![(key as u32 * 7 % 100) as u8], vec![key])).collect::<Vec<_>>();
+
+		let forward = pairs.iter().cloned().collect::<TrieBackend<SharedMemoryDB<BlakeTwo256>, BlakeTwo256>>();
+		let mut backward = new_shared_in_mem::<BlakeTwo256>();
+		backward.extend(pairs.chunks(10).rev().map(|chunk|
+			(None, chunk.iter().rev().map(|(k, v)| (k.clone(), Some(v.clone()))).collect())));
+
+		let mut expected = pairs;
+		expected.sort();
+		assert_eq!(forward.pairs(), expected);
+		assert_eq!(backward.pairs(), expected);
+		assert_eq!(forward.root(), backward.root());
+		assert_eq!(
+			forward.storage_root(std::iter::empty()).0,
+			backward.storage_root(std::iter::empty()).0,
+		);
+		assert_eq!(backward.into_iter().collect::<Vec<_>>(), expected);
+	}
+
+	#[test]
+	fn prefix_keys_are_the_same_as_in_trie_backend() {
+		let trie = crate::trie_backend::tests::test_trie();
+		let pairs = trie.pairs().into_iter().collect::<BTreeMap<_, _>>();
+		let storage: TrieBackend<SharedMemoryDB<BlakeTwo256>, BlakeTwo256> = pairs.clone().into();
+
+		for prefix in vec![&b""[..], &b"value"[..], &b":"[..], &[200][..], &b"missing"[..]] {
+			let expected = pairs.keys()
+				.filter(|key| key.starts_with(prefix))
+				.cloned()
+				.collect::<Vec<_>>();
+
+			let mut walked = Vec::new();
+			storage.for_keys_with_prefix(prefix, |key| walked.push(key.to_vec()));
+			assert_eq!(walked, expected);
+			assert_eq!(storage.keys(prefix), expected);
+			assert_eq!(trie.keys(prefix), expected);
+		}
+	}
+
+	#[test]
+	fn state_is_restored_from_snapshot() {
+		let child_info = ChildInfo::new_default(b"child");
+		let storage = new_in_mem::<BlakeTwo256>().update(vec![
+			(None, vec![(b"key".to_vec(), Some(b"value".to_vec()))]),
+			(Some(child_info.clone()), vec![(b"child_key".to_vec(), Some(b"child_value".to_vec()))]),
+		]);
+
+		let snapshot = storage.export_state();
+		let encoded = snapshot.encode();
+		assert_eq!(&encoded[..4], &STATE_SNAPSHOT_MAGIC);
+		let decoded = StateSnapshot::decode(&encoded).unwrap();
+		assert_eq!(decoded, snapshot);
+
+		let restored = TrieBackend::<SharedMemoryDB<BlakeTwo256>, BlakeTwo256>::from_state(decoded);
+		assert_eq!(restored.root(), storage.root());
+		assert_eq!(restored.storage(b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(restored.child_storage(&child_info, b"child_key").unwrap(), Some(b"child_value".to_vec()));
+		assert_eq!(restored.export_state(), snapshot);
+
+		// snapshots of other versions are rejected
+		let mut other_version = encoded.clone();
+		other_version[4] = 2;
+		assert!(StateSnapshot::<<BlakeTwo256 as Hasher>::Out>::decode(&other_version).is_err());
+		assert!(StateSnapshot::<<BlakeTwo256 as Hasher>::Out>::decode(&encoded[4..]).is_err());
+	}
 }
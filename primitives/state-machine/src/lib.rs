@@ -25,6 +25,7 @@ pub mod backend;
 mod in_memory_backend;
 #[cfg(feature = "std")]
 mod changes_trie;
+pub mod changes_trie_keys;
 mod error;
 mod ext;
 #[cfg(feature = "std")]
@@ -36,6 +37,10 @@ pub(crate) mod overlayed_changes;
 mod proving_backend;
 mod trie_backend;
 mod trie_backend_essence;
+#[cfg(feature = "std")]
+mod node_cache;
+#[cfg(feature = "std")]
+mod observer;
 mod stats;
 #[cfg(feature = "std")]
 mod read_only;
@@ -123,9 +128,12 @@ pub use crate::overlayed_changes::{
 	StorageChanges, StorageTransactionCache,
 	OffchainChangesCollection,
 	OffchainOverlayedChanges,
-	IndexOperation,
+	IndexOperation, ReadsPerExtrinsic, ExtrinsicChanges, ReservedExtrinsicIndex, ExtrinsicId,
+	ChangesAlreadyTracked, ValueSpill, SpillHandle, InMemoryValueSpill,
+	NO_EXTRINSIC_INDEX, BLOCK_INITIALIZATION_EXTRINSIC_INDEX, BLOCK_FINALIZATION_EXTRINSIC_INDEX,
+	KEY_DELETION_EXTRINSIC_INDEX,
 };
-pub use crate::backend::Backend;
+pub use crate::backend::{Backend, OwnedPrefix};
 pub use crate::trie_backend_essence::{TrieBackendStorage, Storage};
 pub use crate::trie_backend::TrieBackend;
 pub use crate::stats::{UsageInfo, UsageUnit, StateMachineStats};
@@ -145,28 +153,103 @@ mod changes_trie {
 mod std_reexport {
 	pub use sp_trie::{trie_types::{Layout, TrieDBMut}, StorageProof, TrieMut, DBValue, MemoryDB};
 	pub use crate::testing::TestExternalities;
+	pub use crate::node_cache::NodeCache;
 	pub use crate::basic::BasicExternalities;
 	pub use crate::read_only::{ReadOnlyExternalities, InspectState};
 	pub use crate::changes_trie::{
 		AnchorBlockId as ChangesTrieAnchorBlockId,
 		State as ChangesTrieState,
 		Storage as ChangesTrieStorage,
+		BlockTrieStorage as ChangesTrieBlockTrieStorage,
 		RootsStorage as ChangesTrieRootsStorage,
 		InMemoryStorage as InMemoryChangesTrieStorage,
+		InMemoryStorageBuilder as InMemoryChangesTrieStorageBuilder,
+		ProofRecorder as ChangesTrieProofRecorder,
+		ProofCheckStorage as ChangesTrieProofCheckStorage,
+		CachedStorage as CachedChangesTrieStorage,
+		OverlayedChangesTrieStorage,
+		KvBackend as ChangesTrieKvBackend,
+		DbColumns as ChangesTrieDbColumns,
+		DbStorage as ChangesTrieDbStorage,
+		DbStorageTransaction as ChangesTrieDbStorageTransaction,
+		InMemoryKvBackend as InMemoryChangesTrieKvBackend,
+		RootsJournal as ChangesTrieRootsJournal,
+		verify_root_proof as verify_changes_trie_root_proof,
 		BuildCache as ChangesTrieBuildCache,
+		DigestCache as ChangesTrieDigestCache,
+		DigestCacheEntry as ChangesTrieDigestCacheEntry,
+		KeyKind as ChangesTrieKeyKind,
+		PendingDigest as ChangesTriePendingDigest,
+		ExistenceCache as ChangesTrieExistenceCache,
+		NoExistenceCache as NoChangesTrieExistenceCache,
+		InMemoryExistenceCache as InMemoryChangesTrieExistenceCache,
+		ChangesFilter as ChangesTrieChangesFilter,
+		DefaultChangesFilter as DefaultChangesTrieChangesFilter,
+		AllChangesFilter as AllChangesTrieChangesFilter,
 		CacheAction as ChangesTrieCacheAction,
 		ConfigurationRange as ChangesTrieConfigurationRange,
-		key_changes, key_changes_proof,
+		ConfigurationExt as ChangesTrieConfigurationExt,
+		Error as ChangesTrieError,
+		key_changes, key_changes_with_values, KeyChangesQuery, key_change_kinds, key_changes_proof,
+		ChangeKind as ChangesTrieChangeKind,
+		enumerate_changed_keys, enumerate_changed_keys_with_prefix,
+		StateProvider as ChangesTrieStateProvider,
 		key_changes_proof_check, key_changes_proof_check_with_db,
+		key_changes_limited, key_changes_proof_limited, key_changes_proof_check_limited,
+		KeyChangesLimits, KeyChangesCursor, KeyChangesPage,
+		key_changes_partial, key_changes_proof_partial, key_changes_proof_check_partial,
+		KeyChangesResult,
+		key_changed_since, key_changed_since_proof, key_changed_since_proof_check,
+		KeysChanges, keys_changes, keys_changes_proof, keys_changes_proof_check,
 		prune as prune_changes_tries,
+		oldest_non_prunable_block as oldest_non_prunable_changes_trie_block,
 		disabled_state as disabled_changes_trie_state,
+		empty_root as empty_changes_trie_root,
+		try_build_changes_trie, try_build_changes_trie_strict, try_build_changes_trie_with_stats,
+		try_build_changes_trie_outcome, try_build_changes_trie_outcome_with_stats,
+		try_build_changes_trie_streaming,
+		BuildStats as ChangesTrieBuildStats,
+		ChangedKeys as ChangesTrieChangedKeys,
+		BuildParams as ChangesTrieBuildParams,
+		EmptyBlockPolicy as ChangesTrieEmptyBlockPolicy,
+		MissingRootPolicy as ChangesTrieMissingRootPolicy,
+		ChangesTrieOutcome,
+		HeapSize as ChangesTrieHeapSize,
+		prepare_input as prepare_changes_trie_input,
+		prepare_input_with_limit as prepare_changes_trie_input_with_limit,
+		prepare_input_with_limit_from_source as prepare_changes_trie_input_with_limit_from_source,
+		InputParts as ChangesTrieInputParts,
+		compute_root_from_parts as compute_changes_trie_root_from_parts,
+		verify_digest_pairs_against_proof as verify_changes_trie_digest_pairs_against_proof,
+		ChangesSource as ChangesTrieChangesSource,
+		ChangedKey as ChangesTrieChangedKey,
+		CollectedChanges as ChangesTrieCollectedChanges,
+		RootCheck as ChangesTrieRootCheck,
+		compute_and_check_root as compute_and_check_changes_trie_root,
+		digest_build_iterator as changes_trie_digest_build_iterator,
+		DigestBuildIterator as ChangesTrieDigestBuildIterator,
+		digest_parent_blocks as changes_trie_digest_parent_blocks,
 		BlockNumber as ChangesTrieBlockNumber,
+		input as changes_trie_input,
+		consistency as changes_trie_consistency,
+		Reader as ChangesTrieReader,
+		rebuild_range as rebuild_changes_tries_range,
+		BlockChanges as ChangesTrieBlockChanges,
+		ChangesTrieSnapshot,
+		SNAPSHOT_MAGIC as CHANGES_TRIE_SNAPSHOT_MAGIC,
+		SNAPSHOT_VERSION as CHANGES_TRIE_SNAPSHOT_VERSION,
+		audit as changes_trie_audit,
+		bench_utils as changes_trie_bench_utils,
 	};
 	pub use crate::proving_backend::{
 		create_proof_check_backend, ProofRecorder, ProvingBackend, ProvingBackendRecorder,
 	};
 	pub use crate::error::{Error, ExecutionError};
-	pub use crate::in_memory_backend::new_in_mem;
+	pub use crate::observer::{ExecutionObserver, ExecutionAggregates, LoggingStats, ReadSource};
+	pub use crate::in_memory_backend::{
+		new_in_mem, new_shared_in_mem, InMemoryBackendStorage, SharedMemoryDB, StateSnapshot,
+		STATE_SNAPSHOT_MAGIC, STATE_SNAPSHOT_VERSION,
+	};
 }
 
 #[cfg(feature = "std")]
@@ -176,6 +259,7 @@ mod execution {
 	use log::{warn, trace};
 	use hash_db::Hasher;
 	use codec::{Decode, Encode, Codec};
+	use num_traits::Zero;
 	use sp_core::{
 		storage::ChildInfo, NativeOrEncoded, NeverNativeValue, hexdisplay::HexDisplay,
 		traits::{CodeExecutor, ReadRuntimeVersionExt, RuntimeCode, SpawnNamed},
@@ -201,6 +285,9 @@ mod execution {
 	/// Trie backend with in-memory storage.
 	pub type InMemoryBackend<H> = TrieBackend<MemoryDB<H>, H>;
 
+	/// Trie backend with in-memory storage, which shares trie nodes with its snapshots.
+	pub type SharedInMemoryBackend<H> = TrieBackend<SharedMemoryDB<H>, H>;
+
 	/// Strategy for executing a call into the runtime.
 	#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 	pub enum ExecutionStrategy {
@@ -308,6 +395,7 @@ mod execution {
 		storage_transaction_cache: Option<&'a mut StorageTransactionCache<B::Transaction, H, N>>,
 		runtime_code: &'a RuntimeCode<'a>,
 		stats: StateMachineStats,
+		observer: Option<&'a dyn ExecutionObserver>,
 	}
 
 	impl<'a, B, H, N, Exec> Drop for StateMachine<'a, B, H, N, Exec> where
@@ -353,9 +441,17 @@ mod execution {
 				storage_transaction_cache: None,
 				runtime_code,
 				stats: StateMachineStats::default(),
+				observer: None,
 			}
 		}
 
+		/// Notify the given observer about reads and writes of the top-level storage, storage
+		/// roots and changes tries that are computed by the runtime.
+		pub fn with_observer(mut self, observer: Option<&'a dyn ExecutionObserver>) -> Self {
+			self.observer = observer;
+			self
+		}
+
 		/// Use given `cache` as storage transaction cache.
 		///
 		/// The cache will be used to cache storage transactions that can be build while executing a
@@ -412,7 +508,7 @@ mod execution {
 				self.backend,
 				self.changes_trie_state.clone(),
 				Some(&mut self.extensions),
-			);
+			).with_observer(self.observer);
 
 			let id = ext.id;
 			trace!(
@@ -462,15 +558,20 @@ mod execution {
 			let (result, was_native) = self.execute_aux(true, native_call.take());
 
 			if was_native {
+				let native_changes_trie_input = self.changes_trie_extrinsics_input();
+				let native_untracked_prefixes = self.overlay.untracked_prefixes().to_vec();
 				self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
 				let (wasm_result, _) = self.execute_aux(
 					false,
 					native_call,
 				);
+				let wasm_changes_trie_input = self.changes_trie_extrinsics_input();
 
-				if (result.is_ok() && wasm_result.is_ok()
+				if ((result.is_ok() && wasm_result.is_ok()
 					&& result.as_ref().ok() == wasm_result.as_ref().ok())
-					|| result.is_err() && wasm_result.is_err()
+					|| result.is_err() && wasm_result.is_err())
+					&& changes_trie_inputs_match(&wasm_changes_trie_input, &native_changes_trie_input)
+					&& self.overlay.untracked_prefixes() == &native_untracked_prefixes[..]
 				{
 					result
 				} else {
@@ -482,6 +583,27 @@ mod execution {
 			}
 		}
 
+		/// Prepare ExtrinsicIndex input of the changes trie from the current overlay.
+		///
+		/// Returns `Ok(None)` if changes tries are disabled.
+		fn changes_trie_extrinsics_input(&self) -> Result<Option<changes_trie::ExtrinsicsInput<N>>, String> {
+			let state = match self.changes_trie_state.as_ref() {
+				Some(state) => state,
+				None => return Ok(None),
+			};
+			// block number is the same for both executions, so any number works here
+			changes_trie::prepare_extrinsics_input_pairs::<_, H, _>(
+				self.backend,
+				state.existence_cache,
+				&state.params,
+				&Zero::zero(),
+				&state.config,
+				self.overlay,
+			)
+				.map(Some)
+				.map_err(|e| e.to_string())
+		}
+
 		fn execute_call_with_native_else_wasm_strategy<R, NC>(
 			&mut self,
 			mut native_call: Option<NC>,
@@ -564,6 +686,51 @@ mod execution {
 		}
 	}
 
+	/// Compare changes trie inputs that have been prepared after wasm and native executions.
+	///
+	/// The first difference is logged. Failure to prepare the input after any of executions is
+	/// treated as a mismatch.
+	fn changes_trie_inputs_match<N: ChangesTrieBlockNumber>(
+		wasm_input: &Result<Option<changes_trie::ExtrinsicsInput<N>>, String>,
+		native_input: &Result<Option<changes_trie::ExtrinsicsInput<N>>, String>,
+	) -> bool {
+		let ((wasm_top, wasm_children), (native_top, native_children)) = match (wasm_input, native_input) {
+			(Ok(Some(wasm_input)), Ok(Some(native_input))) => (wasm_input, native_input),
+			(Ok(None), Ok(None)) => return true,
+			(Err(e), _) | (_, Err(e)) => {
+				warn!("Failed to prepare changes trie input: {}", e);
+				return false;
+			},
+			_ => return false,
+		};
+
+		let top_diff = changes_trie_audit::diff_inputs(wasm_top, native_top)
+			.into_iter()
+			.next()
+			.map(|diff| (None, diff));
+		let children_diff = || wasm_children.keys()
+			.chain(native_children.keys())
+			.collect::<std::collections::BTreeSet<_>>()
+			.into_iter()
+			.find_map(|child_index| changes_trie_audit::diff_inputs(
+				wasm_children.get(child_index).map(|pairs| &pairs[..]).unwrap_or(&[]),
+				native_children.get(child_index).map(|pairs| &pairs[..]).unwrap_or(&[]),
+			).into_iter().next().map(|diff| (Some(child_index), diff)));
+		match top_diff.or_else(children_diff) {
+			Some((child_index, diff)) => {
+				warn!(
+					"Changes trie input mismatch between wasm and native in {}: {:?}",
+					child_index
+						.map(|child_index| format!("child trie {}", HexDisplay::from(&*child_index.storage_key)))
+						.unwrap_or_else(|| "top trie".into()),
+					diff,
+				);
+				false
+			},
+			None => true,
+		}
+	}
+
 	/// Prove execution using the given state backend, overlayed changes, and call executor.
 	pub fn prove_execution<B, H, N, Exec, Spawn>(
 		mut backend: B,
@@ -1004,6 +1171,7 @@ mod tests {
 						ChangesTrieConfig {
 							digest_interval: 777,
 							digest_levels: 333,
+							..Default::default()
 						}.encode()
 					)
 				);
@@ -1075,6 +1243,44 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn execution_is_observed() {
+		use crate::observer::tests::{Event, RecordingObserver};
+
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let observer = RecordingObserver::default();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: true,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_observer(Some(&observer));
+
+		assert_eq!(
+			state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(),
+			vec![66],
+		);
+		drop(state_machine);
+		assert_eq!(*observer.events.lock(), vec![
+			Event::Write(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec()),
+			Event::Read(b"value1".to_vec(), ReadSource::Backend),
+			Event::Read(b"value2".to_vec(), ReadSource::Backend),
+		]);
+	}
+
 
 	#[test]
 	fn execute_works_with_native_else_wasm() {
@@ -1138,6 +1344,94 @@ mod tests {
 		assert!(consensus_failed);
 	}
 
+	/// Executor that changes the same key in native and wasm, but at given extrinsic indices.
+	#[derive(Clone)]
+	struct ExtrinsicIndexExecutor {
+		native_extrinsic_index: u32,
+		wasm_extrinsic_index: u32,
+	}
+
+	impl CodeExecutor for ExtrinsicIndexExecutor {
+		type Error = u8;
+
+		fn call<
+			R: Encode + Decode + PartialEq,
+			NC: FnOnce() -> result::Result<R, Box<dyn std::error::Error + Send + Sync>> + UnwindSafe,
+		>(
+			&self,
+			ext: &mut dyn Externalities,
+			_: &RuntimeCode,
+			_method: &str,
+			_data: &[u8],
+			use_native: bool,
+			_native_call: Option<NC>,
+		) -> (CallResult<R, Self::Error>, bool) {
+			let extrinsic_index = match use_native {
+				true => self.native_extrinsic_index,
+				false => self.wasm_extrinsic_index,
+			};
+			ext.place_storage(
+				sp_core::storage::well_known_keys::EXTRINSIC_INDEX.to_vec(),
+				Some(extrinsic_index.encode()),
+			);
+			ext.place_storage(b"key".to_vec(), Some(b"value".to_vec()));
+			(Ok(NativeOrEncoded::Encoded(Vec::new())), use_native)
+		}
+	}
+
+	impl sp_core::traits::ReadRuntimeVersion for ExtrinsicIndexExecutor {
+		fn read_runtime_version(
+			&self,
+			_: &[u8],
+			_: &mut dyn Externalities,
+		) -> std::result::Result<Vec<u8>, String> {
+			unimplemented!("Not required in tests.")
+		}
+	}
+
+	#[test]
+	fn dual_execution_strategy_detects_changes_trie_input_mismatch() {
+		let execute = |native_extrinsic_index: u32| {
+			let mut consensus_failed = false;
+			let backend = trie_backend::tests::test_trie();
+			let changes_trie_storage = InMemoryChangesTrieStorage::<BlakeTwo256, u64>::new();
+			let changes_trie_config = ChangesTrieConfig {
+				digest_interval: 0,
+				digest_levels: 0,
+				..Default::default()
+			};
+			let mut overlayed_changes = Default::default();
+			let wasm_code = RuntimeCode::empty();
+			let executor = ExtrinsicIndexExecutor { native_extrinsic_index, wasm_extrinsic_index: 2 };
+
+			let mut state_machine = StateMachine::new(
+				&backend,
+				Some(ChangesTrieState::new(changes_trie_config, 0, &changes_trie_storage)),
+				&mut overlayed_changes,
+				&executor,
+				"test",
+				&[],
+				Default::default(),
+				&wasm_code,
+				TaskExecutor::new(),
+			);
+
+			assert!(
+				state_machine.execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+					ExecutionManager::Both(|we, _ne| {
+						consensus_failed = true;
+						we
+					}),
+					None,
+				).is_ok()
+			);
+			consensus_failed
+		};
+
+		assert!(!execute(2));
+		assert!(execute(1));
+	}
+
 	#[test]
 	fn prove_execution_and_proof_check_works() {
 		let executor = DummyCodeExecutor {
@@ -0,0 +1,130 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of encoded trie nodes that could be shared by several trie backends.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use hash_db::Hasher;
+use lru::LruCache;
+use parking_lot::Mutex;
+use sp_trie::DBValue;
+
+/// Cache of encoded trie nodes, indexed by node hash.
+///
+/// Tries that are read one after another (e.g. changes tries of digest input blocks) often
+/// share upper nodes. When the same cache is used by trie backends of all these tries, shared
+/// nodes are read from the underlying storage once.
+///
+/// At most `capacity` nodes are held. When the cache is full, the least recently used node
+/// is evicted.
+pub struct NodeCache<H: Hasher> {
+	hits: AtomicU64,
+	misses: AtomicU64,
+	nodes: Mutex<LruCache<H::Out, DBValue>>,
+}
+
+impl<H: Hasher> NodeCache<H> {
+	/// Create new node cache that holds at most `capacity` nodes.
+	pub fn new(capacity: usize) -> Self {
+		NodeCache {
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+			nodes: Mutex::new(LruCache::new(capacity)),
+		}
+	}
+
+	/// Get number of cached nodes.
+	pub fn len(&self) -> usize {
+		self.nodes.lock().len()
+	}
+
+	/// Returns true if there are no cached nodes.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Get number of lookups that have found the node in the cache.
+	pub fn hits(&self) -> u64 {
+		self.hits.load(Ordering::Relaxed)
+	}
+
+	/// Get number of lookups that haven't found the node in the cache.
+	pub fn misses(&self) -> u64 {
+		self.misses.load(Ordering::Relaxed)
+	}
+
+	/// Get cached node with given hash.
+	pub fn get(&self, hash: &H::Out) -> Option<DBValue> {
+		let node = self.nodes.lock().get(hash).cloned();
+		match node.is_some() {
+			true => self.hits.fetch_add(1, Ordering::Relaxed),
+			false => self.misses.fetch_add(1, Ordering::Relaxed),
+		};
+		node
+	}
+
+	/// Insert node with given hash.
+	pub fn insert(&self, hash: H::Out, node: DBValue) {
+		self.nodes.lock().put(hash, node);
+	}
+
+	/// Remove all nodes.
+	pub fn clear(&self) {
+		self.nodes.lock().clear();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sp_core::Blake2Hasher;
+	use super::*;
+
+	fn node(n: u8) -> (<Blake2Hasher as Hasher>::Out, DBValue) {
+		(Blake2Hasher::hash(&[n]), vec![n])
+	}
+
+	#[test]
+	fn hits_and_misses_are_counted() {
+		let cache = NodeCache::<Blake2Hasher>::new(4);
+		let (hash, value) = node(1);
+		assert_eq!(cache.get(&hash), None);
+		cache.insert(hash, value.clone());
+		assert_eq!(cache.get(&hash), Some(value));
+		assert_eq!((cache.hits(), cache.misses()), (1, 1));
+	}
+
+	#[test]
+	fn least_recently_used_node_is_evicted() {
+		let cache = NodeCache::<Blake2Hasher>::new(2);
+		let (hash1, value1) = node(1);
+		let (hash2, value2) = node(2);
+		let (hash3, value3) = node(3);
+		cache.insert(hash1, value1);
+		cache.insert(hash2, value2);
+		assert!(cache.get(&hash1).is_some());
+
+		cache.insert(hash3, value3);
+		assert_eq!(cache.len(), 2);
+		assert!(cache.get(&hash1).is_some());
+		assert!(cache.get(&hash2).is_none());
+		assert!(cache.get(&hash3).is_some());
+
+		let cache = NodeCache::<Blake2Hasher>::new(0);
+		cache.insert(hash1, vec![1]);
+		assert!(cache.is_empty());
+	}
+}
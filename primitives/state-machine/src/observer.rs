@@ -0,0 +1,206 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hooks that observe the state machine execution.
+//!
+//! The observer is notified about reads and writes of the top-level storage, about computing
+//! the storage root and about building the changes trie of the block, so slow blocks could be
+//! diagnosed without profiling the whole node.
+
+use std::time::Duration;
+use parking_lot::Mutex;
+use log::debug;
+use sp_core::hexdisplay::HexDisplay;
+use crate::changes_trie::BuildStats;
+
+/// Where the value that has been read by the runtime has been found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+	/// The value has been changed in the overlay.
+	Overlay,
+	/// The value has been read from the backend.
+	Backend,
+}
+
+/// Observer of the state machine execution.
+///
+/// All methods are no-op by default. Methods are called synchronously from the execution,
+/// so implementations should be cheap. The unit type is the no-op observer.
+pub trait ExecutionObserver: Send + Sync {
+	/// The value of the top-level storage `key` has been read from the `source`.
+	fn read(&self, _key: &[u8], _source: ReadSource, _duration: Duration) {}
+
+	/// The value of the top-level storage `key` has been changed in the overlay.
+	fn write(&self, _key: &[u8]) {}
+
+	/// The changes trie of the block has been built.
+	fn changes_trie_build(&self, _stats: &BuildStats) {}
+
+	/// The storage root has been computed.
+	fn storage_root(&self, _duration: Duration) {}
+}
+
+impl ExecutionObserver for () {}
+
+/// Aggregated reads and writes that have been observed since the last storage root.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExecutionAggregates {
+	/// Number of values that have been read from the overlay.
+	pub overlay_reads: u64,
+	/// Number of values that have been read from the backend.
+	pub backend_reads: u64,
+	/// Total time spent on reads.
+	pub read_time: Duration,
+	/// Number of values that have been changed.
+	pub writes: u64,
+}
+
+/// Observer that writes `debug` log lines with per-block aggregates.
+///
+/// Reads and writes are aggregated until the storage root is computed, which normally happens
+/// once, when the block is finalized. Reads that are slower than the `slow_read` threshold
+/// are logged individually.
+#[derive(Debug, Default)]
+pub struct LoggingStats {
+	slow_read: Option<Duration>,
+	aggregates: Mutex<ExecutionAggregates>,
+}
+
+impl LoggingStats {
+	/// Create observer that also logs every read that takes longer than `slow_read`.
+	pub fn with_slow_read_threshold(slow_read: Duration) -> Self {
+		LoggingStats {
+			slow_read: Some(slow_read),
+			aggregates: Default::default(),
+		}
+	}
+
+	/// Aggregates that have been collected since the last storage root.
+	pub fn aggregates(&self) -> ExecutionAggregates {
+		self.aggregates.lock().clone()
+	}
+}
+
+impl ExecutionObserver for LoggingStats {
+	fn read(&self, key: &[u8], source: ReadSource, duration: Duration) {
+		{
+			let mut aggregates = self.aggregates.lock();
+			match source {
+				ReadSource::Overlay => aggregates.overlay_reads += 1,
+				ReadSource::Backend => aggregates.backend_reads += 1,
+			}
+			aggregates.read_time += duration;
+		}
+
+		if self.slow_read.map(|slow_read| duration > slow_read).unwrap_or(false) {
+			debug!(
+				target: "state",
+				"Slow read of {} from {:?}: {:?}",
+				HexDisplay::from(&key),
+				source,
+				duration,
+			);
+		}
+	}
+
+	fn write(&self, _key: &[u8]) {
+		self.aggregates.lock().writes += 1;
+	}
+
+	fn changes_trie_build(&self, stats: &BuildStats) {
+		debug!(
+			target: "state",
+			"Changes trie built in {:?}: {} extrinsic pairs, {} digest pairs, {} digest blocks walked, \
+			{} storage reads",
+			stats.elapsed,
+			stats.extrinsic_pairs,
+			stats.digest_pairs,
+			stats.digest_blocks_walked,
+			stats.storage_reads,
+		);
+	}
+
+	fn storage_root(&self, duration: Duration) {
+		let aggregates = std::mem::take(&mut *self.aggregates.lock());
+		debug!(
+			target: "state",
+			"Storage root computed in {:?}: {} overlay reads, {} backend reads in {:?}, {} writes",
+			duration,
+			aggregates.overlay_reads,
+			aggregates.backend_reads,
+			aggregates.read_time,
+			aggregates.writes,
+		);
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// Kind of the observed event.
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub(crate) enum Event {
+		Read(Vec<u8>, ReadSource),
+		Write(Vec<u8>),
+		ChangesTrieBuild,
+		StorageRoot,
+	}
+
+	/// Observer that records all events.
+	#[derive(Default)]
+	pub(crate) struct RecordingObserver {
+		pub events: Mutex<Vec<Event>>,
+	}
+
+	impl ExecutionObserver for RecordingObserver {
+		fn read(&self, key: &[u8], source: ReadSource, _duration: Duration) {
+			self.events.lock().push(Event::Read(key.to_vec(), source));
+		}
+
+		fn write(&self, key: &[u8]) {
+			self.events.lock().push(Event::Write(key.to_vec()));
+		}
+
+		fn changes_trie_build(&self, _stats: &BuildStats) {
+			self.events.lock().push(Event::ChangesTrieBuild);
+		}
+
+		fn storage_root(&self, _duration: Duration) {
+			self.events.lock().push(Event::StorageRoot);
+		}
+	}
+
+	#[test]
+	fn logging_stats_are_aggregated_until_storage_root() {
+		let stats = LoggingStats::default();
+		stats.read(&[1], ReadSource::Overlay, Duration::from_millis(1));
+		stats.read(&[2], ReadSource::Backend, Duration::from_millis(2));
+		stats.read(&[2], ReadSource::Backend, Duration::from_millis(3));
+		stats.write(&[1]);
+		stats.changes_trie_build(&Default::default());
+		assert_eq!(stats.aggregates(), ExecutionAggregates {
+			overlay_reads: 1,
+			backend_reads: 2,
+			read_time: Duration::from_millis(6),
+			writes: 1,
+		});
+
+		stats.storage_root(Duration::from_millis(10));
+		assert_eq!(stats.aggregates(), ExecutionAggregates::default());
+	}
+}
@@ -17,14 +17,14 @@
 
 //! Houses the code that implements the transactional overlay storage.
 
-use super::{StorageKey, StorageValue, Extrinsics};
+use super::{StorageKey, StorageValue, Extrinsics, SpillHandle};
 
 #[cfg(feature = "std")]
 use std::collections::HashSet as Set;
 #[cfg(not(feature = "std"))]
 use sp_std::collections::btree_set::BTreeSet as Set;
 
-use sp_std::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use sp_std::{vec::Vec, collections::{btree_map::BTreeMap, btree_set::BTreeSet}};
 use sp_std::hash::Hash;
 use smallvec::SmallVec;
 use crate::warn;
@@ -69,6 +69,8 @@ struct InnerValue<V> {
 	/// The set of extrinsic indices where the values has been changed.
 	/// Is filled only if runtime has announced changes trie support.
 	extrinsics: Extrinsics,
+	/// Handle of the value in the `ValueSpill`, if the value has been moved there.
+	spilled: Option<SpillHandle>,
 }
 
 /// An overlay that contains all versions of a value for a specific key.
@@ -109,6 +111,9 @@ pub struct OverlayedMap<K: Ord + Hash, V> {
 	num_client_transactions: usize,
 	/// Determines whether the node is using the overlay from the client or the runtime.
 	execution_mode: ExecutionMode,
+	/// Handles of spilled values that have been overwritten or rolled back, and must be
+	/// removed from the `ValueSpill`.
+	released_spills: Vec<SpillHandle>,
 }
 
 impl<K: Ord + Hash, V> Default for OverlayedMap<K, V> {
@@ -118,6 +123,7 @@ impl<K: Ord + Hash, V> Default for OverlayedMap<K, V> {
 			dirty_keys: SmallVec::new(),
 			num_client_transactions: Default::default(),
 			execution_mode: Default::default(),
+			released_spills: Vec::new(),
 		}
 	}
 }
@@ -146,6 +152,25 @@ impl<V> OverlayedEntry<V> {
 		set
 	}
 
+	/// Sorted list of unique extrinsic indices which modified the value.
+	///
+	/// Gives the same result as `extrinsics`, but doesn't allocate a set. Indices are
+	/// usually recorded in ascending order, so the list is only sorted when it isn't.
+	pub fn sorted_extrinsics(&self) -> Vec<u32> {
+		let mut extrinsics = Vec::new();
+		self.transactions.iter().for_each(|t| t.extrinsics.copy_extrinsics_into_vec(&mut extrinsics));
+		if !extrinsics.windows(2).all(|w| w[0] < w[1]) {
+			extrinsics.sort_unstable();
+			extrinsics.dedup();
+		}
+		extrinsics
+	}
+
+	/// Handle of the current value in the `ValueSpill`, if it has been moved there.
+	pub fn spilled(&self) -> Option<SpillHandle> {
+		self.transactions.last().expect(PROOF_OVERLAY_NON_EMPTY).spilled
+	}
+
 	/// Mutable reference to the most recent version.
 	fn value_mut(&mut self) -> &mut V {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).value
@@ -164,33 +189,111 @@ impl<V> OverlayedEntry<V> {
 	/// Writes a new version of a value.
 	///
 	/// This makes sure that the old version is not overwritten and can be properly
-	/// rolled back when required.
+	/// rolled back when required. Returns the spill handle of the overwritten version.
 	fn set(
 		&mut self,
 		value: V,
+		spilled: Option<SpillHandle>,
 		first_write_in_tx: bool,
 		at_extrinsic: Option<u32>,
-	) {
+	) -> Option<SpillHandle> {
+		let mut released = None;
 		if first_write_in_tx || self.transactions.is_empty() {
 			self.transactions.push(InnerValue {
 				value,
 				extrinsics: Default::default(),
+				spilled,
 			});
 		} else {
-			*self.value_mut() = value;
+			let current = self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY);
+			current.value = value;
+			released = sp_std::mem::replace(&mut current.spilled, spilled);
 		}
 
 		if let Some(extrinsic) = at_extrinsic {
 			self.transaction_extrinsics_mut().insert(extrinsic);
 		}
+
+		released
 	}
 }
 
 impl OverlayedEntry<Option<StorageValue>> {
 	/// The value as seen by the current transaction.
+	///
+	/// Values that have been moved to the `ValueSpill` are empty here. They're read with
+	/// `OverlayedChanges::storage`.
 	pub fn value(&self) -> Option<&StorageValue> {
 		self.value_ref().as_ref()
 	}
+
+	/// Take back the current value from the `ValueSpill`, if it has been moved there.
+	fn restore_spilled(&mut self, take: &mut impl FnMut(SpillHandle) -> StorageValue) {
+		let current = self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY);
+		if let Some(handle) = current.spilled.take() {
+			current.value = Some(take(handle));
+		}
+	}
+
+	/// Estimated number of heap bytes, owned by all versions of the value.
+	pub fn heap_size(&self) -> usize {
+		let versions_size = if self.transactions.spilled() {
+			self.transactions.capacity() * sp_std::mem::size_of::<InnerValue<Option<StorageValue>>>()
+		} else {
+			0
+		};
+		versions_size + self.transactions.iter()
+			.map(|version| version.value.as_ref().map(|value| value.capacity()).unwrap_or(0)
+				+ version.extrinsics.0.capacity() * sp_std::mem::size_of::<u32>())
+			.sum::<usize>()
+	}
+}
+
+impl OverlayedChangeSet {
+	/// Set the value that has been moved to the `ValueSpill` for the specified key.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn set_spilled(
+		&mut self,
+		key: StorageKey,
+		handle: SpillHandle,
+		at_extrinsic: Option<u32>,
+	) {
+		let overlayed = self.changes.entry(key.clone()).or_default();
+		let released = overlayed.set(
+			Some(StorageValue::new()),
+			Some(handle),
+			insert_dirty(&mut self.dirty_keys, key),
+			at_extrinsic,
+		);
+		self.released_spills.extend(released);
+	}
+
+	/// Take back the current value of the specified key from the `ValueSpill`, if it has
+	/// been moved there.
+	pub fn restore_spilled_value(
+		&mut self,
+		key: &[u8],
+		mut take: impl FnMut(SpillHandle) -> StorageValue,
+	) {
+		if let Some(overlayed) = self.changes.get_mut(key) {
+			overlayed.restore_spilled(&mut take);
+		}
+	}
+
+	/// Take back current values of all keys from the `ValueSpill`.
+	pub fn restore_spilled_values(&mut self, mut take: impl FnMut(SpillHandle) -> StorageValue) {
+		for overlayed in self.changes.values_mut() {
+			overlayed.restore_spilled(&mut take);
+		}
+	}
+
+	/// Estimated number of heap bytes, owned by keys and values of all changes.
+	pub fn heap_size(&self) -> usize {
+		self.changes.iter()
+			.map(|(key, entry)| key.capacity() + entry.heap_size())
+			.sum()
+	}
 }
 
 /// Inserts a key into the dirty set.
@@ -213,6 +316,7 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 			dirty_keys: repeat(Set::new()).take(self.transaction_depth()).collect(),
 			num_client_transactions: self.num_client_transactions,
 			execution_mode: self.execution_mode,
+			released_spills: Vec::new(),
 		}
 	}
 
@@ -240,7 +344,23 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 		at_extrinsic: Option<u32>,
 	) {
 		let overlayed = self.changes.entry(key.clone()).or_default();
-		overlayed.set(value, insert_dirty(&mut self.dirty_keys, key), at_extrinsic);
+		let released = overlayed.set(value, None, insert_dirty(&mut self.dirty_keys, key), at_extrinsic);
+		self.released_spills.extend(released);
+	}
+
+	/// Take handles of spilled values that have been overwritten or rolled back since
+	/// the last call.
+	pub fn take_released_spills(&mut self) -> Vec<SpillHandle> {
+		sp_std::mem::take(&mut self.released_spills)
+	}
+
+	/// Forget extrinsics that have changed the values. Values are kept.
+	pub fn clear_extrinsics(&mut self) {
+		for entry in self.changes.values_mut() {
+			for transaction in entry.transactions.iter_mut() {
+				transaction.extrinsics = Default::default();
+			}
+		}
 	}
 
 	/// Get a list of all changes as seen by current transaction.
@@ -348,7 +468,8 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 			");
 
 			if rollback {
-				overlayed.pop_transaction();
+				let dropped_tx = overlayed.pop_transaction();
+				self.released_spills.extend(dropped_tx.spilled);
 
 				// We need to remove the key as an `OverlayValue` with no transactions
 				// violates its invariant of always having at least one transaction.
@@ -370,8 +491,10 @@ impl<K: Ord + Hash + Clone, V> OverlayedMap<K, V> {
 				// the previous transaction or a value committed without any open transaction.
 				if has_predecessor {
 					let dropped_tx = overlayed.pop_transaction();
-					*overlayed.value_mut() = dropped_tx.value;
-					overlayed.transaction_extrinsics_mut().extend(dropped_tx.extrinsics);
+					let current = overlayed.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY);
+					current.value = dropped_tx.value;
+					current.extrinsics.extend(dropped_tx.extrinsics);
+					self.released_spills.extend(sp_std::mem::replace(&mut current.spilled, dropped_tx.spilled));
 				}
 			}
 		}
@@ -396,6 +519,10 @@ impl OverlayedChangeSet {
 		at_extrinsic: Option<u32>,
 	) -> &mut Option<StorageValue> {
 		let overlayed = self.changes.entry(key.clone()).or_default();
+		debug_assert!(
+			overlayed.transactions.last().map(|tx| tx.spilled.is_none()).unwrap_or(true),
+			"Spilled values are restored before they're modified",
+		);
 		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key);
 		let clone_into_new_tx = if let Some(tx) = overlayed.transactions.last() {
 			if first_write_in_tx {
@@ -408,7 +535,7 @@ impl OverlayedChangeSet {
 		};
 
 		if let Some(cloned) = clone_into_new_tx {
-			overlayed.set(cloned, first_write_in_tx, at_extrinsic);
+			overlayed.set(cloned, None, first_write_in_tx, at_extrinsic);
 		}
 		overlayed.value_mut()
 	}
@@ -422,7 +549,8 @@ impl OverlayedChangeSet {
 		at_extrinsic: Option<u32>,
 	) {
 		for (key, val) in self.changes.iter_mut().filter(|(k, v)| predicate(k, v)) {
-			val.set(None, insert_dirty(&mut self.dirty_keys, key.clone()), at_extrinsic);
+			let released = val.set(None, None, insert_dirty(&mut self.dirty_keys, key.clone()), at_extrinsic);
+			self.released_spills.extend(released);
 		}
 	}
 
@@ -805,4 +933,39 @@ mod test {
 		assert_eq!(changeset.exit_runtime(), Ok(()));
 		assert_eq!(changeset.exit_runtime(), Err(NotInRuntime));
 	}
+
+	#[test]
+	fn sorted_extrinsics_are_the_same_as_extrinsics_set() {
+		use rand::{Rng, SeedableRng, rngs::StdRng};
+
+		let mut rng = StdRng::seed_from_u64(42);
+		for _ in 0..100 {
+			let mut changeset = OverlayedChangeSet::default();
+			let mut extrinsic = 0;
+			for _ in 0..rng.gen_range(1, 200) {
+				match rng.gen_range(0, 10) {
+					0 => changeset.start_transaction(),
+					1 => { let _ = changeset.commit_transaction(); },
+					2 => { let _ = changeset.rollback_transaction(); },
+					_ => {
+						// mostly ascending indices, with occasional going back
+						extrinsic = match rng.gen_range(0, 5) {
+							0 => rng.gen_range(0, 16),
+							1 => extrinsic,
+							_ => extrinsic + 1,
+						};
+						let key = vec![rng.gen_range(0, 8)];
+						changeset.set(key, Some(vec![extrinsic as u8]), Some(extrinsic));
+					},
+				}
+			}
+
+			for (_, entry) in changeset.changes() {
+				assert_eq!(
+					entry.sorted_extrinsics(),
+					entry.extrinsics().into_iter().collect::<Vec<_>>(),
+				);
+			}
+		}
+	}
 }
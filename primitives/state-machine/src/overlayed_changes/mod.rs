@@ -19,13 +19,15 @@
 
 mod changeset;
 mod offchain;
+mod spill;
 
 pub use offchain::OffchainOverlayedChanges;
+pub use self::spill::{InMemoryValueSpill, SpillHandle, ValueSpill};
 use crate::{
-	backend::Backend,
+	backend::{Backend, Consolidate},
 	stats::StateMachineStats,
 };
-use sp_std::{vec::Vec, any::{TypeId, Any}, boxed::Box};
+use sp_std::{vec::Vec, any::{TypeId, Any}, boxed::Box, cell::RefCell};
 use self::changeset::OverlayedChangeSet;
 
 #[cfg(feature = "std")]
@@ -33,7 +35,12 @@ use crate::{
 	ChangesTrieTransaction,
 	changes_trie::{
 		build_changes_trie,
+		BuildStats as ChangesTrieBuildStats,
+		HeapSize,
 		State as ChangesTrieState,
+		Configuration as ChangesTrieConfig,
+		ConfigurationExt as _,
+		Error as ChangesTrieError,
 	},
 };
 use crate::changes_trie::BlockNumber;
@@ -41,8 +48,8 @@ use crate::changes_trie::BlockNumber;
 use std::collections::{HashMap as Map, hash_map::Entry as MapEntry};
 #[cfg(not(feature = "std"))]
 use sp_std::collections::btree_map::{BTreeMap as Map, Entry as MapEntry};
-use sp_std::collections::btree_set::BTreeSet;
-use codec::{Decode, Encode};
+use sp_std::collections::{btree_map::BTreeMap, btree_set::BTreeSet};
+use codec::{Compact, CompactLen, Decode, Encode};
 use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo};
 use sp_core::offchain::OffchainOverlayedChange;
 use hash_db::Hasher;
@@ -54,6 +61,90 @@ pub use self::changeset::{OverlayedValue, NoOpenTransaction, AlreadyInRuntime, N
 /// Changes that are made outside of extrinsics are marked with this index;
 pub const NO_EXTRINSIC_INDEX: u32 = 0xffffffff;
 
+/// Changes that are made after `OverlayedChanges::note_block_initialization` and before the
+/// first extrinsic index is set are marked with this index.
+pub const BLOCK_INITIALIZATION_EXTRINSIC_INDEX: u32 = 0xfffffffe;
+
+/// Changes that are made after `OverlayedChanges::note_block_finalization` are marked with
+/// this index.
+pub const BLOCK_FINALIZATION_EXTRINSIC_INDEX: u32 = 0xfffffffd;
+
+/// Changes trie extrinsic lists of keys that have been deleted in the block are marked with
+/// this index, if the changes trie configuration records deletions.
+pub const KEY_DELETION_EXTRINSIC_INDEX: u32 = 0xfffffffc;
+
+/// Error returned when trying to set extrinsic index that is reserved for changes made
+/// outside of extrinsics.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ReservedExtrinsicIndex(pub u32);
+
+/// Error returned when trying to change untracked prefixes after extrinsics that have changed
+/// keys have been collected in the block.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ChangesAlreadyTracked;
+
+/// Index of the extrinsic that has changed the key.
+///
+/// Changes that are made by the system outside of extrinsics are attributed to the reserved
+/// indices at the end of the `u32` range, so the encoding is the same as of the plain `u32`
+/// index and indices of extrinsics are ordered before the reserved ones. The deletion marker
+/// of the changes trie is also one of the reserved indices.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode)]
+#[repr(transparent)]
+pub struct ExtrinsicId(pub u32);
+
+impl ExtrinsicId {
+	/// Changes that are made outside of extrinsics, when neither block initialization nor
+	/// finalization has been noted.
+	pub const NONE: Self = ExtrinsicId(NO_EXTRINSIC_INDEX);
+	/// Changes that are made while the block is being initialized.
+	pub const BLOCK_INITIALIZATION: Self = ExtrinsicId(BLOCK_INITIALIZATION_EXTRINSIC_INDEX);
+	/// Changes that are made while the block is being finalized.
+	pub const BLOCK_FINALIZATION: Self = ExtrinsicId(BLOCK_FINALIZATION_EXTRINSIC_INDEX);
+	/// Marks the key that has been deleted in the block. Never attributed to changes.
+	pub const KEY_DELETION: Self = ExtrinsicId(KEY_DELETION_EXTRINSIC_INDEX);
+
+	/// Returns true if the index is reserved, i.e. the change has been made by the system,
+	/// rather than by the extrinsic, or this is the deletion marker.
+	pub fn is_system(&self) -> bool {
+		self.0 >= KEY_DELETION_EXTRINSIC_INDEX
+	}
+
+	/// Returns index of the extrinsic, or None if the change has been made by the system.
+	pub fn extrinsic_index(&self) -> Option<u32> {
+		match self.is_system() {
+			true => None,
+			false => Some(self.0),
+		}
+	}
+}
+
+impl From<u32> for ExtrinsicId {
+	fn from(index: u32) -> Self {
+		ExtrinsicId(index)
+	}
+}
+
+impl From<ExtrinsicId> for u32 {
+	fn from(id: ExtrinsicId) -> Self {
+		id.0
+	}
+}
+
+impl sp_std::fmt::Debug for ExtrinsicId {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
+		match *self {
+			ExtrinsicId::NONE => write!(f, "None"),
+			ExtrinsicId::BLOCK_INITIALIZATION => write!(f, "BlockInitialization"),
+			ExtrinsicId::BLOCK_FINALIZATION => write!(f, "BlockFinalization"),
+			ExtrinsicId::KEY_DELETION => write!(f, "KeyDeletion"),
+			ExtrinsicId(index) => write!(f, "{}", index),
+		}
+	}
+}
+
 /// Storage key.
 pub type StorageKey = Vec<u8>;
 
@@ -79,6 +170,11 @@ impl Extrinsics {
 		dest.extend(self.0.iter())
 	}
 
+	/// Appends extrinsics to the `Vec`, keeping duplicates and order.
+	fn copy_extrinsics_into_vec(&self, dest: &mut Vec<u32>) {
+		dest.extend_from_slice(&self.0)
+	}
+
 	/// Add an extrinsics.
 	fn insert(&mut self, ext: u32) {
 		if Some(&ext) != self.0.last() {
@@ -92,6 +188,264 @@ impl Extrinsics {
 	}
 }
 
+/// Keys that have been read by every extrinsic.
+pub type ReadsPerExtrinsic = BTreeMap<u32, BTreeSet<StorageKey>>;
+
+/// Keys that have been read from top storage (`None`) and child storages, by every extrinsic.
+type ReadsLayer = BTreeMap<Option<StorageKey>, ReadsPerExtrinsic>;
+
+/// Keep trace of keys that have been read.
+///
+/// Reads are layered the same way as changes are: the first layer holds committed reads and
+/// there's one more layer for every open transaction.
+#[derive(Debug, Clone)]
+struct ReadTracking {
+	layers: Vec<ReadsLayer>,
+}
+
+impl ReadTracking {
+	/// Create read tracking for the given transaction depth.
+	fn new(transaction_depth: usize) -> Self {
+		ReadTracking {
+			layers: (0..=transaction_depth).map(|_| Default::default()).collect(),
+		}
+	}
+
+	/// Record read of the key.
+	fn record(&mut self, storage_key: Option<&[u8]>, extrinsic: u32, key: &[u8]) {
+		if let Some(layer) = self.layers.last_mut() {
+			layer.entry(storage_key.map(|storage_key| storage_key.to_vec()))
+				.or_default()
+				.entry(extrinsic)
+				.or_default()
+				.insert(key.to_vec());
+		}
+	}
+
+	/// Start a new transaction layer.
+	fn start_transaction(&mut self) {
+		self.layers.push(Default::default());
+	}
+
+	/// Discard reads of the last transaction layer.
+	fn rollback_transaction(&mut self) {
+		if self.layers.len() > 1 {
+			self.layers.pop();
+		}
+	}
+
+	/// Merge reads of all transaction layers above the given transaction depth.
+	fn commit_transactions(&mut self, transaction_depth: usize) {
+		while self.layers.len() > transaction_depth + 1 {
+			let layer = match self.layers.pop() {
+				Some(layer) => layer,
+				None => return,
+			};
+			if let Some(parent) = self.layers.last_mut() {
+				for (storage_key, reads) in layer {
+					let parent_reads = parent.entry(storage_key).or_default();
+					for (extrinsic, keys) in reads {
+						parent_reads.entry(extrinsic).or_default().extend(keys);
+					}
+				}
+			}
+		}
+	}
+
+	/// Keys read from the given storage, as seen by the current transaction.
+	fn reads(&self, storage_key: Option<&[u8]>) -> ReadsPerExtrinsic {
+		let storage_key = storage_key.map(|storage_key| storage_key.to_vec());
+		let mut result = ReadsPerExtrinsic::new();
+		for reads in self.layers.iter().filter_map(|layer| layer.get(&storage_key)) {
+			for (extrinsic, keys) in reads {
+				result.entry(*extrinsic).or_default().extend(keys.iter().cloned());
+			}
+		}
+		result
+	}
+}
+
+/// Estimation of the changes trie input that is built from the collected extrinsics indices.
+///
+/// Every changed key ends up in a single `ExtrinsicIndex` input pair of the changes trie
+/// (top-level or child), whose value is the list of extrinsics that have changed the key. So
+/// the estimation only grows when the key is changed by an extrinsic that hasn't changed it yet.
+/// Encoded block number is not included into the estimation, because it is not known to the
+/// overlay.
+///
+/// Weights that have been noted by the extrinsics are also kept here. They never make it into
+/// the changes trie, but follow the same transactional semantics as the estimation.
+#[derive(Debug, Default, Clone)]
+pub struct ExtrinsicChanges {
+	/// Maximal estimated size of the changes trie input. `None` if not limited.
+	max_changes_bytes: Option<u64>,
+	/// Estimated size of the changes trie input, as seen by the current transaction.
+	estimated_trie_size: u64,
+	/// Weights of extrinsics, as seen by the current transaction.
+	weights: BTreeMap<ExtrinsicId, u64>,
+	/// State of every open transaction.
+	transactions: Vec<ExtrinsicChangesTransaction>,
+	/// True if the limit has been exceeded since it has been set. Isn't reset on rollback.
+	limit_exceeded: bool,
+	/// Largest extrinsic index, set by the runtime, that exceeds the max number of extrinsics
+	/// in the block. Isn't reset on rollback.
+	excess_extrinsic_index: Option<u32>,
+	/// Number of the block which changes are collected, converted into `u128` (it doesn't
+	/// depend on the block number type then). Only set by the `OverlayedChanges::reset_for_block`
+	/// and `OverlayedChanges::set_block_number`.
+	block: Option<u128>,
+}
+
+/// State of `ExtrinsicChanges` that is required to rollback the transaction.
+#[derive(Debug, Default, Clone)]
+struct ExtrinsicChangesTransaction {
+	/// Estimated size at the beginning of the transaction.
+	estimated_trie_size: u64,
+	/// Weights that have been replaced by the transaction, as they were at its beginning.
+	replaced_weights: BTreeMap<ExtrinsicId, Option<u64>>,
+}
+
+impl ExtrinsicChanges {
+	/// Maximal estimated size of the changes trie input. `None` if not limited.
+	pub fn max_changes_bytes(&self) -> Option<u64> {
+		self.max_changes_bytes
+	}
+
+	/// Estimated size (in bytes) of the encoded `ExtrinsicIndex` input pairs of the changes trie,
+	/// as seen by the current transaction.
+	pub fn estimated_trie_size(&self) -> u64 {
+		self.estimated_trie_size
+	}
+
+	/// Returns true if the estimated size has exceeded the limit since the limit has been set.
+	pub fn limit_exceeded(&self) -> bool {
+		self.limit_exceeded
+	}
+
+	/// Weights that have been noted by extrinsics, as seen by the current transaction.
+	pub fn weights(&self) -> &BTreeMap<ExtrinsicId, u64> {
+		&self.weights
+	}
+
+	/// Largest extrinsic index, set by the runtime, that exceeds the max number of extrinsics
+	/// in the block.
+	pub fn excess_extrinsic_index(&self) -> Option<u32> {
+		self.excess_extrinsic_index
+	}
+
+	/// Number of the block which changes are collected, if it is known.
+	pub fn block<N: BlockNumber>(&self) -> Option<N> {
+		self.block.and_then(crate::changes_trie::block_number_from_u128)
+	}
+
+	/// Returns true if no changes have been attributed to extrinsics and no weights have been
+	/// noted, as seen by the current transaction.
+	pub fn is_empty(&self) -> bool {
+		self.estimated_trie_size == 0 && self.weights.is_empty()
+	}
+
+	/// Set weight of the given extrinsic.
+	fn note_weight(&mut self, extrinsic: ExtrinsicId, weight: u64) {
+		let replaced_weight = self.weights.insert(extrinsic, weight);
+		if let Some(transaction) = self.transactions.last_mut() {
+			transaction.replaced_weights.entry(extrinsic).or_insert(replaced_weight);
+		}
+	}
+
+	/// Remember extrinsic index that exceeds the max number of extrinsics in the block.
+	fn note_excess_extrinsic_index(&mut self, index: u32) {
+		self.excess_extrinsic_index = self.excess_extrinsic_index.max(Some(index));
+	}
+
+	/// Set the limit and check it against the current estimation.
+	fn set_limit(&mut self, max_changes_bytes: Option<u64>) {
+		self.max_changes_bytes = max_changes_bytes;
+		self.limit_exceeded = false;
+		self.grow(0);
+	}
+
+	/// Add given number of bytes to the estimation.
+	fn grow(&mut self, bytes: u64) {
+		self.estimated_trie_size = self.estimated_trie_size.saturating_add(bytes);
+		if let Some(max_changes_bytes) = self.max_changes_bytes {
+			if self.estimated_trie_size > max_changes_bytes {
+				self.limit_exceeded = true;
+			}
+		}
+	}
+
+	/// Add size of `ExtrinsicIndex` pair growth to the estimation, if the key is changed
+	/// by the given extrinsic.
+	fn record_change(
+		&mut self,
+		changeset: &OverlayedChangeSet,
+		key: &[u8],
+		extrinsic: Option<u32>,
+	) {
+		let extrinsic = match extrinsic {
+			Some(extrinsic) => extrinsic,
+			None => return,
+		};
+		let extrinsics = changeset.get(key)
+			.map(|entry| entry.extrinsics())
+			.unwrap_or_default();
+		if extrinsics.contains(&extrinsic) {
+			return;
+		}
+
+		self.grow(
+			extrinsic_index_pair_size(key, extrinsics.len() + 1)
+				- extrinsic_index_pair_size(key, extrinsics.len())
+		);
+	}
+
+	/// Remember estimation at the beginning of the new transaction.
+	fn start_transaction(&mut self) {
+		self.transactions.push(ExtrinsicChangesTransaction {
+			estimated_trie_size: self.estimated_trie_size,
+			replaced_weights: BTreeMap::new(),
+		});
+	}
+
+	/// Restore estimation and weights as they were at the beginning of the last transaction.
+	fn rollback_transaction(&mut self) {
+		if let Some(transaction) = self.transactions.pop() {
+			self.estimated_trie_size = transaction.estimated_trie_size;
+			for (extrinsic, weight) in transaction.replaced_weights {
+				match weight {
+					Some(weight) => self.weights.insert(extrinsic, weight),
+					None => self.weights.remove(&extrinsic),
+				};
+			}
+		}
+	}
+
+	/// Merge state of all transactions above the given transaction depth into the parent
+	/// transaction.
+	fn commit_transactions(&mut self, transaction_depth: usize) {
+		while self.transactions.len() > transaction_depth {
+			let transaction = self.transactions.pop().expect("len is greater than depth; qed");
+			if let Some(parent) = self.transactions.last_mut() {
+				for (extrinsic, weight) in transaction.replaced_weights {
+					parent.replaced_weights.entry(extrinsic).or_insert(weight);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl HeapSize for ExtrinsicChanges {
+	fn heap_size(&self) -> usize {
+		let weight_size = sp_std::mem::size_of::<(ExtrinsicId, u64)>();
+		self.weights.len() * weight_size
+			+ self.transactions.capacity() * sp_std::mem::size_of::<ExtrinsicChangesTransaction>()
+			+ self.transactions.iter()
+				.map(|transaction| transaction.replaced_weights.len() * weight_size)
+				.sum::<usize>()
+	}
+}
+
 /// The set of changes that are overlaid onto the backend.
 ///
 /// It allows changes to be modified using nestable transactions.
@@ -101,16 +455,117 @@ pub struct OverlayedChanges {
 	top: OverlayedChangeSet,
 	/// Child storage changes. The map key is the child storage key without the common prefix.
 	children: Map<StorageKey, (OverlayedChangeSet, ChildInfo)>,
+	/// Child storages that have been changed since their roots have been computed for the
+	/// last time. Keys are child storage keys without the common prefix.
+	dirty_children: BTreeSet<StorageKey>,
 	/// Offchain related changes.
 	offchain: OffchainOverlayedChanges,
 	/// Transaction index changes,
 	transaction_index_ops: Vec<IndexOperation>,
 	/// True if extrinsics stats must be collected.
 	collect_extrinsics: bool,
+	/// Extrinsic index that has been set outside of the runtime.
+	explicit_extrinsic_index: ExplicitExtrinsicIndex,
+	/// Prefixes of top-level keys whose changes are never attributed to extrinsics.
+	untracked_prefixes: Vec<StorageKey>,
+	/// Keys that have been read. `None` if reads are not tracked.
+	read_tracking: Option<RefCell<ReadTracking>>,
+	/// Estimation of the changes trie input.
+	extrinsic_changes: ExtrinsicChanges,
+	/// Spill of large values. `None` if values are never spilled.
+	spilled_values: Option<SpilledValues>,
 	/// Collect statistic on this execution.
 	stats: StateMachineStats,
 }
 
+/// Extrinsic index that has been set outside of the runtime, along with indices that have been
+/// set at the beginning of open transactions.
+///
+/// The index is tracked outside of the storage, so setting it never changes the storage root.
+#[derive(Debug, Default, Clone)]
+struct ExplicitExtrinsicIndex {
+	/// Current index, or `None` if the index that is stored by the runtime is used.
+	current: Option<u32>,
+	/// Indices at the beginning of open transactions.
+	transactions: Vec<Option<u32>>,
+}
+
+impl ExplicitExtrinsicIndex {
+	/// Forget index of the extrinsic, so that the index that is stored by the runtime is used.
+	/// Reserved indices are kept until the next extrinsic index is set.
+	fn forget_extrinsic(&mut self) {
+		if self.current.map(|index| !ExtrinsicId(index).is_system()).unwrap_or(false) {
+			self.current = None;
+		}
+	}
+
+	/// Remember index at the beginning of the new transaction.
+	fn start_transaction(&mut self) {
+		self.transactions.push(self.current);
+	}
+
+	/// Restore index as it was at the beginning of the last transaction.
+	fn rollback_transaction(&mut self) {
+		if let Some(current) = self.transactions.pop() {
+			self.current = current;
+		}
+	}
+
+	/// Forget indices of all transactions above the given transaction depth.
+	fn commit_transactions(&mut self, transaction_depth: usize) {
+		self.transactions.truncate(transaction_depth);
+	}
+}
+
+const PROOF_SPILLED_VALUE: &str = "\
+	Spill handles are only kept by the overlay until they're released, and released values
+	are removed from the spill; qed";
+
+/// Spill of large values, along with the size of values that are moved there.
+struct SpilledValues {
+	/// Values larger than this number of bytes are moved to the spill.
+	threshold: usize,
+	/// Storage of spilled values.
+	spill: Box<dyn ValueSpill>,
+}
+
+impl SpilledValues {
+	/// Read the spilled value.
+	fn load(&self, handle: SpillHandle) -> &[u8] {
+		self.spill.load(handle).expect(PROOF_SPILLED_VALUE)
+	}
+
+	/// Take the spilled value back.
+	fn take(&mut self, handle: SpillHandle) -> StorageValue {
+		self.spill.take(handle).expect(PROOF_SPILLED_VALUE)
+	}
+
+	/// Remove values that aren't referenced by the changeset anymore from the spill.
+	fn release(&mut self, changeset: &mut OverlayedChangeSet) {
+		for handle in changeset.take_released_spills() {
+			self.take(handle);
+		}
+	}
+}
+
+impl Clone for SpilledValues {
+	fn clone(&self) -> Self {
+		SpilledValues {
+			threshold: self.threshold,
+			spill: self.spill.clone_spill(),
+		}
+	}
+}
+
+impl sp_std::fmt::Debug for SpilledValues {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
+		f.debug_struct("SpilledValues")
+			.field("threshold", &self.threshold)
+			.field("stored_bytes", &self.spill.stored_bytes())
+			.finish()
+	}
+}
+
 /// Transcation index operation.
 #[derive(Debug, Clone)]
 pub enum IndexOperation {
@@ -250,6 +705,23 @@ impl<Transaction: Default, H: Hasher, N: BlockNumber> Default for StorageChanges
 }
 
 impl OverlayedChanges {
+	/// Create overlay that moves values larger than `threshold_bytes` to the `spill`.
+	///
+	/// The overlay keeps handles of spilled values instead of values themselves, so large
+	/// values (e.g. runtime code) aren't held in memory by every transaction that has
+	/// written them. Spilled values are transparently read from the spill.
+	pub fn with_value_spill(threshold_bytes: usize, spill: Box<dyn ValueSpill>) -> Self {
+		OverlayedChanges {
+			spilled_values: Some(SpilledValues { threshold: threshold_bytes, spill }),
+			..Default::default()
+		}
+	}
+
+	/// Storage of spilled values, if large values are spilled.
+	pub fn value_spill(&self) -> Option<&dyn ValueSpill> {
+		self.spilled_values.as_ref().map(|spilled_values| &*spilled_values.spill)
+	}
+
 	/// Whether no changes are contained in the top nor in any of the child changes.
 	pub fn is_empty(&self) -> bool {
 		self.top.is_empty() && self.children.is_empty()
@@ -260,15 +732,205 @@ impl OverlayedChanges {
 		self.collect_extrinsics = collect_extrinsics;
 	}
 
+	/// Collect extrinsics indices if changes tries are enabled in the state of given backend.
+	///
+	/// Returns the changes trie configuration that has been read from the state.
+	#[cfg(feature = "std")]
+	pub fn set_collect_extrinsics_from_state<H: Hasher, B: Backend<H>, N: BlockNumber>(
+		&mut self,
+		backend: &B,
+	) -> Result<Option<ChangesTrieConfig>, ChangesTrieError<N>> {
+		let config = ChangesTrieConfig::from_storage(backend)?;
+		self.set_collect_extrinsics(config.is_some());
+		Ok(config)
+	}
+
+	/// Returns true if extrinsics indices where key(s) has been changed are collected.
+	pub fn collects_extrinsics(&self) -> bool {
+		self.collect_extrinsics
+	}
+
+	/// Ask not to collect extrinsics indices for top-level keys that start with any of the
+	/// given prefixes.
+	///
+	/// Values of these keys are changed as usual, but the keys never make it into the changes
+	/// trie. Since this affects the changes trie root, prefixes may only be changed before
+	/// extrinsics that have changed keys are collected in the block. Prefixes are kept when
+	/// the overlay is reset for the next block.
+	pub fn set_untracked_prefixes(
+		&mut self,
+		untracked_prefixes: Vec<StorageKey>,
+	) -> Result<(), ChangesAlreadyTracked> {
+		if self.extrinsic_changes.estimated_trie_size() != 0 {
+			return Err(ChangesAlreadyTracked);
+		}
+
+		self.untracked_prefixes = untracked_prefixes;
+		Ok(())
+	}
+
+	/// Prefixes of top-level keys whose changes are not attributed to extrinsics.
+	pub fn untracked_prefixes(&self) -> &[StorageKey] {
+		&self.untracked_prefixes
+	}
+
+	/// Returns true if changes of the top-level key are not attributed to extrinsics.
+	fn is_untracked(&self, key: &[u8]) -> bool {
+		self.untracked_prefixes.iter().any(|prefix| key.starts_with(prefix))
+	}
+
+	/// Set the limit of the estimated changes trie input size.
+	///
+	/// Writes are never rejected because of the limit. Instead, the block builder should check
+	/// `changes_limit_exceeded` after every extrinsic and stop including extrinsics when the
+	/// limit is exceeded. `None` removes the limit.
+	pub fn set_changes_limit(&mut self, max_changes_bytes: Option<u64>) {
+		self.extrinsic_changes.set_limit(max_changes_bytes);
+	}
+
+	/// Returns true if the estimated changes trie input size has exceeded the limit since the
+	/// limit has been set.
+	///
+	/// The flag isn't reset when the transaction that has exceeded the limit is rolled back.
+	pub fn changes_limit_exceeded(&self) -> bool {
+		self.extrinsic_changes.limit_exceeded()
+	}
+
+	/// Get estimation of the changes trie input.
+	pub fn extrinsic_changes(&self) -> &ExtrinsicChanges {
+		&self.extrinsic_changes
+	}
+
+	/// Forget the estimation of the changes trie input and weights of extrinsics. The changes
+	/// limit is kept.
+	///
+	/// Should be called when the next block is started and there are no open transactions.
+	pub fn reset_extrinsic_changes(&mut self) {
+		let max_changes_bytes = self.extrinsic_changes.max_changes_bytes();
+		self.extrinsic_changes = Default::default();
+		self.extrinsic_changes.set_limit(max_changes_bytes);
+	}
+
+	/// Prepare the overlay that has been used for the previous block to collect changes of
+	/// the given block.
+	///
+	/// Values are kept, but extrinsics that have changed them are forgotten along with the
+	/// estimation of the changes trie input and weights of extrinsics. Changes are attributed
+	/// to the block initialization until the next `set_extrinsic_index` call. Changes trie of
+	/// any other block can't be built from the overlay after that.
+	///
+	/// Should be called when there are no open transactions.
+	#[cfg(feature = "std")]
+	pub fn reset_for_block<N: BlockNumber>(&mut self, block: N, config: Option<&ChangesTrieConfig>) {
+		self.top.clear_extrinsics();
+		self.children.values_mut().for_each(|(changeset, _)| changeset.clear_extrinsics());
+		self.reset_extrinsic_changes();
+		self.set_block_number(block);
+		self.set_collect_extrinsics(config.is_some());
+		self.note_block_initialization();
+	}
+
+	/// Set number of the block which changes are collected.
+	///
+	/// Unlike `reset_for_block`, changes that have already been collected are kept, so the block
+	/// builder may set the number once it is known. Changes trie of any other block can't be
+	/// built from the overlay after that.
+	#[cfg(feature = "std")]
+	pub fn set_block_number<N: BlockNumber>(&mut self, block: N) {
+		self.extrinsic_changes.block = crate::changes_trie::block_number_into_u128(block);
+	}
+
+	/// Set weight of the current extrinsic.
+	///
+	/// Weights are only kept in memory and never make it into the changes trie or the storage
+	/// changes. Weights that have been noted within a transaction are discarded when the
+	/// transaction is rolled back.
+	pub fn note_extrinsic_weight(&mut self, weight: u64) {
+		let extrinsic = self.raw_extrinsic_index();
+		self.extrinsic_changes.note_weight(ExtrinsicId(extrinsic), weight);
+	}
+
+	/// Remember that the runtime has set extrinsic index that exceeds the max number of
+	/// extrinsics in the block. The changes trie of the block can't be built then.
+	pub(crate) fn note_excess_extrinsic_index(&mut self, index: u32) {
+		self.extrinsic_changes.note_excess_extrinsic_index(index);
+	}
+
+	/// Get weights of all extrinsics, as seen by the current transaction.
+	///
+	/// The block builder may check these after every extrinsic to decide whether to keep
+	/// including extrinsics.
+	pub fn extrinsic_weights(&self) -> BTreeMap<ExtrinsicId, u64> {
+		self.extrinsic_changes.weights().clone()
+	}
+
+	/// Get total weight of all extrinsics, as seen by the current transaction.
+	pub fn total_weight(&self) -> u64 {
+		self.extrinsic_changes.weights().values()
+			.fold(0u64, |total, weight| total.saturating_add(*weight))
+	}
+
+	/// Ask to track/not to track keys that are read by every extrinsic.
+	///
+	/// Tracked reads are only kept in memory and never make it into the storage changes.
+	/// Disabling read tracking discards all tracked reads.
+	pub fn set_read_tracking(&mut self, read_tracking: bool) {
+		self.read_tracking = match read_tracking {
+			true => Some(self.read_tracking.take()
+				.unwrap_or_else(|| RefCell::new(ReadTracking::new(self.transaction_depth())))),
+			false => None,
+		};
+	}
+
+	/// Record that the key has been read from the top storage. Does nothing if reads are
+	/// not tracked.
+	pub(crate) fn record_read(&self, key: &[u8]) {
+		if let Some(read_tracking) = self.read_tracking.as_ref() {
+			read_tracking.borrow_mut().record(None, self.raw_extrinsic_index(), key);
+		}
+	}
+
+	/// Record that the key has been read from the child storage. Does nothing if reads are
+	/// not tracked.
+	pub(crate) fn record_child_read(&self, child_info: &ChildInfo, key: &[u8]) {
+		if let Some(read_tracking) = self.read_tracking.as_ref() {
+			read_tracking.borrow_mut().record(
+				Some(child_info.storage_key()),
+				self.raw_extrinsic_index(),
+				key,
+			);
+		}
+	}
+
+	/// Get keys that have been read from the top storage by every extrinsic,
+	/// as seen by the current transaction.
+	///
+	/// Returns empty map if reads are not tracked.
+	pub fn reads_per_extrinsic(&self) -> ReadsPerExtrinsic {
+		self.read_tracking.as_ref()
+			.map(|read_tracking| read_tracking.borrow().reads(None))
+			.unwrap_or_default()
+	}
+
+	/// Get keys that have been read from the child storage with the given storage key
+	/// by every extrinsic, as seen by the current transaction.
+	///
+	/// Returns empty map if reads are not tracked.
+	pub fn child_reads_per_extrinsic(&self, storage_key: &[u8]) -> ReadsPerExtrinsic {
+		self.read_tracking.as_ref()
+			.map(|read_tracking| read_tracking.borrow().reads(Some(storage_key)))
+			.unwrap_or_default()
+	}
+
 	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
 	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
 	/// value has been set.
 	pub fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
 		self.top.get(key).map(|x| {
-			let value = x.value();
+			let value = overlayed_value(&self.spilled_values, x);
 			let size_read = value.map(|x| x.len() as u64).unwrap_or(0);
 			self.stats.tally_read_modified(size_read);
-			value.map(AsRef::as_ref)
+			value
 		})
 	}
 
@@ -283,7 +945,12 @@ impl OverlayedChanges {
 		key: &[u8],
 		init: impl Fn() -> StorageValue,
 	) -> &mut StorageValue {
-		let value = self.top.modify(key.to_vec(), init, self.extrinsic_index());
+		let extrinsic_index = self.top_extrinsic_index(key);
+		self.extrinsic_changes.record_change(&self.top, key, extrinsic_index);
+		if let Some(spilled_values) = self.spilled_values.as_mut() {
+			self.top.restore_spilled_value(key, |handle| spilled_values.take(handle));
+		}
+		let value = self.top.modify(key.to_vec(), init, extrinsic_index);
 
 		// if the value was deleted initialise it back with an empty vec
 		value.get_or_insert_with(StorageValue::default)
@@ -294,19 +961,24 @@ impl OverlayedChanges {
 	/// value has been set.
 	pub fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Option<&[u8]>> {
 		let map = self.children.get(child_info.storage_key())?;
-		let value = map.0.get(key)?.value();
+		let value = overlayed_value(&self.spilled_values, map.0.get(key)?);
 		let size_read = value.map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_read_modified(size_read);
-		Some(value.map(AsRef::as_ref))
+		Some(value)
 	}
 
 	/// Set a new value for the specified key.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
 	pub fn set_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
+		let extrinsic_index = self.top_extrinsic_index(&key);
 		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_write_overlay(size_write);
-		self.top.set(key, val, self.extrinsic_index());
+		self.extrinsic_changes.record_change(&self.top, &key, extrinsic_index);
+		if key == EXTRINSIC_INDEX {
+			self.explicit_extrinsic_index.forget_extrinsic();
+		}
+		set_value(&mut self.top, &mut self.spilled_values, key, val, extrinsic_index);
 	}
 
 	/// Set a new value for the specified key and child.
@@ -324,6 +996,7 @@ impl OverlayedChanges {
 		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_write_overlay(size_write);
 		let storage_key = child_info.storage_key().to_vec();
+		self.dirty_children.insert(storage_key.clone());
 		let top = &self.top;
 		let (changeset, info) = self.children.entry(storage_key).or_insert_with(||
 			(
@@ -333,7 +1006,8 @@ impl OverlayedChanges {
 		);
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
-		changeset.set(key, val, extrinsic_index);
+		self.extrinsic_changes.record_change(changeset, &key, extrinsic_index);
+		set_value(changeset, &mut self.spilled_values, key, val, extrinsic_index);
 	}
 
 	/// Clear child storage of given storage key.
@@ -345,6 +1019,7 @@ impl OverlayedChanges {
 	) {
 		let extrinsic_index = self.extrinsic_index();
 		let storage_key = child_info.storage_key().to_vec();
+		self.dirty_children.insert(storage_key.clone());
 		let top = &self.top;
 		let (changeset, info) = self.children.entry(storage_key).or_insert_with(||
 			(
@@ -354,14 +1029,29 @@ impl OverlayedChanges {
 		);
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
+		record_clear(&mut self.extrinsic_changes, changeset, |_| true, extrinsic_index);
 		changeset.clear_where(|_, _| true, extrinsic_index);
+		release_spilled_values(&mut self.spilled_values, changeset);
 	}
 
 	/// Removes all key-value pairs which keys share the given prefix.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
 	pub(crate) fn clear_prefix(&mut self, prefix: &[u8]) {
-		self.top.clear_where(|key, _| key.starts_with(prefix), self.extrinsic_index());
+		let extrinsic_index = self.extrinsic_index();
+		let untracked_prefixes = &self.untracked_prefixes;
+		let is_tracked = |key: &[u8]| !untracked_prefixes.iter().any(|prefix| key.starts_with(prefix));
+		record_clear(
+			&mut self.extrinsic_changes,
+			&self.top,
+			|key| key.starts_with(prefix) && is_tracked(key),
+			extrinsic_index,
+		);
+		self.top.clear_where(|key, _| key.starts_with(prefix) && is_tracked(key), extrinsic_index);
+		if !untracked_prefixes.is_empty() {
+			self.top.clear_where(|key, _| key.starts_with(prefix) && !is_tracked(key), None);
+		}
+		release_spilled_values(&mut self.spilled_values, &mut self.top);
 	}
 
 	/// Removes all key-value pairs which keys share the given prefix.
@@ -374,6 +1064,7 @@ impl OverlayedChanges {
 	) {
 		let extrinsic_index = self.extrinsic_index();
 		let storage_key = child_info.storage_key().to_vec();
+		self.dirty_children.insert(storage_key.clone());
 		let top = &self.top;
 		let (changeset, info) = self.children.entry(storage_key).or_insert_with(||
 			(
@@ -383,7 +1074,14 @@ impl OverlayedChanges {
 		);
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
+		record_clear(
+			&mut self.extrinsic_changes,
+			changeset,
+			|key| key.starts_with(prefix),
+			extrinsic_index,
+		);
 		changeset.clear_where(|key, _| key.starts_with(prefix), extrinsic_index);
+		release_spilled_values(&mut self.spilled_values, changeset);
 	}
 
 	/// Returns the current nesting depth of the transaction stack.
@@ -408,6 +1106,11 @@ impl OverlayedChanges {
 			changeset.start_transaction();
 		}
 		self.offchain.overlay_mut().start_transaction();
+		if let Some(read_tracking) = self.read_tracking.as_mut() {
+			read_tracking.get_mut().start_transaction();
+		}
+		self.extrinsic_changes.start_transaction();
+		self.explicit_extrinsic_index.start_transaction();
 	}
 
 	/// Rollback the last transaction started by `start_transaction`.
@@ -416,13 +1119,22 @@ impl OverlayedChanges {
 	/// there is no open transaction that can be rolled back.
 	pub fn rollback_transaction(&mut self) -> Result<(), NoOpenTransaction> {
 		self.top.rollback_transaction()?;
+		release_spilled_values(&mut self.spilled_values, &mut self.top);
+		self.dirty_children.extend(self.children.keys().cloned());
+		let spilled_values = &mut self.spilled_values;
 		retain_map(&mut self.children, |_, (changeset, _)| {
 			changeset.rollback_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
+			release_spilled_values(spilled_values, changeset);
 			!changeset.is_empty()
 		});
 		self.offchain.overlay_mut().rollback_transaction()
 			.expect("Top and offchain changesets are started in lockstep; qed");
+		if let Some(read_tracking) = self.read_tracking.as_mut() {
+			read_tracking.get_mut().rollback_transaction();
+		}
+		self.extrinsic_changes.rollback_transaction();
+		self.explicit_extrinsic_index.rollback_transaction();
 		Ok(())
 	}
 
@@ -432,12 +1144,17 @@ impl OverlayedChanges {
 	/// is no open transaction that can be committed.
 	pub fn commit_transaction(&mut self) -> Result<(), NoOpenTransaction> {
 		self.top.commit_transaction()?;
+		release_spilled_values(&mut self.spilled_values, &mut self.top);
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.commit_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
+			release_spilled_values(&mut self.spilled_values, changeset);
 		}
 		self.offchain.overlay_mut().commit_transaction()
 			.expect("Top and offchain changesets are started in lockstep; qed");
+		self.commit_read_tracking_transactions();
+		self.extrinsic_changes.commit_transactions(self.transaction_depth());
+		self.explicit_extrinsic_index.commit_transactions(self.transaction_depth());
 		Ok(())
 	}
 
@@ -462,18 +1179,32 @@ impl OverlayedChanges {
 	/// Calling this while outside the runtime will return an error.
 	pub fn exit_runtime(&mut self) -> Result<(), NotInRuntime> {
 		self.top.exit_runtime()?;
+		release_spilled_values(&mut self.spilled_values, &mut self.top);
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.exit_runtime()
 				.expect("Top and children changesets are entering runtime in lockstep; qed");
+			release_spilled_values(&mut self.spilled_values, changeset);
 		}
 		self.offchain.overlay_mut().exit_runtime()
 			.expect("Top and offchain changesets are started in lockstep; qed");
+		self.commit_read_tracking_transactions();
+		self.extrinsic_changes.commit_transactions(self.transaction_depth());
+		self.explicit_extrinsic_index.commit_transactions(self.transaction_depth());
 		Ok(())
 	}
 
+	/// Merge tracked reads of transactions that are not open anymore.
+	fn commit_read_tracking_transactions(&mut self) {
+		let transaction_depth = self.transaction_depth();
+		if let Some(read_tracking) = self.read_tracking.as_mut() {
+			read_tracking.get_mut().commit_transactions(transaction_depth);
+		}
+	}
+
 	/// Consume all changes (top + children) and return them.
 	///
 	/// After calling this function no more changes are contained in this changeset.
+	/// Spilled values are taken back from the spill.
 	///
 	/// Panics:
 	/// Panics if `transaction_depth() > 0`
@@ -482,6 +1213,12 @@ impl OverlayedChanges {
 		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
 	) {
 		use sp_std::mem::take;
+		if let Some(spilled_values) = self.spilled_values.as_mut() {
+			self.top.restore_spilled_values(|handle| spilled_values.take(handle));
+			for (_, (changeset, _)) in self.children.iter_mut() {
+				changeset.restore_spilled_values(|handle| spilled_values.take(handle));
+			}
+		}
 		(
 			take(&mut self.top).drain_commited(),
 			take(&mut self.children).into_iter()
@@ -504,22 +1241,73 @@ impl OverlayedChanges {
 	}
 
 	/// Get an iterator over all child changes as seen by the current transaction.
-	pub fn children(&self)
+	///
+	/// Values that have been moved to the `ValueSpill` are empty in the returned entries, so
+	/// entries aren't exposed outside of the crate. Use `effective_children` to read values.
+	pub(crate) fn children(&self)
 		-> impl Iterator<Item=(impl Iterator<Item=(&StorageKey, &OverlayedValue)>, &ChildInfo)> {
 		self.children.iter().map(|(_, v)| (v.0.changes(), &v.1))
 	}
 
 	/// Get an iterator over all top changes as been by the current transaction.
-	pub fn changes(&self) -> impl Iterator<Item=(&StorageKey, &OverlayedValue)> {
+	///
+	/// Values that have been moved to the `ValueSpill` are empty in the returned entries, so
+	/// entries aren't exposed outside of the crate. Use `effective_changes` to read values.
+	pub(crate) fn changes(&self) -> impl Iterator<Item=(&StorageKey, &OverlayedValue)> {
 		self.top.changes()
 	}
 
 	/// Get an optional iterator over all child changes stored under the supplied key.
-	pub fn child_changes(&self, key: &[u8])
+	///
+	/// Values that have been moved to the `ValueSpill` are empty in the returned entries, so
+	/// entries aren't exposed outside of the crate. Use `effective_child_changes` to read values.
+	pub(crate) fn child_changes(&self, key: &[u8])
 		-> Option<(impl Iterator<Item=(&StorageKey, &OverlayedValue)>, &ChildInfo)> {
 		self.children.get(key).map(|(overlay, info)| (overlay.changes(), info))
 	}
 
+	/// Get an iterator over values of all top changes as seen by the current transaction.
+	///
+	/// Every changed key is met once and keys are sorted. `None` value means that the
+	/// key has been deleted.
+	pub fn effective_changes(&self) -> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		effective_values(self.changes(), &self.spilled_values)
+	}
+
+	/// Get an iterator over values of all child changes as seen by the current transaction.
+	///
+	/// Child tries are sorted by their storage keys. Every changed key of the child trie
+	/// is met once and keys are sorted.
+	pub fn effective_children(&self)
+		-> impl Iterator<Item=(impl Iterator<Item=(&[u8], Option<&[u8]>)>, &ChildInfo)> {
+		let mut children = self.children.iter().collect::<Vec<_>>();
+		children.sort_by(|(a, _), (b, _)| a.cmp(b));
+		let spilled_values = &self.spilled_values;
+		children.into_iter()
+			.map(move |(_, (changes, info))| (effective_values(changes.changes(), spilled_values), info))
+	}
+
+	/// Get an optional iterator over values of all child changes stored under the supplied key,
+	/// as seen by the current transaction.
+	///
+	/// Every changed key is met once and keys are sorted.
+	pub fn effective_child_changes(&self, key: &[u8])
+		-> Option<(impl Iterator<Item=(&[u8], Option<&[u8]>)>, &ChildInfo)> {
+		self.child_changes(key).map(|(changes, info)| (effective_values(changes, &self.spilled_values), info))
+	}
+
+	/// Returns true if the child storage with given key (without the common prefix) has been
+	/// changed since its root has been computed for the last time.
+	pub fn is_child_dirty(&self, storage_key: &[u8]) -> bool {
+		self.dirty_children.contains(storage_key)
+	}
+
+	/// Note that the root of the child storage with given key has been computed from the
+	/// current changes.
+	pub fn mark_child_root_computed(&mut self, storage_key: &[u8]) {
+		self.dirty_children.remove(storage_key);
+	}
+
 	/// Get an list of all index operations.
 	pub fn transaction_index_ops(&self) -> &[IndexOperation] {
 		&self.transaction_index_ops
@@ -597,10 +1385,42 @@ impl OverlayedChanges {
 		})
 	}
 
-	/// Inserts storage entry responsible for current extrinsic index.
-	#[cfg(test)]
-	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) {
-		self.top.set(EXTRINSIC_INDEX.to_vec(), Some(extrinsic_index.encode()), None);
+	/// Set current extrinsic index.
+	///
+	/// All following changes are attributed to this extrinsic, until the runtime stores another
+	/// index. The index itself is not written to the storage. Indices that are reserved for
+	/// changes made outside of extrinsics are rejected.
+	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) -> Result<(), ReservedExtrinsicIndex> {
+		if ExtrinsicId(extrinsic_index).is_system() {
+			return Err(ReservedExtrinsicIndex(extrinsic_index));
+		}
+
+		self.explicit_extrinsic_index.current = Some(extrinsic_index);
+		Ok(())
+	}
+
+	/// Note that the block is being initialized.
+	///
+	/// Changes that are made until the next `set_extrinsic_index` call are attributed to the
+	/// `BLOCK_INITIALIZATION_EXTRINSIC_INDEX`. The mark is discarded if the transaction
+	/// is rolled back.
+	pub fn note_block_initialization(&mut self) {
+		self.explicit_extrinsic_index.current = Some(BLOCK_INITIALIZATION_EXTRINSIC_INDEX);
+	}
+
+	/// Note that the block is being finalized.
+	///
+	/// Changes that are made until the next `set_extrinsic_index` call are attributed to the
+	/// `BLOCK_FINALIZATION_EXTRINSIC_INDEX`, even if the runtime hasn't cleared the index of
+	/// the last extrinsic. The mark is discarded if the transaction is rolled back.
+	pub fn note_block_finalization(&mut self) {
+		self.explicit_extrinsic_index.current = Some(BLOCK_FINALIZATION_EXTRINSIC_INDEX);
+	}
+
+	/// Returns index of the extrinsic that the changes are currently attributed to, or None if
+	/// changes are made outside of extrinsics.
+	pub fn current_extrinsic_index(&self) -> Option<u32> {
+		ExtrinsicId(self.raw_extrinsic_index()).extrinsic_index()
 	}
 
 	/// Returns current extrinsic index to use in changes trie construction.
@@ -611,14 +1431,32 @@ impl OverlayedChanges {
 	/// `NO_EXTRINSIC_INDEX` index.
 	fn extrinsic_index(&self) -> Option<u32> {
 		match self.collect_extrinsics {
-			true => Some(
-				self.storage(EXTRINSIC_INDEX)
-					.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
-					.unwrap_or(NO_EXTRINSIC_INDEX)),
+			true => Some(self.raw_extrinsic_index()),
 			false => None,
 		}
 	}
 
+	/// Same as `extrinsic_index`, but returns `None` if changes of the top-level key are not
+	/// tracked.
+	fn top_extrinsic_index(&self, key: &[u8]) -> Option<u32> {
+		match self.is_untracked(key) {
+			true => None,
+			false => self.extrinsic_index(),
+		}
+	}
+
+	/// Returns current extrinsic index, reserved index if block initialization or finalization
+	/// has been noted, or `NO_EXTRINSIC_INDEX` if it is not set.
+	fn raw_extrinsic_index(&self) -> u32 {
+		if let Some(explicit_extrinsic_index) = self.explicit_extrinsic_index.current {
+			return explicit_extrinsic_index;
+		}
+
+		self.storage(EXTRINSIC_INDEX)
+			.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
+			.unwrap_or(NO_EXTRINSIC_INDEX)
+	}
+
 	/// Generate the storage root using `backend` and all changes
 	/// as seen by the current transaction.
 	///
@@ -630,11 +1468,8 @@ impl OverlayedChanges {
 	) -> H::Out
 		where H::Out: Ord + Encode,
 	{
-		let delta = self.changes().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])));
-		let child_delta = self.children()
-			.map(|(changes, info)| (info, changes.map(
-				|(k, v)| (&k[..], v.value().map(|v| &v[..]))
-			)));
+		let delta = self.effective_changes();
+		let child_delta = self.effective_children().map(|(changes, info)| (info, changes));
 
 		let (root, transaction) = backend.full_storage_root(delta, child_delta);
 
@@ -644,6 +1479,38 @@ impl OverlayedChanges {
 		root
 	}
 
+	/// Same as `storage_root`, but roots of the changed child storages (along with their
+	/// transactions) are provided by the `child_root`, instead of being computed by the backend.
+	///
+	/// `child_root` is called for every changed child storage, in the order of storage keys.
+	pub fn storage_root_with_child_roots<H: Hasher, N: BlockNumber, B: Backend<H>>(
+		&self,
+		backend: &B,
+		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+		mut child_root: impl FnMut(&ChildInfo) -> (H::Out, bool, B::Transaction),
+	) -> H::Out
+		where H::Out: Ord + Encode,
+	{
+		let mut transaction = B::Transaction::default();
+		let mut child_roots = Vec::new();
+		for (_, child_info) in self.effective_children() {
+			let (child_root, is_empty, child_transaction) = child_root(child_info);
+			transaction.consolidate(child_transaction);
+			let prefixed_storage_key = child_info.prefixed_storage_key().into_inner();
+			child_roots.push((prefixed_storage_key, if is_empty { None } else { Some(child_root.encode()) }));
+		}
+
+		let delta = self.effective_changes()
+			.chain(child_roots.iter().map(|(key, value)| (&key[..], value.as_ref().map(|value| &value[..]))));
+		let (root, top_transaction) = backend.storage_root(delta);
+		transaction.consolidate(top_transaction);
+
+		cache.transaction = Some(transaction);
+		cache.transaction_storage_root = Some(root);
+
+		root
+	}
+
 	/// Generate the changes trie root.
 	///
 	/// Returns the changes trie root and caches the storage transaction into the given `cache`.
@@ -659,6 +1526,27 @@ impl OverlayedChanges {
 		parent_hash: H::Out,
 		panic_on_storage_error: bool,
 		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+	) -> Result<Option<H::Out>, ()> where H::Out: Ord + Encode + 'static {
+		self.changes_trie_root_with_stats(
+			backend,
+			changes_trie_state,
+			parent_hash,
+			panic_on_storage_error,
+			cache,
+			&mut None,
+		)
+	}
+
+	/// Same as `changes_trie_root`, but also collects build statistics if `stats` is `Some`.
+	#[cfg(feature = "std")]
+	pub fn changes_trie_root_with_stats<'a, H: Hasher, N: BlockNumber, B: Backend<H>>(
+		&self,
+		backend: &B,
+		changes_trie_state: Option<&'a ChangesTrieState<'a, H, N>>,
+		parent_hash: H::Out,
+		panic_on_storage_error: bool,
+		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+		stats: &mut Option<ChangesTrieBuildStats>,
 	) -> Result<Option<H::Out>, ()> where H::Out: Ord + Encode + 'static {
 		build_changes_trie::<_, H, N>(
 			backend,
@@ -666,9 +1554,13 @@ impl OverlayedChanges {
 			self,
 			parent_hash,
 			panic_on_storage_error,
-		).map(|r| {
-			let root = r.as_ref().map(|r| r.1).clone();
-			cache.changes_trie_transaction = Some(r.map(|(db, _, cache)| (db, cache)));
+			stats,
+		).map(|outcome| {
+			let (root, transaction) = match outcome {
+				Some(outcome) => (outcome.root, outcome.transaction),
+				None => (None, None),
+			};
+			cache.changes_trie_transaction = Some(transaction);
 			cache.changes_trie_transaction_storage_root = Some(root);
 			root
 		})
@@ -714,6 +1606,95 @@ impl OverlayedChanges {
 	}
 }
 
+#[cfg(feature = "std")]
+impl HeapSize for OverlayedChanges {
+	fn heap_size(&self) -> usize {
+		self.top.heap_size()
+			+ self.children.iter()
+				.map(|(storage_key, (changes, _))| storage_key.capacity() + changes.heap_size())
+				.sum::<usize>()
+			+ self.extrinsic_changes.heap_size()
+	}
+}
+
+/// Size of the encoded `ExtrinsicIndex` input pair of the changed key, excluding the block number.
+///
+/// Returns zero if the key hasn't been changed by any extrinsic, because there's no pair then.
+fn extrinsic_index_pair_size(key: &[u8], extrinsics: usize) -> u64 {
+	if extrinsics == 0 {
+		return 0;
+	}
+
+	let key_size = 1 + Compact::<u32>::compact_len(&(key.len() as u32)) + key.len();
+	let value_size = Compact::<u32>::compact_len(&(extrinsics as u32)) + 4 * extrinsics;
+	(key_size + value_size) as u64
+}
+
+/// Record changes of all keys that are going to be cleared in the changeset.
+fn record_clear(
+	extrinsic_changes: &mut ExtrinsicChanges,
+	changeset: &OverlayedChangeSet,
+	predicate: impl Fn(&[u8]) -> bool,
+	extrinsic: Option<u32>,
+) {
+	if extrinsic.is_none() {
+		return;
+	}
+
+	for (key, _) in changeset.changes().filter(|(key, _)| predicate(key)) {
+		extrinsic_changes.record_change(changeset, key, extrinsic);
+	}
+}
+
+/// Map overlayed changes to their current values.
+fn effective_values<'a>(
+	changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>,
+	spilled_values: &'a Option<SpilledValues>,
+) -> impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)> {
+	changes.map(move |(k, v)| (&k[..], overlayed_value(spilled_values, v)))
+}
+
+/// Current value of the overlayed change, read from the spill if it has been moved there.
+fn overlayed_value<'a>(
+	spilled_values: &'a Option<SpilledValues>,
+	value: &'a OverlayedValue,
+) -> Option<&'a [u8]> {
+	match (value.spilled(), spilled_values) {
+		(Some(handle), Some(spilled_values)) => Some(spilled_values.load(handle)),
+		_ => value.value().map(AsRef::as_ref),
+	}
+}
+
+/// Set the value of the key in the changeset, moving it to the spill if it is large.
+fn set_value(
+	changeset: &mut OverlayedChangeSet,
+	spilled_values: &mut Option<SpilledValues>,
+	key: StorageKey,
+	value: Option<StorageValue>,
+	extrinsic: Option<u32>,
+) {
+	let spilled_values = match spilled_values.as_mut() {
+		Some(spilled_values) => spilled_values,
+		None => return changeset.set(key, value, extrinsic),
+	};
+
+	match value {
+		Some(value) if value.len() > spilled_values.threshold => {
+			let handle = spilled_values.spill.store(value);
+			changeset.set_spilled(key, handle, extrinsic);
+		},
+		value => changeset.set(key, value, extrinsic),
+	}
+	spilled_values.release(changeset);
+}
+
+/// Remove values that aren't referenced by the changeset anymore from the spill.
+fn release_spilled_values(spilled_values: &mut Option<SpilledValues>, changeset: &mut OverlayedChangeSet) {
+	if let Some(spilled_values) = spilled_values.as_mut() {
+		spilled_values.release(changeset);
+	}
+}
+
 #[cfg(feature = "std")]
 fn retain_map<K, V, F>(map: &mut Map<K, V>, f: F)
 	where
@@ -949,13 +1930,13 @@ mod tests {
 
 		overlay.set_storage(vec![100], Some(vec![101]));
 
-		overlay.set_extrinsic_index(0);
+		overlay.set_extrinsic_index(0).unwrap();
 		overlay.set_storage(vec![1], Some(vec![2]));
 
-		overlay.set_extrinsic_index(1);
+		overlay.set_extrinsic_index(1).unwrap();
 		overlay.set_storage(vec![3], Some(vec![4]));
 
-		overlay.set_extrinsic_index(2);
+		overlay.set_extrinsic_index(2).unwrap();
 		overlay.set_storage(vec![1], Some(vec![6]));
 
 		assert_extrinsics(&overlay.top, vec![1], vec![0, 2]);
@@ -964,10 +1945,10 @@ mod tests {
 
 		overlay.start_transaction();
 
-		overlay.set_extrinsic_index(3);
+		overlay.set_extrinsic_index(3).unwrap();
 		overlay.set_storage(vec![3], Some(vec![7]));
 
-		overlay.set_extrinsic_index(4);
+		overlay.set_extrinsic_index(4).unwrap();
 		overlay.set_storage(vec![1], Some(vec![8]));
 
 		assert_extrinsics(&overlay.top, vec![1], vec![0, 2, 4]);
@@ -981,6 +1962,88 @@ mod tests {
 		assert_extrinsics(&overlay.top, vec![100], vec![NO_EXTRINSIC_INDEX]);
 	}
 
+	#[test]
+	fn reset_for_block_forgets_extrinsics_but_keeps_values() {
+		let config = ChangesTrieConfig { digest_interval: 4, digest_levels: 2, ..Default::default() };
+		let mut overlay = OverlayedChanges::default();
+		overlay.reset_for_block(1u64, Some(&config));
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.note_extrinsic_weight(10);
+		assert_extrinsics(&overlay.top, vec![1], vec![0]);
+		assert_eq!(overlay.extrinsic_changes().block(), Some(1u64));
+
+		overlay.reset_for_block(2u64, Some(&config));
+		assert_eq!(overlay.storage(&[1]).unwrap(), Some(&[2][..]));
+		assert_extrinsics(&overlay.top, vec![1], vec![]);
+		assert!(overlay.extrinsic_weights().is_empty());
+		assert_eq!(overlay.current_extrinsic_index(), None);
+		assert_eq!(overlay.extrinsic_changes().block(), Some(2u64));
+		// the number doesn't depend on the block number type
+		assert_eq!(overlay.extrinsic_changes().block(), Some(2u32));
+
+		overlay.set_storage(vec![3], Some(vec![4]));
+		assert_extrinsics(&overlay.top, vec![3], vec![BLOCK_INITIALIZATION_EXTRINSIC_INDEX]);
+	}
+
+	#[test]
+	fn reserved_extrinsic_indices_are_rejected() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.current_extrinsic_index(), None);
+
+		overlay.note_block_initialization();
+		assert_eq!(overlay.current_extrinsic_index(), None);
+		assert_eq!(
+			overlay.set_extrinsic_index(BLOCK_INITIALIZATION_EXTRINSIC_INDEX),
+			Err(ReservedExtrinsicIndex(BLOCK_INITIALIZATION_EXTRINSIC_INDEX)),
+		);
+		assert_eq!(
+			overlay.set_extrinsic_index(BLOCK_FINALIZATION_EXTRINSIC_INDEX),
+			Err(ReservedExtrinsicIndex(BLOCK_FINALIZATION_EXTRINSIC_INDEX)),
+		);
+		assert_eq!(
+			overlay.set_extrinsic_index(KEY_DELETION_EXTRINSIC_INDEX),
+			Err(ReservedExtrinsicIndex(KEY_DELETION_EXTRINSIC_INDEX)),
+		);
+		assert_eq!(overlay.current_extrinsic_index(), None);
+
+		overlay.set_extrinsic_index(5).unwrap();
+		assert_eq!(overlay.current_extrinsic_index(), Some(5));
+
+		overlay.note_block_finalization();
+		assert_eq!(overlay.current_extrinsic_index(), None);
+	}
+
+	#[test]
+	fn explicit_extrinsic_index_is_transactional_and_kept_outside_of_storage() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		overlay.set_extrinsic_index(0).unwrap();
+		assert_eq!(overlay.storage(EXTRINSIC_INDEX), None);
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(1).unwrap();
+		overlay.start_transaction();
+		overlay.note_block_finalization();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.current_extrinsic_index(), Some(1));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.current_extrinsic_index(), Some(0));
+
+		overlay.start_transaction();
+		overlay.note_block_finalization();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.current_extrinsic_index(), None);
+
+		assert_extrinsics(&overlay.top, vec![1], vec![NO_EXTRINSIC_INDEX]);
+		assert_extrinsics(&overlay.top, vec![3], vec![BLOCK_FINALIZATION_EXTRINSIC_INDEX]);
+		assert_eq!(overlay.storage(EXTRINSIC_INDEX), None);
+	}
+
 	#[test]
 	fn next_storage_key_change_works() {
 		let mut overlay = OverlayedChanges::default();
@@ -1059,4 +2122,405 @@ mod tests {
 		assert_eq!(next_to_40.0.to_vec(), vec![50]);
 		assert_eq!(next_to_40.1.value(), Some(&vec![50]));
 	}
+
+	#[test]
+	fn effective_changes_work() {
+		let child_info_1 = ChildInfo::new_default(b"Child1");
+		let child_info_2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+
+		overlay.set_storage(vec![30], Some(vec![30]));
+		overlay.set_storage(vec![10], Some(vec![10]));
+		overlay.set_child_storage(&child_info_2, vec![20], Some(vec![20]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![10], Some(vec![11]));
+		overlay.set_storage(vec![20], None);
+		overlay.set_child_storage(&child_info_1, vec![20], Some(vec![21]));
+		overlay.set_child_storage(&child_info_1, vec![10], None);
+
+		assert_eq!(overlay.effective_changes().collect::<Vec<_>>(), vec![
+			(&[10][..], Some(&[11][..])),
+			(&[20][..], None),
+			(&[30][..], Some(&[30][..])),
+		]);
+		assert_eq!(
+			overlay.effective_children()
+				.map(|(changes, info)| (info.storage_key().to_vec(), changes.collect::<Vec<_>>()))
+				.collect::<Vec<_>>(),
+			vec![
+				(b"Child1".to_vec(), vec![(&[10][..], None), (&[20][..], Some(&[21][..]))]),
+				(b"Child2".to_vec(), vec![(&[20][..], Some(&[20][..]))]),
+			],
+		);
+
+		overlay.rollback_transaction().unwrap();
+
+		assert_eq!(overlay.effective_changes().collect::<Vec<_>>(), vec![
+			(&[10][..], Some(&[10][..])),
+			(&[30][..], Some(&[30][..])),
+		]);
+		assert!(overlay.effective_child_changes(b"Child1").is_none());
+		assert_eq!(
+			overlay.effective_child_changes(b"Child2").unwrap().0.collect::<Vec<_>>(),
+			vec![(&[20][..], Some(&[20][..]))],
+		);
+	}
+
+	#[test]
+	fn changes_of_untracked_keys_are_not_attributed_to_extrinsics() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_untracked_prefixes(vec![b"kscratch".to_vec()]).unwrap();
+		assert_eq!(overlay.untracked_prefixes(), &[b"kscratch".to_vec()]);
+
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.set_storage(b"ktracked".to_vec(), Some(vec![1]));
+		overlay.set_storage(b"kscratch1".to_vec(), Some(vec![1]));
+		*overlay.value_mut_or_insert_with(b"kscratch2", Vec::new) = vec![2];
+		overlay.set_extrinsic_index(1).unwrap();
+		overlay.clear_prefix(b"k");
+
+		assert_eq!(overlay.storage(b"ktracked"), Some(None));
+		assert_eq!(overlay.storage(b"kscratch1"), Some(None));
+		assert_extrinsics(&overlay.top, b"ktracked", vec![0, 1]);
+		assert_extrinsics(&overlay.top, b"kscratch1", vec![]);
+		assert_extrinsics(&overlay.top, b"kscratch2", vec![]);
+		assert_eq!(
+			overlay.extrinsic_changes().estimated_trie_size(),
+			extrinsic_index_pair_size(b"ktracked", 2),
+		);
+
+		// prefixes can't be changed once changes are tracked, but are kept for the next block
+		assert_eq!(overlay.set_untracked_prefixes(Vec::new()), Err(ChangesAlreadyTracked));
+		overlay.reset_for_block(2u64, Some(&Default::default()));
+		assert_eq!(overlay.untracked_prefixes(), &[b"kscratch".to_vec()]);
+		overlay.set_storage(b"kscratch1".to_vec(), Some(vec![3]));
+		assert_eq!(overlay.set_untracked_prefixes(Vec::new()), Ok(()));
+	}
+
+	#[test]
+	fn extrinsic_changes_are_estimated() {
+		// 1 byte of variant + 1 byte of key length + 1 byte key + 1 byte of list length
+		let pair_size = |extrinsics: u64| 4 + 4 * extrinsics;
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![1], Some(vec![2]));
+		assert_eq!(overlay.extrinsic_changes().estimated_trie_size(), pair_size(1));
+
+		overlay.set_extrinsic_index(1).unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		assert_eq!(overlay.extrinsic_changes().estimated_trie_size(), pair_size(2) + pair_size(1));
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.extrinsic_changes().estimated_trie_size(), pair_size(1));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.start_transaction();
+		overlay.clear_prefix(&[1]);
+		overlay.commit_transaction().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.extrinsic_changes().estimated_trie_size(), pair_size(2) + pair_size(1));
+
+		assert!(!overlay.changes_limit_exceeded());
+		overlay.set_changes_limit(Some(pair_size(2) + pair_size(1)));
+		assert!(!overlay.changes_limit_exceeded());
+
+		// the flag isn't reset by rollback
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert!(overlay.changes_limit_exceeded());
+		overlay.rollback_transaction().unwrap();
+		assert!(overlay.changes_limit_exceeded());
+		assert_eq!(overlay.extrinsic_changes().estimated_trie_size(), pair_size(2) + pair_size(1));
+	}
+
+	#[test]
+	fn extrinsic_ids_are_encoded_as_u32() {
+		let ids = vec![
+			0,
+			1,
+			KEY_DELETION_EXTRINSIC_INDEX - 1,
+			KEY_DELETION_EXTRINSIC_INDEX,
+			BLOCK_FINALIZATION_EXTRINSIC_INDEX,
+			BLOCK_INITIALIZATION_EXTRINSIC_INDEX,
+			NO_EXTRINSIC_INDEX,
+		];
+		for (index, raw_id) in ids.iter().enumerate() {
+			let id = ExtrinsicId(*raw_id);
+			assert_eq!(id.encode(), raw_id.encode());
+			assert_eq!(ExtrinsicId::decode(&mut &raw_id.encode()[..]), Ok(id));
+			assert_eq!(u32::from(id), *raw_id);
+			assert_eq!(id.is_system(), index >= 3);
+			assert_eq!(id.extrinsic_index(), if index >= 3 { None } else { Some(*raw_id) });
+			for other_raw_id in &ids {
+				assert_eq!(id.cmp(&ExtrinsicId(*other_raw_id)), raw_id.cmp(other_raw_id));
+			}
+		}
+
+		assert_ne!(ExtrinsicId::BLOCK_FINALIZATION, ExtrinsicId::NONE);
+		assert_eq!(
+			format!("{:?}", vec![ExtrinsicId(5), ExtrinsicId::BLOCK_INITIALIZATION, ExtrinsicId::BLOCK_FINALIZATION, ExtrinsicId::NONE]),
+			"[5, BlockInitialization, BlockFinalization, None]",
+		);
+		assert_eq!(
+			ids.iter().cloned().map(ExtrinsicId).collect::<Vec<_>>().encode(),
+			ids.encode(),
+		);
+	}
+
+	#[test]
+	fn system_changes_are_attributed_to_system_extrinsic_ids() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.note_block_initialization();
+		assert_eq!(overlay.current_extrinsic_index(), None);
+		overlay.note_extrinsic_weight(1);
+		overlay.set_extrinsic_index(0).unwrap();
+		assert_eq!(overlay.current_extrinsic_index(), Some(0));
+		overlay.note_extrinsic_weight(2);
+		overlay.note_block_finalization();
+		overlay.note_extrinsic_weight(3);
+
+		let weights = overlay.extrinsic_weights();
+		assert_eq!(
+			weights.iter().map(|(id, weight)| (id.is_system(), *weight)).collect::<Vec<_>>(),
+			vec![(false, 2), (true, 3), (true, 1)],
+		);
+		assert_eq!(
+			overlay.set_extrinsic_index(BLOCK_INITIALIZATION_EXTRINSIC_INDEX),
+			Err(ReservedExtrinsicIndex(BLOCK_INITIALIZATION_EXTRINSIC_INDEX)),
+		);
+	}
+
+	#[test]
+	fn extrinsic_weights_follow_transactions() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.note_extrinsic_weight(10);
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(1).unwrap();
+		overlay.note_extrinsic_weight(20);
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.note_extrinsic_weight(15);
+		assert_eq!(overlay.total_weight(), 35);
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.extrinsic_weights(), vec![(ExtrinsicId(0), 10)].into_iter().collect());
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(1).unwrap();
+		overlay.note_extrinsic_weight(20);
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(2).unwrap();
+		overlay.note_extrinsic_weight(30);
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.total_weight(), 60);
+
+		// weights of the committed nested transaction are discarded with the parent transaction
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.extrinsic_weights(), vec![(ExtrinsicId(0), 10)].into_iter().collect());
+		assert_eq!(overlay.total_weight(), 10);
+	}
+
+	#[test]
+	fn child_storages_are_marked_dirty_when_changed() {
+		let child_info_1 = ChildInfo::new_default(b"Child1");
+		let child_info_2 = ChildInfo::new_default(b"Child2");
+		let mut overlay = OverlayedChanges::default();
+		assert!(!overlay.is_child_dirty(b"Child1"));
+
+		overlay.set_child_storage(&child_info_1, vec![1], Some(vec![1]));
+		overlay.clear_child_prefix(&child_info_2, &[2]);
+		assert!(overlay.is_child_dirty(b"Child1"));
+		assert!(overlay.is_child_dirty(b"Child2"));
+
+		overlay.mark_child_root_computed(b"Child1");
+		overlay.mark_child_root_computed(b"Child2");
+		assert!(!overlay.is_child_dirty(b"Child1"));
+
+		// children that have been changed before the transaction are dirty after rollback
+		overlay.start_transaction();
+		overlay.clear_child_storage(&child_info_2);
+		overlay.rollback_transaction().unwrap();
+		assert!(overlay.is_child_dirty(b"Child1"));
+		assert!(overlay.is_child_dirty(b"Child2"));
+	}
+
+	#[test]
+	#[should_panic(expected = "Drain is not allowed with open transactions.")]
+	fn drain_storage_changes_fails_with_open_transactions() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+
+		let _ = overlay.drain_storage_changes::<_, _, u64>(
+			&backend,
+			None,
+			Default::default(),
+			&mut Default::default(),
+		);
+	}
+
+	#[test]
+	fn reads_are_tracked_per_extrinsic() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_read_tracking(true);
+		overlay.set_extrinsic_index(0).unwrap();
+
+		{
+			let mut cache = StorageTransactionCache::default();
+			let mut ext = Ext::new(
+				&mut overlay,
+				&mut cache,
+				&backend,
+				crate::changes_trie::disabled_state::<_, u64>(),
+				None,
+			);
+
+			ext.storage(&[1]);
+			ext.exists_child_storage(&child_info, &[2]);
+
+			ext.set_storage(EXTRINSIC_INDEX.to_vec(), 1u32.encode());
+			ext.storage_hash(&[3]);
+
+			// reads of the rolled back transaction are discarded
+			ext.storage_start_transaction();
+			ext.storage(&[4]);
+			ext.child_storage(&child_info, &[5]);
+			ext.storage_rollback_transaction().unwrap();
+
+			// reads of the committed transaction are kept
+			ext.storage_start_transaction();
+			ext.exists_storage(&[6]);
+			ext.storage_commit_transaction().unwrap();
+		}
+
+		let expected_reads: ReadsPerExtrinsic = vec![
+			(0, vec![vec![1]].into_iter().collect()),
+			(1, vec![vec![3], vec![6]].into_iter().collect()),
+		].into_iter().collect();
+		assert_eq!(overlay.reads_per_extrinsic(), expected_reads);
+
+		let expected_child_reads: ReadsPerExtrinsic = vec![
+			(0, vec![vec![2]].into_iter().collect()),
+		].into_iter().collect();
+		assert_eq!(overlay.child_reads_per_extrinsic(child_info.storage_key()), expected_child_reads);
+
+		// reads are never written
+		assert!(overlay.changes().all(|(key, _)| key.as_slice() == EXTRINSIC_INDEX));
+
+		overlay.set_read_tracking(false);
+		assert!(overlay.reads_per_extrinsic().is_empty());
+	}
+
+	#[test]
+	fn empty_changes_are_detected() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		assert!(overlay.is_empty());
+		assert!(overlay.extrinsic_changes().is_empty());
+
+		overlay.start_transaction();
+		overlay.set_extrinsic_index(0).unwrap();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert!(!overlay.is_empty());
+		assert!(!overlay.extrinsic_changes().is_empty());
+
+		overlay.rollback_transaction().unwrap();
+		assert!(overlay.is_empty());
+		assert!(overlay.extrinsic_changes().is_empty());
+	}
+
+	#[test]
+	fn large_values_are_spilled() {
+		const VALUE_SIZE: usize = 10 * 1024 * 1024;
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::with_value_spill(1024, Box::new(InMemoryValueSpill::new()));
+		let stored_bytes = |overlay: &OverlayedChanges| overlay.value_spill().unwrap().stored_bytes();
+		let is_large_value = |value: Option<&[u8]>| value
+			.map(|value| value.len() == VALUE_SIZE && value.iter().all(|byte| *byte == 42))
+			.unwrap_or(false);
+
+		// the only copy of the large value is in the spill
+		overlay.start_transaction();
+		overlay.set_storage(b"code".to_vec(), Some(vec![42; VALUE_SIZE]));
+		overlay.set_storage(b"small".to_vec(), Some(vec![1; 32]));
+		assert_eq!(stored_bytes(&overlay), VALUE_SIZE as u64);
+		assert!(overlay.heap_size() < 1024);
+		overlay.commit_transaction().unwrap();
+		assert_eq!(stored_bytes(&overlay), VALUE_SIZE as u64);
+		assert!(overlay.heap_size() < 1024);
+		assert!(is_large_value(overlay.storage(b"code").unwrap()));
+		assert_eq!(overlay.storage(b"small"), Some(Some(&[1; 32][..])));
+
+		// storage root is computed from the spilled value
+		let mut expected_overlay = OverlayedChanges::default();
+		expected_overlay.set_storage(b"code".to_vec(), Some(vec![42; VALUE_SIZE]));
+		expected_overlay.set_storage(b"small".to_vec(), Some(vec![1; 32]));
+		let expected_root = expected_overlay.storage_root(&backend, &mut StorageTransactionCache::<_, _, u64>::default());
+		drop(expected_overlay);
+		let mut cache = StorageTransactionCache::default();
+		assert_eq!(overlay.storage_root(&backend, &mut cache), expected_root);
+		assert_eq!(stored_bytes(&overlay), VALUE_SIZE as u64);
+
+		// the value is taken back from the spill when changes are drained
+		let changes = overlay.drain_storage_changes::<_, _, u64>(
+			&backend,
+			None,
+			Default::default(),
+			&mut cache,
+		).unwrap();
+		assert_eq!(stored_bytes(&overlay), 0);
+		assert_eq!(changes.main_storage_changes.len(), 2);
+		assert!(is_large_value(changes.main_storage_changes[0].1.as_deref()));
+	}
+
+	#[test]
+	fn spilled_values_are_released() {
+		let mut overlay = OverlayedChanges::with_value_spill(4, Box::new(InMemoryValueSpill::new()));
+		let stored_bytes = |overlay: &OverlayedChanges| overlay.value_spill().unwrap().stored_bytes();
+
+		// overwritten and rolled back values are released
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1; 10]));
+		overlay.set_storage(vec![1], Some(vec![2; 20]));
+		assert_eq!(stored_bytes(&overlay), 20);
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(stored_bytes(&overlay), 0);
+		assert_eq!(overlay.storage(&[1]), None);
+
+		// value of the parent transaction is released when the overwriting transaction is committed
+		overlay.set_storage(vec![1], Some(vec![1; 10]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![2]));
+		assert_eq!(stored_bytes(&overlay), 10);
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[2][..])));
+		overlay.commit_transaction().unwrap();
+		assert_eq!(stored_bytes(&overlay), 0);
+
+		// modified value is taken back from the spill
+		overlay.set_storage(vec![2], Some(vec![3; 10]));
+		overlay.value_mut_or_insert_with(&[2], Vec::new).push(4);
+		assert_eq!(stored_bytes(&overlay), 0);
+		assert_eq!(overlay.storage(&[2]).unwrap().unwrap().len(), 11);
+
+		// cleared values are released
+		let child_info = ChildInfo::new_default(b"child");
+		overlay.set_storage(vec![3], Some(vec![5; 10]));
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![6; 10]));
+		assert_eq!(overlay.child_storage(&child_info, &[3]), Some(Some(&[6; 10][..])));
+		assert_eq!(stored_bytes(&overlay), 20);
+		overlay.clear_prefix(&[3]);
+		overlay.clear_child_storage(&child_info);
+		assert_eq!(stored_bytes(&overlay), 0);
+		assert_eq!(overlay.storage(&[3]), Some(None));
+	}
 }
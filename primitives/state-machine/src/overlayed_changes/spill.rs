@@ -0,0 +1,97 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of large values that are moved out of the overlay.
+
+use sp_std::{boxed::Box, collections::btree_map::BTreeMap};
+use super::StorageValue;
+
+/// Handle of the value that has been moved to the `ValueSpill`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpillHandle(pub u64);
+
+/// Storage of large values that are moved out of the `OverlayedChanges`.
+///
+/// The overlay keeps handles of stored values instead of values themselves. Values are
+/// read from the spill when they're queried and taken back from it when changes are drained
+/// or when they're overwritten.
+pub trait ValueSpill: Send {
+	/// Store the value, returning handle to it.
+	fn store(&mut self, value: StorageValue) -> SpillHandle;
+
+	/// Read the stored value. Returns None if there's no value with given handle.
+	fn load(&self, handle: SpillHandle) -> Option<&[u8]>;
+
+	/// Remove the stored value, returning it. Returns None if there's no value with given handle.
+	fn take(&mut self, handle: SpillHandle) -> Option<StorageValue>;
+
+	/// Total size of all stored values.
+	fn stored_bytes(&self) -> u64;
+
+	/// Create the copy of this spill with all stored values. Handles of stored values are
+	/// valid in the copy.
+	fn clone_spill(&self) -> Box<dyn ValueSpill>;
+}
+
+/// In-memory `ValueSpill`.
+///
+/// Doesn't save any memory, but it allows to check that the overlay doesn't keep copies
+/// of spilled values.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryValueSpill {
+	/// Stored values.
+	values: BTreeMap<SpillHandle, StorageValue>,
+	/// Handle of the next stored value.
+	next_handle: u64,
+	/// Total size of all stored values.
+	stored_bytes: u64,
+}
+
+impl InMemoryValueSpill {
+	/// Create new empty spill.
+	pub fn new() -> Self {
+		Self::default()
+	}
+}
+
+impl ValueSpill for InMemoryValueSpill {
+	fn store(&mut self, value: StorageValue) -> SpillHandle {
+		let handle = SpillHandle(self.next_handle);
+		self.next_handle += 1;
+		self.stored_bytes += value.len() as u64;
+		self.values.insert(handle, value);
+		handle
+	}
+
+	fn load(&self, handle: SpillHandle) -> Option<&[u8]> {
+		self.values.get(&handle).map(|value| &value[..])
+	}
+
+	fn take(&mut self, handle: SpillHandle) -> Option<StorageValue> {
+		let value = self.values.remove(&handle)?;
+		self.stored_bytes -= value.len() as u64;
+		Some(value)
+	}
+
+	fn stored_bytes(&self) -> u64 {
+		self.stored_bytes
+	}
+
+	fn clone_spill(&self) -> Box<dyn ValueSpill> {
+		Box::new(self.clone())
+	}
+}
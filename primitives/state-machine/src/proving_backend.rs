@@ -29,7 +29,7 @@ use sp_trie::{
 pub use sp_trie::{Recorder, trie_types::{Layout, TrieError}};
 use crate::trie_backend::TrieBackend;
 use crate::trie_backend_essence::{Ephemeral, TrieBackendEssence, TrieBackendStorage};
-use crate::{Error, ExecutionError, Backend, DBValue};
+use crate::{Error, ExecutionError, Backend, DBValue, OwnedPrefix};
 use sp_core::storage::ChildInfo;
 
 /// Patricia trie-based backend specialized in get value proofs.
@@ -200,7 +200,7 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> ProvingBackend<'a, S, H>
 		proof_recorder: ProofRecorder<H::Out>,
 	) -> Self {
 		let essence = backend.essence();
-		let root = essence.root().clone();
+		let root = *essence.root();
 		let recorder = ProofRecorderBackend {
 			backend: essence.backend_storage(),
 			proof_recorder,
@@ -233,7 +233,7 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> TrieBackendStorage<H>
 		}
 
 		let backend_value = self.backend.get(key, prefix)?;
-		self.proof_recorder.record(key.clone(), backend_value.clone());
+		self.proof_recorder.record(*key, backend_value.clone());
 		Ok(backend_value)
 	}
 }
@@ -268,6 +268,18 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.child_storage(child_info, key)
 	}
 
+	fn exists_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<bool>>, Self::Error> {
+		self.0.exists_storage_batch(keys)
+	}
+
+	fn exists_child_storage_batch(
+		&self,
+		child_info: &ChildInfo,
+		keys: &[&[u8]],
+	) -> Result<Vec<Option<bool>>, Self::Error> {
+		self.0.exists_child_storage_batch(child_info, keys)
+	}
+
 	fn apply_to_key_values_while<F: FnMut(Vec<u8>, Vec<u8>) -> bool>(
 		&self,
 		child_info: Option<&ChildInfo>,
@@ -340,6 +352,13 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.storage_root(delta)
 	}
 
+	fn storage_root_with_pruning<'b>(
+		&self,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> (H::Out, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		self.0.storage_root_with_pruning(delta)
+	}
+
 	fn child_storage_root<'b>(
 		&self,
 		child_info: &ChildInfo,
@@ -348,6 +367,14 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_root_with_pruning<'b>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		self.0.child_storage_root_with_pruning(child_info, delta)
+	}
+
 	fn register_overlay_stats(&self, _stats: &crate::stats::StateMachineStats) { }
 
 	fn usage_info(&self) -> crate::stats::UsageInfo {
@@ -23,20 +23,23 @@ use crate::{
 	backend::Backend, OverlayedChanges, StorageTransactionCache, ext::Ext, InMemoryBackend,
 	StorageKey, StorageValue,
 	changes_trie::{
+		AnchorBlockId as ChangesTrieAnchorBlockId,
 		Configuration as ChangesTrieConfiguration,
 		InMemoryStorage as ChangesTrieInMemoryStorage,
 		BlockNumber as ChangesTrieBlockNumber,
+		RootsStorage as ChangesTrieRootsStorage,
 		State as ChangesTrieState,
 	},
 };
 
-use codec::Decode;
+use codec::Encode;
 use hash_db::Hasher;
+use num_traits::One;
 use sp_core::{
 	offchain::testing::TestPersistentOffchainDB,
 	storage::{
 		well_known_keys::{CHANGES_TRIE_CONFIG, CODE, is_child_storage_key},
-		Storage,
+		Storage, ChildInfo,
 	},
 	traits::TaskExecutorExt,
 	testing::TaskExecutor,
@@ -57,6 +60,8 @@ where
 	pub backend: InMemoryBackend<H>,
 	changes_trie_config: Option<ChangesTrieConfiguration>,
 	changes_trie_storage: ChangesTrieInMemoryStorage<H, N>,
+	/// Number of the block that is currently built.
+	changes_trie_block: N,
 	/// Extensions.
 	pub extensions: Extensions,
 }
@@ -76,6 +81,8 @@ where
 					config,
 					zero: 0.into(),
 					storage: &self.changes_trie_storage,
+					existence_cache: &crate::changes_trie::NoExistenceCache,
+					params: Default::default(),
 				}),
 				None => None,
 			},
@@ -93,30 +100,65 @@ where
 		Self::new_with_code(&[], Storage::default())
 	}
 
+	/// Create a new instance of `TestExternalities` with empty storage and changes tries enabled.
+	///
+	/// Changes tries are built for every block that is committed with
+	/// [`commit_block`](Self::commit_block):
+	///
+	/// ```
+	/// use sp_core::{Blake2Hasher, ChangesTrieConfiguration, traits::Externalities};
+	/// use sp_state_machine::TestExternalities;
+	///
+	/// fn digest_root(write_at_block_2: bool) -> sp_core::H256 {
+	/// 	let config = ChangesTrieConfiguration::new(4, 1);
+	/// 	let mut ext = TestExternalities::<Blake2Hasher, u64>::new_with_changes_trie(config);
+	/// 	for block in 1..4u8 {
+	/// 		if block != 2 || write_at_block_2 {
+	/// 			ext.ext().set_storage(vec![block], vec![block]);
+	/// 		}
+	/// 		ext.commit_block();
+	/// 	}
+	///
+	/// 	// digest of blocks 1..3 is built at block 4
+	/// 	ext.commit_block()
+	/// }
+	///
+	/// assert_ne!(digest_root(true), digest_root(false));
+	/// ```
+	pub fn new_with_changes_trie(config: ChangesTrieConfiguration) -> Self {
+		let mut storage = Storage::default();
+		storage.top.insert(CHANGES_TRIE_CONFIG.to_vec(), config.encode());
+		Self::new(storage)
+	}
+
 	/// Create a new instance of `TestExternalities` with code and storage.
 	pub fn new_with_code(code: &[u8], mut storage: Storage) -> Self {
-		let mut overlay = OverlayedChanges::default();
-		let changes_trie_config = storage.top.get(CHANGES_TRIE_CONFIG)
-			.and_then(|v| Decode::decode(&mut &v[..]).ok());
-		overlay.set_collect_extrinsics(changes_trie_config.is_some());
-
 		assert!(storage.top.keys().all(|key| !is_child_storage_key(key)));
 		assert!(storage.children_default.keys().all(|key| is_child_storage_key(key)));
 
 		storage.top.insert(CODE.to_vec(), code.to_vec());
+		let backend: InMemoryBackend<H> = storage.into();
+
+		let mut overlay = OverlayedChanges::default();
+		let changes_trie_config = overlay.set_collect_extrinsics_from_state::<H, _, N>(&backend)
+			.expect("changes trie configuration in the test storage can't be decoded");
 
 		let mut extensions = Extensions::default();
 		extensions.register(TaskExecutorExt::new(TaskExecutor::new()));
 
 		let offchain_db = TestPersistentOffchainDB::new();
 
+		let changes_trie_storage = ChangesTrieInMemoryStorage::new();
+		changes_trie_storage.insert_block_hash(block_hash::<H, N>(&0.into()), 0.into());
+
 		TestExternalities {
 			overlay,
 			offchain_db,
 			changes_trie_config,
 			extensions,
-			changes_trie_storage: ChangesTrieInMemoryStorage::new(),
-			backend: storage.into(),
+			changes_trie_storage,
+			changes_trie_block: One::one(),
+			backend,
 			storage_transaction_cache: Default::default(),
 		}
 	}
@@ -141,6 +183,11 @@ where
 		self.backend.insert(vec![(None, vec![(k, Some(v))])]);
 	}
 
+	/// Insert key/value into the child trie of the backend.
+	pub fn insert_child(&mut self, child_info: &ChildInfo, k: StorageKey, v: StorageValue) {
+		self.backend.insert(vec![(Some(child_info.clone()), vec![(k, Some(v))])]);
+	}
+
 	/// Registers the given extension for this instance.
 	pub fn register_extension<E: Any + Extension>(&mut self, ext: E) {
 		self.extensions.register(ext);
@@ -151,21 +198,81 @@ where
 		&mut self.changes_trie_storage
 	}
 
+	/// Hash of the parent of the block that is currently built.
+	///
+	/// Changes trie of the current block is built on top of this block.
+	pub fn parent_hash(&self) -> H::Out {
+		block_hash::<H, N>(&(self.changes_trie_block.clone() - One::one()))
+	}
+
+	/// Root of the changes trie of the given committed block.
+	pub fn changes_trie_root_at(&self, block: N) -> Option<H::Out> {
+		let anchor = ChangesTrieAnchorBlockId {
+			hash: self.parent_hash(),
+			number: self.changes_trie_block.clone() - One::one(),
+		};
+		self.changes_trie_storage.root(&anchor, block).ok().flatten()
+	}
+
+	/// Commit all pending changes to the underlying backend and the changes trie of the current
+	/// block to the changes trie storage. Returns the root of the changes trie.
+	///
+	/// Estimation of the changes trie input is reset and the next block is started.
+	///
+	/// # Panic
+	///
+	/// This will panic if changes tries are disabled or if there are still open transactions.
+	pub fn commit_block(&mut self) -> H::Out {
+		let config = self.changes_trie_config.clone()
+			.expect("changes tries must be enabled to commit blocks");
+		let block = self.changes_trie_block.clone();
+		let parent_hash = self.parent_hash();
+		let state = ChangesTrieState::new(config, 0.into(), &self.changes_trie_storage);
+		let mut cache = StorageTransactionCache::default();
+		let root = self.overlay.changes_trie_root(
+			&self.backend,
+			Some(&state),
+			parent_hash,
+			true,
+			&mut cache,
+		)
+			.expect("parent block hash is known to the changes trie storage; qed")
+			.expect("changes trie state is provided; qed");
+		let changes = self.overlay.drain_storage_changes(
+			&self.backend,
+			Some(&state),
+			parent_hash,
+			&mut cache,
+		).expect("changes trie transaction has been computed above; qed");
+
+		self.backend.apply_transaction(changes.transaction_storage_root, changes.transaction);
+		if let Some((changes_trie, cache_action)) = changes.changes_trie_transaction {
+			self.changes_trie_storage.insert(block.clone(), root, changes_trie);
+			self.changes_trie_storage.cache_mut().perform(cache_action);
+		}
+
+		self.storage_transaction_cache.reset();
+		self.overlay.reset_extrinsic_changes();
+		self.changes_trie_storage.insert_block_hash(block_hash::<H, N>(&block), block.clone());
+		self.changes_trie_block = block + One::one();
+		root
+	}
+
 	/// Return a new backend with all pending changes.
 	///
 	/// In contrast to [`commit_all`](Self::commit_all) this will not panic if there are open
 	/// transactions.
 	fn as_backend(&self) -> InMemoryBackend<H> {
-		let top: Vec<_> = self.overlay.changes()
-			.map(|(k, v)| (k.clone(), v.value().cloned()))
+		let top: Vec<_> = self.overlay.effective_changes()
+			.map(|(k, v)| (k.to_vec(), v.map(|v| v.to_vec())))
 			.collect();
 		let mut transaction = vec![(None, top)];
 
-		for (child_changes, child_info) in self.overlay.children() {
+		for (child_changes, child_info) in self.overlay.effective_children() {
 			transaction.push((
 				Some(child_info.clone()),
 				child_changes
-					.map(|(k, v)| (k.clone(), v.value().cloned()))
+					.map(|(k, v)| (k.to_vec(), v.map(|v| v.to_vec())))
 					.collect(),
 			))
 		}
@@ -212,6 +319,12 @@ where
 	}
 }
 
+/// Hash of the block with given number. Blocks of the `TestExternalities` have no headers, so
+/// the hash is derived from the number.
+fn block_hash<H: Hasher, N: Encode>(block: &N) -> H::Out {
+	H::hash(&block.encode())
+}
+
 impl<H: Hasher, N: ChangesTrieBlockNumber> std::fmt::Debug for TestExternalities<H, N>
 	where H::Out: Ord + codec::Codec,
 {
@@ -353,6 +466,59 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn storage_root_depends_on_every_child_trie() {
+		let child_info_1 = ChildInfo::new_default(&b"test_child1"[..]);
+		let child_info_2 = ChildInfo::new_default(&b"test_child2"[..]);
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert_child(&child_info_1, b"doe".to_vec(), b"reindeer".to_vec());
+		ext.insert_child(&child_info_2, b"doe".to_vec(), b"reindeer".to_vec());
+		let mut ext = ext.ext();
+
+		let initial_root = ext.storage_root();
+		assert_eq!(ext.child_storage(&child_info_1, &b"doe"[..]), Some(b"reindeer".to_vec()));
+		assert_eq!(ext.child_storage(&child_info_2, &b"doe"[..]), Some(b"reindeer".to_vec()));
+
+		ext.place_child_storage(&child_info_1, b"dog".to_vec(), Some(b"puppy".to_vec()));
+		let root_after_child_1 = ext.storage_root();
+		assert_ne!(root_after_child_1, initial_root);
+
+		ext.place_child_storage(&child_info_2, b"dog".to_vec(), Some(b"puppy".to_vec()));
+		let root_after_child_2 = ext.storage_root();
+		assert_ne!(root_after_child_2, root_after_child_1);
+		assert_ne!(root_after_child_2, initial_root);
+
+		// child tries are isolated from each other
+		ext.place_child_storage(&child_info_1, b"doe".to_vec(), None);
+		assert!(ext.child_storage(&child_info_1, &b"doe"[..]).is_none());
+		assert_eq!(ext.child_storage(&child_info_2, &b"doe"[..]), Some(b"reindeer".to_vec()));
+		assert_ne!(ext.storage_root(), root_after_child_2);
+	}
+
+	#[test]
+	fn commit_block_builds_changes_tries() {
+		let config = ChangesTrieConfiguration { digest_interval: 2, digest_levels: 1, ..Default::default() };
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::new_with_changes_trie(config);
+
+		// nothing is changed at blocks 1 and 2, so they have the same changes trie root
+		let root1 = ext.commit_block();
+		let root2 = ext.commit_block();
+		assert_eq!(root1, root2);
+
+		ext.ext().set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+		let root3 = ext.commit_block();
+		let root4 = ext.commit_block();
+		assert_ne!(root3, root1);
+		assert_ne!(root4, root1);
+		assert_ne!(root4, root3);
+
+		assert_eq!(ext.changes_trie_root_at(3), Some(root3));
+		assert_eq!(ext.changes_trie_root_at(4), Some(root4));
+		assert_eq!(ext.changes_trie_root_at(5), None);
+		assert_eq!(ext.ext().storage(b"doe"), Some(b"reindeer".to_vec()));
+		assert_eq!(ext.overlayed_changes().extrinsic_changes().estimated_trie_size(), 0);
+	}
+
 	#[test]
 	fn as_backend_generates_same_backend_as_commit_all() {
 		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
@@ -27,6 +27,8 @@ use crate::{
 	StorageKey, StorageValue, Backend,
 	trie_backend_essence::{TrieBackendEssence, TrieBackendStorage, Ephemeral},
 };
+#[cfg(feature = "std")]
+use crate::{backend::OwnedPrefix, trie_backend_essence::{NodeRefs, removed_nodes}};
 use sp_std::{boxed::Box, vec::Vec};
 
 /// Patricia trie-based backend. Transaction type is an overlay of changes to commit.
@@ -74,6 +76,86 @@ impl<S: TrieBackendStorage<H>, H: Hasher> sp_std::fmt::Debug for TrieBackend<S,
 	}
 }
 
+impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackend<S, H> where H::Out: Ord + Codec {
+	/// Calculate the storage root with given delta, counting references of inserted and removed
+	/// nodes in `node_refs`.
+	fn storage_root_with_node_refs<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		#[cfg(feature = "std")]
+		node_refs: Option<&mut NodeRefs<H>>,
+	) -> (H::Out, S::Overlay) {
+		let mut write_overlay = S::Overlay::default();
+		let mut root = *self.essence.root();
+
+		{
+			let eph = Ephemeral::new(
+				self.essence.backend_storage(),
+				&mut write_overlay,
+			);
+			#[cfg(feature = "std")]
+			let eph = eph.with_node_refs(node_refs);
+			let mut eph = eph;
+
+			match delta_trie_root::<Layout<H>, _, _, _, _, _>(&mut eph, root, delta) {
+				Ok(ret) => root = ret,
+				Err(e) => warn!(target: "trie", "Failed to write to trie: {}", e),
+			}
+		}
+
+		(root, write_overlay)
+	}
+
+	/// Calculate the child storage root with given delta, counting references of inserted and
+	/// removed nodes in `node_refs`.
+	fn child_storage_root_with_node_refs<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		#[cfg(feature = "std")]
+		node_refs: Option<&mut NodeRefs<H>>,
+	) -> (H::Out, bool, S::Overlay) {
+		let default_root = match child_info.child_type() {
+			ChildType::ParentKeyId => empty_child_trie_root::<Layout<H>>()
+		};
+
+		let mut write_overlay = S::Overlay::default();
+		let prefixed_storage_key = child_info.prefixed_storage_key();
+		let mut root = match self.storage(prefixed_storage_key.as_slice()) {
+			Ok(value) =>
+				value.and_then(|r| Decode::decode(&mut &r[..]).ok()).unwrap_or(default_root),
+			Err(e) => {
+				warn!(target: "trie", "Failed to read child storage root: {}", e);
+				default_root
+			},
+		};
+
+		{
+			let eph = Ephemeral::new(
+				self.essence.backend_storage(),
+				&mut write_overlay,
+			);
+			#[cfg(feature = "std")]
+			let eph = eph.with_node_refs(node_refs);
+			let mut eph = eph;
+
+			match child_delta_trie_root::<Layout<H>, _, _, _, _, _, _>(
+				child_info.keyspace(),
+				&mut eph,
+				root,
+				delta,
+			) {
+				Ok(ret) => root = ret,
+				Err(e) => warn!(target: "trie", "Failed to write to trie: {}", e),
+			}
+		}
+
+		let is_default = root == default_root;
+
+		(root, is_default, write_overlay)
+	}
+}
+
 impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 	H::Out: Ord + Codec,
 {
@@ -93,6 +175,18 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		self.essence.child_storage(child_info, key)
 	}
 
+	fn exists_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<bool>>, Self::Error> {
+		self.essence.exists_storage_batch(keys)
+	}
+
+	fn exists_child_storage_batch(
+		&self,
+		child_info: &ChildInfo,
+		keys: &[&[u8]],
+	) -> Result<Vec<Option<bool>>, Self::Error> {
+		self.essence.exists_child_storage_batch(child_info, keys)
+	}
+
 	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
 		self.essence.next_storage_key(key)
 	}
@@ -164,42 +258,31 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 	}
 
 	fn keys(&self, prefix: &[u8]) -> Vec<StorageKey> {
-		let collect_all = || -> Result<_, Box<TrieError<H::Out>>> {
-			let trie = TrieDB::<H>::new(self.essence(), self.essence.root())?;
-			let mut v = Vec::new();
-			for x in trie.iter()? {
-				let (key, _) = x?;
-				if key.starts_with(prefix) {
-					v.push(key.to_vec());
-				}
-			}
-
-			Ok(v)
-		};
-
-		collect_all().map_err(|e| debug!(target: "trie", "Error extracting trie keys: {}", e)).unwrap_or_default()
+		// seek to the prefix instead of walking the whole trie
+		let mut keys = Vec::new();
+		self.essence.for_keys_with_prefix(prefix, |key| keys.push(key.to_vec()));
+		keys
 	}
 
 	fn storage_root<'a>(
 		&self,
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, Self::Transaction) where H::Out: Ord {
-		let mut write_overlay = S::Overlay::default();
-		let mut root = *self.essence.root();
-
-		{
-			let mut eph = Ephemeral::new(
-				self.essence.backend_storage(),
-				&mut write_overlay,
-			);
-
-			match delta_trie_root::<Layout<H>, _, _, _, _, _>(&mut eph, root, delta) {
-				Ok(ret) => root = ret,
-				Err(e) => warn!(target: "trie", "Failed to write to trie: {}", e),
-			}
-		}
+		self.storage_root_with_node_refs(
+			delta,
+			#[cfg(feature = "std")]
+			None,
+		)
+	}
 
-		(root, write_overlay)
+	#[cfg(feature = "std")]
+	fn storage_root_with_pruning<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		let mut node_refs = NodeRefs::<H>::default();
+		let (root, write_overlay) = self.storage_root_with_node_refs(delta, Some(&mut node_refs));
+		(root, write_overlay, removed_nodes::<H>(node_refs))
 	}
 
 	fn child_storage_root<'a>(
@@ -207,41 +290,27 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		child_info: &ChildInfo,
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
-		let default_root = match child_info.child_type() {
-			ChildType::ParentKeyId => empty_child_trie_root::<Layout<H>>()
-		};
-
-		let mut write_overlay = S::Overlay::default();
-		let prefixed_storage_key = child_info.prefixed_storage_key();
-		let mut root = match self.storage(prefixed_storage_key.as_slice()) {
-			Ok(value) =>
-				value.and_then(|r| Decode::decode(&mut &r[..]).ok()).unwrap_or_else(|| default_root.clone()),
-			Err(e) => {
-				warn!(target: "trie", "Failed to read child storage root: {}", e);
-				default_root.clone()
-			},
-		};
-
-		{
-			let mut eph = Ephemeral::new(
-				self.essence.backend_storage(),
-				&mut write_overlay,
-			);
-
-			match child_delta_trie_root::<Layout<H>, _, _, _, _, _, _>(
-				child_info.keyspace(),
-				&mut eph,
-				root,
-				delta,
-			) {
-				Ok(ret) => root = ret,
-				Err(e) => warn!(target: "trie", "Failed to write to trie: {}", e),
-			}
-		}
-
-		let is_default = root == default_root;
+		self.child_storage_root_with_node_refs(
+			child_info,
+			delta,
+			#[cfg(feature = "std")]
+			None,
+		)
+	}
 
-		(root, is_default, write_overlay)
+	#[cfg(feature = "std")]
+	fn child_storage_root_with_pruning<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction, Vec<(OwnedPrefix, H::Out)>) where H::Out: Ord {
+		let mut node_refs = NodeRefs::<H>::default();
+		let (root, is_default, write_overlay) = self.child_storage_root_with_node_refs(
+			child_info,
+			delta,
+			Some(&mut node_refs),
+		);
+		(root, is_default, write_overlay, removed_nodes::<H>(node_refs))
 	}
 
 	fn as_trie_backend(&mut self) -> Option<&TrieBackend<Self::TrieBackendStorage, H>> {
@@ -354,6 +423,86 @@ pub mod tests {
 		assert!(new_root != test_trie().storage_root(iter::empty()).0);
 	}
 
+	#[test]
+	fn storage_root_with_pruning_returns_removed_nodes() {
+		use hash_db::HashDB;
+
+		let (mut db, root) = test_db();
+		let old_trie = TrieBackend::new(db.clone(), root);
+		let old_pairs = old_trie.pairs();
+		let delta = vec![
+			(&b"key"[..], Some(&b"new-value"[..])),
+			(&b"value1"[..], None),
+		];
+
+		let (new_root, tx, removed) = old_trie.storage_root_with_pruning(delta.clone().into_iter());
+		assert_eq!(new_root, old_trie.storage_root(delta.into_iter()).0);
+		assert!(!removed.is_empty());
+		assert!(removed.iter().all(|(prefix, hash)| db.contains(hash, (&prefix.0, prefix.1))));
+
+		// apply transaction => removed nodes are gone, the new trie is complete
+		let removed_nodes = removed.iter()
+			.map(|(prefix, hash)| (prefix.clone(), *hash, HashDB::get(&db, hash, (&prefix.0, prefix.1)).unwrap()))
+			.collect::<Vec<_>>();
+		db.consolidate(tx);
+		db.purge();
+		assert!(removed.iter().all(|(prefix, hash)| !db.contains(hash, (&prefix.0, prefix.1))));
+		let new_trie = TrieBackend::new(db.clone(), new_root);
+		assert_eq!(new_trie.storage(b"key").unwrap(), Some(b"new-value".to_vec()));
+		assert_eq!(new_trie.storage(b"value1").unwrap(), None);
+		assert_eq!(new_trie.pairs().len(), old_pairs.len() - 1);
+		assert!(TrieBackend::new(db.clone(), root).storage(b"key").is_err());
+
+		// re-inserting removed nodes => the old trie is complete again
+		for (prefix, hash, node) in removed_nodes {
+			db.emplace(hash, (&prefix.0, prefix.1), node);
+		}
+		assert_eq!(TrieBackend::new(db, root).pairs(), old_pairs);
+	}
+
+	#[test]
+	fn child_storage_root_with_pruning_returns_removed_nodes() {
+		use hash_db::HashDB;
+
+		let child_info = ChildInfo::new_default(CHILD_KEY_1);
+		let (mut db, root) = test_db();
+		let old_trie = TrieBackend::new(db.clone(), root);
+		let delta = vec![(&b"value3"[..], Some(&[143][..]))];
+
+		let (new_child_root, is_default, tx, removed) = old_trie.child_storage_root_with_pruning(
+			&child_info,
+			delta.clone().into_iter(),
+		);
+		assert_eq!(new_child_root, old_trie.child_storage_root(&child_info, delta.into_iter()).0);
+		assert!(!is_default);
+		assert!(!removed.is_empty());
+		// nodes of the child trie are stored under the keyspace of the child trie
+		assert!(removed.iter().all(|(prefix, _)| prefix.0.starts_with(child_info.keyspace())));
+
+		let removed_nodes = removed.iter()
+			.map(|(prefix, hash)| (prefix.clone(), *hash, HashDB::get(&db, hash, (&prefix.0, prefix.1)).unwrap()))
+			.collect::<Vec<_>>();
+		db.consolidate(tx);
+		db.purge();
+		assert!(TrieBackend::new(db.clone(), root).child_storage(&child_info, b"value3").is_err());
+
+		for (prefix, hash, node) in removed_nodes {
+			db.emplace(hash, (&prefix.0, prefix.1), node);
+		}
+		assert_eq!(
+			TrieBackend::new(db, root).child_storage(&child_info, b"value3").unwrap(),
+			Some(vec![142]),
+		);
+	}
+
+	#[test]
+	fn storage_root_with_pruning_does_not_remove_nodes_of_empty_delta() {
+		let (root, mut tx, removed) = test_trie().storage_root_with_pruning(iter::empty());
+		assert_eq!(root, test_trie().storage_root(iter::empty()).0);
+		assert!(tx.drain().is_empty());
+		assert!(removed.is_empty());
+	}
+
 	#[test]
 	fn prefix_walking_works() {
 		let trie = test_trie();
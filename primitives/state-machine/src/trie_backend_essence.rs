@@ -19,7 +19,7 @@
 //! from storage.
 
 #[cfg(feature = "std")]
-use std::sync::Arc;
+use std::{sync::Arc, collections::HashMap};
 use sp_std::{ops::Deref, boxed::Box, vec::Vec};
 use crate::{warn, debug};
 use hash_db::{self, Hasher, Prefix};
@@ -27,7 +27,9 @@ use sp_trie::{Trie, MemoryDB, PrefixedMemoryDB, DBValue,
 	empty_child_trie_root, read_trie_value, read_child_trie_value,
 	KeySpacedDB, TrieDBIterator};
 use sp_trie::trie_types::{TrieDB, TrieError, Layout};
-use crate::{backend::Consolidate, StorageKey, StorageValue};
+use crate::{backend::{Consolidate, OwnedPrefix}, StorageKey, StorageValue};
+#[cfg(feature = "std")]
+use crate::node_cache::NodeCache;
 use sp_core::storage::ChildInfo;
 use codec::Encode;
 
@@ -51,6 +53,8 @@ pub struct TrieBackendEssence<S: TrieBackendStorage<H>, H: Hasher> {
 	storage: S,
 	root: H::Out,
 	empty: H::Out,
+	#[cfg(feature = "std")]
+	cache: Option<Arc<NodeCache<H>>>,
 }
 
 impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out: Encode {
@@ -60,6 +64,18 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 			storage,
 			root,
 			empty: H::hash(&[0u8]),
+			#[cfg(feature = "std")]
+			cache: None,
+		}
+	}
+
+	/// Create new trie-based backend that looks up nodes in the `cache` before reading them
+	/// from the storage. Nodes that are read from the storage are inserted into the cache.
+	#[cfg(feature = "std")]
+	pub fn with_cache(storage: S, root: H::Out, cache: Arc<NodeCache<H>>) -> Self {
+		TrieBackendEssence {
+			cache: Some(cache),
+			..TrieBackendEssence::new(storage, root)
 		}
 	}
 
@@ -189,6 +205,71 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 			.map_err(map_e)
 	}
 
+	/// Check whether given keys exist in storage. All lookups share one trie instance.
+	///
+	/// Existence is `None` if trie nodes that are required to check it are missing.
+	pub fn exists_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<bool>>> {
+		self.exists_keys_from_root(&self.root, None, keys)
+	}
+
+	/// Check whether given keys exist in child storage. All lookups share one trie instance.
+	///
+	/// Existence is `None` if trie nodes that are required to check it are missing.
+	pub fn exists_child_storage_batch(
+		&self,
+		child_info: &ChildInfo,
+		keys: &[&[u8]],
+	) -> Result<Vec<Option<bool>>> {
+		let child_root = match self.child_root(child_info)? {
+			Some(child_root) => child_root,
+			None => return Ok(keys.iter().map(|_| Some(false)).collect()),
+		};
+
+		let mut hash = H::Out::default();
+
+		if child_root.len() != hash.as_ref().len() {
+			return Err(format!("Invalid child storage hash at {:?}", child_info.storage_key()));
+		}
+		// note: child_root and hash must be same size, panics otherwise.
+		hash.as_mut().copy_from_slice(&child_root[..]);
+
+		self.exists_keys_from_root(&hash, Some(child_info), keys)
+	}
+
+	/// Check existence of keys in main trie or child trie by providing corresponding root.
+	fn exists_keys_from_root(
+		&self,
+		root: &H::Out,
+		child_info: Option<&ChildInfo>,
+		keys: &[&[u8]],
+	) -> Result<Vec<Option<bool>>> {
+		let dyn_eph: &dyn hash_db::HashDBRef<_, _>;
+		let keyspace_eph;
+		if let Some(child_info) = child_info.as_ref() {
+			keyspace_eph = KeySpacedDB::new(self, child_info.keyspace());
+			dyn_eph = &keyspace_eph;
+		} else {
+			dyn_eph = self;
+		}
+
+		let trie = match TrieDB::<H>::new(dyn_eph, root) {
+			Ok(trie) => trie,
+			Err(e) => match *e {
+				TrieError::IncompleteDatabase(_) => return Ok(keys.iter().map(|_| None).collect()),
+				_ => return Err(format!("TrieDB creation error: {}", e)),
+			},
+		};
+		keys.iter()
+			.map(|key| match trie.contains(key) {
+				Ok(exists) => Ok(Some(exists)),
+				Err(e) => match *e {
+					TrieError::IncompleteDatabase(_) => Ok(None),
+					_ => Err(format!("Trie lookup error: {}", e)),
+				},
+			})
+			.collect()
+	}
+
 	/// Retrieve all entries keys of storage and call `f` for each of those keys.
 	/// Aborts as soon as `f` returns false.
 	///
@@ -276,6 +357,33 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 		let _ = self.trie_iter_inner(&self.root, Some(prefix), |k, _v| { f(&k); true }, None, None, false);
 	}
 
+	/// Execute given closure for all keys starting with prefix.
+	///
+	/// Aborts as soon as `f` returns false. Returns error if the trie can't be read.
+	pub fn try_for_keys_with_prefix<F: FnMut(&[u8]) -> bool>(&self, prefix: &[u8], mut f: F) -> Result<()> {
+		self.trie_iter_inner(&self.root, Some(prefix), |k, _v| f(&k), None, None, false)
+			.map(|_| ())
+	}
+
+	/// Execute given closure for all keys in the `start..end` range.
+	///
+	/// Aborts as soon as `f` returns false. Returns error if the trie can't be read.
+	pub fn for_keys_in_range<F: FnMut(&[u8]) -> bool>(
+		&self,
+		start: &[u8],
+		end: &[u8],
+		mut f: F,
+	) -> Result<()> {
+		self.trie_iter_inner(
+			&self.root,
+			None,
+			|k, _v| k.as_slice() < end && f(&k),
+			None,
+			Some(start),
+			false,
+		).map(|_| ())
+	}
+
 	fn trie_iter_inner<F: FnMut(Vec<u8>, Vec<u8>) -> bool>(
 		&self,
 		root: &H::Out,
@@ -324,11 +432,36 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 	pub fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) {
 		let _ = self.trie_iter_inner(&self.root, Some(prefix), |k, v| {f(&k, &v); true}, None, None, false);
 	}
+
+	/// Execute given closure for all key and values starting with prefix.
+	///
+	/// Returns error if the trie can't be read.
+	pub fn try_for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) -> Result<()> {
+		self.trie_iter_inner(&self.root, Some(prefix), |k, v| {f(&k, &v); true}, None, None, false)
+			.map(|_| ())
+	}
+}
+
+/// Reference counts of trie nodes that have been inserted (positive) and removed (negative)
+/// while the trie has been updated.
+#[cfg(feature = "std")]
+pub(crate) type NodeRefs<H> = HashMap<(OwnedPrefix, <H as Hasher>::Out), i32>;
+
+/// Nodes that have been removed more times than they have been inserted.
+#[cfg(feature = "std")]
+pub(crate) fn removed_nodes<H: Hasher>(node_refs: NodeRefs<H>) -> Vec<(OwnedPrefix, H::Out)> {
+	node_refs.into_iter()
+		.filter(|(_, refs)| *refs < 0)
+		.map(|(node, _)| node)
+		.collect()
 }
 
 pub(crate) struct Ephemeral<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
 	storage: &'a S,
 	overlay: &'a mut S::Overlay,
+	/// References of inserted and removed nodes, if tracked.
+	#[cfg(feature = "std")]
+	node_refs: Option<&'a mut NodeRefs<H>>,
 }
 
 impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> hash_db::AsHashDB<H, DBValue>
@@ -343,6 +476,24 @@ impl<'a, S: TrieBackendStorage<H>, H: Hasher> Ephemeral<'a, S, H> {
 		Ephemeral {
 			storage,
 			overlay,
+			#[cfg(feature = "std")]
+			node_refs: None,
+		}
+	}
+
+	/// Count references of nodes that are inserted and removed in `node_refs`.
+	#[cfg(feature = "std")]
+	pub fn with_node_refs(mut self, node_refs: Option<&'a mut NodeRefs<H>>) -> Self {
+		self.node_refs = node_refs;
+		self
+	}
+
+	/// Note that the node has been inserted into (positive `refs`) or removed from (negative
+	/// `refs`) the overlay.
+	fn note_node(&mut self, _prefix: Prefix, _key: &H::Out, _refs: i32) {
+		#[cfg(feature = "std")]
+		if let Some(node_refs) = self.node_refs.as_mut() {
+			*node_refs.entry(((_prefix.0.to_vec(), _prefix.1), *_key)).or_default() += _refs;
 		}
 	}
 }
@@ -369,14 +520,18 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: Hasher> hash_db::HashDB<H, DBValue>
 	}
 
 	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
-		hash_db::HashDB::insert(self.overlay, prefix, value)
+		let key = hash_db::HashDB::insert(self.overlay, prefix, value);
+		self.note_node(prefix, &key, 1);
+		key
 	}
 
 	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: DBValue) {
+		self.note_node(prefix, &key, 1);
 		hash_db::HashDB::emplace(self.overlay, key, prefix, value)
 	}
 
 	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
+		self.note_node(prefix, key, -1);
 		hash_db::HashDB::remove(self.overlay, key, prefix)
 	}
 }
@@ -442,8 +597,22 @@ impl<S: TrieBackendStorage<H>, H: Hasher> hash_db::HashDB<H, DBValue>
 		if *key == self.empty {
 			return Some([0u8].to_vec())
 		}
+		#[cfg(feature = "std")]
+		{
+			if let Some(node) = self.cache.as_ref().and_then(|cache| cache.get(key)) {
+				return Some(node);
+			}
+		}
 		match self.storage.get(&key, prefix) {
-			Ok(x) => x,
+			Ok(x) => {
+				#[cfg(feature = "std")]
+				{
+					if let (Some(cache), Some(node)) = (self.cache.as_ref(), x.as_ref()) {
+						cache.insert(*key, node.clone());
+					}
+				}
+				x
+			},
 			Err(e) => {
 				warn!(target: "trie", "Failed to read from DB: {}", e);
 				None
@@ -545,4 +714,62 @@ mod test {
 			essence_2.next_child_storage_key(child_info, b"6"), Ok(None)
 		);
 	}
+
+	fn keys_essence() -> TrieBackendEssence<MemoryDB<Blake2Hasher>, Blake2Hasher> {
+		let mut root = H256::default();
+		let mut mdb = MemoryDB::<Blake2Hasher>::default();
+		{
+			let mut trie = TrieDBMut::new(&mut mdb, &mut root);
+			for key in &[&b"a1"[..], b"a2", b"a3", b"b1", b"b2", b"c"] {
+				// values are large enough for leaves not to be inlined
+				trie.insert(key, &[42; 40]).expect("insert failed");
+			}
+		}
+		TrieBackendEssence::new(mdb, root)
+	}
+
+	#[test]
+	fn keys_iteration_is_terminated_early() {
+		let essence = keys_essence();
+
+		let mut keys = Vec::new();
+		assert_eq!(essence.try_for_keys_with_prefix(b"a", |key| {
+			keys.push(key.to_vec());
+			keys.len() < 2
+		}), Ok(()));
+		assert_eq!(keys, vec![b"a1".to_vec(), b"a2".to_vec()]);
+
+		let mut keys = Vec::new();
+		assert_eq!(essence.for_keys_in_range(b"a2", b"b2", |key| {
+			keys.push(key.to_vec());
+			true
+		}), Ok(()));
+		assert_eq!(keys, vec![b"a2".to_vec(), b"a3".to_vec(), b"b1".to_vec()]);
+
+		let mut keys = Vec::new();
+		assert_eq!(essence.for_keys_in_range(b"a25", b"d", |key| {
+			keys.push(key.to_vec());
+			key != b"b1"
+		}), Ok(()));
+		assert_eq!(keys, vec![b"a3".to_vec(), b"b1".to_vec()]);
+	}
+
+	#[test]
+	fn keys_iteration_fails_on_missing_trie_node() {
+		let essence = keys_essence();
+		let root = *essence.root();
+		let mut mdb = essence.into_storage();
+		// leaves of the same value are shared, so `remove_and_purge` would only decrement
+		// their reference counter
+		let node = mdb.keys().into_iter()
+			.find(|(node, rc)| *node != root && *rc == 1)
+			.map(|(node, _)| node)
+			.expect("trie has non-shared branch nodes");
+		mdb.remove_and_purge(&node, hash_db::EMPTY_PREFIX);
+		let essence = TrieBackendEssence::new(mdb, root);
+
+		assert!(essence.try_for_keys_with_prefix(b"", |_| true).is_err());
+		assert!(essence.for_keys_in_range(b"", b"d", |_| true).is_err());
+		assert!(essence.try_for_key_values_with_prefix(b"", |_, _| ()).is_err());
+	}
 }
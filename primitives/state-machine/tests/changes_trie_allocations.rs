@@ -0,0 +1,97 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allocations that are made when changes trie input is prepared.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use codec::Encode;
+use sp_core::{Blake2Hasher, ChangesTrieConfiguration, storage::well_known_keys::EXTRINSIC_INDEX};
+use sp_state_machine::{
+	InMemoryBackend, InMemoryChangesTrieStorage, OverlayedChanges,
+	ChangesTrieAnchorBlockId, ChangesTrieConfigurationRange, prepare_changes_trie_input,
+};
+
+/// Number of keys that are changed in the block.
+const KEYS: usize = 10_000;
+/// Length of every changed key.
+const KEY_LEN: usize = 1024;
+
+/// Allocator that counts allocations of the current thread.
+struct CountingAllocator;
+
+thread_local! {
+	static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+	static ALLOCATED_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+		let _ = ALLOCATED_BYTES.try_with(|bytes| bytes.set(bytes.get() + layout.size()));
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Number of allocations and number of allocated bytes of the current thread.
+fn allocated() -> (usize, usize) {
+	(ALLOCATIONS.with(Cell::get), ALLOCATED_BYTES.with(Cell::get))
+}
+
+#[test]
+fn changed_keys_are_copied_once_when_input_is_prepared() {
+	let backend = InMemoryBackend::<Blake2Hasher>::default();
+	let storage = InMemoryChangesTrieStorage::<Blake2Hasher, u64>::new();
+	let config = ChangesTrieConfiguration { digest_interval: 0, digest_levels: 0, ..Default::default() };
+	let mut overlay = OverlayedChanges::default();
+	overlay.set_collect_extrinsics(true);
+	overlay.set_storage(EXTRINSIC_INDEX.to_vec(), Some(0u32.encode()));
+	for index in 0..KEYS {
+		let mut key = vec![0u8; KEY_LEN];
+		key[..8].copy_from_slice(&(index as u64).to_be_bytes());
+		overlay.set_storage(key, Some(vec![1]));
+	}
+	let parent = ChangesTrieAnchorBlockId { hash: Default::default(), number: 0 };
+
+	let (allocations_before, bytes_before) = allocated();
+	let (input, _, _) = prepare_changes_trie_input(
+		&backend,
+		&storage,
+		ChangesTrieConfigurationRange { config: &config, zero: 0, end: None },
+		&overlay,
+		&parent,
+		1,
+	).unwrap();
+	let input = input.collect::<Vec<_>>();
+	let (allocations_after, bytes_after) = allocated();
+	let (allocations, bytes) = (allocations_after - allocations_before, bytes_after - bytes_before);
+
+	// changes of the extrinsic index key are ignored by default
+	assert_eq!(input.len(), KEYS);
+	// every pair owns a copy of its key and a set of extrinsics, nothing else is allocated per key
+	assert!(allocations < 3 * KEYS, "{} allocations for {} keys", allocations, KEYS);
+	// keys are copied into their input pairs, and are never cloned again
+	assert!(bytes >= KEYS * KEY_LEN);
+	assert!(bytes < 2 * KEYS * KEY_LEN, "{} bytes allocated for {} keys", bytes, KEYS);
+}
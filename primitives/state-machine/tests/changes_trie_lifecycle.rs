@@ -0,0 +1,89 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries lifecycle: blocks are executed, their changes tries are built and committed,
+//! digests are built on top of them, and key changes are queried and proved.
+
+pub mod test_helpers;
+
+use std::collections::BTreeSet;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use test_helpers::{Scenario, ScenarioParams, config, key};
+
+/// Seed of the RNG that generates both writes and queries.
+const SEED: u64 = 42;
+/// Number of blocks in the chain.
+const BLOCKS: u64 = 200;
+/// Number of key changes queries.
+const QUERIES: usize = 50;
+/// Number of queries, whose results are also proved.
+const PROVED_QUERIES: usize = 10;
+
+fn scenario() -> Scenario {
+	Scenario::generate(config(4, 2), ScenarioParams {
+		seed: SEED,
+		blocks: BLOCKS,
+		max_extrinsics: 8,
+		max_writes_per_extrinsic: 4,
+		key_space: 64,
+	})
+}
+
+#[test]
+fn changes_tries_lifecycle() {
+	let scenario = scenario();
+	assert_eq!(scenario.best_block, BLOCKS);
+
+	// digests are built exactly where the configuration predicts
+	let expected_digest_blocks = (1..=BLOCKS)
+		.filter(|block| scenario.config.digest_level_at_block(0, *block).is_some())
+		.collect::<BTreeSet<_>>();
+	assert_eq!(scenario.digest_blocks, expected_digest_blocks);
+
+	let mut rng = StdRng::seed_from_u64(SEED);
+	let queries = (0..QUERIES)
+		.map(|_| {
+			let key = key(rng.gen_range(0, scenario.params.key_space));
+			let begin = rng.gen_range(1, BLOCKS + 1);
+			let end = rng.gen_range(begin, BLOCKS + 1);
+			(key, begin, end)
+		})
+		.collect::<Vec<_>>();
+
+	for (key, begin, end) in &queries {
+		assert_eq!(
+			scenario.key_changes(key, *begin, *end),
+			Ok(scenario.expected_key_changes(key, *begin, *end)),
+			"changes of key {:?} at blocks {}..={}",
+			key,
+			begin,
+			end,
+		);
+	}
+
+	for (key, begin, end) in queries.iter().take(PROVED_QUERIES) {
+		let proof = scenario.key_changes_proof(key, *begin, *end).unwrap();
+		assert_eq!(
+			scenario.check_key_changes_proof(proof, key, *begin, *end),
+			Ok(scenario.expected_key_changes(key, *begin, *end)),
+			"proof of changes of key {:?} at blocks {}..={}",
+			key,
+			begin,
+			end,
+		);
+	}
+}
@@ -0,0 +1,235 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes tries lifecycle scenario, shared by integration tests.
+//!
+//! Blocks with random writes are executed through the `Ext`, their changes tries are built and
+//! committed into the in-memory storage, and every write is remembered, so results of key
+//! changes queries could be compared with the ground truth.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use codec::{Decode, Encode};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use sp_core::{Blake2Hasher, ChangesTrieConfiguration, H256, storage::well_known_keys::EXTRINSIC_INDEX};
+use sp_externalities::Externalities;
+use sp_state_machine::{
+	Ext, ExecutionObserver, InMemoryBackend, OverlayedChanges, StorageKey, StorageTransactionCache,
+	ChangesTrieAnchorBlockId, ChangesTrieBuildStats, ChangesTrieConfigurationRange, ChangesTrieError,
+	ChangesTrieState, InMemoryChangesTrieStorage, key_changes, key_changes_proof, key_changes_proof_check,
+};
+
+/// Changes trie configuration with given digest parameters.
+pub fn config(digest_interval: u32, digest_levels: u32) -> ChangesTrieConfiguration {
+	ChangesTrieConfiguration { digest_interval, digest_levels, ..Default::default() }
+}
+
+/// Storage key with given index.
+pub fn key(index: u32) -> StorageKey {
+	index.to_be_bytes().to_vec()
+}
+
+/// Hash of the block with given number.
+pub fn block_hash(block: u64) -> H256 {
+	H256::from_low_u64_be(block)
+}
+
+/// Parameters of the generated chain.
+#[derive(Debug, Clone)]
+pub struct ScenarioParams {
+	/// Seed of the RNG that generates writes.
+	pub seed: u64,
+	/// Number of blocks (starting from block#1).
+	pub blocks: u64,
+	/// Max number of extrinsics in the block. Blocks without extrinsics are generated too.
+	pub max_extrinsics: u32,
+	/// Max number of writes made by the single extrinsic.
+	pub max_writes_per_extrinsic: u32,
+	/// Number of distinct keys that are written (see `key`).
+	pub key_space: u32,
+}
+
+/// Chain, whose blocks have been executed and whose changes tries have been committed.
+pub struct Scenario {
+	/// Configuration of changes tries of the chain.
+	pub config: ChangesTrieConfiguration,
+	/// Parameters the chain has been generated with.
+	pub params: ScenarioParams,
+	/// Changes tries of the chain.
+	pub storage: InMemoryChangesTrieStorage<Blake2Hasher, u64>,
+	/// Number of the last block of the chain.
+	pub best_block: u64,
+	/// Blocks where digests have been built.
+	pub digest_blocks: BTreeSet<u64>,
+	/// Changes trie roots of all blocks, as they're known to light clients.
+	pub roots: Vec<(u64, H256)>,
+	/// Ground truth: blocks and extrinsics that have written every key.
+	changes: BTreeMap<StorageKey, BTreeSet<(u64, u32)>>,
+}
+
+impl Scenario {
+	/// Execute `params.blocks` blocks and commit their changes tries.
+	pub fn generate(config: ChangesTrieConfiguration, params: ScenarioParams) -> Self {
+		let mut rng = StdRng::seed_from_u64(params.seed);
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let storage = InMemoryChangesTrieStorage::new();
+		storage.insert_block_hash(block_hash(0), 0);
+		let mut scenario = Scenario {
+			config,
+			params,
+			storage,
+			best_block: 0,
+			digest_blocks: BTreeSet::new(),
+			roots: Vec::new(),
+			changes: BTreeMap::new(),
+		};
+		for _ in 0..scenario.params.blocks {
+			scenario.import_block(&backend, &mut rng);
+		}
+		scenario
+	}
+
+	/// Execute the next block with random writes, build and commit its changes trie.
+	pub fn import_block<R: Rng>(&mut self, backend: &InMemoryBackend<Blake2Hasher>, rng: &mut R) {
+		let block = self.best_block + 1;
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		let mut cache = StorageTransactionCache::<_, Blake2Hasher, u64>::default();
+
+		let extrinsics = rng.gen_range(0, self.params.max_extrinsics + 1);
+		for extrinsic in 0..extrinsics {
+			let mut ext = Ext::new(&mut overlay, &mut cache, backend, None, None);
+			ext.place_storage(EXTRINSIC_INDEX.to_vec(), Some(extrinsic.encode()));
+			for _ in 0..rng.gen_range(1, self.params.max_writes_per_extrinsic + 1) {
+				let key = key(rng.gen_range(0, self.params.key_space));
+				ext.place_storage(key.clone(), Some(block.encode()));
+				self.changes.entry(key).or_default().insert((block, extrinsic));
+			}
+		}
+
+		let observer = DigestObserver::default();
+		let (root, mdb) = {
+			let state = ChangesTrieState::new(self.config.clone(), 0, &self.storage);
+			let mut ext = Ext::new(&mut overlay, &mut cache, backend, Some(state), None)
+				.with_observer(Some(&observer));
+			let root = ext.storage_changes_root(&block_hash(block - 1).encode())
+				.expect("parent block is known to the storage; qed")
+				.expect("changes tries are enabled and empty tries have roots; qed");
+			let (mdb, _) = ext.take_changes_trie_transaction()
+				.expect("changes trie has just been built; qed");
+			(H256::decode(&mut &root[..]).expect("root is encoded hash; qed"), mdb)
+		};
+		self.storage.insert_built(block, root, mdb);
+		self.storage.insert_block_hash(block_hash(block), block);
+		self.roots.push((block, root));
+		if *observer.digest_blocks_walked.lock().unwrap() > 0 {
+			self.digest_blocks.insert(block);
+		}
+		self.best_block = block;
+	}
+
+	/// Changes trie configuration range of the chain.
+	pub fn config_range(&self) -> ChangesTrieConfigurationRange<'_, u64> {
+		ChangesTrieConfigurationRange { config: &self.config, zero: 0, end: None }
+	}
+
+	/// Anchor of the block with given number.
+	pub fn anchor(&self, block: u64) -> ChangesTrieAnchorBlockId<H256, u64> {
+		ChangesTrieAnchorBlockId { hash: block_hash(block), number: block }
+	}
+
+	/// Changes of given key at blocks `begin..=end`, in descending order.
+	pub fn expected_key_changes(&self, key: &[u8], begin: u64, end: u64) -> Vec<(u64, u32)> {
+		self.changes.get(key)
+			.map(|changes| changes.iter()
+				.rev()
+				.filter(|(block, _)| *block >= begin && *block <= end)
+				.cloned()
+				.collect())
+			.unwrap_or_default()
+	}
+
+	/// Query changes of given key at blocks `begin..=end` from the changes tries storage.
+	///
+	/// Changes are sorted in descending order, because digests are walked breadth-first.
+	pub fn key_changes(&self, key: &[u8], begin: u64, end: u64) -> Result<Vec<(u64, u32)>, ChangesTrieError<u64>> {
+		key_changes::<Blake2Hasher, u64>(
+			self.config_range(),
+			&self.storage,
+			begin,
+			&self.anchor(end),
+			self.best_block,
+			None,
+			key,
+		).and_then(|changes| changes.collect()).map(sorted_changes)
+	}
+
+	/// Prove changes of given key at blocks `begin..=end`.
+	pub fn key_changes_proof(&self, key: &[u8], begin: u64, end: u64) -> Result<Vec<Vec<u8>>, ChangesTrieError<u64>> {
+		key_changes_proof::<Blake2Hasher, u64>(
+			self.config_range(),
+			&self.storage,
+			begin,
+			&self.anchor(end),
+			self.best_block,
+			None,
+			key,
+		)
+	}
+
+	/// Check the proof of changes of given key at blocks `begin..=end`, knowing nothing but
+	/// changes trie roots (as light clients do).
+	///
+	/// Changes are sorted in descending order, because digests are walked breadth-first.
+	pub fn check_key_changes_proof(
+		&self,
+		proof: Vec<Vec<u8>>,
+		key: &[u8],
+		begin: u64,
+		end: u64,
+	) -> Result<Vec<(u64, u32)>, ChangesTrieError<u64>> {
+		let roots_storage = InMemoryChangesTrieStorage::<Blake2Hasher, u64>::with_blocks(self.roots.clone());
+		key_changes_proof_check::<Blake2Hasher, u64>(
+			self.config_range(),
+			&roots_storage,
+			proof,
+			begin,
+			&self.anchor(end),
+			self.best_block,
+			None,
+			key,
+		).map(sorted_changes)
+	}
+}
+
+/// Sort key changes in descending order.
+fn sorted_changes(mut changes: Vec<(u64, u32)>) -> Vec<(u64, u32)> {
+	changes.sort_by(|a, b| b.cmp(a));
+	changes
+}
+
+/// Remembers how many digest input blocks have been walked by the last changes trie build.
+#[derive(Default)]
+struct DigestObserver {
+	digest_blocks_walked: Mutex<u64>,
+}
+
+impl ExecutionObserver for DigestObserver {
+	fn changes_trie_build(&self, stats: &ChangesTrieBuildStats) {
+		*self.digest_blocks_walked.lock().unwrap() = stats.digest_blocks_walked;
+	}
+}
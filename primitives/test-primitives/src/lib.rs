@@ -83,5 +83,6 @@ pub fn changes_trie_config() -> sp_core::ChangesTrieConfiguration {
 	sp_core::ChangesTrieConfiguration {
 		digest_interval: 4,
 		digest_levels: 2,
+		..Default::default()
 	}
 }